@@ -0,0 +1,59 @@
+// Compares the memory taken by transaction records before and after they were made compact.
+// Run with 'cargo bench --bench record_memory'.
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::Instant;
+
+use octopus::{Transaction, TransactionID, TransactionRecord, TransactionType};
+use rust_decimal::Decimal;
+
+const RECORDS: u32 = 1_000_000;
+
+// The layout records had before, a full copy of the accepted Transaction
+#[allow(dead_code)]
+struct LegacyRecord {
+    transaction: Transaction,
+    is_disputed: bool,
+}
+
+fn fill<V>(make: impl Fn(TransactionID) -> V) -> (HashMap<TransactionID, V>, f64) {
+    let start = Instant::now();
+    let map = (0..RECORDS)
+        .map(|tx| (tx, make(tx)))
+        .collect::<HashMap<_, _>>();
+    (map, start.elapsed().as_secs_f64())
+}
+
+// Bytes held by the map's buckets, ignoring the few control bytes per bucket
+fn table_bytes<V>(map: &HashMap<TransactionID, V>) -> usize {
+    map.capacity() * (size_of::<TransactionID>() + size_of::<V>())
+}
+
+fn main() {
+    let amount = Decimal::new(12345, 4);
+    let (legacy, legacy_secs) = fill(|tx| LegacyRecord {
+        transaction: Transaction::new(
+            TransactionType::Deposit,
+            (tx % 1000) as u16,
+            tx,
+            Some(amount),
+        ),
+        is_disputed: false,
+    });
+    let (compact, compact_secs) =
+        fill(|tx| TransactionRecord::new(&TransactionType::Deposit, (tx % 1000) as u16, amount));
+
+    println!("{} records", RECORDS);
+    println!(
+        "legacy:  {:>3} bytes/record, {:>6.1} MiB, filled in {:.3}s",
+        size_of::<LegacyRecord>(),
+        table_bytes(&legacy) as f64 / (1024.0 * 1024.0),
+        legacy_secs
+    );
+    println!(
+        "compact: {:>3} bytes/record, {:>6.1} MiB, filled in {:.3}s",
+        size_of::<TransactionRecord>(),
+        table_bytes(&compact) as f64 / (1024.0 * 1024.0),
+        compact_secs
+    );
+}
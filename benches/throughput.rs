@@ -0,0 +1,140 @@
+// Throughput of Database::process and of the whole CSV path on synthetic workloads, to catch
+// performance regressions. Run with 'cargo bench --bench throughput'.
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use csv::ReaderBuilder;
+use octopus::{ClientID, CsvColumns, Database, Transaction, TransactionID, TransactionType};
+use rust_decimal::Decimal;
+
+const CLIENTS: u16 = 1_000;
+const TRANSACTIONS: u32 = 20_000;
+// Percentages of deposits that get disputed
+const DISPUTE_RATES: [u32; 3] = [0, 5, 20];
+
+// xorshift, so workloads are the same from run to run without pulling in a rand crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+fn transaction(
+    tx_type: TransactionType,
+    client: ClientID,
+    tx: TransactionID,
+    amount: Option<Decimal>,
+) -> Transaction {
+    Transaction::new(tx_type, client, tx, amount)
+}
+
+// Mostly deposits and withdrawals spread over the clients. Disputed deposits are settled later
+// on, half resolved and half charged back.
+fn workload(clients: u16, transactions: u32, dispute_rate: u32) -> Vec<Transaction> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut workload = Vec::with_capacity(transactions as usize);
+    let mut open_disputes = Vec::new();
+    for tx in 1..=transactions {
+        let client = rng.below(clients as u64) as ClientID;
+        let amount = Some(Decimal::new(rng.below(1_000_000) as i64 + 1, 4));
+        match rng.below(100) {
+            0..70 => {
+                workload.push(transaction(TransactionType::Deposit, client, tx, amount));
+                if (rng.below(100) as u32) < dispute_rate {
+                    workload.push(transaction(TransactionType::Dispute, client, tx, None));
+                    open_disputes.push((client, tx));
+                }
+            }
+            _ => workload.push(transaction(TransactionType::Withdrawal, client, tx, amount)),
+        }
+        if open_disputes.len() > 16 {
+            let (client, tx) = open_disputes.swap_remove(rng.below(16) as usize);
+            let settle = match rng.below(2) {
+                0 => TransactionType::Resolve,
+                _ => TransactionType::Chargeback,
+            };
+            workload.push(transaction(settle, client, tx, None));
+        }
+    }
+    workload
+}
+
+fn to_csv(workload: &[Transaction]) -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for transaction in workload {
+        let tx_type = match transaction.tx_type {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            _ => "chargeback",
+        };
+        let amount = transaction
+            .amount
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        csv.push_str(&format!(
+            "{}, {}, {}, {}\n",
+            tx_type, transaction.client, transaction.tx, amount
+        ));
+    }
+    csv
+}
+
+fn process(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process");
+    for rate in DISPUTE_RATES {
+        let workload = workload(CLIENTS, TRANSACTIONS, rate);
+        group.throughput(Throughput::Elements(workload.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("disputes", rate),
+            &workload,
+            |b, workload| {
+                b.iter(|| {
+                    let mut db = Database::default();
+                    for transaction in workload {
+                        // Rejections such as insufficient funds are part of the workload
+                        let _ = db.process(transaction);
+                    }
+                    db
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+// Parsing the way the binary does, trimmed and deserialized byte record by byte record, then
+// processing
+fn csv_end_to_end(c: &mut Criterion) {
+    let mut group = c.benchmark_group("csv");
+    for rate in DISPUTE_RATES {
+        let csv = to_csv(&workload(CLIENTS, TRANSACTIONS, rate));
+        group.throughput(Throughput::Bytes(csv.len() as u64));
+        group.bench_with_input(BenchmarkId::new("disputes", rate), &csv, |b, csv| {
+            b.iter(|| {
+                let mut db = Database::default();
+                let mut rdr = ReaderBuilder::new()
+                    .trim(csv::Trim::All)
+                    .from_reader(csv.as_bytes());
+                let columns = CsvColumns::new(rdr.byte_headers().unwrap());
+                let mut record = csv::ByteRecord::new();
+                while rdr.read_byte_record(&mut record).unwrap() {
+                    let _ = db.process(&columns.deserialize(&record).unwrap());
+                }
+                db
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, process, csv_end_to_end);
+criterion_main!(benches);
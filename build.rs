@@ -0,0 +1,12 @@
+// Only the grpc feature needs the gRPC code
+#[cfg(not(feature = "grpc"))]
+fn main() {}
+
+#[cfg(feature = "grpc")]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use a bundled protoc so building doesn't require one to be installed
+    // SAFETY: build scripts are single threaded
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?) };
+    tonic_build::compile_protos("proto/octopus.proto")?;
+    Ok(())
+}
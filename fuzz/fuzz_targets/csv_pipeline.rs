@@ -0,0 +1,72 @@
+// Arbitrary bytes through the CSV reader and into the engine, the way the binary reads its input:
+// the byte order mark skipped, records read as bytes and deserialized by CsvColumns with the
+// amount format the first byte picks. Malformed rows may be rejected but must never panic, and
+// whatever is accepted has to leave a consistent state behind.
+#![no_main]
+
+// The binary's own reader, it isn't part of the library. Error offsets aren't reported here.
+#[path = "../../src/bom.rs"]
+#[allow(dead_code)]
+mod bom;
+
+use bom::SkipBom;
+use csv::{ByteRecord, ReaderBuilder};
+use libfuzzer_sys::fuzz_target;
+use octopus::{AmountFormat, CsvColumns, Database, DecimalSeparator};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&options, input)) = data.split_first() else {
+        return;
+    };
+    let amount_format = AmountFormat {
+        decimal_separator: match options & 0b11 {
+            0 => None,
+            1 => Some(DecimalSeparator::Dot),
+            2 => Some(DecimalSeparator::Comma),
+            _ => Some(DecimalSeparator::Auto),
+        },
+        scientific: options & 0b100 != 0,
+        quoted: options & 0b1000 != 0,
+    };
+    let lenient = options & 0b10000 != 0;
+
+    let mut db = Database::default().with_admin_ops(true);
+    // Built as main.rs's process_csv builds it
+    let mut rdr = ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_reader(SkipBom::new(input));
+    let columns = match rdr.byte_headers() {
+        Ok(headers) if lenient => CsvColumns::lenient(headers),
+        Ok(headers) => CsvColumns::new(headers),
+        Err(_) => return,
+    }
+    .with_amount_format(amount_format);
+    let mut record = ByteRecord::new();
+    loop {
+        match rdr.read_byte_record(&mut record) {
+            Ok(true) => {
+                if let Ok(mut transaction) = columns.deserialize(&record) {
+                    if lenient {
+                        transaction.tx_type = transaction.tx_type.lenient();
+                    }
+                    let _ = db.process(&transaction);
+                }
+            }
+            Ok(false) => break,
+            // Reading goes on past a malformed row, as it does in the binary
+            Err(e) if !e.is_io_error() => continue,
+            Err(_) => break,
+        }
+    }
+    // cargo fuzz builds with debug assertions, so the checker is there
+    if let Err(violation) = db.check_invariants() {
+        panic!("{:?}", violation);
+    }
+    for entry in db.accounts() {
+        let (_, account) = entry.unwrap();
+        for (_, balance) in account.balances() {
+            db.precision().format(balance.total());
+        }
+    }
+});
@@ -0,0 +1,28 @@
+CREATE TABLE accounts (
+    client INTEGER PRIMARY KEY,
+    locked BOOLEAN NOT NULL,
+    status TEXT NOT NULL
+);
+
+CREATE TABLE balances (
+    client INTEGER NOT NULL REFERENCES accounts (client),
+    currency TEXT NOT NULL,
+    available NUMERIC NOT NULL,
+    held NUMERIC NOT NULL,
+    PRIMARY KEY (client, currency)
+);
+
+CREATE TABLE transactions (
+    scope INTEGER NOT NULL,
+    tx BIGINT NOT NULL,
+    type TEXT NOT NULL,
+    client INTEGER NOT NULL,
+    amount NUMERIC NOT NULL,
+    timestamp BIGINT,
+    currency TEXT,
+    disputed BOOLEAN NOT NULL,
+    resolved BOOLEAN NOT NULL,
+    charged_back BOOLEAN NOT NULL,
+    disputes SMALLINT NOT NULL,
+    PRIMARY KEY (scope, tx)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE pruned (
+    scope INTEGER NOT NULL,
+    tx BIGINT NOT NULL,
+    PRIMARY KEY (scope, tx)
+);
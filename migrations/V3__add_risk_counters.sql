@@ -0,0 +1,4 @@
+ALTER TABLE accounts
+    ADD COLUMN disputes BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN chargebacks BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN rejected_withdrawals BIGINT NOT NULL DEFAULT 0;
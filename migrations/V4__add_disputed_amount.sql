@@ -0,0 +1,2 @@
+ALTER TABLE transactions
+    ADD COLUMN disputed_amount NUMERIC;
@@ -0,0 +1,2 @@
+ALTER TABLE transactions
+    ADD COLUMN reversed BOOLEAN NOT NULL DEFAULT false;
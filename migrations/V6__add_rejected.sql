@@ -0,0 +1,2 @@
+ALTER TABLE transactions
+    ADD COLUMN rejected BOOLEAN NOT NULL DEFAULT false;
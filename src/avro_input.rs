@@ -0,0 +1,225 @@
+use apache_avro::{Reader, Schema, types::Value};
+use csv::StringRecord;
+use octopus::{Transaction, lenient_column};
+use rust_decimal::Decimal;
+use std::{io, io::Read, sync::Arc};
+
+use crate::report::{ErrorReporter, Location, RawRecord};
+use crate::stats::Stats;
+
+// Reads an Avro object container file of transaction records. Like Parquet, fields are named
+// like the CSV header and their values are deserialized as a CSV row would be. Strings, enums,
+// numbers, decimals and nullable unions of them are accepted, and timestamp logical types are
+// truncated to seconds. Locations are record numbers, 1 being the first record.
+pub fn process(
+    source: &Arc<str>,
+    input: impl Read,
+    lenient: bool,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    mut submit: impl FnMut(Transaction, Location),
+) {
+    let _span = tracing::info_span!("input", source = %source).entered();
+    let location = |line| Location {
+        source: Arc::clone(source),
+        line,
+    };
+    let fail = |line, e: String| {
+        stats.unparsable();
+        reporter.input_failed();
+        reporter.unparsable(
+            &location(line),
+            &csv::Error::from(io::Error::other(e)),
+            &RawRecord::default(),
+        );
+    };
+    let reader = match Reader::new(input) {
+        Ok(reader) => reader,
+        Err(e) => return fail(None, e.to_string()),
+    };
+    let (headers, scales) = match fields(reader.writer_schema()) {
+        Some((headers, scales)) if lenient => {
+            (headers.iter().map(lenient_column).collect(), scales)
+        }
+        Some(fields) => fields,
+        None => return fail(None, "expected a schema of records".to_string()),
+    };
+
+    let mut rows: u64 = 0;
+    let mut record = StringRecord::new();
+    for value in reader {
+        if reporter.halted() {
+            return;
+        }
+        rows += 1;
+        let fields = match value {
+            Ok(Value::Record(fields)) => fields,
+            Ok(_) => return fail(Some(rows), "expected a record".to_string()),
+            Err(e) => return fail(Some(rows), e.to_string()),
+        };
+        record.clear();
+        let result = match row(&fields, &scales, &mut record) {
+            Err(e) => Err(csv::Error::from(io::Error::other(e))),
+            Ok(()) => record.deserialize::<Transaction>(Some(&headers)),
+        };
+        match result {
+            Ok(transaction) => submit(transaction, location(Some(rows))),
+            Err(e) => {
+                stats.unparsable();
+                let raw = RawRecord::new(record.as_byte_record(), None);
+                reporter.unparsable(&location(Some(rows)), &e, &raw);
+            }
+        }
+    }
+    tracing::debug!(rows, "input processed");
+}
+
+// The field names, as a header, and the scale of the decimal ones
+pub fn fields(schema: &Schema) -> Option<(StringRecord, Vec<Option<u32>>)> {
+    let Schema::Record(record) = schema else {
+        return None;
+    };
+    let headers = record.fields.iter().map(|f| f.name.as_str()).collect();
+    let scales = record.fields.iter().map(|f| scale(&f.schema)).collect();
+    Some((headers, scales))
+}
+
+// Adds the values of a record to the row as CSV fields, in the order of the header fields()
+// gives. Values that can't be a field are left empty, the first of them being the error.
+pub fn row(
+    fields: &[(String, Value)],
+    scales: &[Option<u32>],
+    row: &mut StringRecord,
+) -> Result<(), String> {
+    let mut unsupported = None;
+    for ((_, value), scale) in fields.iter().zip(scales) {
+        match field(value, *scale) {
+            Ok(field) => row.push_field(field.trim()),
+            Err(e) => {
+                unsupported.get_or_insert(e);
+                row.push_field("")
+            }
+        }
+    }
+    unsupported.map_or(Ok(()), Err)
+}
+
+fn scale(schema: &Schema) -> Option<u32> {
+    match schema {
+        Schema::Decimal(decimal) => u32::try_from(decimal.scale).ok(),
+        Schema::Union(union) => union.variants().iter().find_map(scale),
+        _ => None,
+    }
+}
+
+fn field(value: &Value, scale: Option<u32>) -> Result<String, String> {
+    Ok(match value {
+        Value::Null => String::new(),
+        Value::Union(_, value) => field(value, scale)?,
+        Value::String(s) | Value::Enum(_, s) => s.clone(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::Long(n) => n.to_string(),
+        Value::Float(n) => n.to_string(),
+        Value::Double(n) => n.to_string(),
+        Value::Decimal(decimal) => {
+            let bytes = Vec::<u8>::try_from(decimal).map_err(|e| e.to_string())?;
+            unscaled(&bytes)
+                .and_then(|n| Decimal::try_from_i128_with_scale(n, scale.unwrap_or(0)).ok())
+                .ok_or("decimal out of range")?
+                .to_string()
+        }
+        Value::TimestampMillis(n) | Value::LocalTimestampMillis(n) => {
+            n.div_euclid(1_000).to_string()
+        }
+        Value::TimestampMicros(n) | Value::LocalTimestampMicros(n) => {
+            n.div_euclid(1_000_000).to_string()
+        }
+        Value::TimestampNanos(n) | Value::LocalTimestampNanos(n) => {
+            n.div_euclid(1_000_000_000).to_string()
+        }
+        other => return Err(format!("unsupported Avro value {:?}", other)),
+    })
+}
+
+// Avro decimals are big-endian two's complement
+fn unscaled(bytes: &[u8]) -> Option<i128> {
+    if bytes.len() > 16 {
+        return None;
+    }
+    let fill = match bytes.first() {
+        Some(byte) if byte & 0x80 != 0 => 0xff,
+        _ => 0,
+    };
+    let mut buf = [fill; 16];
+    buf[16 - bytes.len()..].copy_from_slice(bytes);
+    Some(i128::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apache_avro::Writer;
+    use octopus::TransactionType;
+
+    const SCHEMA: &str = r#"{
+        "type": "record",
+        "name": "Transaction",
+        "fields": [
+            {"name": "type", "type": {"type": "enum", "name": "Type",
+                "symbols": ["deposit", "withdrawal", "dispute"]}},
+            {"name": "client", "type": "int"},
+            {"name": "tx", "type": "long"},
+            {"name": "amount", "type": ["null",
+                {"type": "bytes", "logicalType": "decimal", "precision": 10, "scale": 4}]}
+        ]
+    }"#;
+
+    #[test]
+    fn test_avro_records_become_transactions() {
+        let schema = Schema::parse_str(SCHEMA).unwrap();
+        let mut writer = Writer::new(&schema, Vec::new());
+        for (tx_type, index, client, amount) in [
+            ("deposit", 0, 1, Some(15_000i32)),
+            ("withdrawal", 1, 1, Some(-5_000)),
+            ("dispute", 2, 70_000, None),
+        ] {
+            let amount = match amount {
+                Some(n) => {
+                    Value::Union(1, Box::new(Value::Decimal(n.to_be_bytes().to_vec().into())))
+                }
+                None => Value::Union(0, Box::new(Value::Null)),
+            };
+            writer
+                .append(Value::Record(vec![
+                    ("type".into(), Value::Enum(index, tx_type.into())),
+                    ("client".into(), Value::Int(client)),
+                    ("tx".into(), Value::Long(1)),
+                    ("amount".into(), amount),
+                ]))
+                .unwrap();
+        }
+        let bytes = writer.into_inner().unwrap();
+
+        let (reporter, stats) = (ErrorReporter::new(None, None).unwrap(), Stats::new());
+        let mut transactions = Vec::new();
+        process(
+            &Arc::from("payments.avro"),
+            bytes.as_slice(),
+            false,
+            &reporter,
+            &stats,
+            |transaction, location| transactions.push((transaction, location.line)),
+        );
+
+        assert_eq!(transactions.len(), 2);
+        let (deposit, line) = &transactions[0];
+        assert_eq!(*line, Some(1));
+        assert_eq!(deposit.tx_type, TransactionType::Deposit);
+        assert_eq!(deposit.amount, Some(Decimal::new(15, 1)));
+        let (withdrawal, _) = &transactions[1];
+        assert_eq!(withdrawal.amount, Some(Decimal::new(-5, 1)));
+        // Client 70000 doesn't fit a client id
+        assert_eq!(stats.counts().unparsable, 1);
+    }
+}
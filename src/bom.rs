@@ -0,0 +1,102 @@
+use std::io::{self, Read};
+
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+// Drops the UTF-8 byte order mark Excel and other Windows tools start their CSV exports with,
+// which the csv crate would otherwise keep as part of the first header. Inputs without one are
+// passed through untouched.
+pub struct SkipBom<R> {
+    input: R,
+    // The first bytes of the input, until they are known not to be a BOM and handed out
+    start: Vec<u8>,
+    checked: bool,
+    skipped: bool,
+}
+
+impl<R: Read> SkipBom<R> {
+    pub fn new(input: R) -> Self {
+        SkipBom {
+            input,
+            start: Vec::with_capacity(BOM.len()),
+            checked: false,
+            skipped: false,
+        }
+    }
+
+    // Bytes dropped from the start of the input, to tell where in the file a position is
+    pub fn skipped(&self) -> u64 {
+        match self.skipped {
+            true => BOM.len() as u64,
+            false => 0,
+        }
+    }
+}
+
+impl<R: Read> Read for SkipBom<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.checked {
+            // Kept on errors, so a retry picks up where this one failed
+            (&mut self.input)
+                .take((BOM.len() - self.start.len()) as u64)
+                .read_to_end(&mut self.start)?;
+            self.checked = true;
+            if self.start == BOM {
+                self.start.clear();
+                self.skipped = true;
+            }
+        }
+        if self.start.is_empty() {
+            return self.input.read(buf);
+        }
+        let read = self.start.as_slice().read(buf)?;
+        self.start.drain(..read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8]) -> Vec<u8> {
+        let mut read = Vec::new();
+        SkipBom::new(input).read_to_end(&mut read).unwrap();
+        read
+    }
+
+    #[test]
+    fn test_bom_is_skipped() {
+        assert_eq!(read(b"\xef\xbb\xbftype,client\r\n"), b"type,client\r\n");
+        let mut input = SkipBom::new(&b"\xef\xbb\xbftype"[..]);
+        assert_eq!(input.skipped(), 0);
+        input.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(input.skipped(), 3);
+        // Only at the start of the input
+        assert_eq!(read(b"a\xef\xbb\xbf"), b"a\xef\xbb\xbf");
+    }
+
+    #[test]
+    fn test_inputs_without_bom_are_untouched() {
+        assert_eq!(read(b""), b"");
+        assert_eq!(read(b"t"), b"t");
+        assert_eq!(read(b"\xef\xbb"), b"\xef\xbb");
+        assert_eq!(read(b"type,client\n"), b"type,client\n");
+    }
+
+    #[test]
+    fn test_excel_export_parses_like_plain_csv() {
+        let excel = include_bytes!("../fixtures/excel_transactions.csv");
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(SkipBom::new(&excel[..]));
+        assert_eq!(
+            rdr.headers().unwrap(),
+            vec!["type", "client", "tx", "amount"]
+        );
+        let records = rdr.records().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0], vec!["deposit", "1", "1", "1.5"]);
+        // The last field of a row doesn't keep the \r of its CRLF
+        assert_eq!(records[3], vec!["dispute", "2", "2", ""]);
+    }
+}
@@ -0,0 +1,179 @@
+use octopus::{ClientID, LimitRule, TransactionType};
+use serde::Deserialize;
+use std::collections::HashSet;
+use toml::{Table, Value};
+
+// Turns a --config TOML file into the command line flags it stands for, so its settings go
+// through the same parsing and validation as the command line. Tables only group settings:
+// 'precision = 2' under [engine] is '--precision 2'. A boolean true is the bare flag, false
+// leaves it out, and 'inputs' lists the input files, returned apart from the flags.
+pub fn load(path: &str) -> Result<(Vec<String>, Vec<String>), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let table = text
+        .parse::<Table>()
+        .map_err(|e| format!("{}: {}", path, e.message()))?;
+    let (mut flags, mut inputs) = (Vec::new(), Vec::new());
+    to_flags(&table, &mut flags, &mut inputs).map_err(|e| format!("{}: {}", path, e))?;
+    Ok((flags, inputs))
+}
+
+// The rules of a --limits TOML file, one [[rule]] table each
+pub fn load_limits(path: &str) -> Result<Vec<LimitRule>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_limits(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse_limits(text: &str) -> Result<Vec<LimitRule>, String> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct LimitsFile {
+        #[serde(default)]
+        rule: Vec<LimitRule>,
+    }
+    let file: LimitsFile = toml::from_str(text).map_err(|e| e.message().to_string())?;
+    for rule in &file.rule {
+        if let Some(TransactionType::Other(name)) = &rule.tx_type {
+            return Err(format!("rule '{}': unknown type '{}'", rule.id, name));
+        }
+        if rule.max_amount.is_none() && rule.max_count.is_none() && rule.max_total.is_none() {
+            return Err(format!(
+                "rule '{}' sets none of max_amount, max_count and max_total",
+                rule.id
+            ));
+        }
+        if rule.max_amount.is_some_and(|max| max.is_sign_negative())
+            || rule.max_total.is_some_and(|max| max.is_sign_negative())
+        {
+            return Err(format!("rule '{}' has a negative limit", rule.id));
+        }
+    }
+    Ok(file.rule)
+}
+
+// The client IDs of a --blocklist or --allowlist file, one per line. Blank lines and anything
+// after a '#' are ignored.
+pub fn load_clients(path: &str) -> Result<HashSet<ClientID>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_clients(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse_clients(text: &str) -> Result<HashSet<ClientID>, String> {
+    let mut clients = HashSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let client = line.split('#').next().unwrap_or_default().trim();
+        if client.is_empty() {
+            continue;
+        }
+        clients.insert(
+            client
+                .parse()
+                .map_err(|_| format!("line {}: expected a client ID, got '{}'", i + 1, client))?,
+        );
+    }
+    Ok(clients)
+}
+
+fn to_flags(
+    table: &Table,
+    flags: &mut Vec<String>,
+    inputs: &mut Vec<String>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("config", _) => return Err("a config file cannot include another".to_string()),
+            (_, Value::Table(table)) => to_flags(table, flags, inputs)?,
+            ("inputs", Value::Array(files)) => {
+                for input in files {
+                    match input {
+                        Value::String(path) => inputs.push(path.clone()),
+                        other => return Err(format!("'inputs' expects file names, got {}", other)),
+                    }
+                }
+            }
+            (_, Value::Boolean(true)) => flags.push(format!("--{}", key)),
+            (_, Value::Boolean(false)) => (),
+            (_, Value::String(value)) => flags.extend([format!("--{}", key), value.clone()]),
+            (_, Value::Integer(_) | Value::Float(_)) => {
+                flags.extend([format!("--{}", key), value.to_string()])
+            }
+            (_, other) => return Err(format!("unsupported value for '{}': {}", key, other)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(toml: &str) -> Result<(Vec<String>, Vec<String>), String> {
+        let (mut flags, mut inputs) = (Vec::new(), Vec::new());
+        to_flags(&toml.parse::<Table>().unwrap(), &mut flags, &mut inputs)?;
+        Ok((flags, inputs))
+    }
+
+    #[test]
+    fn test_settings_become_flags() {
+        assert_eq!(
+            flags(
+                "inputs = ['a.csv', 'b.csv.gz']\n\
+                 [engine]\n\
+                 precision = 2\n\
+                 allow-negative-disputes = true\n\
+                 dispute-policy = 'deposits-and-withdrawals'\n\
+                 require-open = false\n\
+                 [output]\n\
+                 output-format = 'json'\n"
+            )
+            .unwrap(),
+            (
+                vec![
+                    "--allow-negative-disputes".to_string(),
+                    "--dispute-policy".to_string(),
+                    "deposits-and-withdrawals".to_string(),
+                    "--precision".to_string(),
+                    "2".to_string(),
+                    "--output-format".to_string(),
+                    "json".to_string(),
+                ],
+                vec!["a.csv".to_string(), "b.csv.gz".to_string()]
+            )
+        );
+        assert!(flags("config = 'other.toml'").is_err());
+        assert!(flags("threads = [1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_client_lists() {
+        assert_eq!(
+            parse_clients("# sanctioned\n7\n\n 12 # since March\n7\n").unwrap(),
+            HashSet::from([7, 12])
+        );
+        let err = parse_clients("1\nclient 2\n").unwrap_err();
+        assert_eq!(err, "line 2: expected a client ID, got 'client 2'");
+    }
+
+    #[test]
+    fn test_limit_rules() {
+        let rules = parse_limits(
+            "[[rule]]\n\
+             id = 'daily-deposits'\n\
+             type = 'deposit'\n\
+             max_count = 10\n\
+             max_total = 5000\n\
+             per = 'day'\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].max_total, Some(rust_decimal::dec!(5000)));
+        assert!(parse_limits("").unwrap().is_empty());
+        for bad in [
+            "[[rule]]\nid = 'a'\ntype = 'payout'\nmax_count = 1",
+            "[[rule]]\nid = 'a'\nper = 'day'",
+            "[[rule]]\nid = 'a'\nmax_amount = -1",
+            "[[rules]]\nid = 'a'\nmax_amount = 1",
+        ] {
+            assert!(parse_limits(bad).is_err(), "{}", bad);
+        }
+    }
+}
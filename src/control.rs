@@ -0,0 +1,154 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{Arc, mpsc},
+    thread,
+};
+
+use crate::report::ErrorReporter;
+use crate::stats::Stats;
+
+// Commands that need the Database, answered by the processing loop between two rows
+pub enum Request {
+    // The account table as CSV, followed by an empty line
+    Snapshot(mpsc::Sender<String>),
+    // Flushes --state-dir and the error report
+    Flush(mpsc::Sender<String>),
+}
+
+// The --control socket. Every connection may send one command per line and gets one answer per
+// command: 'stats' prints the counts so far as JSON and 'shutdown' stops reading the inputs, the
+// run then ending as if they were exhausted. 'snapshot' and 'flush' go through pending(), so they
+// wait for the next row when the input is idle.
+pub struct Control {
+    path: PathBuf,
+    requests: mpsc::Receiver<Request>,
+}
+
+impl Control {
+    pub fn start(path: &str, stats: Arc<Stats>, reporter: Arc<ErrorReporter>) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (sender, stats, reporter) =
+                    (sender.clone(), Arc::clone(&stats), Arc::clone(&reporter));
+                thread::spawn(move || {
+                    if let Err(err) = handle(stream, &sender, &stats, &reporter) {
+                        tracing::debug!(%err, "control connection failed");
+                    }
+                });
+            }
+        });
+        Ok(Control {
+            path: path.into(),
+            requests,
+        })
+    }
+
+    pub fn pending(&self) -> mpsc::TryIter<'_, Request> {
+        self.requests.try_iter()
+    }
+}
+
+// Removes the socket file, commands still waiting are answered with an error
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn handle(
+    stream: UnixStream,
+    sender: &mpsc::Sender<Request>,
+    stats: &Stats,
+    reporter: &ErrorReporter,
+) -> io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = match line?.trim() {
+            "" => continue,
+            "stats" => serde_json::to_string(&stats.counts())
+                .map(|counts| counts + "\n")
+                .unwrap_or_else(|e| format!("error {}\n", e)),
+            "shutdown" => {
+                tracing::info!("shutdown requested on the control socket");
+                reporter.stop();
+                "ok\n".to_string()
+            }
+            "snapshot" => ask(sender, Request::Snapshot),
+            "flush" => ask(sender, Request::Flush),
+            other => format!("error unknown command '{}'\n", other),
+        };
+        out.write_all(reply.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn ask(sender: &mpsc::Sender<Request>, request: fn(mpsc::Sender<String>) -> Request) -> String {
+    let (reply, answer) = mpsc::channel();
+    match sender
+        .send(request(reply))
+        .ok()
+        .and_then(|_| answer.recv().ok())
+    {
+        Some(answer) => answer,
+        None => "error processing finished\n".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_are_answered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.sock");
+        let stats = Arc::new(Stats::new());
+        let reporter = Arc::new(ErrorReporter::new(None, None).unwrap());
+        let control = Control::start(
+            path.to_str().unwrap(),
+            Arc::clone(&stats),
+            Arc::clone(&reporter),
+        )
+        .unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        let send = |command: &str| {
+            (&stream)
+                .write_all(format!("{}\n", command).as_bytes())
+                .unwrap();
+        };
+        send("stats");
+        assert!(
+            lines
+                .next()
+                .unwrap()
+                .unwrap()
+                .starts_with("{\"processed\":0")
+        );
+        send("bogus");
+        assert!(lines.next().unwrap().unwrap().starts_with("error unknown"));
+        send("flush");
+        let request = loop {
+            if let Some(request) = control.pending().next() {
+                break request;
+            }
+            thread::yield_now();
+        };
+        match request {
+            Request::Flush(reply) => reply.send("ok\n".to_string()).unwrap(),
+            Request::Snapshot(_) => panic!("expected a flush"),
+        }
+        assert_eq!(lines.next().unwrap().unwrap(), "ok");
+        send("shutdown");
+        assert_eq!(lines.next().unwrap().unwrap(), "ok");
+        assert!(reporter.halted());
+
+        drop(control);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,344 @@
+use octopus::{AccountRow, ClientID, Currency, Database, PrecisionPolicy, storage::StorageError};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufWriter, Read, Write},
+};
+
+use crate::bom::SkipBom;
+use crate::cli::OutputFormat;
+
+// One balance of an accounts file as octopus writes it. Extra columns such as those of
+// --extended-output are ignored, and files without a currency column hold one balance per client.
+#[derive(Debug, Clone, Deserialize)]
+struct AccountLine {
+    client: ClientID,
+    #[serde(default)]
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+impl From<AccountRow> for AccountLine {
+    fn from(row: AccountRow) -> Self {
+        AccountLine {
+            client: row.client,
+            currency: row.currency,
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReconcileError {
+    // The expected balances file isn't an accounts CSV
+    Expected(csv::Error),
+    Storage(StorageError),
+}
+
+impl std::fmt::Display for ReconcileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReconcileError::Expected(err) => write!(f, "{}", err),
+            ReconcileError::Storage(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReconcileError {}
+
+type Accounts = BTreeMap<(ClientID, Option<Currency>), AccountLine>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    // Only in the new file
+    New,
+    // Only in the old file
+    Removed,
+    // In both, with another balance or lock
+    Changed,
+}
+
+// How one balance differs between two runs. The amounts are new minus old, a balance missing
+// from one file counting as zero there.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DiffLine {
+    pub client: ClientID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub change: Change,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    // true when the account got locked since the old run, false when it got unlocked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked: Option<bool>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct AccountsDiff {
+    pub lines: Vec<DiffLine>,
+}
+
+impl AccountsDiff {
+    // Compares two accounts CSV files, listing every balance that differs by client and currency
+    pub fn compare(
+        old: impl Read,
+        new: impl Read,
+        precision: PrecisionPolicy,
+    ) -> Result<Self, csv::Error> {
+        Ok(AccountsDiff::between(
+            read(old)?,
+            read(new)?,
+            precision,
+            Decimal::ZERO,
+        ))
+    }
+
+    // Compares the Database's balances, as new, with an accounts CSV file of expected ones, as
+    // old. Amounts off by no more than the tolerance are taken to match.
+    pub fn reconcile(
+        expected: impl Read,
+        db: &Database,
+        tolerance: Decimal,
+    ) -> Result<Self, ReconcileError> {
+        let expected = read(expected).map_err(ReconcileError::Expected)?;
+        let mut computed = Accounts::new();
+        for account in db.accounts() {
+            let (client, account) = account.map_err(ReconcileError::Storage)?;
+            for row in AccountRow::rows(db.precision(), client, &account) {
+                computed.insert((row.client, row.currency), AccountLine::from(row));
+            }
+        }
+        Ok(AccountsDiff::between(
+            expected,
+            computed,
+            db.precision(),
+            tolerance,
+        ))
+    }
+
+    fn between(
+        old: Accounts,
+        new: Accounts,
+        precision: PrecisionPolicy,
+        tolerance: Decimal,
+    ) -> Self {
+        // Locking applies to the whole account, whichever of its rows says so
+        let locked = |accounts: &Accounts| {
+            accounts
+                .values()
+                .filter(|line| line.locked)
+                .map(|line| line.client)
+                .collect::<BTreeSet<_>>()
+        };
+        let (old_locked, new_locked) = (locked(&old), locked(&new));
+        let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+        let mut lines = Vec::new();
+        for &(client, currency) in keys {
+            let (before, after) = (old.get(&(client, currency)), new.get(&(client, currency)));
+            let change = match (before, after) {
+                (None, _) => Change::New,
+                (_, None) => Change::Removed,
+                (Some(_), Some(_)) => Change::Changed,
+            };
+            let amounts = |line: Option<&AccountLine>| {
+                line.map_or((Decimal::ZERO, Decimal::ZERO, Decimal::ZERO), |line| {
+                    (line.available, line.held, line.total)
+                })
+            };
+            let ((old_available, old_held, old_total), (new_available, new_held, new_total)) =
+                (amounts(before), amounts(after));
+            let (was_locked, is_locked) =
+                (old_locked.contains(&client), new_locked.contains(&client));
+            let moved = [
+                new_available - old_available,
+                new_held - old_held,
+                new_total - old_total,
+            ]
+            .iter()
+            .any(|delta| delta.abs() > tolerance);
+            if change == Change::Changed && !moved && was_locked == is_locked {
+                continue;
+            }
+            lines.push(DiffLine {
+                client,
+                currency,
+                change,
+                available: precision.format(new_available - old_available),
+                held: precision.format(new_held - old_held),
+                total: precision.format(new_total - old_total),
+                locked: (was_locked != is_locked).then_some(is_locked),
+            });
+        }
+        AccountsDiff { lines }
+    }
+
+    pub fn count(&self, change: Change) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| line.change == change)
+            .count()
+    }
+
+    // Balances of accounts locked since the old run, one per client
+    pub fn newly_locked(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| line.locked == Some(true))
+            .map(|line| line.client)
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    pub fn write(&self, format: OutputFormat, output: impl Write) -> io::Result<()> {
+        match format {
+            OutputFormat::Csv => {
+                let mut wtr = csv::Writer::from_writer(output);
+                wtr.write_record([
+                    "client",
+                    "currency",
+                    "change",
+                    "available",
+                    "held",
+                    "total",
+                    "locked",
+                ])?;
+                for line in &self.lines {
+                    wtr.write_record([
+                        line.client.to_string(),
+                        line.currency
+                            .map(|currency| currency.to_string())
+                            .unwrap_or_default(),
+                        match line.change {
+                            Change::New => "new",
+                            Change::Removed => "removed",
+                            Change::Changed => "changed",
+                        }
+                        .to_string(),
+                        line.available.clone(),
+                        line.held.clone(),
+                        line.total.clone(),
+                        line.locked
+                            .map(|locked| locked.to_string())
+                            .unwrap_or_default(),
+                    ])?;
+                }
+                wtr.flush()
+            }
+            OutputFormat::Json => {
+                let mut out = BufWriter::new(output);
+                serde_json::to_writer(&mut out, &self.lines)?;
+                writeln!(out)?;
+                out.flush()
+            }
+            OutputFormat::Ndjson => {
+                let mut out = BufWriter::new(output);
+                for line in &self.lines {
+                    serde_json::to_writer(&mut out, line)?;
+                    writeln!(out)?;
+                }
+                out.flush()
+            }
+        }
+    }
+}
+
+fn read(input: impl Read) -> Result<Accounts, csv::Error> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(SkipBom::new(input))
+        .deserialize::<AccountLine>()
+        .map(|line| line.map(|line| ((line.client, line.currency), line)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_reports_changes() {
+        let old = "client,currency,available,held,total,locked\n\
+                   1,,10.0000,0.0000,10.0000,false\n\
+                   1,EUR,5.0000,0.0000,5.0000,false\n\
+                   2,,3.0000,0.0000,3.0000,false\n\
+                   3,,1.0000,0.0000,1.0000,false\n";
+        let new = "client,currency,available,held,total,locked\n\
+                   1,,10.0000,0.0000,10.0000,true\n\
+                   1,EUR,2.5000,2.5000,5.0000,true\n\
+                   3,,1.0000,0.0000,1.0000,false\n\
+                   4,,7.0000,0.0000,7.0000,false\n";
+        let diff =
+            AccountsDiff::compare(old.as_bytes(), new.as_bytes(), PrecisionPolicy::default())
+                .unwrap();
+        assert_eq!(diff.count(Change::New), 1);
+        assert_eq!(diff.count(Change::Removed), 1);
+        assert_eq!(diff.newly_locked(), 1);
+
+        let mut csv = Vec::new();
+        diff.write(OutputFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,currency,change,available,held,total,locked\n\
+             1,,changed,0.0000,0.0000,0.0000,true\n\
+             1,EUR,changed,-2.5000,2.5000,0.0000,true\n\
+             2,,removed,-3.0000,0.0000,-3.0000,\n\
+             4,,new,7.0000,0.0000,7.0000,\n"
+        );
+        // Files without a currency column, and with columns the diff doesn't know
+        let old = "client,available,held,total,locked,disputes\n1,1.5,0,1.5,false,0\n";
+        let diff =
+            AccountsDiff::compare(old.as_bytes(), old.as_bytes(), PrecisionPolicy::default())
+                .unwrap();
+        assert_eq!(diff, AccountsDiff::default());
+        assert!(
+            AccountsDiff::compare(
+                "client,available\n1,x\n".as_bytes(),
+                old.as_bytes(),
+                PrecisionPolicy::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_reconcile_with_tolerance() {
+        let mut db = Database::default();
+        for (client, tx, amount) in [(1, 1, "10.004"), (2, 2, "3")] {
+            db.process(&octopus::Transaction::new(
+                octopus::TransactionType::Deposit,
+                client,
+                tx,
+                Some(amount.parse().unwrap()),
+            ))
+            .unwrap();
+        }
+        let expected = "client,available,held,total,locked\n\
+                        1,10.0000,0.0000,10.0000,false\n\
+                        2,3.5000,0.0000,3.5000,false\n";
+        let diff =
+            AccountsDiff::reconcile(expected.as_bytes(), &db, "0.01".parse().unwrap()).unwrap();
+        assert_eq!(
+            diff.lines,
+            vec![DiffLine {
+                client: 2,
+                currency: None,
+                change: Change::Changed,
+                available: "-0.5000".to_string(),
+                held: "0.0000".to_string(),
+                total: "-0.5000".to_string(),
+                locked: None,
+            }]
+        );
+        let diff = AccountsDiff::reconcile(expected.as_bytes(), &db, Decimal::ZERO).unwrap();
+        assert_eq!(diff.lines.len(), 2);
+    }
+}
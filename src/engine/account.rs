@@ -0,0 +1,798 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, io};
+
+use super::currency::Currency;
+use super::ledger::LedgerEvent;
+use super::policy::PrecisionPolicy;
+use super::transaction::ClientID;
+
+// Funds held in one currency
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Balance {
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+}
+
+impl Balance {
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.available + self.held
+    }
+}
+
+// Where an account is in its life. Accounts spring into existence with their first transaction
+// unless open transactions are required.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AccountStatus {
+    #[default]
+    Implicit,
+    // Through an open transaction
+    Open,
+    // For good, every later transaction is rejected
+    Closed,
+}
+
+// How often a client opened disputes, was charged back and had withdrawals rejected, for spotting
+// risky clients
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RiskCounters {
+    pub disputes: u32,
+    pub chargebacks: u32,
+    pub rejected_withdrawals: u32,
+}
+
+impl RiskCounters {
+    // Whether the client was charged back more than max_chargebacks times
+    pub fn exceeds(&self, max_chargebacks: u32) -> bool {
+        self.chargebacks > max_chargebacks
+    }
+}
+
+// One balance per currency the client used, None being transactions without a currency. The
+// lock applies to the whole account.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Account {
+    pub(crate) balances: BTreeMap<Option<Currency>, Balance>,
+    pub(crate) locked: bool,
+    pub(crate) status: AccountStatus,
+    pub(crate) risk: RiskCounters,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    Locked,
+    InsufficientFunds,
+    NotLocked,
+    // The result doesn't fit in a Decimal
+    Overflow,
+    // A fee would take available below the fee floor
+    FeeFloorExceeded,
+    Closed,
+    // An open transaction for an account opened before
+    AlreadyOpen,
+    // A close while funds are left or owed
+    NonZeroBalance,
+}
+pub type AccountResult = Result<(), AccountError>;
+
+impl AccountError {
+    // Stable identifier for reports and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::Locked => "account_locked",
+            AccountError::InsufficientFunds => "insufficient_funds",
+            AccountError::NotLocked => "not_locked",
+            AccountError::Overflow => "overflow",
+            AccountError::FeeFloorExceeded => "fee_floor_exceeded",
+            AccountError::Closed => "account_closed",
+            AccountError::AlreadyOpen => "already_open",
+            AccountError::NonZeroBalance => "non_zero_balance",
+        }
+    }
+
+    // Stable numeric identifier, 100 and up so it never collides with TransactionError's
+    pub fn number(&self) -> u16 {
+        match self {
+            AccountError::Locked => 101,
+            AccountError::InsufficientFunds => 102,
+            AccountError::NotLocked => 103,
+            AccountError::Overflow => 104,
+            AccountError::FeeFloorExceeded => 105,
+            AccountError::Closed => 106,
+            AccountError::AlreadyOpen => 107,
+            AccountError::NonZeroBalance => 108,
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccountError::Locked => "account is locked",
+            AccountError::InsufficientFunds => "insufficient funds",
+            AccountError::NotLocked => "account is not locked",
+            AccountError::Overflow => "amount out of range",
+            AccountError::FeeFloorExceeded => "fee would exceed the fee floor",
+            AccountError::Closed => "account is closed",
+            AccountError::AlreadyOpen => "account is already open",
+            AccountError::NonZeroBalance => "account still holds or owes funds",
+        })
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl Account {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // available(), held() and get_total() are those of the balance without a currency, the only
+    // one unless transactions name a currency
+    pub fn available(&self) -> Decimal {
+        self.balance(None).available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.balance(None).held
+    }
+
+    pub fn balance(&self, currency: Option<Currency>) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    // Every balance by currency. An account that never held funds reports a zero balance without
+    // a currency, so it still shows up once in the output.
+    pub fn balances(&self) -> impl Iterator<Item = (Option<Currency>, Balance)> + '_ {
+        let empty = self
+            .balances
+            .is_empty()
+            .then_some((None, Balance::default()));
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (*currency, *balance))
+            .chain(empty)
+    }
+
+    // Whether some balance has a currency, which gives CSV output its currency column
+    pub fn has_currency(&self) -> bool {
+        self.balances.keys().any(Option::is_some)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    pub fn risk(&self) -> RiskCounters {
+        self.risk
+    }
+
+    // Closed accounts reject everything, locked ones everything but admin operations
+    fn check_usable(&self) -> AccountResult {
+        match (self.status, self.locked) {
+            (AccountStatus::Closed, _) => Err(AccountError::Closed),
+            (_, true) => Err(AccountError::Locked),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn open(&mut self) -> AccountResult {
+        match self.status {
+            AccountStatus::Implicit => {
+                self.status = AccountStatus::Open;
+                Ok(())
+            }
+            AccountStatus::Open => Err(AccountError::AlreadyOpen),
+            AccountStatus::Closed => Err(AccountError::Closed),
+        }
+    }
+
+    // Only an account with nothing available or held in any currency can be closed
+    pub(crate) fn close(&mut self) -> AccountResult {
+        self.check_usable()?;
+        if self
+            .balances
+            .values()
+            .any(|balance| balance.available != Decimal::ZERO || balance.held != Decimal::ZERO)
+        {
+            return Err(AccountError::NonZeroBalance);
+        }
+        self.status = AccountStatus::Closed;
+        Ok(())
+    }
+
+    pub(crate) fn deposit(&mut self, currency: Option<Currency>, amount: Decimal) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        self.update(currency, add(balance.available, amount)?, balance.held)
+    }
+
+    pub(crate) fn withdraw(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> AccountResult {
+        self.withdraw_into_overdraft(currency, amount, Decimal::ZERO)
+    }
+
+    // A withdrawal that may take available down to -limit
+    pub(crate) fn withdraw_into_overdraft(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+        limit: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        let available = sub(balance.available, amount)?;
+        if available < -limit {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.update(currency, available, balance.held)
+    }
+
+    pub(crate) fn dispute(&mut self, currency: Option<Currency>, amount: Decimal) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        if balance.available < amount {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.update(
+            currency,
+            sub(balance.available, amount)?,
+            add(balance.held, amount)?,
+        )?;
+        self.risk.disputes = self.risk.disputes.saturating_add(1);
+        Ok(())
+    }
+
+    // Holds the disputed amount even if that drives available negative
+    pub(crate) fn dispute_into_debt(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        self.update(
+            currency,
+            sub(balance.available, amount)?,
+            add(balance.held, amount)?,
+        )?;
+        self.risk.disputes = self.risk.disputes.saturating_add(1);
+        Ok(())
+    }
+
+    pub(crate) fn resolve(&mut self, currency: Option<Currency>, amount: Decimal) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        if balance.held < amount {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.update(
+            currency,
+            add(balance.available, amount)?,
+            sub(balance.held, amount)?,
+        )
+    }
+
+    pub(crate) fn chargeback(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        if balance.held < amount {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.update(currency, balance.available, sub(balance.held, amount)?)?;
+        self.locked = true;
+        self.risk.chargebacks = self.risk.chargebacks.saturating_add(1);
+        Ok(())
+    }
+
+    // Debits a fee even beyond available funds, as long as available stays at or above floor
+    pub(crate) fn charge_fee(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+        floor: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        let available = sub(balance.available, amount)?;
+        if available < floor {
+            return Err(AccountError::FeeFloorExceeded);
+        }
+        self.update(currency, available, balance.held)
+    }
+
+    // A disputed withdrawal has already left the account, so the disputed amount is credited
+    // into held rather than moved out of available
+    pub(crate) fn dispute_withdrawal(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        self.update(currency, balance.available, add(balance.held, amount)?)?;
+        self.risk.disputes = self.risk.disputes.saturating_add(1);
+        Ok(())
+    }
+
+    // The withdrawal stands, so the held credit is released
+    pub(crate) fn resolve_withdrawal(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        if balance.held < amount {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.update(currency, balance.available, sub(balance.held, amount)?)
+    }
+
+    // The withdrawal is reversed, so the held credit becomes available again
+    pub(crate) fn chargeback_withdrawal(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> AccountResult {
+        let balance = self.balance(currency);
+        self.check_usable()?;
+        if balance.held < amount {
+            return Err(AccountError::InsufficientFunds);
+        }
+        self.update(
+            currency,
+            add(balance.available, amount)?,
+            sub(balance.held, amount)?,
+        )?;
+        self.locked = true;
+        self.risk.chargebacks = self.risk.chargebacks.saturating_add(1);
+        Ok(())
+    }
+
+    // Administrative release of an account locked by a chargeback
+    pub(crate) fn unlock(&mut self) -> AccountResult {
+        if !self.locked {
+            return Err(AccountError::NotLocked);
+        }
+        self.locked = false;
+        Ok(())
+    }
+
+    pub fn get_total(&self) -> Decimal {
+        self.balance(None).total()
+    }
+
+    // Runs op as if the account weren't locked, a locked account stays locked afterwards
+    pub(crate) fn ignoring_lock(
+        &mut self,
+        op: impl FnOnce(&mut Account) -> AccountResult,
+    ) -> AccountResult {
+        let locked = self.locked;
+        self.locked = false;
+        let result = op(self);
+        self.locked |= locked;
+        result
+    }
+
+    // Replays an event the ledger recorded, skipping the checks of the live operations
+    pub(crate) fn apply(&mut self, event: &LedgerEvent) -> AccountResult {
+        match *event {
+            LedgerEvent::FundsCredited {
+                currency, amount, ..
+            } => {
+                let balance = self.balance(currency);
+                self.update(currency, add(balance.available, amount)?, balance.held)
+            }
+            LedgerEvent::FundsDebited {
+                currency, amount, ..
+            } => {
+                let balance = self.balance(currency);
+                self.update(currency, sub(balance.available, amount)?, balance.held)
+            }
+            LedgerEvent::FundsHeld {
+                currency, amount, ..
+            } => {
+                let balance = self.balance(currency);
+                self.update(currency, balance.available, add(balance.held, amount)?)
+            }
+            LedgerEvent::FundsReleased {
+                currency, amount, ..
+            } => {
+                let balance = self.balance(currency);
+                self.update(currency, balance.available, sub(balance.held, amount)?)
+            }
+            LedgerEvent::AccountLocked { .. } => {
+                self.locked = true;
+                Ok(())
+            }
+            LedgerEvent::AccountUnlocked { .. } => {
+                self.locked = false;
+                Ok(())
+            }
+            LedgerEvent::StatusChanged { status, .. } => {
+                self.status = status;
+                Ok(())
+            }
+            LedgerEvent::RiskChanged { risk, .. } => {
+                self.risk = risk;
+                Ok(())
+            }
+            LedgerEvent::AccountOpened { .. } | LedgerEvent::RecordWritten { .. } => Ok(()),
+        }
+    }
+
+    // Balances are only written once both, and their total, are known to fit in a Decimal,
+    // so a failed operation leaves the account untouched and totals can't overflow
+    fn update(
+        &mut self,
+        currency: Option<Currency>,
+        available: Decimal,
+        held: Decimal,
+    ) -> AccountResult {
+        available.checked_add(held).ok_or(AccountError::Overflow)?;
+        self.balances.insert(currency, Balance { available, held });
+        Ok(())
+    }
+}
+
+// One balance of an account, as the output files and the HTTP API report it. Amounts carry exactly --precision decimal places and serialize as strings, so no
+// precision is lost to JSON floats.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountRow {
+    pub client: ClientID,
+    // Left out for the balance without a currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl AccountRow {
+    // The columns of CSV output. The currency column is only there when some balance has a
+    // currency, so feeds without currencies keep their five columns.
+    pub const CSV_HEADER: &[&str] = &["client", "currency", "available", "held", "total", "locked"];
+
+    pub fn new(
+        precision: PrecisionPolicy,
+        client: ClientID,
+        currency: Option<Currency>,
+        account: &Account,
+    ) -> Self {
+        let balance = account.balance(currency);
+        AccountRow {
+            client,
+            currency,
+            available: precision.fixed(balance.available()),
+            held: precision.fixed(balance.held()),
+            total: precision.fixed(balance.total()),
+            locked: account.is_locked(),
+        }
+    }
+
+    // One row per currency the account holds
+    pub fn rows(
+        precision: PrecisionPolicy,
+        client: ClientID,
+        account: &Account,
+    ) -> impl Iterator<Item = AccountRow> + '_ {
+        account
+            .balances()
+            .map(move |(currency, _)| AccountRow::new(precision, client, currency, account))
+    }
+
+    // Writes the row as CSV, as a tuple since a CSV row can't leave the currency out the way JSON
+    // does. Without `currency` the currency column is left out, the slice holding it being empty.
+    pub fn write_csv(
+        &self,
+        wtr: &mut csv::Writer<impl io::Write>,
+        currency: bool,
+    ) -> csv::Result<()> {
+        wtr.serialize((
+            self.client,
+            currency.then_some(self.currency).as_slice(),
+            self.available,
+            self.held,
+            self.total,
+            self.locked,
+        ))
+    }
+
+    // The header of CSV output with these columns, such as CSV_HEADER, left without the currency
+    // column unless `currency`
+    pub fn csv_header<'a>(columns: &[&'a str], currency: bool) -> Vec<&'a str> {
+        columns
+            .iter()
+            .copied()
+            .filter(|column| currency || *column != "currency")
+            .collect()
+    }
+}
+
+fn add(a: Decimal, b: Decimal) -> Result<Decimal, AccountError> {
+    a.checked_add(b).ok_or(AccountError::Overflow)
+}
+
+fn sub(a: Decimal, b: Decimal) -> Result<Decimal, AccountError> {
+    a.checked_sub(b).ok_or(AccountError::Overflow)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_rows_serialize_at_the_output_precision() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.5)).unwrap();
+        let precision = PrecisionPolicy {
+            decimal_places: 2,
+            ..PrecisionPolicy::default()
+        };
+        let row = AccountRow::new(precision, 1, None, &acc);
+        assert_eq!(
+            serde_json::to_string(&row).unwrap(),
+            r#"{"client":1,"available":"10.50","held":"0.00","total":"10.50","locked":false}"#
+        );
+    }
+
+    #[test]
+    fn test_csv_rows_have_a_currency_column_only_with_currencies() {
+        let mut acc = Account::new();
+        acc.deposit(Currency::new("EUR"), dec!(1.5)).unwrap();
+        let row = AccountRow::new(PrecisionPolicy::default(), 1, Currency::new("EUR"), &acc);
+        let csv = |currency| {
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(Vec::new());
+            wtr.write_record(AccountRow::csv_header(AccountRow::CSV_HEADER, currency))
+                .unwrap();
+            row.write_csv(&mut wtr, currency).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        assert!(acc.has_currency());
+        assert_eq!(
+            csv(true),
+            "client,currency,available,held,total,locked\n1,EUR,1.5000,0.0000,1.5000,false\n"
+        );
+        assert_eq!(
+            csv(false),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+        assert!(!Account::new().has_currency());
+    }
+
+    #[test]
+    fn test_deposit_increases_available_and_total() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.5)).unwrap();
+        assert_eq!(acc.available(), dec!(10.5));
+        assert_eq!(acc.get_total(), dec!(10.5));
+    }
+
+    #[test]
+    fn test_withdraw_succeeds_when_sufficient_funds() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.withdraw(None, dec!(4.0)).unwrap();
+        assert_eq!(acc.available(), dec!(6.0));
+        assert_eq!(acc.get_total(), dec!(6.0));
+    }
+
+    #[test]
+    fn test_withdraw_does_nothing_if_insufficient_funds() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(5.0)).unwrap();
+        assert!(acc.withdraw(None, dec!(10.0)).is_err());
+        assert_eq!(acc.available(), dec!(5.0));
+        assert_eq!(acc.get_total(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_withdraw_into_overdraft_stops_at_limit() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(5.0)).unwrap();
+        acc.withdraw_into_overdraft(None, dec!(15.0), dec!(10.0))
+            .unwrap();
+        assert_eq!(acc.available(), dec!(-10.0));
+        assert!(matches!(
+            acc.withdraw_into_overdraft(None, dec!(0.01), dec!(10.0)),
+            Err(AccountError::InsufficientFunds)
+        ));
+        assert_eq!(acc.available(), dec!(-10.0));
+    }
+
+    #[test]
+    fn test_withdraw_does_nothing_if_account_locked() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(5.0)).unwrap();
+        acc.locked = true;
+        assert!(acc.withdraw(None, dec!(2.0)).is_err());
+        assert_eq!(acc.available(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_dispute_moves_funds_from_available_to_held() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.dispute(None, dec!(4.0)).unwrap();
+        assert_eq!(acc.available(), dec!(6.0));
+        assert_eq!(acc.held(), dec!(4.0));
+        assert_eq!(acc.get_total(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_resolve_returns_held_to_available() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.dispute(None, dec!(3.0)).unwrap();
+        acc.resolve(None, dec!(3.0)).unwrap();
+        assert_eq!(acc.available(), dec!(10.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_chargeback_removes_held_and_locks_account() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.dispute(None, dec!(7.0)).unwrap();
+        acc.chargeback(None, dec!(7.0)).unwrap();
+        assert_eq!(acc.held(), dec!(0.0));
+        assert_eq!(acc.available(), dec!(3.0));
+        assert_eq!(acc.get_total(), dec!(3.0));
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_credits_held_and_chargeback_returns_it() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.withdraw(None, dec!(4.0)).unwrap();
+        acc.dispute_withdrawal(None, dec!(4.0)).unwrap();
+        assert_eq!(acc.available(), dec!(6.0));
+        assert_eq!(acc.held(), dec!(4.0));
+        acc.chargeback_withdrawal(None, dec!(4.0)).unwrap();
+        assert_eq!(acc.available(), dec!(10.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn test_dispute_into_debt_leaves_available_negative() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.withdraw(None, dec!(8.0)).unwrap();
+        assert!(acc.dispute(None, dec!(10.0)).is_err());
+        acc.dispute_into_debt(None, dec!(10.0)).unwrap();
+        assert_eq!(acc.available(), dec!(-8.0));
+        assert_eq!(acc.held(), dec!(10.0));
+        acc.chargeback(None, dec!(10.0)).unwrap();
+        assert_eq!(acc.get_total(), dec!(-8.0));
+        assert!(acc.locked);
+    }
+
+    #[test]
+    fn test_overflow_is_rejected_without_changing_balances() {
+        let mut acc = Account::new();
+        acc.deposit(None, Decimal::MAX).unwrap();
+        assert!(matches!(
+            acc.deposit(None, dec!(1.0)),
+            Err(AccountError::Overflow)
+        ));
+        assert_eq!(acc.available(), Decimal::MAX);
+
+        let mut acc = Account::new();
+        acc.deposit(None, Decimal::MAX).unwrap();
+        acc.dispute(None, Decimal::MAX).unwrap();
+        // available and held would each fit, but their total wouldn't
+        assert!(matches!(
+            acc.deposit(None, dec!(1.0)),
+            Err(AccountError::Overflow)
+        ));
+        assert_eq!(acc.available(), Decimal::ZERO);
+        assert_eq!(acc.get_total(), Decimal::MAX);
+
+        let mut acc = Account::new();
+        acc.deposit(None, Decimal::MAX).unwrap();
+        acc.dispute(None, dec!(1.0)).unwrap();
+        assert!(matches!(
+            acc.dispute_withdrawal(None, Decimal::MAX),
+            Err(AccountError::Overflow)
+        ));
+        assert_eq!(acc.held(), dec!(1.0));
+    }
+
+    #[test]
+    fn test_total_is_sum_of_available_and_held() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(10.0)).unwrap();
+        acc.dispute(None, dec!(4.0)).unwrap();
+        assert_eq!(acc.get_total(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_currencies_have_separate_balances() {
+        let eur = Currency::new("EUR");
+        let usd = Currency::new("USD");
+        let mut acc = Account::new();
+        assert_eq!(
+            acc.balances().collect::<Vec<_>>(),
+            [(None, Balance::default())]
+        );
+        acc.deposit(eur, dec!(10.0)).unwrap();
+        acc.deposit(usd, dec!(3.0)).unwrap();
+        assert!(matches!(
+            acc.withdraw(usd, dec!(5.0)),
+            Err(AccountError::InsufficientFunds)
+        ));
+        acc.dispute(eur, dec!(4.0)).unwrap();
+        assert_eq!(acc.balance(eur).available(), dec!(6.0));
+        assert_eq!(acc.balance(eur).held(), dec!(4.0));
+        assert_eq!(acc.balance(usd).total(), dec!(3.0));
+        assert_eq!(acc.get_total(), Decimal::ZERO);
+        // A chargeback in one currency locks the whole account
+        acc.chargeback(eur, dec!(4.0)).unwrap();
+        assert!(matches!(
+            acc.deposit(usd, dec!(1.0)),
+            Err(AccountError::Locked)
+        ));
+        assert_eq!(
+            acc.balances()
+                .map(|(currency, _)| currency)
+                .collect::<Vec<_>>(),
+            [eur, usd]
+        );
+    }
+
+    #[test]
+    fn test_fee_may_drive_available_down_to_floor() {
+        let mut acc = Account::new();
+        acc.deposit(None, dec!(5.0)).unwrap();
+        acc.charge_fee(None, dec!(8.0), dec!(-3.0)).unwrap();
+        assert_eq!(acc.available(), dec!(-3.0));
+        assert!(matches!(
+            acc.charge_fee(None, dec!(0.01), dec!(-3.0)),
+            Err(AccountError::FeeFloorExceeded)
+        ));
+        assert_eq!(acc.available(), dec!(-3.0));
+    }
+
+    #[test]
+    fn test_open_and_close() {
+        let mut acc = Account::new();
+        acc.open().unwrap();
+        assert!(matches!(acc.open(), Err(AccountError::AlreadyOpen)));
+        acc.deposit(Currency::new("EUR"), dec!(1.0)).unwrap();
+        assert!(matches!(acc.close(), Err(AccountError::NonZeroBalance)));
+        acc.withdraw(Currency::new("EUR"), dec!(1.0)).unwrap();
+        acc.close().unwrap();
+        assert_eq!(acc.status(), AccountStatus::Closed);
+        assert!(matches!(
+            acc.deposit(None, dec!(1.0)),
+            Err(AccountError::Closed)
+        ));
+        assert!(matches!(acc.open(), Err(AccountError::Closed)));
+    }
+}
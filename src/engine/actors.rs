@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{self, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use super::database::{Database, TransactionError, TransactionResult};
+use super::policy::TxIdScope;
+use super::sharded::with_recipient;
+use super::transaction::{ClientID, Transaction};
+
+// How many transactions may queue up for a single client before its submitters have to wait
+const ACTOR_QUEUE_CAPACITY: usize = 64;
+
+struct Actor {
+    sender: mpsc::Sender<Job>,
+    task: JoinHandle<Database>,
+}
+
+enum Job {
+    Process(Transaction, oneshot::Sender<TransactionResult>),
+    // A transfer to another client, applied with the Database of that client's actor, lent by it
+    // and handed back once done
+    Transfer {
+        transaction: Transaction,
+        recipient: ClientID,
+        reply: oneshot::Sender<TransactionResult>,
+        lent: oneshot::Receiver<Database>,
+        give_back: oneshot::Sender<Database>,
+    },
+    // Lends the actor's Database for a transfer to its client, waiting for it to come back so
+    // nothing else of the client applies meanwhile
+    Lend(oneshot::Sender<Database>, oneshot::Receiver<Database>),
+}
+
+// Runs every client as its own tokio task owning a Database with just that client's account
+// and transaction history. The router only looks up the client's channel, so clients never
+// contend with each other and a slow client doesn't hold up the rest. The Database itself runs
+// on tokio's blocking pool, as storage may block.
+// A transfer waits in the sender's actor for the recipient's actor to lend its Database, as
+// ShardedDatabase does. Both are queued under one lock, so that two transfers between the same
+// actors reach them in the same order and can't wait on each other. Ids are only known to the
+// actor of their client, so only TxIdScope::PerClient is supported.
+pub struct ActorDatabase {
+    make_db: Arc<dyn Fn() -> Database + Send + Sync>,
+    actors: Mutex<HashMap<ClientID, Actor>>,
+    transfers: sync::Mutex<()>,
+}
+
+impl ActorDatabase {
+    // Must be called from within a tokio runtime. make_db builds the state of each new client,
+    // and is refused if that keeps ids unique across clients, which no actor could tell.
+    pub fn new(make_db: impl Fn() -> Database + Send + Sync + 'static) -> Result<Self, String> {
+        if make_db().tx_id_scope() != TxIdScope::PerClient {
+            return Err("an ActorDatabase needs transaction ids scoped per client".to_string());
+        }
+        Ok(ActorDatabase {
+            make_db: Arc::new(make_db),
+            actors: Mutex::new(HashMap::new()),
+            transfers: sync::Mutex::new(()),
+        })
+    }
+
+    pub async fn process(&self, transaction: Transaction) -> TransactionResult {
+        let stopped = |_| TransactionError::EngineStopped;
+        let (reply, result) = oneshot::channel();
+        let sender = self.sender_for(transaction.client);
+        match transaction.to_client.filter(|to| *to != transaction.client) {
+            Some(recipient) => {
+                let lender = self.sender_for(recipient);
+                let (lend, lent) = oneshot::channel();
+                let (give_back, given_back) = oneshot::channel();
+                let job = Job::Transfer {
+                    transaction,
+                    recipient,
+                    reply,
+                    lent,
+                    give_back,
+                };
+                let _queuing = self.transfers.lock().await;
+                sender.send(job).await.map_err(stopped)?;
+                lender
+                    .send(Job::Lend(lend, given_back))
+                    .await
+                    .map_err(stopped)?;
+            }
+            None => sender
+                .send(Job::Process(transaction, reply))
+                .await
+                .map_err(stopped)?,
+        }
+        result.await.map_err(|_| TransactionError::EngineStopped)?
+    }
+
+    // Spawns the client's actor on first contact
+    fn sender_for(&self, client: ClientID) -> mpsc::Sender<Job> {
+        let mut actors = self.actors.lock().unwrap();
+        let actor = actors.entry(client).or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel(ACTOR_QUEUE_CAPACITY);
+            let task = tokio::spawn(run((self.make_db)(), receiver));
+            Actor { sender, task }
+        });
+        actor.sender.clone()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.actors.lock().unwrap().len()
+    }
+
+    // Stops every actor once its queue is drained and merges their state into one Database
+    pub async fn finish(self) -> Result<Database, TransactionError> {
+        let actors = self.actors.into_inner().unwrap();
+        let mut merged = (self.make_db)();
+        for (_, actor) in actors {
+            drop(actor.sender);
+            let db = actor
+                .task
+                .await
+                .map_err(|_| TransactionError::EngineStopped)?;
+            merged.merge(db)?;
+        }
+        Ok(merged)
+    }
+}
+
+async fn run(mut db: Database, mut jobs: mpsc::Receiver<Job>) -> Database {
+    while let Some(job) = jobs.recv().await {
+        db = match job {
+            Job::Process(transaction, reply) => {
+                let (db, result) = blocking(move || {
+                    let result = db.process(&transaction);
+                    (db, result)
+                })
+                .await;
+                let _ = reply.send(result);
+                db
+            }
+            Job::Transfer {
+                transaction,
+                recipient,
+                reply,
+                lent,
+                give_back,
+            } => match lent.await {
+                Ok(mut lent) => {
+                    let (db, lent, result) = blocking(move || {
+                        let result = with_recipient(&mut db, &mut lent, recipient, |db| {
+                            db.process(&transaction)
+                        });
+                        (db, lent, result)
+                    })
+                    .await;
+                    let _ = give_back.send(lent);
+                    let _ = reply.send(result);
+                    db
+                }
+                Err(_) => {
+                    let _ = reply.send(Err(TransactionError::EngineStopped));
+                    db
+                }
+            },
+            Job::Lend(lend, given_back) => match lend.send(db) {
+                // The Database is gone if the borrowing actor panicked, so this one stops too
+                Ok(()) => given_back.await.expect("the actor borrowing it panicked"),
+                Err(db) => db,
+            },
+        }
+    }
+    db
+}
+
+// Runs `f` on a thread of tokio's blocking pool, resuming its panic if it panics
+async fn blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{AccountError, DisputePolicy, TransactionType};
+    use rust_decimal::dec;
+
+    fn transaction(tx_type: TransactionType, client: ClientID, tx: u32) -> Transaction {
+        Transaction::new(tx_type, client, tx, Some(dec!(2.0)))
+    }
+
+    #[tokio::test]
+    async fn test_clients_run_as_independent_actors() {
+        let actors = Arc::new(
+            ActorDatabase::new(|| {
+                Database::default()
+                    .with_dispute_policy(DisputePolicy::DepositsAndWithdrawals)
+                    .with_tx_id_scope(TxIdScope::PerClient)
+            })
+            .unwrap(),
+        );
+        let tasks = (1..=5u16)
+            .map(|client| {
+                let actors = Arc::clone(&actors);
+                tokio::spawn(async move {
+                    let base = client as u32 * 10;
+                    for tx in base..base + 3 {
+                        actors
+                            .process(transaction(TransactionType::Deposit, client, tx))
+                            .await
+                            .unwrap();
+                    }
+                    actors
+                        .process(transaction(TransactionType::Dispute, client, base))
+                        .await
+                        .unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(actors.client_count(), 5);
+
+        let transfer = transaction(TransactionType::Transfer, 1, 99).with_to_client(Some(2));
+        actors.process(transfer).await.unwrap();
+        let back = transaction(TransactionType::Transfer, 2, 99).with_to_client(Some(1));
+        actors.process(back).await.unwrap();
+        let overdrawn = Transaction::new(TransactionType::Transfer, 3, 99, Some(dec!(5.0)))
+            .with_to_client(Some(4));
+        assert!(matches!(
+            actors.process(overdrawn).await,
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+
+        let actors = Arc::into_inner(actors).unwrap();
+        let db = actors.finish().await.unwrap();
+        for client in 1..=5 {
+            let acc = db.account(client).unwrap().unwrap();
+            assert_eq!(acc.available(), dec!(4.0));
+            assert_eq!(acc.held(), dec!(2.0));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_global_transaction_ids_are_refused() {
+        assert!(ActorDatabase::new(Database::default).is_err());
+    }
+}
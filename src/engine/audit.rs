@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+
+use super::transaction::{ClientID, TransactionID};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AdminAction {
+    Unlock,
+}
+
+// Record of an administrative operation, kept for as long as the Database lives
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub action: AdminAction,
+    pub client: ClientID,
+    // The admin transaction that triggered it, None when called through the library API
+    pub tx: Option<TransactionID>,
+}
@@ -0,0 +1,421 @@
+use std::sync::{Mutex, MutexGuard};
+
+use super::account::Account;
+use super::database::{Database, TransactionError, TransactionResult};
+use super::policy::{PrecisionPolicy, TxIdScope};
+use super::sharded::{IdOwners, ShardError, merge_partitions, shard_of, with_recipient};
+use super::transaction::{ClientID, Transaction};
+use crate::storage::{StorageError, StorageResult};
+
+// A Database callers on many threads can share, e.g. behind an Arc in a multi-threaded server.
+// Clients are split over shards by 'client % N', each a Database behind its own lock, so
+// transactions of clients in different shards run in parallel while those of one client are
+// applied one at a time, in the order their callers got the lock. A transfer to a client of
+// another shard locks both shards, in index order so that callers can't deadlock, and applies
+// with the recipient's account lent to the sender's shard. With TxIdScope::Global the shard
+// that took each id last is remembered, and a transaction taking an id another shard took also
+// locks that one to find out whether it still holds the id. Results then match those of a single
+// Database applying the transactions in the order they got their locks, with the exceptions
+// ShardedDatabase has.
+pub struct ConcurrentDatabase {
+    shards: Vec<Mutex<Database>>,
+    // Of the first shard, every shard is expected to be configured alike
+    precision: PrecisionPolicy,
+    tx_id_scope: TxIdScope,
+    // Never held while waiting for a shard's lock
+    owners: Mutex<IdOwners>,
+}
+
+// A guard for each shard, None for those not needed or whose lock holder panicked
+type Locked<'a> = Vec<Option<MutexGuard<'a, Database>>>;
+
+impl ConcurrentDatabase {
+    pub fn new(shards: usize, mut make_db: impl FnMut() -> Database) -> Self {
+        match Self::try_new(shards, || Ok::<_, std::convert::Infallible>(make_db())) {
+            Ok(db) => db,
+            Err(never) => match never {},
+        }
+    }
+
+    // Like new, for Databases that can fail to open, such as those of a state store
+    pub fn try_new<E>(
+        shards: usize,
+        mut make_db: impl FnMut() -> Result<Database, E>,
+    ) -> Result<Self, E> {
+        let shards = (0..shards.max(1))
+            .map(|_| make_db())
+            .collect::<Result<Vec<_>, E>>()?;
+        Ok(ConcurrentDatabase {
+            precision: shards[0].precision(),
+            tx_id_scope: shards[0].tx_id_scope(),
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            owners: Mutex::new(IdOwners::default()),
+        })
+    }
+
+    pub fn process(&self, transaction: &Transaction) -> TransactionResult {
+        self.with_transaction(transaction, |db| db.process(transaction))
+    }
+
+    // Runs `f` on the Database holding the transaction's client, with its shard locked, for
+    // anything beyond process such as Database::process_with_effect. For a transfer between
+    // shards, the recipient's account is lent to that Database meanwhile. Like process, a shard
+    // whose lock holder panicked is left alone from then on.
+    pub fn with_transaction<R>(
+        &self,
+        transaction: &Transaction,
+        f: impl FnOnce(&mut Database) -> Result<R, TransactionError>,
+    ) -> Result<R, TransactionError> {
+        let mut locked = self.lock(std::slice::from_ref(transaction));
+        self.apply(&mut locked, transaction, f)
+    }
+
+    // Runs `f` on each transaction in order with every shard the batch needs locked, so no
+    // transaction of another caller applies in between. A rejection doesn't stop the rest of
+    // the batch, and each outcome is at its transaction's index.
+    pub fn with_batch<R>(
+        &self,
+        transactions: &[Transaction],
+        mut f: impl FnMut(&mut Database, &Transaction) -> Result<R, TransactionError>,
+    ) -> Vec<Result<R, TransactionError>> {
+        let mut locked = self.lock(transactions);
+        transactions
+            .iter()
+            .map(|transaction| self.apply(&mut locked, transaction, |db| f(db, transaction)))
+            .collect()
+    }
+
+    // Like Database::process_batch, with no other caller's transaction applied in between
+    pub fn process_batch(&self, transactions: Vec<Transaction>) -> Vec<TransactionResult> {
+        self.with_batch(&transactions, Database::process)
+    }
+
+    // Locks the shards of the transactions' clients and recipients, and those that took their
+    // ids last, in index order. Which shard took an id may change until that shard is locked,
+    // so the owners are looked up again once locked, until no other shard is needed.
+    fn lock(&self, transactions: &[Transaction]) -> Locked<'_> {
+        let mut needed = vec![false; self.shards.len()];
+        for transaction in transactions {
+            let clients = std::iter::once(transaction.client).chain(transaction.to_client);
+            for client in clients {
+                needed[shard_of(client, self.shards.len())] = true;
+            }
+        }
+        loop {
+            self.owners_needed(transactions, &mut needed);
+            let locked = self
+                .shards
+                .iter()
+                .zip(&needed)
+                .map(|(shard, needed)| needed.then(|| shard.lock().ok()).flatten())
+                .collect();
+            let before = needed.clone();
+            self.owners_needed(transactions, &mut needed);
+            if needed == before {
+                return locked;
+            }
+        }
+    }
+
+    fn owners_needed(&self, transactions: &[Transaction], needed: &mut [bool]) {
+        if self.tx_id_scope != TxIdScope::Global {
+            return;
+        }
+        let owners = self.owners.lock().unwrap();
+        for transaction in transactions.iter().filter(|t| t.tx_type.takes_id()) {
+            if let Some(owner) = owners.get(transaction.tx) {
+                needed[owner] = true;
+            }
+        }
+    }
+
+    fn apply<R>(
+        &self,
+        locked: &mut Locked<'_>,
+        transaction: &Transaction,
+        f: impl FnOnce(&mut Database) -> Result<R, TransactionError>,
+    ) -> Result<R, TransactionError> {
+        let shard = shard_of(transaction.client, self.shards.len());
+        self.claim_id(locked, transaction, shard)?;
+        let recipient = transaction
+            .to_client
+            .filter(|to_client| shard_of(*to_client, self.shards.len()) != shard);
+        match recipient {
+            Some(recipient) => {
+                let (sender, lender) = pair(locked, shard, shard_of(recipient, self.shards.len()))
+                    .ok_or(TransactionError::EngineStopped)?;
+                with_recipient(sender, lender, recipient, f)
+            }
+            None => f(locked[shard]
+                .as_deref_mut()
+                .ok_or(TransactionError::EngineStopped)?),
+        }
+    }
+
+    // Takes the transaction's id for its shard, unless another shard still holds it, in which
+    // case it is a duplicate in its shard too. The shard that took the id last is locked.
+    fn claim_id(
+        &self,
+        locked: &mut Locked<'_>,
+        transaction: &Transaction,
+        shard: usize,
+    ) -> Result<(), TransactionError> {
+        if self.tx_id_scope != TxIdScope::Global || !transaction.tx_type.takes_id() {
+            return Ok(());
+        }
+        let mut owners = self.owners.lock().unwrap();
+        if let Some(owner) = owners.get(transaction.tx).filter(|owner| *owner != shard) {
+            let held = match &locked[owner] {
+                Some(db) => db.holds_id(transaction.tx)?,
+                None => return Err(TransactionError::EngineStopped),
+            };
+            if held {
+                if let Some(db) = &mut locked[shard] {
+                    db.take_id_elsewhere(transaction.tx);
+                }
+                return Ok(());
+            }
+        }
+        owners.set(transaction.tx, shard);
+        Ok(())
+    }
+
+    pub fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+        self.with_client(client, |db| db.account(client))
+            .unwrap_or_else(|| Err(poisoned()))
+    }
+
+    // Every account in client order. Shards are read one after the other, each under its lock,
+    // so transactions of other shards may apply meanwhile.
+    pub fn accounts(&self) -> StorageResult<Vec<(ClientID, Account)>> {
+        let mut accounts = Vec::new();
+        for shard in &self.shards {
+            let db = shard.lock().map_err(|_| poisoned())?;
+            for entry in db.accounts() {
+                accounts.push(entry?);
+            }
+        }
+        accounts.sort_unstable_by_key(|(client, _)| *client);
+        Ok(accounts)
+    }
+
+    pub fn precision(&self) -> PrecisionPolicy {
+        self.precision
+    }
+
+    // Flushes the storage of every shard in turn, see Database::flush
+    pub fn flush(&self) -> StorageResult<()> {
+        for shard in &self.shards {
+            shard.lock().map_err(|_| poisoned())?.flush()?;
+        }
+        Ok(())
+    }
+
+    // Runs `f` on the Database holding the client, with its shard locked, for anything that
+    // isn't a transaction such as Database::write_snapshot, transactions going through
+    // with_transaction. None once the shard's lock is poisoned.
+    pub fn with_client<R>(
+        &self,
+        client: ClientID,
+        f: impl FnOnce(&mut Database) -> R,
+    ) -> Option<R> {
+        let mut db = self.shards[shard_of(client, self.shards.len())]
+            .lock()
+            .ok()?;
+        Some(f(&mut db))
+    }
+
+    // Merges the shards into one Database, e.g. to write the accounts out or take a snapshot
+    pub fn into_database(self) -> Result<Database, ShardError> {
+        merge_partitions(
+            self.shards
+                .into_iter()
+                .map(|shard| shard.into_inner().map_err(|_| ShardError::WorkerPanicked)),
+        )
+    }
+}
+
+// The Databases of two different shards, in the order asked for. None unless both are locked.
+fn pair<'a>(
+    locked: &'a mut Locked<'_>,
+    first: usize,
+    second: usize,
+) -> Option<(&'a mut Database, &'a mut Database)> {
+    let (low, high) = locked.split_at_mut(first.max(second));
+    let lower = low[first.min(second)].as_deref_mut()?;
+    let higher = high[0].as_deref_mut()?;
+    match first < second {
+        true => Some((lower, higher)),
+        false => Some((higher, lower)),
+    }
+}
+
+fn poisoned() -> StorageError {
+    StorageError::Backend("shard lock poisoned".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use rust_decimal::dec;
+    use std::thread;
+
+    fn transaction(tx_type: TransactionType, client: ClientID, tx: u32) -> Transaction {
+        Transaction::new(tx_type, client, tx, Some(dec!(2)))
+    }
+
+    #[test]
+    fn test_threads_share_one_database() {
+        let db = ConcurrentDatabase::new(4, Database::default);
+        thread::scope(|scope| {
+            for client in 0..8u16 {
+                let db = &db;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let tx = client as u32 * 1000 + i;
+                        db.process(&transaction(TransactionType::Deposit, client, tx))
+                            .unwrap();
+                    }
+                    db.process(&transaction(
+                        TransactionType::Withdrawal,
+                        client,
+                        999_999 - client as u32,
+                    ))
+                    .unwrap();
+                });
+            }
+        });
+        assert_eq!(db.account(3).unwrap().unwrap().available(), dec!(98));
+
+        // Client 0 of shard 0 took id 1
+        let transfer = transaction(TransactionType::Transfer, 1, 1).with_to_client(Some(2));
+        assert!(matches!(
+            db.process(&transfer),
+            Err(TransactionError::Duplicate)
+        ));
+        let transfer = transaction(TransactionType::Transfer, 1, 100_000).with_to_client(Some(2));
+        db.process(&transfer).unwrap();
+        assert_eq!(db.account(1).unwrap().unwrap().available(), dec!(96));
+        assert_eq!(db.account(2).unwrap().unwrap().available(), dec!(100));
+        let withdrawal = transaction(TransactionType::Withdrawal, 5, 100_001);
+        let prepared = db.with_transaction(&withdrawal, |db| {
+            db.prepare(&withdrawal)
+                .and_then(|prepared| prepared.commit())
+        });
+        assert!(matches!(prepared, Ok(())));
+        let transfer = transaction(TransactionType::Transfer, 2, 100_002).with_to_client(Some(5));
+        let effect = db.with_transaction(&transfer, |db| db.process_with_effect(&transfer));
+        assert_eq!(effect.unwrap().len(), 2);
+        let clients = db.accounts().unwrap().into_iter().map(|(client, _)| client);
+        assert_eq!(clients.collect::<Vec<_>>(), (0..8).collect::<Vec<_>>());
+
+        let merged = db.into_database().unwrap();
+        assert_eq!(merged.accounts().count(), 8);
+        assert_eq!(merged.account(5).unwrap().unwrap().available(), dec!(98));
+    }
+
+    #[test]
+    fn test_transfers_across_shards_match_single_threaded() {
+        // Every client funds itself, then sends every other client less than it got, so the
+        // order the threads get their locks in doesn't change the outcome
+        let feed = |client: ClientID| {
+            let base = client as u32 * 100;
+            let deposit = Transaction::new(TransactionType::Deposit, client, base, Some(dec!(50)));
+            let transfers = (0..8).filter(move |to| *to != client).map(move |to| {
+                transaction(TransactionType::Transfer, client, base + 1 + to as u32)
+                    .with_to_client(Some(to))
+            });
+            std::iter::once(deposit).chain(transfers)
+        };
+        // Each client then reuses the id of the next one's deposit
+        let reused = |client: ClientID| {
+            transaction(
+                TransactionType::Deposit,
+                client,
+                (client as u32 + 1) % 8 * 100,
+            )
+        };
+
+        let mut single = Database::default();
+        for client in 0..8 {
+            for transaction in feed(client) {
+                single.process(&transaction).unwrap();
+            }
+        }
+        let db = ConcurrentDatabase::new(3, Database::default);
+        thread::scope(|scope| {
+            for client in 0..8 {
+                let db = &db;
+                scope.spawn(move || {
+                    for transaction in feed(client) {
+                        db.process(&transaction).unwrap();
+                    }
+                });
+            }
+        });
+        for client in 0..8 {
+            assert!(matches!(
+                single.process(&reused(client)),
+                Err(TransactionError::Duplicate)
+            ));
+            assert!(matches!(
+                db.process(&reused(client)),
+                Err(TransactionError::Duplicate)
+            ));
+        }
+
+        let merged = db.into_database().unwrap();
+        for client in 0..8 {
+            let (expected, account) = (account_of(&single, client), account_of(&merged, client));
+            assert_eq!(account.available(), dec!(50));
+            assert_eq!(
+                (account.available(), account.held()),
+                (expected.available(), expected.held())
+            );
+        }
+    }
+
+    fn account_of(db: &Database, client: ClientID) -> Account {
+        db.account(client).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_batches_apply_without_interleaving() {
+        let db = ConcurrentDatabase::new(2, Database::default);
+        let batch = vec![
+            transaction(TransactionType::Deposit, 1, 1),
+            transaction(TransactionType::Withdrawal, 1, 2),
+            transaction(TransactionType::Deposit, 2, 3),
+        ];
+        let other = thread::scope(|scope| {
+            let mut other = None;
+            let results = db.with_batch(&batch, |shard, batched| {
+                if batched.tx == 1 {
+                    // Would take the deposit before the batch's withdrawal if it got in between
+                    other = Some(
+                        scope.spawn(|| db.process(&transaction(TransactionType::Withdrawal, 1, 4))),
+                    );
+                    thread::sleep(std::time::Duration::from_millis(50));
+                }
+                shard.process(batched)
+            });
+            assert!(results.iter().all(Result::is_ok), "{:?}", results);
+            other.unwrap().join().unwrap()
+        });
+        assert!(matches!(
+            other,
+            Err(TransactionError::AccountError(
+                crate::engine::AccountError::InsufficientFunds
+            ))
+        ));
+        assert_eq!(db.account(2).unwrap().unwrap().available(), dec!(2));
+
+        let results = db.process_batch(vec![
+            transaction(TransactionType::Deposit, 3, 5),
+            transaction(TransactionType::Deposit, 3, 5),
+        ]);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(TransactionError::Duplicate)));
+    }
+}
@@ -0,0 +1,516 @@
+use csv::ByteRecord;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, de::Error};
+use std::{
+    borrow::Cow,
+    str::{self, FromStr},
+};
+
+use super::transaction::{Transaction, TransactionType};
+
+// Where the columns of a transaction CSV are, for deserializing its records without allocating.
+// Columns may come in any order, and columns that aren't a transaction field are ignored. Inputs
+// with only the type, client, tx and amount fields, by far the most common, are parsed by hand
+// straight from the record's bytes. Any other layout, and any field the fast path can't parse,
+// goes through serde, so errors are the ones serde gives.
+#[derive(Debug, Clone)]
+pub struct CsvColumns {
+    headers: ByteRecord,
+    fast: Option<FastColumns>,
+    // How the amount and rate columns are written, and where they are
+    amount_format: AmountFormat,
+    decimal_columns: Vec<(usize, &'static str)>,
+}
+
+// How the amounts and rates of a CSV input are written, beyond the plain decimal numbers such
+// as 12.50 that are always read. The default takes amounts as serde always has: 1e3 is 1000,
+// and "2500.00", quoted once more than the CSV quoting undoes, is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmountFormat {
+    // None for a '.' decimal point without thousands separators
+    pub decimal_separator: Option<DecimalSeparator>,
+    // Whether scientific notation is read rather than rejected
+    pub scientific: bool,
+    // Whether amounts in double or single quotes are read for the number they quote
+    pub quoted: bool,
+}
+
+impl Default for AmountFormat {
+    fn default() -> Self {
+        AmountFormat {
+            decimal_separator: None,
+            scientific: true,
+            quoted: false,
+        }
+    }
+}
+
+// The decimal separator of amounts, the other of '.' and ',' separating thousands, for partner
+// files written as 1.234,56 or 1,234.56. Auto takes the last separator of each amount as its
+// decimal separator, unless it appears more than once. A lone separator followed by three digits
+// could be either, so Auto rejects amounts such as 1,234 rather than guess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecimalSeparator {
+    Dot,
+    Comma,
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FastColumns {
+    tx_type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+}
+
+impl CsvColumns {
+    pub fn new(headers: &ByteRecord) -> Self {
+        let position = |name: &[u8]| headers.iter().position(|header| header == name);
+        let known = [&b"type"[..], b"client", b"tx", b"amount"];
+        let others = [
+            &b"to_client"[..],
+            b"timestamp",
+            b"currency",
+            b"to_currency",
+            b"rate",
+        ];
+        let fast = match headers.iter().all(|header| !others.contains(&header))
+            && known
+                .iter()
+                .all(|name| headers.iter().filter(|header| header == name).count() <= 1)
+        {
+            true => match (position(b"type"), position(b"client"), position(b"tx")) {
+                (Some(tx_type), Some(client), Some(tx)) => Some(FastColumns {
+                    tx_type,
+                    client,
+                    tx,
+                    amount: position(b"amount"),
+                }),
+                _ => None,
+            },
+            false => None,
+        };
+        CsvColumns {
+            headers: headers.clone(),
+            fast,
+            amount_format: AmountFormat::default(),
+            decimal_columns: Vec::new(),
+        }
+    }
+
+    pub fn with_amount_format(mut self, amount_format: AmountFormat) -> Self {
+        self.amount_format = amount_format;
+        self.decimal_columns = self
+            .headers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, header)| match header {
+                b"amount" => Some((i, "amount")),
+                b"rate" => Some((i, "rate")),
+                _ => None,
+            })
+            .collect();
+        self
+    }
+
+    // Headers named as --lenient accepts them, see lenient_column
+    pub fn lenient(headers: &ByteRecord) -> Self {
+        CsvColumns::new(
+            &headers
+                .iter()
+                .map(|header| lenient_column(&String::from_utf8_lossy(header)))
+                .collect(),
+        )
+    }
+
+    pub fn headers(&self) -> &ByteRecord {
+        &self.headers
+    }
+
+    pub fn deserialize(&self, record: &ByteRecord) -> csv::Result<Transaction> {
+        let normalized = match self.normalize(record) {
+            Ok(normalized) => normalized,
+            Err(e) => return Err(rejected(record, e)),
+        };
+        let record = normalized.as_ref().unwrap_or(record);
+        match self.fast.and_then(|columns| columns.parse(record)) {
+            Some(transaction) => Ok(transaction),
+            None => record.deserialize(Some(&self.headers)),
+        }
+    }
+
+    // The record with its amounts as plain decimal numbers, None when they already are
+    fn normalize(&self, record: &ByteRecord) -> Result<Option<ByteRecord>, String> {
+        if self.amount_format == AmountFormat::default() {
+            return Ok(None);
+        }
+        let mut replaced = Vec::new();
+        for &(i, column) in &self.decimal_columns {
+            // Fields that aren't UTF-8 are left to serde to reject
+            if let Some(Ok(field)) = record.get(i).map(str::from_utf8) {
+                let amount = self
+                    .amount_format
+                    .normalize(field)
+                    .map_err(|e| format!("{} {}", column, e))?;
+                if amount != field {
+                    replaced.push((i, amount.into_owned()));
+                }
+            }
+        }
+        if replaced.is_empty() {
+            return Ok(None);
+        }
+        let mut normalized: ByteRecord = record
+            .iter()
+            .enumerate()
+            .map(
+                |(i, field)| match replaced.iter().find(|(column, _)| *column == i) {
+                    Some((_, amount)) => amount.as_bytes(),
+                    None => field,
+                },
+            )
+            .collect();
+        normalized.set_position(record.position().cloned());
+        Ok(Some(normalized))
+    }
+}
+
+impl AmountFormat {
+    // `amount` as a plain decimal number, or why it isn't one written in this format. Amounts
+    // that aren't numbers at all are passed on for serde to reject.
+    pub fn normalize(self, amount: &str) -> Result<Cow<'_, str>, String> {
+        let unquoted = match self.quoted {
+            true => unquote(amount).map_or(amount, str::trim),
+            false => amount,
+        };
+        let normalized = match self.decimal_separator {
+            Some(separator) => separator.normalize(unquoted)?,
+            None => Cow::Borrowed(unquoted),
+        };
+        if !self.scientific
+            && normalized.contains(['e', 'E'])
+            && Decimal::from_scientific(&normalized).is_ok()
+        {
+            return Err(format!("'{}' is in scientific notation", amount));
+        }
+        Ok(normalized)
+    }
+}
+
+// What's between the double or single quotes around `amount`, if it has them
+fn unquote(amount: &str) -> Option<&str> {
+    ['"', '\'']
+        .into_iter()
+        .find_map(|quote| amount.strip_prefix(quote)?.strip_suffix(quote))
+}
+
+impl DecimalSeparator {
+    // `amount` with a '.' decimal point and without thousands separators, or why it isn't an
+    // amount written with this separator. Thousands have to be grouped by three, so 1.5 is
+    // rejected rather than read as 15 when commas separate decimals.
+    pub fn normalize(self, amount: &str) -> Result<Cow<'_, str>, String> {
+        let last = amount.rfind(['.', ',']);
+        let (point, thousands) = match self {
+            DecimalSeparator::Dot => ('.', ','),
+            DecimalSeparator::Comma => (',', '.'),
+            DecimalSeparator::Auto => match last.map(|i| amount.as_bytes()[i]) {
+                Some(separator) if ambiguous(amount, separator as char) => {
+                    return Err(format!(
+                        "'{}' is ambiguous, '{}' may separate decimals or thousands",
+                        amount, separator as char
+                    ));
+                }
+                Some(b',') if amount.find(',') == last => (',', '.'),
+                Some(b',') => ('.', ','),
+                Some(_) if amount.find('.') == last => ('.', ','),
+                Some(_) => (',', '.'),
+                None => ('.', ','),
+            },
+        };
+        if !amount.contains(thousands) && (point == '.' || !amount.contains(point)) {
+            return Ok(Cow::Borrowed(amount));
+        }
+        let invalid = || {
+            format!(
+                "'{}' is not written with '{}' as decimal separator",
+                amount, point
+            )
+        };
+        let (integer, fraction) = amount.split_once(point).unwrap_or((amount, ""));
+        if fraction.contains(['.', ',']) {
+            return Err(invalid());
+        }
+        if integer.contains(thousands) {
+            let mut groups = integer.trim_start_matches(['-', '+']).split(thousands);
+            let first = groups.next().unwrap_or_default();
+            if !(1..=3).contains(&first.len()) || groups.any(|group| group.len() != 3) {
+                return Err(invalid());
+            }
+        }
+        let mut normalized = integer.replace(thousands, "");
+        if amount.contains(point) {
+            normalized.push('.');
+            normalized.push_str(fraction);
+        }
+        Ok(Cow::Owned(normalized))
+    }
+}
+
+// Whether `separator`, the last in `amount`, could be a decimal or a thousands separator: it
+// appears once, with three digits after it and one to three before, as in 1,234 or -12.500. A
+// leading 0 can't start a group of thousands, so 0,125 is a decimal.
+fn ambiguous(amount: &str, separator: char) -> bool {
+    let Some((integer, fraction)) = amount.split_once(separator) else {
+        return false;
+    };
+    let integer = integer.trim_start_matches(['-', '+']);
+    let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    !fraction.contains(separator)
+        && fraction.len() == 3
+        && digits(fraction)
+        && (1..=3).contains(&integer.len())
+        && digits(integer)
+        && !integer.starts_with('0')
+}
+
+// Fails `record` with `message` the way serde fails a field it can't deserialize
+fn rejected(record: &ByteRecord, message: String) -> csv::Error {
+    let mut rejected = ByteRecord::from(vec![message]);
+    rejected.set_position(record.position().cloned());
+    rejected
+        .deserialize::<Rejected>(None)
+        .expect_err("Rejected never deserializes")
+}
+
+#[derive(Debug)]
+struct Rejected;
+
+impl<'de> Deserialize<'de> for Rejected {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Err(D::Error::custom(String::deserialize(deserializer)?))
+    }
+}
+
+// The column a header names when read leniently: case, surrounding spaces and whether words are
+// separated by spaces, dashes or underscores don't matter, and a few common aliases are accepted
+pub fn lenient_column(header: &str) -> String {
+    let column = header.trim().to_lowercase().replace([' ', '-'], "_");
+    match column.as_str() {
+        "tx_type" | "transaction_type" => "type".to_string(),
+        "client_id" => "client".to_string(),
+        "tx_id" | "transaction_id" => "tx".to_string(),
+        _ => column,
+    }
+}
+
+impl FastColumns {
+    fn parse(self, record: &ByteRecord) -> Option<Transaction> {
+        let field = |i: usize| record.get(i).and_then(|field| str::from_utf8(field).ok());
+        let tx_type = match record.get(self.tx_type)? {
+            b"deposit" => TransactionType::Deposit,
+            b"withdrawal" => TransactionType::Withdrawal,
+            b"dispute" => TransactionType::Dispute,
+            b"resolve" => TransactionType::Resolve,
+            b"chargeback" => TransactionType::Chargeback,
+            _ => return None,
+        };
+        let amount = match self.amount.map(field) {
+            None => None,
+            Some(Some("")) => None,
+            // Scientific notation is left to serde
+            Some(amount) => Some(Decimal::from_str(amount?).ok()?),
+        };
+        Some(Transaction::new(
+            tx_type,
+            field(self.client)?.parse().ok()?,
+            field(self.tx)?.parse().ok()?,
+            amount,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    fn record(fields: &[&str]) -> ByteRecord {
+        ByteRecord::from(fields.to_vec())
+    }
+
+    #[test]
+    fn test_fast_path_matches_serde() {
+        let columns = CsvColumns::new(&record(&["amount", "tx", "client", "type"]));
+        assert!(columns.fast.is_some());
+        for fields in [
+            ["1.5", "1", "2", "deposit"],
+            ["", "7", "2", "dispute"],
+            ["2", "8", "65535", "withdrawal"],
+        ] {
+            let record = record(&fields);
+            let serde: Transaction = record.deserialize(Some(columns.headers())).unwrap();
+            assert_eq!(columns.deserialize(&record).unwrap(), serde);
+        }
+        let transaction = columns
+            .deserialize(&record(&["1e2", "1", "2", "deposit"]))
+            .unwrap();
+        assert_eq!(transaction.amount, Some(dec!(100)));
+        // Errors and unknown types still come from serde
+        assert!(
+            columns
+                .deserialize(&record(&["1", "1", "70000", "deposit"]))
+                .is_err()
+        );
+        let transaction = columns
+            .deserialize(&record(&["1", "1", "2", "Deposit"]))
+            .unwrap();
+        assert_eq!(
+            transaction.tx_type,
+            TransactionType::Other("Deposit".to_string())
+        );
+    }
+
+    #[test]
+    fn test_lenient_headers() {
+        let columns = CsvColumns::lenient(&record(&["Type", " Client ID", "TX", "Amount"]));
+        assert_eq!(
+            columns.headers(),
+            &record(&["type", "client", "tx", "amount"])
+        );
+        assert!(columns.fast.is_some());
+        assert_eq!(lenient_column("To-Client"), "to_client");
+        assert_eq!(lenient_column("transaction type"), "type");
+    }
+
+    #[test]
+    fn test_extra_columns_are_ignored() {
+        let columns = CsvColumns::new(&record(&["note", "client", "type", "tx", "amount", "id"]));
+        assert!(columns.fast.is_some());
+        let record = record(&["hello", "2", "withdrawal", "9", "1.25", "x"]);
+        let transaction = columns.deserialize(&record).unwrap();
+        assert_eq!(
+            transaction,
+            record.deserialize(Some(columns.headers())).unwrap()
+        );
+        assert_eq!((transaction.client, transaction.tx), (2, 9));
+    }
+
+    #[test]
+    fn test_decimal_separators() {
+        let normalize =
+            |separator: DecimalSeparator, amount| separator.normalize(amount).map(Cow::into_owned);
+        for (separator, amount, normalized) in [
+            (DecimalSeparator::Comma, "1.234,56", "1234.56"),
+            (DecimalSeparator::Comma, "12,5", "12.5"),
+            (DecimalSeparator::Comma, "-1.000.000", "-1000000"),
+            (DecimalSeparator::Dot, "1,234.56", "1234.56"),
+            (DecimalSeparator::Dot, "0.0001", "0.0001"),
+            (DecimalSeparator::Auto, "1.234,56", "1234.56"),
+            (DecimalSeparator::Auto, "1,234.56", "1234.56"),
+            (DecimalSeparator::Auto, "1,234,567", "1234567"),
+            (DecimalSeparator::Auto, "1.234.567", "1234567"),
+            (DecimalSeparator::Auto, "2,5", "2.5"),
+            (DecimalSeparator::Auto, "2.5", "2.5"),
+            (DecimalSeparator::Auto, "0,125", "0.125"),
+            (DecimalSeparator::Auto, "1234,567", "1234.567"),
+            (DecimalSeparator::Auto, "1,2345", "1.2345"),
+            (DecimalSeparator::Comma, "1,234", "1.234"),
+            (DecimalSeparator::Dot, "1,234", "1234"),
+            (DecimalSeparator::Auto, "", ""),
+        ] {
+            assert_eq!(normalize(separator, amount).unwrap(), normalized);
+        }
+        for (separator, amount) in [
+            // Not read as 15
+            (DecimalSeparator::Comma, "1.5"),
+            (DecimalSeparator::Comma, "1.234.56"),
+            (DecimalSeparator::Dot, "1.234,56"),
+            (DecimalSeparator::Dot, "12,34"),
+            (DecimalSeparator::Auto, "1.234,567,8"),
+            // 1.234 or 1234, only a separator given up front tells
+            (DecimalSeparator::Auto, "1,234"),
+            (DecimalSeparator::Auto, "1.234"),
+            (DecimalSeparator::Auto, "-12,500"),
+            (DecimalSeparator::Auto, "999.999"),
+        ] {
+            assert!(normalize(separator, amount).is_err(), "{}", amount);
+        }
+        // Amounts that need no change aren't copied
+        assert!(matches!(
+            DecimalSeparator::Comma.normalize("12"),
+            Ok(Cow::Borrowed("12"))
+        ));
+    }
+
+    #[test]
+    fn test_amounts_are_read_with_the_decimal_separator() {
+        let columns = CsvColumns::new(&record(&["type", "client", "tx", "amount", "rate"]))
+            .with_amount_format(AmountFormat {
+                decimal_separator: Some(DecimalSeparator::Comma),
+                ..AmountFormat::default()
+            });
+        let transaction = columns
+            .deserialize(&record(&["deposit", "1", "2", "1.234,5", ""]))
+            .unwrap();
+        assert_eq!(transaction.amount, Some(dec!(1234.5)));
+        let transaction = columns
+            .deserialize(&record(&["convert", "1", "3", "10", "0,92"]))
+            .unwrap();
+        assert_eq!(transaction.rate, Some(dec!(0.92)));
+        let e = columns
+            .deserialize(&record(&["deposit", "1", "4", "1.5", ""]))
+            .unwrap_err();
+        assert!(!e.is_io_error());
+        assert!(e.to_string().contains("amount '1.5'"), "{}", e);
+    }
+
+    #[test]
+    fn test_scientific_and_quoted_amounts() {
+        let header = record(&["type", "client", "tx", "amount"]);
+        let deposit = |amount| record(&["deposit", "1", "2", amount]);
+        let columns = CsvColumns::new(&header);
+        assert_eq!(
+            columns.deserialize(&deposit("1e3")).unwrap().amount,
+            Some(dec!(1000))
+        );
+        let e = columns.deserialize(&deposit(r#""2500.00""#)).unwrap_err();
+        assert!(
+            e.to_string().contains(r#"amount "2500.00" is quoted"#),
+            "{}",
+            e
+        );
+
+        let columns = CsvColumns::new(&header).with_amount_format(AmountFormat {
+            scientific: false,
+            quoted: true,
+            ..AmountFormat::default()
+        });
+        for (amount, expected) in [(r#""2500.00""#, dec!(2500)), ("' 12.5 '", dec!(12.5))] {
+            let transaction = columns.deserialize(&deposit(amount)).unwrap();
+            assert_eq!(transaction.amount, Some(expected));
+        }
+        let e = columns.deserialize(&deposit(r#""1E3""#)).unwrap_err();
+        let scientific = r#"amount '"1E3"' is in scientific notation"#;
+        assert!(e.to_string().contains(scientific), "{}", e);
+        // Only numbers count as scientific notation
+        let e = columns.deserialize(&deposit("eleven")).unwrap_err();
+        let invalid = "amount 'eleven' is not a decimal number";
+        assert!(e.to_string().contains(invalid), "{}", e);
+    }
+
+    #[test]
+    fn test_other_layouts_go_through_serde() {
+        let columns = CsvColumns::new(&record(&["type", "client", "tx", "amount", "currency"]));
+        assert!(columns.fast.is_none());
+        let transaction = columns
+            .deserialize(&record(&["deposit", "1", "2", "3.0", "eur"]))
+            .unwrap();
+        assert_eq!(transaction.currency.unwrap().to_string(), "EUR");
+        assert!(
+            CsvColumns::new(&record(&["type", "client", "tx", "tx"]))
+                .fast
+                .is_none()
+        );
+    }
+}
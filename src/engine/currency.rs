@@ -0,0 +1,90 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Visitor};
+use std::fmt;
+
+// A three letter currency code such as EUR, stored uppercase. Copy and 3 bytes, so transaction
+// records can carry one without growing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    // None unless the code is exactly three ASCII letters, in any case
+    pub fn new(code: &str) -> Option<Self> {
+        let bytes: [u8; 3] = code.as_bytes().try_into().ok()?;
+        match bytes.iter().all(u8::is_ascii_alphabetic) {
+            true => Some(Currency(bytes.map(|byte| byte.to_ascii_uppercase()))),
+            false => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from ASCII letters
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+
+    // The encoding SledStorage keys balances by
+    #[cfg(feature = "sled")]
+    pub(crate) fn to_bytes(self) -> [u8; 3] {
+        self.0
+    }
+
+    #[cfg(feature = "sled")]
+    pub(crate) fn from_bytes(bytes: [u8; 3]) -> Option<Self> {
+        Currency::new(std::str::from_utf8(&bytes).ok()?)
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(CurrencyVisitor)
+    }
+}
+
+// Takes the code as borrowed when the format can, CSV rows being read without allocating
+struct CurrencyVisitor;
+
+impl Visitor<'_> for CurrencyVisitor {
+    type Value = Currency;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a three letter currency code")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, code: &str) -> Result<Currency, E> {
+        Currency::new(code.trim())
+            .ok_or_else(|| E::custom(format!("'{}' is not a three letter currency code", code)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_three_letters_uppercased() {
+        assert_eq!(Currency::new("eur"), Currency::new("EUR"));
+        assert_eq!(Currency::new("Usd").unwrap().to_string(), "USD");
+        assert_eq!(Currency::new("EURO"), None);
+        assert_eq!(Currency::new("E1R"), None);
+        assert_eq!(Currency::new(""), None);
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn test_byte_encoding() {
+        let gbp = Currency::new("GBP").unwrap();
+        assert_eq!(Currency::from_bytes(gbp.to_bytes()), Some(gbp));
+        assert_eq!(Currency::from_bytes([0; 3]), None);
+    }
+}
@@ -0,0 +1,3678 @@
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+#[cfg(feature = "snapshot")]
+use std::io::{Read, Write};
+use std::mem;
+use std::time::Duration;
+
+use super::account::{Account, AccountError, AccountResult, AccountStatus};
+use super::audit::{AdminAction, AuditEntry};
+use super::currency::Currency;
+use super::history::{self, BalanceDelta, History, HistoryEntry, TransactionEffect};
+use super::hook::{HookVerdict, TransactionHook};
+use super::ledger::{Ledger, LedgerEvent};
+use super::limits::Limits;
+use super::policy::{
+    AmountLimits, BlockPolicy, DisputeFunding, DisputePolicy, DisputeRules, LockedAccountPolicy,
+    PrecisionPolicy, RetentionPolicy, StandardDisputeRules, TxIdScope,
+};
+use super::retention::RetentionIndex;
+#[cfg(feature = "snapshot")]
+use super::snapshot::{Snapshot, SnapshotError};
+use super::transaction::{
+    ClientID, RecordKey, Timestamp, Transaction, TransactionID, TransactionRecord, TransactionType,
+};
+use super::wal::Wal;
+use crate::storage::{AccountEntries, MemoryStorage, StorageBackend, StorageError, StorageResult};
+
+// Record writes between two sweeps of the retention policy
+const PRUNE_INTERVAL: usize = 10_000;
+
+#[derive(Debug)]
+pub struct Database {
+    storage: Box<dyn StorageBackend>,
+    dispute_rules: StandardDisputeRules,
+    // Replaces dispute_rules when set
+    custom_dispute_rules: Option<Box<dyn DisputeRules>>,
+    locked_policy: LockedAccountPolicy,
+    precision: PrecisionPolicy,
+    allow_admin_ops: bool,
+    require_monotonic_time: bool,
+    dispute_window: Option<Duration>,
+    max_disputes_per_tx: Option<u8>,
+    fee_floor: Decimal,
+    overdraft_limit: Decimal,
+    require_open: bool,
+    tx_id_scope: TxIdScope,
+    // Latest timestamp seen, transactions may not go back before it when time must be monotonic
+    last_timestamp: Option<Timestamp>,
+    audit_log: Vec<AuditEntry>,
+    // Only kept when enabled through with_ledger
+    ledger: Option<Ledger>,
+    // Only kept when enabled through with_history
+    history: Option<History>,
+    skip_replays: bool,
+    replays_skipped: u64,
+    // Only kept when enabled through with_wal
+    wal: Option<Wal>,
+    retention: RetentionPolicy,
+    amount_limits: AmountLimits,
+    limits: Limits,
+    block_policy: BlockPolicy,
+    hooks: Vec<Box<dyn TransactionHook>>,
+    // How many accepted transactions the hooks tagged with each tag
+    tags: BTreeMap<String, u64>,
+    // Records written since the last sweep of the retention policy
+    records_written: usize,
+    // What the sweep goes through, built by the first one and dropped when it may be off, such
+    // as after a merge, to be built again
+    retained: Option<RetentionIndex>,
+    records_pruned: u64,
+    // Account writes of the transaction being processed, collected by process_with_effect only
+    effects: Option<Vec<(ClientID, Account, Account)>>,
+    // Accounts as they were when the open settlement period started, for the clients it touched.
+    // Only kept when enabled through with_settlement.
+    period_opening: Option<HashMap<ClientID, Account>>,
+    // Writes of the transaction being prepared, held back until it is committed
+    staged: Option<Vec<StagedWrite>>,
+    // Ids a record of another partition holds, when this is a shard of a ShardedDatabase or
+    // ConcurrentDatabase and ids are global
+    taken_elsewhere: HashSet<TransactionID>,
+}
+
+#[derive(Debug)]
+enum StagedWrite {
+    Account(ClientID, Option<Account>, Account),
+    Record(TransactionID, TransactionRecord),
+}
+
+// What a staged write replaced, None for nothing stored at all
+enum Undo {
+    Account(ClientID, Option<Account>),
+    Record(RecordKey, Option<TransactionRecord>),
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::with_storage(MemoryStorage::default())
+    }
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    NegativeAmount,
+    Duplicate,
+    AccountError(AccountError),
+    MissingAmount,
+    InvalidDispute,
+    ReferenceNotFound,
+    // A dispute, resolve or chargeback of a transaction whose record the retention policy dropped
+    ReferencePruned,
+    // A transfer without a to_client
+    MissingDestination,
+    // A transfer to the source client itself, or a convert into the source currency
+    InvalidTransfer,
+    // A convert without a rate, or with a rate that isn't positive
+    InvalidRate,
+    // A transfer between two partitions that can't be applied together. Every database applies
+    // them now, but the code stays reserved for clients that know it.
+    CrossShard,
+    // An admin transaction while admin ops are not allowed
+    AdminOpsDisabled,
+    AccountNotFound,
+    // A transaction without a timestamp while time is required to be monotonic
+    MissingTimestamp,
+    // A transaction timestamped before one already processed
+    OutOfOrder,
+    // A dispute, resolve or chargeback naming another currency than the disputed transaction
+    CurrencyMismatch,
+    // A dispute of a transaction already disputed as often as allowed
+    DisputeLimitReached,
+    // A dispute coming later than the dispute window after the disputed transaction
+    DisputeWindowExpired,
+    // A transaction for a client, or transfer to a client, that never ran an open transaction
+    // while opening is required
+    AccountNotOpen,
+    // The AsyncDatabase worker is gone
+    EngineStopped,
+    // A type this version doesn't know, see TransactionType::Other
+    UnknownType,
+    // An amount above AmountLimits::max_amount
+    AmountTooLarge,
+    // An amount with more decimal places than AmountLimits::max_precision
+    AmountTooPrecise,
+    // Breaks the limit rule with this ID, see Limits
+    LimitExceeded(String),
+    // For a client the BlockPolicy blocks
+    ClientBlocked,
+    // A partial dispute taking more than is left undisputed of the transaction
+    DisputeTooLarge,
+    // The referenced transaction can't be reversed, or already was
+    InvalidReversal,
+    // The TransactionHook rejected it, for the reason given
+    RejectedByHook(String),
+    // The engine's ingestion queue is full, the transaction may be submitted again later
+    QueueFull,
+    Storage(StorageError),
+}
+pub type TransactionResult = Result<(), TransactionError>;
+
+impl TransactionError {
+    // Stable identifier for reports and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::NegativeAmount => "negative_amount",
+            TransactionError::Duplicate => "duplicate",
+            TransactionError::AccountError(err) => err.code(),
+            TransactionError::MissingAmount => "missing_amount",
+            TransactionError::InvalidDispute => "invalid_dispute",
+            TransactionError::ReferenceNotFound => "reference_not_found",
+            TransactionError::ReferencePruned => "reference_pruned",
+            TransactionError::MissingDestination => "missing_destination",
+            TransactionError::InvalidTransfer => "invalid_transfer",
+            TransactionError::InvalidRate => "invalid_rate",
+            TransactionError::CrossShard => "cross_shard",
+            TransactionError::AdminOpsDisabled => "admin_ops_disabled",
+            TransactionError::AccountNotFound => "account_not_found",
+            TransactionError::MissingTimestamp => "missing_timestamp",
+            TransactionError::OutOfOrder => "out_of_order",
+            TransactionError::DisputeWindowExpired => "dispute_window_expired",
+            TransactionError::DisputeLimitReached => "dispute_limit_reached",
+            TransactionError::CurrencyMismatch => "currency_mismatch",
+            TransactionError::AccountNotOpen => "account_not_open",
+            TransactionError::EngineStopped => "engine_stopped",
+            TransactionError::UnknownType => "unknown_type",
+            TransactionError::AmountTooLarge => "amount_too_large",
+            TransactionError::AmountTooPrecise => "amount_too_precise",
+            TransactionError::LimitExceeded(_) => "limit_exceeded",
+            TransactionError::ClientBlocked => "client_blocked",
+            TransactionError::DisputeTooLarge => "dispute_too_large",
+            TransactionError::InvalidReversal => "invalid_reversal",
+            TransactionError::RejectedByHook(_) => "rejected_by_hook",
+            TransactionError::QueueFull => "queue_full",
+            TransactionError::Storage(_) => "storage",
+        }
+    }
+
+    // Stable numeric identifier for consumers that can't carry strings, never reused. Account
+    // errors keep their own, from 100 up.
+    pub fn number(&self) -> u16 {
+        match self {
+            TransactionError::NegativeAmount => 1,
+            TransactionError::Duplicate => 2,
+            TransactionError::AccountError(err) => err.number(),
+            TransactionError::MissingAmount => 3,
+            TransactionError::InvalidDispute => 4,
+            TransactionError::ReferenceNotFound => 5,
+            TransactionError::MissingDestination => 6,
+            TransactionError::InvalidTransfer => 7,
+            TransactionError::InvalidRate => 8,
+            TransactionError::CrossShard => 9,
+            TransactionError::AdminOpsDisabled => 10,
+            TransactionError::AccountNotFound => 11,
+            TransactionError::MissingTimestamp => 12,
+            TransactionError::OutOfOrder => 13,
+            TransactionError::DisputeWindowExpired => 14,
+            TransactionError::DisputeLimitReached => 15,
+            TransactionError::CurrencyMismatch => 16,
+            TransactionError::AccountNotOpen => 17,
+            TransactionError::EngineStopped => 18,
+            TransactionError::Storage(_) => 19,
+            TransactionError::ReferencePruned => 20,
+            TransactionError::AmountTooLarge => 21,
+            TransactionError::AmountTooPrecise => 22,
+            TransactionError::UnknownType => 23,
+            TransactionError::LimitExceeded(_) => 24,
+            TransactionError::ClientBlocked => 25,
+            TransactionError::DisputeTooLarge => 26,
+            TransactionError::InvalidReversal => 27,
+            TransactionError::RejectedByHook(_) => 28,
+            TransactionError::QueueFull => 29,
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransactionError::NegativeAmount => "amount must not be negative",
+            TransactionError::Duplicate => "transaction ID already used",
+            TransactionError::AccountError(err) => return err.fmt(f),
+            TransactionError::MissingAmount => "amount missing",
+            TransactionError::InvalidDispute => "transaction is not in a state to be disputed",
+            TransactionError::ReferenceNotFound => "referenced transaction not found",
+            TransactionError::ReferencePruned => "referenced transaction no longer kept",
+            TransactionError::MissingDestination => "transfer without a destination client",
+            TransactionError::InvalidTransfer => "transfer or convert into its own source",
+            TransactionError::InvalidRate => "rate missing or not positive",
+            TransactionError::CrossShard => "transfer between clients of different shards",
+            TransactionError::AdminOpsDisabled => "admin operations are not allowed",
+            TransactionError::AccountNotFound => "account not found",
+            TransactionError::MissingTimestamp => "timestamp missing",
+            TransactionError::OutOfOrder => "timestamp before one already processed",
+            TransactionError::DisputeWindowExpired => "dispute window expired",
+            TransactionError::DisputeLimitReached => "transaction disputed too often",
+            TransactionError::CurrencyMismatch => "currency differs from the disputed transaction",
+            TransactionError::AccountNotOpen => "account was never opened",
+            TransactionError::EngineStopped => "engine stopped",
+            TransactionError::UnknownType => "unknown transaction type",
+            TransactionError::AmountTooLarge => "amount above the allowed maximum",
+            TransactionError::AmountTooPrecise => "amount has more decimal places than allowed",
+            TransactionError::LimitExceeded(id) => return write!(f, "limit {} exceeded", id),
+            TransactionError::ClientBlocked => "client is blocked",
+            TransactionError::DisputeTooLarge => "dispute amount above what is left undisputed",
+            TransactionError::InvalidReversal => "transaction is not in a state to be reversed",
+            TransactionError::RejectedByHook(reason) => {
+                return write!(f, "rejected by hook: {}", reason);
+            }
+            TransactionError::QueueFull => "ingestion queue full, try again later",
+            TransactionError::Storage(err) => return err.fmt(f),
+        })
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::AccountError(err) => Some(err),
+            TransactionError::Storage(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<StorageError> for TransactionError {
+    fn from(err: StorageError) -> Self {
+        TransactionError::Storage(err)
+    }
+}
+
+impl From<AccountError> for TransactionError {
+    fn from(err: AccountError) -> Self {
+        TransactionError::AccountError(err)
+    }
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_storage(storage: impl StorageBackend + 'static) -> Self {
+        Database {
+            storage: Box::new(storage),
+            dispute_rules: StandardDisputeRules::default(),
+            custom_dispute_rules: None,
+            locked_policy: LockedAccountPolicy::default(),
+            precision: PrecisionPolicy::default(),
+            allow_admin_ops: false,
+            require_monotonic_time: false,
+            dispute_window: None,
+            max_disputes_per_tx: None,
+            fee_floor: Decimal::ZERO,
+            overdraft_limit: Decimal::ZERO,
+            require_open: false,
+            tx_id_scope: TxIdScope::default(),
+            last_timestamp: None,
+            audit_log: Vec::new(),
+            ledger: None,
+            history: None,
+            skip_replays: false,
+            replays_skipped: 0,
+            wal: None,
+            retention: RetentionPolicy::default(),
+            amount_limits: AmountLimits::default(),
+            limits: Limits::default(),
+            block_policy: BlockPolicy::default(),
+            hooks: Vec::new(),
+            tags: BTreeMap::new(),
+            records_written: 0,
+            retained: None,
+            records_pruned: 0,
+            effects: None,
+            period_opening: None,
+            staged: None,
+            taken_elsewhere: HashSet::new(),
+        }
+    }
+
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.dispute_rules.policy = dispute_policy;
+        self
+    }
+
+    pub fn with_dispute_funding(mut self, dispute_funding: DisputeFunding) -> Self {
+        self.dispute_rules.funding = dispute_funding;
+        self
+    }
+
+    // House rules taking over from with_dispute_policy and with_dispute_funding
+    pub fn with_dispute_rules(mut self, dispute_rules: impl DisputeRules + 'static) -> Self {
+        self.custom_dispute_rules = Some(Box::new(dispute_rules));
+        self
+    }
+
+    pub fn dispute_rules(&self) -> &dyn DisputeRules {
+        match &self.custom_dispute_rules {
+            Some(rules) => rules.as_ref(),
+            None => &self.dispute_rules,
+        }
+    }
+
+    pub fn with_locked_policy(mut self, locked_policy: LockedAccountPolicy) -> Self {
+        self.locked_policy = locked_policy;
+        self
+    }
+
+    // Admin transactions such as 'unlock' are rejected unless allowed here
+    pub fn with_admin_ops(mut self, allow_admin_ops: bool) -> Self {
+        self.allow_admin_ops = allow_admin_ops;
+        self
+    }
+
+    // Rejects transactions without a timestamp or timestamped before an earlier one. Equal
+    // timestamps are fine.
+    pub fn with_require_monotonic_time(mut self, require_monotonic_time: bool) -> Self {
+        self.require_monotonic_time = require_monotonic_time;
+        self
+    }
+
+    // Rejects disputes timestamped more than the window after the disputed transaction. Only
+    // applies when both carry a timestamp.
+    pub fn with_dispute_window(mut self, dispute_window: Duration) -> Self {
+        self.dispute_window = Some(dispute_window);
+        self
+    }
+
+    // How many times one transaction may be disputed, 1 closing it for good once its dispute is
+    // settled. Unlimited by default.
+    pub fn with_max_disputes_per_tx(mut self, max_disputes_per_tx: u8) -> Self {
+        self.max_disputes_per_tx = Some(max_disputes_per_tx);
+        self
+    }
+
+    // The lowest available balance a fee may leave, e.g. -50 to let fees run up a debt of 50.
+    // Zero by default, so fees never drive available negative.
+    pub fn with_fee_floor(mut self, fee_floor: Decimal) -> Self {
+        self.fee_floor = fee_floor;
+        self
+    }
+
+    // How far below zero withdrawals and outgoing transfers may take available, e.g. 100 for a
+    // credit line of 100. Zero by default.
+    pub fn with_overdraft_limit(mut self, overdraft_limit: Decimal) -> Self {
+        self.overdraft_limit = overdraft_limit;
+        self
+    }
+
+    // Rejects transactions for accounts that were never explicitly opened, rather than opening
+    // them implicitly on their first transaction
+    pub fn with_require_open(mut self, require_open: bool) -> Self {
+        self.require_open = require_open;
+        self
+    }
+
+    // With TxIdScope::PerClient a deposit reusing another client's tx id is accepted rather than
+    // rejected as a duplicate, and disputes only find transactions of their own client
+    pub fn with_tx_id_scope(mut self, tx_id_scope: TxIdScope) -> Self {
+        self.tx_id_scope = tx_id_scope;
+        self
+    }
+
+    pub fn with_precision(mut self, precision: PrecisionPolicy) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    // Records every accepted state mutation into the given ledger, usually Ledger::new()
+    pub fn with_ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+
+    // Records the outcome and balance deltas of every processed transaction, usually
+    // History::new(). Unlike the ledger, rejected transactions are kept too.
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    // Accepts a transaction already applied, as recorded, without applying it again rather than
+    // rejecting it, so an input can be fed again after a run that failed part way. Disputes,
+    // resolves and chargebacks count as replays when the record is past them. A dispute of a
+    // resolved transaction is a new one while the rules allow another dispute of it. A
+    // transaction the accounts refused is recorded as rejected, so it is skipped too rather than
+    // applied the second time.
+    pub fn with_skip_replays(mut self, skip_replays: bool) -> Self {
+        self.skip_replays = skip_replays;
+        self
+    }
+
+    // How many transactions with_skip_replays let through without applying them
+    pub fn replays_skipped(&self) -> u64 {
+        self.replays_skipped
+    }
+
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    // Records dropped by the retention policy over the lifetime of this Database
+    pub fn records_pruned(&self) -> u64 {
+        self.records_pruned
+    }
+
+    // Rejects amounts beyond the limits as given, before they are rounded to the precision
+    pub fn with_amount_limits(mut self, amount_limits: AmountLimits) -> Self {
+        self.amount_limits = amount_limits;
+        self
+    }
+
+    // Rejects transactions breaking the limit rules. What clients did in their windows is only
+    // kept in memory, it starts afresh when the engine does.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Rejects every transaction of the clients the policy blocks. Unlike the other policies it
+    // can be swapped at any time, for blocklists that change while the engine runs.
+    pub fn set_block_policy(&mut self, block_policy: BlockPolicy) {
+        self.block_policy = block_policy;
+    }
+
+    // Tracks the accounts touched since the settlement period opened, for settle. The open period
+    // is only kept in memory, it is not part of snapshots.
+    pub fn with_settlement(mut self, settlement: bool) -> Self {
+        self.period_opening = settlement.then(HashMap::new);
+        self
+    }
+
+    // Closes the settlement period, returning by client and currency how much each balance moved
+    // during it, and opens the next one. Balances are untouched. Empty unless settlement is
+    // enabled.
+    pub fn settle(&mut self) -> StorageResult<Vec<BalanceDelta>> {
+        let Some(opening) = self.period_opening.as_mut() else {
+            return Ok(Vec::new());
+        };
+        let mut opening = opening.drain().collect::<Vec<_>>();
+        opening.sort_unstable_by_key(|(client, _)| *client);
+        let mut deltas = Vec::new();
+        for (client, before) in opening {
+            let after = self.storage.account(client)?.unwrap_or_default();
+            deltas.extend(history::deltas(client, &before, &after));
+        }
+        Ok(deltas)
+    }
+
+    // Runs every transaction through the hook before applying it, see TransactionHook. Hooks run
+    // in the order installed, each seeing the transaction as the previous one left it, and the
+    // first rejection wins. As the WAL logs transactions as given, a hook has to decide the same
+    // way when they are replayed.
+    pub fn with_hook(mut self, hook: impl TransactionHook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    // How many accepted transactions the hooks tagged, by tag
+    pub fn tags(&self) -> &BTreeMap<String, u64> {
+        &self.tags
+    }
+
+    // Logs every transaction to the WAL before processing it. Transactions the WAL held when
+    // opened are to be processed before it is installed.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn wal_mut(&mut self) -> Option<&mut Wal> {
+        self.wal.as_mut()
+    }
+
+    pub fn precision(&self) -> PrecisionPolicy {
+        self.precision
+    }
+
+    pub fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+        self.storage.account(client)
+    }
+
+    // Iterates every account the engine has seen, ordering depends on the storage backend
+    pub fn accounts(&self) -> AccountEntries<'_> {
+        self.storage.accounts()
+    }
+
+    // Every account in ascending client order. Streamed when the storage backend lists them in
+    // that order, otherwise read in full and sorted.
+    pub fn accounts_by_client(&self) -> AccountEntries<'_> {
+        if self.storage.accounts_ordered() {
+            return self.accounts();
+        }
+        match self.accounts().collect::<StorageResult<Vec<_>>>() {
+            Ok(mut accounts) => {
+                accounts.sort_unstable_by_key(|(client, _)| *client);
+                Box::new(accounts.into_iter().map(Ok))
+            }
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    // The accounts a chargeback locked and no unlock released yet
+    pub fn locked_accounts(&self) -> AccountEntries<'_> {
+        Box::new(self.accounts().filter(|entry| match entry {
+            Ok((_, account)) => account.is_locked(),
+            Err(_) => true,
+        }))
+    }
+
+    // Every admin operation applied so far, oldest first
+    pub fn audit_log(&self) -> &[AuditEntry] {
+        &self.audit_log
+    }
+
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // Every transaction processed under this id, the original one first and then the disputes,
+    // resolves and chargebacks referring to it. Empty unless with_history was set.
+    pub fn transaction_history(&self, tx: TransactionID) -> &[HistoryEntry] {
+        self.history
+            .as_ref()
+            .map_or(&[], |history| history.entries(tx))
+    }
+
+    // Cross-checks every account against the transaction records: totals are representable, held
+    // funds are never negative unless the dispute rules allow it and always match the open
+    // disputes, and every record belongs to a known client
+    #[cfg(debug_assertions)]
+    pub fn check_invariants(&self) -> Result<(), super::invariants::InvariantViolation> {
+        super::invariants::check(
+            self.storage.accounts(),
+            self.storage.records(),
+            self.dispute_rules().allows_negative_available(),
+        )
+    }
+
+    // Rebuilds state from events recorded by another Database's ledger. Events are applied
+    // as-is, without the checks and policies that accepted them in the first place.
+    pub fn replay(&mut self, events: impl IntoIterator<Item = LedgerEvent>) -> TransactionResult {
+        for event in events {
+            match (&event, event.client()) {
+                (LedgerEvent::RecordWritten { tx, record }, _) => self.write_record(*tx, record)?,
+                (_, Some(client)) => {
+                    let before = self.storage.account(client)?;
+                    let mut account = before.clone().unwrap_or_default();
+                    account.apply(&event)?;
+                    self.write_account(client, before.as_ref(), &account)?;
+                }
+                (_, None) => (),
+            }
+        }
+        Ok(())
+    }
+
+    // Serializes every account, transaction record and audit entry, so a later run can carry on
+    // from here through restore_snapshot
+    #[cfg(feature = "snapshot")]
+    pub fn write_snapshot(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        let mut snapshot = Snapshot::new();
+        for entry in self.storage.accounts() {
+            let (client, account) = entry?;
+            snapshot.push_account(client, &account);
+        }
+        for entry in self.storage.records() {
+            let (key, record) = entry?;
+            snapshot.push_record(key.tx, &record);
+        }
+        for key in self.storage.pruned() {
+            snapshot.pruned.push(key?);
+        }
+        snapshot.audit_log = self.audit_log.clone();
+        snapshot.last_timestamp = self.last_timestamp;
+        if let Some(history) = &self.history {
+            for (tx, entry) in history.iter() {
+                snapshot.push_history(tx, entry);
+            }
+        }
+        snapshot.write(writer)
+    }
+
+    // Loads a snapshot written by write_snapshot, replacing any account or record it contains
+    #[cfg(feature = "snapshot")]
+    pub fn restore_snapshot(&mut self, reader: impl Read) -> Result<(), SnapshotError> {
+        let snapshot = Snapshot::read(reader)?;
+        for (tx, record) in snapshot.records() {
+            self.write_record(tx, &record)?;
+        }
+        for key in &snapshot.pruned {
+            self.storage.prune_record(*key)?;
+        }
+        for (client, account) in snapshot.accounts() {
+            let before = self.storage.account(client)?;
+            self.write_account(client, before.as_ref(), &account)?;
+        }
+        if let Some(history) = &mut self.history {
+            for (tx, entry) in snapshot.history() {
+                history.push(tx, entry);
+            }
+        }
+        self.audit_log.extend(snapshot.audit_log);
+        self.last_timestamp = self.last_timestamp.max(snapshot.last_timestamp);
+        Ok(())
+    }
+
+    // Every account and record write goes through these so the ledger sees it. Observers only
+    // hear of a write once it is stored.
+    fn write_account(
+        &mut self,
+        client: ClientID,
+        before: Option<&Account>,
+        after: &Account,
+    ) -> StorageResult<()> {
+        if let Some(staged) = &mut self.staged {
+            staged.push(StagedWrite::Account(client, before.cloned(), after.clone()));
+            return Ok(());
+        }
+        self.storage.put_account(client, after)?;
+        self.account_written(client, before, after);
+        Ok(())
+    }
+
+    fn account_written(&mut self, client: ClientID, before: Option<&Account>, after: &Account) {
+        if let Some(ledger) = &mut self.ledger {
+            ledger.record_account(client, before, after);
+        }
+        if let Some(history) = &mut self.history {
+            history.record_account(client, before, after);
+        }
+        if let Some(opening) = &mut self.period_opening {
+            opening
+                .entry(client)
+                .or_insert_with(|| before.cloned().unwrap_or_default());
+        }
+        if let Some(effects) = &mut self.effects {
+            match effects.iter_mut().find(|(written, ..)| *written == client) {
+                Some((_, _, new)) => *new = after.clone(),
+                None => effects.push((client, before.cloned().unwrap_or_default(), after.clone())),
+            }
+        }
+    }
+
+    fn write_record(&mut self, tx: TransactionID, record: &TransactionRecord) -> StorageResult<()> {
+        if let Some(staged) = &mut self.staged {
+            staged.push(StagedWrite::Record(tx, *record));
+            return Ok(());
+        }
+        self.store_record(tx, record)?;
+        self.record_written(tx, record);
+        Ok(())
+    }
+
+    // Stores the record, or only its key where the retention policy keeps no record of its type
+    fn store_record(&mut self, tx: TransactionID, record: &TransactionRecord) -> StorageResult<()> {
+        let key = self.tx_id_scope.key(record.client(), tx);
+        if self.retention.disputable_only && !self.dispute_rules().is_disputable(&record.tx_type())
+        {
+            self.storage.prune_record(key)?;
+            self.records_pruned += 1;
+            return Ok(());
+        }
+        self.storage.put_record(key, record)?;
+        self.records_written += 1;
+        if let Some(retained) = &mut self.retained {
+            retained.insert(tx, record);
+        }
+        Ok(())
+    }
+
+    fn record_written(&mut self, tx: TransactionID, record: &TransactionRecord) {
+        if let Some(ledger) = &mut self.ledger {
+            ledger.push(LedgerEvent::RecordWritten {
+                tx,
+                record: *record,
+            });
+        }
+    }
+
+    // Stores the writes a Prepared held back. Should one fail, every write up to it is put back
+    // as it was before, so the storage ends up with all of them or none.
+    fn store_staged(&mut self, writes: &[StagedWrite]) -> StorageResult<()> {
+        let counters = (self.records_written, self.records_pruned);
+        let mut undo = Vec::with_capacity(writes.len());
+        for write in writes {
+            // Noted before writing, a failed write may still have stored part of its value
+            let result = match write {
+                StagedWrite::Account(client, before, after) => {
+                    undo.push(Undo::Account(*client, before.clone()));
+                    self.storage.put_account(*client, after)
+                }
+                StagedWrite::Record(tx, record) => {
+                    let key = self.tx_id_scope.key(record.client(), *tx);
+                    self.storage.record(key).and_then(|before| {
+                        undo.push(Undo::Record(key, before));
+                        self.store_record(*tx, record)
+                    })
+                }
+            };
+            if let Err(err) = result {
+                (self.records_written, self.records_pruned) = counters;
+                self.retained = None;
+                return match self.undo(undo) {
+                    Ok(()) => Err(err),
+                    Err(undo_err) => Err(StorageError::Backend(format!(
+                        "{}, and putting back the writes before it failed too: {}",
+                        err, undo_err
+                    ))),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    // Goes through every step even past a failing one, returning the first failure
+    fn undo(&mut self, undo: Vec<Undo>) -> StorageResult<()> {
+        let mut result = Ok(());
+        for step in undo.into_iter().rev() {
+            let undone = match step {
+                Undo::Account(client, Some(before)) => self.storage.put_account(client, &before),
+                Undo::Account(client, None) => self.storage.remove_account(client),
+                Undo::Record(key, Some(before)) => self.storage.put_record(key, &before),
+                Undo::Record(key, None) => self.storage.remove_record(key),
+            };
+            result = result.and(undone);
+        }
+        result
+    }
+
+    // Whether the transaction's id was used before, by a record kept or pruned
+    fn taken(&self, transaction: &Transaction) -> StorageResult<bool> {
+        let key = self.record_key(transaction);
+        Ok(self.storage.contains_record(key)?
+            || self.storage.is_pruned(key)?
+            || self.taken_elsewhere.contains(&transaction.tx))
+    }
+
+    // Shards keep global ids unique between them through these: the shard a transaction taking
+    // an id routes to asks the shard that took it last whether it still holds it, and if so the
+    // id is taken there too
+    pub(crate) fn holds_id(&self, tx: TransactionID) -> StorageResult<bool> {
+        let key = RecordKey::from(tx);
+        Ok(self.storage.contains_record(key)? || self.storage.is_pruned(key)?)
+    }
+
+    pub(crate) fn take_id_elsewhere(&mut self, tx: TransactionID) {
+        self.taken_elsewhere.insert(tx);
+    }
+
+    pub(crate) fn tx_id_scope(&self) -> TxIdScope {
+        self.tx_id_scope
+    }
+
+    // Moves a client's account out of this shard, for the shard of a transfer's sender to apply
+    // the transfer with, and back in through give_account once it did. The ledger and history
+    // aren't told, no money moves.
+    pub(crate) fn take_account(&mut self, client: ClientID) -> StorageResult<Option<Account>> {
+        let account = self.storage.account(client)?;
+        if account.is_some() {
+            self.storage.remove_account(client)?;
+        }
+        Ok(account)
+    }
+
+    pub(crate) fn give_account(
+        &mut self,
+        client: ClientID,
+        account: Option<&Account>,
+    ) -> StorageResult<()> {
+        match account {
+            Some(account) => self.storage.put_account(client, account),
+            None => Ok(()),
+        }
+    }
+
+    // Sweeps the records the retention policy no longer keeps, returning how many were dropped.
+    // Called every PRUNE_INTERVAL record writes, so it only needs calling directly to free memory
+    // at a particular moment. Only the records past the dispute window and those of clients
+    // written to since the last sweep are read, disputed ones always staying.
+    pub fn prune(&mut self) -> StorageResult<u64> {
+        self.records_written = 0;
+        // Taken out while sweeping, a failed sweep leaves it to be built again
+        let mut retained = match self.retained.take() {
+            Some(retained) => retained,
+            None => {
+                let mut retained = RetentionIndex::default();
+                for entry in self.storage.records() {
+                    let (key, record) = entry?;
+                    retained.insert(key.tx, &record);
+                }
+                retained
+            }
+        };
+        let mut pruned = 0;
+        // The clock only advances with monotonic time required, so the newest record stands in
+        let now = retained.newest().max(self.last_timestamp);
+        if let (true, Some(window), Some(now)) =
+            (self.retention.past_dispute_window, self.dispute_window, now)
+        {
+            for (client, tx) in retained.older_than(now.saturating_sub(window.as_secs())) {
+                pruned += self.prune_retained(&mut retained, client, [tx])?;
+            }
+        }
+        if let Some(max) = self.retention.max_per_client {
+            for (client, txs) in retained.over(max) {
+                let mut kept = Vec::with_capacity(txs.len());
+                for tx in txs {
+                    let key = self.tx_id_scope.key(client, tx);
+                    if self
+                        .storage
+                        .record(key)?
+                        .is_some_and(|record| !record.is_disputed())
+                    {
+                        kept.push(tx);
+                    }
+                }
+                let excess = kept.len().saturating_sub(max);
+                pruned +=
+                    self.prune_retained(&mut retained, client, kept.into_iter().take(excess))?;
+            }
+        }
+        self.retained = Some(retained);
+        self.records_pruned += pruned;
+        Ok(pruned)
+    }
+
+    // Prunes the client's records that aren't disputed, returning how many
+    fn prune_retained(
+        &mut self,
+        retained: &mut RetentionIndex,
+        client: ClientID,
+        txs: impl IntoIterator<Item = TransactionID>,
+    ) -> StorageResult<u64> {
+        let mut pruned = 0;
+        for tx in txs {
+            let key = self.tx_id_scope.key(client, tx);
+            match self.storage.record(key)? {
+                Some(record) if !record.is_disputed() => {
+                    self.storage.prune_record(key)?;
+                    retained.remove(tx, &record);
+                    pruned += 1;
+                }
+                _ => (),
+            }
+        }
+        Ok(pruned)
+    }
+
+    // Where the record of the transaction, or of the one a dispute refers to, is stored
+    fn record_key(&self, transaction: &Transaction) -> RecordKey {
+        self.tx_id_scope.key(transaction.client, transaction.tx)
+    }
+
+    // Releases an account locked by a chargeback. Always allowed through the library API,
+    // only the 'unlock' transaction is gated by with_admin_ops.
+    pub fn unlock(&mut self, client: ClientID) -> TransactionResult {
+        self.apply_unlock(client, None)
+    }
+
+    fn apply_unlock(&mut self, client: ClientID, tx: Option<TransactionID>) -> TransactionResult {
+        match self.storage.account(client)? {
+            Some(before) => {
+                let mut account = before.clone();
+                match account.unlock() {
+                    Ok(()) => {
+                        self.write_account(client, Some(&before), &account)?;
+                        self.audit_log.push(AuditEntry {
+                            action: AdminAction::Unlock,
+                            client,
+                            tx,
+                        });
+                        Ok(())
+                    }
+                    Err(err) => Err(TransactionError::AccountError(err)),
+                }
+            }
+            None => Err(TransactionError::AccountNotFound),
+        }
+    }
+
+    pub fn flush(&mut self) -> StorageResult<()> {
+        self.storage.flush()
+    }
+
+    // Folds another partition into this one. Partitions are expected to hold disjoint clients.
+    pub(crate) fn merge(&mut self, other: Database) -> StorageResult<()> {
+        for entry in other.storage.records() {
+            let (key, record) = entry?;
+            self.storage.put_record(key, &record)?;
+        }
+        for key in other.storage.pruned() {
+            self.storage.prune_record(key?)?;
+        }
+        self.records_pruned += other.records_pruned;
+        self.retained = None;
+        for entry in other.storage.accounts() {
+            let (cid, acc) = entry?;
+            self.storage.put_account(cid, &acc)?;
+        }
+        self.audit_log.extend(other.audit_log);
+        self.replays_skipped += other.replays_skipped;
+        for (tag, count) in other.tags {
+            *self.tags.entry(tag).or_default() += count;
+        }
+        self.last_timestamp = self.last_timestamp.max(other.last_timestamp);
+        if let (Some(ledger), Some(other)) = (&mut self.ledger, other.ledger) {
+            ledger.extend(other);
+        }
+        if let (Some(history), Some(other)) = (&mut self.history, other.history) {
+            history.extend(other);
+        }
+        Ok(())
+    }
+
+    fn handle_status_change(
+        &mut self,
+        client: ClientID,
+        action: impl Fn(&mut Account) -> AccountResult,
+    ) -> TransactionResult {
+        let before = self.storage.account(client)?;
+        let mut account = before.clone().unwrap_or_default();
+        action(&mut account)?;
+        self.write_account(client, before.as_ref(), &account)?;
+        Ok(())
+    }
+
+    fn check_blocked(&self, transaction: &Transaction) -> TransactionResult {
+        let mut clients = std::iter::once(transaction.client).chain(transaction.to_client);
+        match clients.any(|client| self.block_policy.blocks(client)) {
+            true => Err(TransactionError::ClientBlocked),
+            false => Ok(()),
+        }
+    }
+
+    // Closed accounts are left to fail in the account operations, whether opening is required or
+    // not
+    fn check_open(&self, transaction: &Transaction) -> TransactionResult {
+        if !self.require_open || transaction.tx_type == TransactionType::Open {
+            return Ok(());
+        }
+        let clients = std::iter::once(transaction.client).chain(transaction.to_client);
+        for client in clients {
+            let status = self
+                .storage
+                .account(client)?
+                .map(|account| account.status());
+            if matches!(status, None | Some(AccountStatus::Implicit)) {
+                return Err(TransactionError::AccountNotOpen);
+            }
+        }
+        Ok(())
+    }
+
+    // Checks an amount as given against the amount limits, then rounds it to the precision. It
+    // must still be positive once rounded.
+    fn checked_amount(&self, amount: Decimal) -> Result<Decimal, TransactionError> {
+        if amount <= Decimal::ZERO {
+            return Err(TransactionError::NegativeAmount);
+        }
+        if let Some(max_precision) = self.amount_limits.max_precision
+            && amount.normalize().scale() > max_precision
+        {
+            return Err(TransactionError::AmountTooPrecise);
+        }
+        if let Some(max_amount) = self.amount_limits.max_amount
+            && amount > max_amount
+        {
+            return Err(TransactionError::AmountTooLarge);
+        }
+        match self.precision.normalize(amount) {
+            amount if amount <= Decimal::ZERO => Err(TransactionError::NegativeAmount),
+            amount => Ok(amount),
+        }
+    }
+
+    fn handle_amount_transaction(
+        &mut self,
+        transaction: &Transaction,
+        action: impl Fn(&mut Account, Option<Currency>, Decimal) -> AccountResult,
+    ) -> TransactionResult {
+        match transaction.amount {
+            Some(amount) => {
+                let amount = self.checked_amount(amount)?;
+                if self.taken(transaction)? {
+                    self.duplicate(transaction, amount)
+                } else {
+                    let before = self.storage.account(transaction.client)?;
+                    let mut account = before.clone().unwrap_or_default();
+                    let result = action(&mut account, transaction.currency, amount);
+                    // Clients are registered even if their first transaction is rejected
+                    self.write_account(transaction.client, before.as_ref(), &account)?;
+                    match result {
+                        Ok(()) => {
+                            self.write_record(
+                                transaction.tx,
+                                &TransactionRecord::new(
+                                    &transaction.tx_type,
+                                    transaction.client,
+                                    amount,
+                                )
+                                .with_timestamp(transaction.timestamp)
+                                .with_currency(transaction.currency),
+                            )?;
+                            Ok(())
+                        }
+                        Err(err) => Err(TransactionError::AccountError(err)),
+                    }
+                }
+            }
+            None => Err(TransactionError::MissingAmount),
+        }
+    }
+    // Debits the source and credits the destination. Both accounts are only written back once
+    // both sides succeeded, so a rejected transfer never applies partially.
+    fn handle_transfer(&mut self, transaction: &Transaction) -> TransactionResult {
+        match (transaction.amount, transaction.to_client) {
+            (Some(amount), Some(to_client)) => {
+                let amount = self.checked_amount(amount)?;
+                if to_client == transaction.client {
+                    Err(TransactionError::InvalidTransfer)
+                } else if self.taken(transaction)? {
+                    self.duplicate(transaction, amount)
+                } else {
+                    let from_before = self.storage.account(transaction.client)?;
+                    let to_before = self.storage.account(to_client)?;
+                    let mut from = from_before.clone().unwrap_or_default();
+                    let mut to = to_before.clone().unwrap_or_default();
+                    let currency = transaction.currency;
+                    match from
+                        .withdraw_into_overdraft(currency, amount, self.overdraft_limit)
+                        .and_then(|()| to.deposit(currency, amount))
+                    {
+                        Ok(()) => {
+                            self.write_account(transaction.client, from_before.as_ref(), &from)?;
+                            self.write_account(to_client, to_before.as_ref(), &to)?;
+                            self.write_record(
+                                transaction.tx,
+                                &TransactionRecord::new(
+                                    &transaction.tx_type,
+                                    transaction.client,
+                                    amount,
+                                )
+                                .with_timestamp(transaction.timestamp)
+                                .with_currency(transaction.currency),
+                            )?;
+                            Ok(())
+                        }
+                        Err(err) => Err(TransactionError::AccountError(err)),
+                    }
+                }
+            }
+            (None, _) => Err(TransactionError::MissingAmount),
+            (Some(_), None) => Err(TransactionError::MissingDestination),
+        }
+    }
+
+    // Debits amount from the source currency and credits amount * rate in the target currency,
+    // both or neither
+    fn handle_convert(&mut self, transaction: &Transaction) -> TransactionResult {
+        let (amount, to_currency, rate) = match (
+            transaction.amount,
+            transaction.to_currency,
+            transaction.rate,
+        ) {
+            (None, _, _) => return Err(TransactionError::MissingAmount),
+            (Some(_), None, _) => return Err(TransactionError::MissingDestination),
+            (Some(_), Some(_), None) => return Err(TransactionError::InvalidRate),
+            (Some(amount), Some(to_currency), Some(rate)) => (amount, to_currency, rate),
+        };
+        let amount = self.checked_amount(amount)?;
+        if rate <= Decimal::ZERO {
+            return Err(TransactionError::InvalidRate);
+        }
+        if transaction.currency == Some(to_currency) {
+            return Err(TransactionError::InvalidTransfer);
+        }
+        let converted = amount
+            .checked_mul(rate)
+            .map(|converted| self.precision.normalize(converted))
+            .ok_or(TransactionError::AccountError(AccountError::Overflow))?;
+        // A rate so small that nothing arrives would make the funds vanish
+        if converted <= Decimal::ZERO {
+            return Err(TransactionError::NegativeAmount);
+        }
+        if self.taken(transaction)? {
+            return self.duplicate(transaction, amount);
+        }
+        let before = self.storage.account(transaction.client)?;
+        let mut account = before.clone().unwrap_or_default();
+        account
+            .withdraw(transaction.currency, amount)
+            .and_then(|()| account.deposit(Some(to_currency), converted))?;
+        self.write_account(transaction.client, before.as_ref(), &account)?;
+        self.write_record(
+            transaction.tx,
+            &TransactionRecord::new(&transaction.tx_type, transaction.client, amount)
+                .with_timestamp(transaction.timestamp)
+                .with_currency(transaction.currency),
+        )?;
+        Ok(())
+    }
+
+    // Keeps the id of a deposit, withdrawal, transfer, convert or fee the accounts refused, in a
+    // record flagged as rejected, so the id isn't free for another transaction and feeding the
+    // input again finds the rejection rather than applying the transaction this time round.
+    // Transactions refused for their form, or for an id already taken, leave nothing, and so does
+    // a prepared one, which changes nothing when rejected.
+    fn record_rejection(&mut self, transaction: &Transaction) -> TransactionResult {
+        let recorded = transaction.tx_type.takes_id();
+        let Some(amount) = transaction.amount.filter(|_| recorded) else {
+            return Ok(());
+        };
+        if self.staged.is_some() || self.taken(transaction)? {
+            return Ok(());
+        }
+        let mut record = TransactionRecord::new(
+            &transaction.tx_type,
+            transaction.client,
+            self.checked_amount(amount)?,
+        )
+        .with_timestamp(transaction.timestamp)
+        .with_currency(transaction.currency);
+        record.set_rejected();
+        // Not a ledger event, the ledger only holds what was applied
+        self.store_record(transaction.tx, &record)?;
+        Ok(())
+    }
+
+    // A transaction whose id is taken is a duplicate, unless replays are skipped and it is the
+    // recorded transaction delivered again, applied or rejected the first time
+    fn duplicate(&mut self, transaction: &Transaction, amount: Decimal) -> TransactionResult {
+        let replayed = self.skip_replays
+            && self
+                .storage
+                .record(self.record_key(transaction))?
+                .is_some_and(|record| {
+                    record.client() == transaction.client
+                        && record.tx_type() == transaction.tx_type
+                        && record.amount() == amount
+                        && record.currency() == transaction.currency
+                });
+        match replayed {
+            true => {
+                self.replays_skipped += 1;
+                Ok(())
+            }
+            false => Err(TransactionError::Duplicate),
+        }
+    }
+
+    // replayed tells records the transaction was already applied to, checked first when replays
+    // are skipped
+    fn handle_dispute_like(
+        &mut self,
+        transaction: &Transaction,
+        replayed: impl Fn(&TransactionRecord) -> bool,
+        condition: impl Fn(&TransactionRecord) -> bool,
+        deposit_action: impl Fn(&mut Account, Option<Currency>, Decimal) -> AccountResult,
+        withdrawal_action: impl Fn(&mut Account, Option<Currency>, Decimal) -> AccountResult,
+        update_record: impl Fn(&mut TransactionRecord),
+    ) -> TransactionResult {
+        // A rejected transaction is as good as never seen
+        let record = self
+            .storage
+            .record(self.record_key(transaction))?
+            .filter(|record| !record.was_rejected());
+        match record {
+            Some(record)
+                if self.skip_replays
+                    && record.client() == transaction.client
+                    && replayed(&record) =>
+            {
+                self.replays_skipped += 1;
+                Ok(())
+            }
+            Some(record)
+                if record.client() == transaction.client
+                    && !record.was_reversed()
+                    && self.dispute_rules().is_disputable(&record.tx_type())
+                    && condition(&record) =>
+            {
+                let mut updated = record;
+                update_record(&mut updated);
+                let opens = !record.is_disputed() && updated.is_disputed();
+                // Disputes hold, and resolves and chargebacks release, the difference
+                let amount = (updated.disputed_amount() - record.disputed_amount()).abs();
+                if updated.disputed_amount() > record.amount() {
+                    return Err(TransactionError::DisputeTooLarge);
+                }
+                // Naming the currency is optional, but it has to be the disputed one
+                if transaction
+                    .currency
+                    .is_some_and(|currency| record.currency() != Some(currency))
+                {
+                    return Err(TransactionError::CurrencyMismatch);
+                }
+                if updated.disputed_amount() > record.disputed_amount()
+                    && self.dispute_expired(transaction, &record)
+                {
+                    return Err(TransactionError::DisputeWindowExpired);
+                }
+                if opens
+                    && self
+                        .max_disputes_per_tx
+                        .is_some_and(|max| record.dispute_count() >= max)
+                {
+                    return Err(TransactionError::DisputeLimitReached);
+                }
+                let before = self.storage.account(transaction.client)?;
+                let mut account = before.clone().unwrap_or_default();
+                let currency = record.currency();
+                let action = |account: &mut Account| match record.tx_type() {
+                    TransactionType::Withdrawal => withdrawal_action(account, currency, amount),
+                    _ => deposit_action(account, currency, amount),
+                };
+                // Settling a dispute that is already open may be allowed on a locked account
+                let settles = record.is_disputed() && !updated.is_disputed();
+                let result = match (settles, self.locked_policy) {
+                    (true, LockedAccountPolicy::SettleOpenDisputes) => {
+                        account.ignoring_lock(action)
+                    }
+                    _ => action(&mut account),
+                };
+                match result {
+                    Ok(()) => {
+                        self.write_account(transaction.client, before.as_ref(), &account)?;
+                        self.write_record(transaction.tx, &updated)?;
+                        Ok(())
+                    }
+                    Err(err) => Err(TransactionError::AccountError(err)),
+                }
+            }
+            Some(_) => Err(TransactionError::InvalidDispute),
+            None => match self.storage.is_pruned(self.record_key(transaction))? {
+                true => Err(TransactionError::ReferencePruned),
+                false => Err(TransactionError::ReferenceNotFound),
+            },
+        }
+    }
+
+    // Undoes a deposit by debiting its amount, or a withdrawal by crediting it back. Unlike a
+    // chargeback it neither holds funds first nor locks the account, and a transaction under
+    // dispute, charged back or already reversed can't be reversed.
+    fn handle_reversal(&mut self, transaction: &Transaction) -> TransactionResult {
+        let record = self
+            .storage
+            .record(self.record_key(transaction))?
+            .filter(|record| !record.was_rejected());
+        match record {
+            Some(record)
+                if self.skip_replays
+                    && record.client() == transaction.client
+                    && record.was_reversed() =>
+            {
+                self.replays_skipped += 1;
+                Ok(())
+            }
+            Some(record)
+                if record.client() == transaction.client
+                    && matches!(
+                        record.tx_type(),
+                        TransactionType::Deposit | TransactionType::Withdrawal
+                    )
+                    && !record.was_reversed()
+                    && !record.is_disputed()
+                    && !record.was_charged_back() =>
+            {
+                if transaction
+                    .currency
+                    .is_some_and(|currency| record.currency() != Some(currency))
+                {
+                    return Err(TransactionError::CurrencyMismatch);
+                }
+                let before = self.storage.account(transaction.client)?;
+                let mut account = before.clone().unwrap_or_default();
+                match record.tx_type() {
+                    TransactionType::Withdrawal => {
+                        account.deposit(record.currency(), record.amount())?
+                    }
+                    _ => account.withdraw(record.currency(), record.amount())?,
+                }
+                let mut updated = record;
+                updated.set_reversed();
+                self.write_account(transaction.client, before.as_ref(), &account)?;
+                self.write_record(transaction.tx, &updated)?;
+                Ok(())
+            }
+            Some(_) => Err(TransactionError::InvalidReversal),
+            None => match self.storage.is_pruned(self.record_key(transaction))? {
+                true => Err(TransactionError::ReferencePruned),
+                false => Err(TransactionError::ReferenceNotFound),
+            },
+        }
+    }
+
+    // The clock moves forward once a transaction passes the check, even if it is rejected later
+    fn check_time(&mut self, transaction: &Transaction) -> TransactionResult {
+        if !self.require_monotonic_time {
+            return Ok(());
+        }
+        match (transaction.timestamp, self.last_timestamp) {
+            (None, _) => Err(TransactionError::MissingTimestamp),
+            (Some(timestamp), Some(last)) if timestamp < last => Err(TransactionError::OutOfOrder),
+            (Some(timestamp), _) => {
+                self.last_timestamp = Some(timestamp);
+                Ok(())
+            }
+        }
+    }
+
+    fn dispute_expired(&self, dispute: &Transaction, record: &TransactionRecord) -> bool {
+        match (self.dispute_window, dispute.timestamp, record.timestamp()) {
+            (Some(window), Some(disputed_at), Some(recorded_at)) => {
+                disputed_at.saturating_sub(recorded_at) > window.as_secs()
+            }
+            _ => false,
+        }
+    }
+
+    pub fn process(&mut self, transaction: &Transaction) -> TransactionResult {
+        // Swept before anything is logged or applied, so a failing sweep leaves no trace
+        if self.retention.sweeps() && self.records_written >= PRUNE_INTERVAL {
+            self.prune()?;
+        }
+        if let Some(wal) = &mut self.wal {
+            wal.append(transaction)
+                .map_err(|e| StorageError::Backend(format!("WAL: {}", e)))?;
+        }
+        if let Some(history) = &mut self.history {
+            history.begin();
+        }
+        let (transformed, result) = match self.run_hooks(transaction) {
+            Ok((transformed, tags)) => {
+                let result = self.apply(transformed.as_ref().unwrap_or(transaction));
+                if result.is_ok() {
+                    for tag in tags {
+                        *self.tags.entry(tag).or_default() += 1;
+                    }
+                }
+                (transformed, result)
+            }
+            Err(err) => (None, Err(err)),
+        };
+        if let Some(history) = &mut self.history {
+            history.finish(transformed.as_ref().unwrap_or(transaction), &result);
+        }
+        result
+    }
+
+    // First phase of applying a transaction while coordinating with an outside system: checks it
+    // all the way through the hooks and the engine's rules, holding back every change. Nothing
+    // else reaches the Database until the Prepared is committed, aborted or dropped, so the
+    // commit is bound to apply exactly what was checked. A rejected transaction changes nothing,
+    // not even the risk counters process would bump. The WAL logs it on commit.
+    pub fn prepare(&mut self, transaction: &Transaction) -> Result<Prepared<'_>, TransactionError> {
+        let (last_timestamp, replays_skipped, audit_len) = (
+            self.last_timestamp,
+            self.replays_skipped,
+            self.audit_log.len(),
+        );
+        self.staged = Some(Vec::new());
+        let result = self.run_hooks(transaction).and_then(|(transformed, tags)| {
+            self.apply(transformed.as_ref().unwrap_or(transaction))
+                .map(|()| (transformed, tags))
+        });
+        let writes = self.staged.take().unwrap_or_default();
+        match result {
+            Ok((transformed, tags)) => Ok(Prepared {
+                original: transaction.clone(),
+                transaction: transformed.unwrap_or_else(|| transaction.clone()),
+                tags,
+                writes,
+                replay: self.replays_skipped != replays_skipped,
+                last_timestamp,
+                replays_skipped,
+                audit_len,
+                db: Some(self),
+            }),
+            Err(err) => {
+                self.last_timestamp = last_timestamp;
+                self.replays_skipped = replays_skipped;
+                self.audit_log.truncate(audit_len);
+                Err(err)
+            }
+        }
+    }
+
+    // The transaction as the hooks left it, if any changed it, and the tags they gave it
+    fn run_hooks(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(Option<Transaction>, Vec<String>), TransactionError> {
+        let mut transformed: Option<Transaction> = None;
+        let mut tags = Vec::new();
+        for i in 0..self.hooks.len() {
+            let current = transformed.as_ref().unwrap_or(transaction);
+            let account = self.storage.account(current.client)?;
+            match self.hooks[i].inspect(current, account.as_ref()) {
+                HookVerdict::Accept { tags: given } => tags.extend(given),
+                HookVerdict::Reject(reason) => {
+                    return Err(TransactionError::RejectedByHook(reason));
+                }
+                HookVerdict::Transform {
+                    transaction,
+                    tags: given,
+                } => {
+                    tags.extend(given);
+                    transformed = Some(transaction);
+                }
+            }
+        }
+        Ok((transformed, tags))
+    }
+
+    // Like process, also returning what the transaction did to each account it touched, in the
+    // order they were first written: a transfer's source before its destination. Transactions
+    // that moved no money, such as a skipped replay, touch none.
+    pub fn process_with_effect(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Vec<TransactionEffect>, TransactionError> {
+        self.effects = Some(Vec::new());
+        let result = self.process(transaction);
+        let effects = self.effects.take().unwrap_or_default();
+        result?;
+        Ok(effects
+            .into_iter()
+            .map(|(client, prior, new)| TransactionEffect {
+                client,
+                tx_type: transaction.tx_type.clone(),
+                prior,
+                new,
+            })
+            .collect())
+    }
+
+    // Processes the transactions in order, each one's outcome at its index in the result. A
+    // rejected transaction doesn't stop the ones after it.
+    pub fn process_batch(&mut self, transactions: Vec<Transaction>) -> Vec<TransactionResult> {
+        transactions
+            .iter()
+            .map(|transaction| self.process(transaction))
+            .collect()
+    }
+
+    fn apply(&mut self, transaction: &Transaction) -> TransactionResult {
+        self.check_time(transaction)?;
+        self.check_blocked(transaction)?;
+        self.check_open(transaction)?;
+        self.limits.check(transaction)?;
+        let replays_skipped = self.replays_skipped;
+        let result = match transaction.tx_type {
+            TransactionType::Deposit => {
+                self.handle_amount_transaction(transaction, Account::deposit)
+            }
+            TransactionType::Withdrawal => {
+                let limit = self.overdraft_limit;
+                self.handle_amount_transaction(transaction, |account, currency, amount| {
+                    let result = account.withdraw_into_overdraft(currency, amount, limit);
+                    if result.is_err() {
+                        account.risk.rejected_withdrawals =
+                            account.risk.rejected_withdrawals.saturating_add(1);
+                    }
+                    result
+                })
+            }
+            TransactionType::Dispute => {
+                // An amount disputes part of the transaction, further partial disputes adding to it
+                let part = transaction
+                    .amount
+                    .map(|amount| self.checked_amount(amount))
+                    .transpose()?;
+                let rules = self.dispute_rules();
+                let redispute = rules.allows_redispute();
+                let max_disputes = self.max_disputes_per_tx;
+                let dispute = match rules.allows_negative_available() {
+                    true => Account::dispute_into_debt,
+                    false => Account::dispute,
+                };
+                self.handle_dispute_like(
+                    transaction,
+                    // Another partial dispute of an open one is never taken for a replay
+                    |record| {
+                        (record.is_disputed() && part.is_none())
+                            || record.was_charged_back()
+                            || (record.was_resolved()
+                                && (!redispute
+                                    || max_disputes
+                                        .is_some_and(|max| record.dispute_count() >= max)))
+                    },
+                    |record| {
+                        (!record.is_disputed() || part.is_some())
+                            && (redispute || !record.was_resolved())
+                    },
+                    dispute,
+                    Account::dispute_withdrawal,
+                    |record| record.dispute(part),
+                )
+            }
+            TransactionType::Resolve => self.handle_dispute_like(
+                transaction,
+                |record| {
+                    !record.is_disputed() && (record.was_resolved() || record.was_charged_back())
+                },
+                |record| record.is_disputed(),
+                Account::resolve,
+                Account::resolve_withdrawal,
+                |record| {
+                    record.set_disputed(false);
+                    record.set_resolved();
+                },
+            ),
+            TransactionType::Chargeback => self.handle_dispute_like(
+                transaction,
+                |record| !record.is_disputed() && record.was_charged_back(),
+                |record| record.is_disputed(),
+                Account::chargeback,
+                Account::chargeback_withdrawal,
+                |record| {
+                    record.set_disputed(false);
+                    record.set_charged_back();
+                },
+            ),
+            TransactionType::Transfer => self.handle_transfer(transaction),
+            TransactionType::Convert => self.handle_convert(transaction),
+            TransactionType::Fee => {
+                let floor = self.fee_floor;
+                self.handle_amount_transaction(transaction, |account, currency, amount| {
+                    account.charge_fee(currency, amount, floor)
+                })
+            }
+            TransactionType::Unlock => match self.allow_admin_ops {
+                true => self.apply_unlock(transaction.client, Some(transaction.tx)),
+                false => Err(TransactionError::AdminOpsDisabled),
+            },
+            TransactionType::Open => self.handle_status_change(transaction.client, Account::open),
+            TransactionType::Close => self.handle_status_change(transaction.client, Account::close),
+            TransactionType::Reversal => self.handle_reversal(transaction),
+            TransactionType::Settle => Ok(()),
+            TransactionType::Other(_) => Err(TransactionError::UnknownType),
+        };
+        if let Err(TransactionError::AccountError(_)) = &result {
+            self.record_rejection(transaction)?;
+        }
+        // A skipped replay was counted the first time round, and a prepared transaction counts
+        // once committed
+        if result.is_ok() && self.replays_skipped == replays_skipped && self.staged.is_none() {
+            self.limits.record(transaction);
+        }
+        result
+    }
+}
+
+// A transaction checked by Database::prepare, holding the Database until it is committed or
+// aborted. Dropping it aborts.
+#[must_use]
+pub struct Prepared<'a> {
+    // Taken once committed or aborted
+    db: Option<&'a mut Database>,
+    // As given, which the WAL logs, and as the hooks left it, which is applied
+    original: Transaction,
+    transaction: Transaction,
+    tags: Vec<String>,
+    writes: Vec<StagedWrite>,
+    // Whether it was a replay, skipped
+    replay: bool,
+    // What an abort puts back
+    last_timestamp: Option<Timestamp>,
+    replays_skipped: u64,
+    audit_len: usize,
+}
+
+impl Prepared<'_> {
+    // The transaction that will be applied, which a hook may have changed
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    // Applies the changes held back. Only the storage, or the WAL, can fail it now, and a
+    // failed commit leaves everything as an abort would: no write stored, nothing logged.
+    pub fn commit(mut self) -> TransactionResult {
+        let Some(db) = self.db.take() else {
+            return Ok(());
+        };
+        let logged = match &mut db.wal {
+            Some(wal) => match wal
+                .end()
+                .and_then(|end| wal.append(&self.original).map(|()| end))
+            {
+                Ok(end) => Some(end),
+                Err(e) => {
+                    self.rollback(db);
+                    return Err(StorageError::Backend(format!("WAL: {}", e)).into());
+                }
+            },
+            None => None,
+        };
+        let writes = mem::take(&mut self.writes);
+        if let Err(mut err) = db.store_staged(&writes) {
+            // Replaying the entry would apply what the caller was told failed
+            if let (Some(wal), Some(end)) = (&mut db.wal, logged)
+                && let Err(e) = wal.truncate(end)
+            {
+                err = StorageError::Backend(format!(
+                    "{}, and taking it out of the WAL failed: {}",
+                    err, e
+                ));
+            }
+            self.rollback(db);
+            return Err(err.into());
+        }
+        if let Some(history) = &mut db.history {
+            history.begin();
+        }
+        for write in &writes {
+            match write {
+                StagedWrite::Account(client, before, after) => {
+                    db.account_written(*client, before.as_ref(), after)
+                }
+                StagedWrite::Record(tx, record) => db.record_written(*tx, record),
+            }
+        }
+        if let Some(history) = &mut db.history {
+            history.finish(&self.transaction, &Ok(()));
+        }
+        if !self.replay {
+            db.limits.record(&self.transaction);
+        }
+        for tag in mem::take(&mut self.tags) {
+            *db.tags.entry(tag).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    pub fn abort(mut self) {
+        if let Some(db) = self.db.take() {
+            self.rollback(db);
+        }
+    }
+
+    fn rollback(&self, db: &mut Database) {
+        db.last_timestamp = self.last_timestamp;
+        db.replays_skipped = self.replays_skipped;
+        db.audit_log.truncate(self.audit_len);
+    }
+}
+
+impl Drop for Prepared<'_> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            self.rollback(db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::account::RiskCounters;
+    use crate::engine::limits::LimitRule;
+    use rust_decimal::dec;
+
+    fn account(db: &Database, client: ClientID) -> Account {
+        db.account(client).unwrap().unwrap()
+    }
+
+    fn setup_deposit_transaction(
+        tx: TransactionID,
+        client: ClientID,
+        amount: Decimal,
+    ) -> Transaction {
+        Transaction::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    fn setup_withdrawal_transaction(
+        tx: TransactionID,
+        client: ClientID,
+        amount: Decimal,
+    ) -> Transaction {
+        Transaction::new(TransactionType::Withdrawal, client, tx, Some(amount))
+    }
+
+    fn setup_dispute_transaction(tx: TransactionID, client: ClientID) -> Transaction {
+        Transaction::new(TransactionType::Dispute, client, tx, None)
+    }
+
+    fn setup_chargeback_transaction(tx: TransactionID, client: ClientID) -> Transaction {
+        Transaction::new(TransactionType::Chargeback, client, tx, None)
+    }
+
+    #[test]
+    fn test_deposit_increases_available_balance() {
+        let mut db = Database::default();
+        let tx = setup_deposit_transaction(1, 1, dec!(100.00));
+        db.process(&tx).unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(100.00));
+        assert_eq!(acc.held(), dec!(0.00));
+        assert!(!acc.is_locked());
+    }
+
+    #[test]
+    fn test_errors_display_and_chain_their_source() {
+        let err = TransactionError::from(AccountError::InsufficientFunds);
+        assert_eq!(err.to_string(), "insufficient funds");
+        assert_eq!((err.code(), err.number()), ("insufficient_funds", 102));
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "insufficient funds");
+        let err = TransactionError::from(StorageError::Backend("disk full".to_string()));
+        assert_eq!(err.to_string(), "storage backend failed: disk full");
+        assert!(std::error::Error::source(&TransactionError::Duplicate).is_none());
+    }
+
+    #[test]
+    fn test_effects_describe_each_account_touched() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10)))
+            .unwrap();
+        let mut transfer = setup_withdrawal_transaction(2, 1, dec!(4));
+        transfer.tx_type = TransactionType::Transfer;
+        transfer.to_client = Some(2);
+        let effects = db.process_with_effect(&transfer).unwrap();
+        assert_eq!(effects.len(), 2);
+        assert_eq!((effects[0].client, effects[1].client), (1, 2));
+        assert_eq!(effects[0].prior.available(), dec!(10));
+        assert_eq!(effects[0].new.available(), dec!(6));
+        assert_eq!(effects[1].deltas()[0].available, dec!(4));
+        assert_eq!(effects[1].tx_type, TransactionType::Transfer);
+
+        let rejected = setup_withdrawal_transaction(3, 1, dec!(100));
+        assert!(db.process_with_effect(&rejected).is_err());
+        db.process(&setup_deposit_transaction(4, 1, dec!(3)))
+            .unwrap();
+        let effects = db
+            .process_with_effect(&setup_dispute_transaction(4, 1))
+            .unwrap();
+        assert_eq!(effects[0].deltas()[0].held, dec!(3));
+    }
+
+    #[test]
+    fn test_batch_results_line_up_with_transactions() {
+        let mut db = Database::default();
+        let results = db.process_batch(vec![
+            setup_deposit_transaction(1, 1, dec!(10)),
+            setup_withdrawal_transaction(2, 1, dec!(20)),
+            setup_deposit_transaction(1, 1, dec!(10)),
+            setup_withdrawal_transaction(3, 1, dec!(4)),
+        ]);
+        let codes = results
+            .iter()
+            .map(|result| result.as_ref().map_err(TransactionError::code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                Ok(&()),
+                Err("insufficient_funds"),
+                Err("duplicate"),
+                Ok(&())
+            ]
+        );
+        assert_eq!(account(&db, 1).available(), dec!(6));
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_risk_counters() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(2, 1, dec!(5.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+        assert!(
+            db.process(&setup_withdrawal_transaction(3, 1, dec!(1.0)))
+                .is_err()
+        );
+        // Rejected disputes don't count
+        assert!(db.process(&setup_dispute_transaction(1, 1)).is_err());
+        let risk = account(&db, 1).risk();
+        assert_eq!(
+            risk,
+            RiskCounters {
+                disputes: 2,
+                chargebacks: 1,
+                rejected_withdrawals: 1,
+            }
+        );
+        assert!(risk.exceeds(0));
+        assert!(!risk.exceeds(1));
+
+        let mut snapshot = Vec::new();
+        db.write_snapshot(&mut snapshot).unwrap();
+        let mut restored = Database::default();
+        restored.restore_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(account(&restored, 1).risk(), risk);
+    }
+
+    #[test]
+    fn test_locked_accounts_are_listed() {
+        let mut db = Database::default();
+        for client in 1..=3 {
+            db.process(&setup_deposit_transaction(
+                u32::from(client),
+                client,
+                dec!(5),
+            ))
+            .unwrap();
+        }
+        db.process(&setup_dispute_transaction(2, 2)).unwrap();
+        db.process(&setup_chargeback_transaction(2, 2)).unwrap();
+        let locked = db
+            .locked_accounts()
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(locked, [2]);
+        db.unlock(2).unwrap();
+        assert_eq!(db.locked_accounts().count(), 0);
+    }
+
+    #[test]
+    fn test_withdrawal_reduces_balance() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)))
+            .unwrap();
+
+        db.process(&Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            2,
+            Some(dec!(30.00)),
+        ))
+        .unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(70.00));
+        assert_eq!(acc.get_total(), dec!(70.00));
+    }
+
+    #[test]
+    fn test_withdrawal_insufficient_funds_does_not_change_balance() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(50.00)))
+            .unwrap();
+
+        let result = db.process(&Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            2,
+            Some(dec!(100.00)),
+        ));
+        assert!(result.is_err());
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(50.00)); // unchanged
+    }
+
+    #[test]
+    fn test_dispute_moves_funds_to_held() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(0.00));
+        assert_eq!(acc.held(), dec!(100.00));
+    }
+
+    #[test]
+    fn test_resolve_returns_held_funds_to_available() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+
+        db.process(&Transaction::new(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(100.00));
+        assert_eq!(acc.held(), dec!(0.00));
+    }
+
+    #[test]
+    fn test_chargeback_removes_held_funds_and_locks_account() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(0.00));
+        assert_eq!(acc.held(), dec!(0.00));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_cannot_deposit_to_locked_account() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+        let result = db.process(&setup_deposit_transaction(2, 1, dec!(50.00)));
+        assert!(result.is_err());
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(0.00)); // deposit rejected
+    }
+
+    #[test]
+    fn test_cannot_withdraw_from_locked_account() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+        let result = db.process(&Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            2,
+            Some(dec!(50.00)),
+        ));
+        assert!(result.is_err());
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(0.00)); // withdrawal ignored
+    }
+
+    #[test]
+    fn test_withdrawal_missing_amount_is_ignored() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(50.00)))
+            .unwrap();
+        let result = db.process(&Transaction::new(TransactionType::Withdrawal, 1, 2, None));
+        assert!(matches!(result, Err(TransactionError::MissingAmount)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(50.00)); // unchanged
+    }
+
+    #[test]
+    fn test_chargeback_without_dispute_does_nothing() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+
+        let result = db.process(&setup_chargeback_transaction(1, 1));
+        assert!(matches!(result, Err(TransactionError::InvalidDispute)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(100.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(!acc.is_locked());
+    }
+    #[test]
+    fn test_resolve_non_disputed_does_nothing() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+
+        let result = db.process(&Transaction::new(TransactionType::Resolve, 1, 1, None));
+        assert!(matches!(result, Err(TransactionError::InvalidDispute)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(100.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+    #[test]
+    fn test_double_dispute_does_nothing() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        let result = db.process(&setup_dispute_transaction(1, 1)); // again
+        assert!(matches!(result, Err(TransactionError::InvalidDispute)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.held(), dec!(100.0));
+        assert_eq!(acc.available(), dec!(0.0));
+    }
+    #[test]
+    fn test_dispute_wrong_client_id() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        let result = db.process(&setup_dispute_transaction(1, 2)); // wrong client ID
+        assert!(matches!(result, Err(TransactionError::InvalidDispute)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.held(), dec!(0.0)); // should not be disputed
+    }
+
+    #[test]
+    fn test_duplicate_deposit_is_ignored() {
+        let mut db = Database::default();
+        let tx = setup_deposit_transaction(1, 1, dec!(100.00));
+        db.process(&tx).unwrap();
+        let result = db.process(&tx); // duplicate tx_id
+        assert!(matches!(result, Err(TransactionError::Duplicate)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(100.00)); // second deposit ignored
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_rejected_by_default() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(40.0)))
+            .unwrap();
+        let result = db.process(&setup_dispute_transaction(2, 1));
+        assert!(matches!(result, Err(TransactionError::InvalidDispute)));
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(60.0));
+        assert_eq!(acc.held(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_and_chargeback_reverse_withdrawal() {
+        let mut db = Database::default().with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(40.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(60.0));
+        assert_eq!(acc.held(), dec!(40.0));
+
+        db.process(&setup_chargeback_transaction(2, 1)).unwrap();
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(100.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_resolve_keeps_withdrawal() {
+        let mut db = Database::default().with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(40.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        db.process(&Transaction::new(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(60.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert!(!acc.is_locked());
+    }
+
+    fn setup_transfer_transaction(
+        tx: TransactionID,
+        client: ClientID,
+        to_client: ClientID,
+        amount: Decimal,
+    ) -> Transaction {
+        Transaction::new(TransactionType::Transfer, client, tx, Some(amount))
+            .with_to_client(Some(to_client))
+    }
+
+    #[test]
+    fn test_transfer_moves_funds_between_clients() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_transfer_transaction(2, 1, 2, dec!(40.0)))
+            .unwrap();
+
+        assert_eq!(account(&db, 1).available(), dec!(60.0));
+        assert_eq!(account(&db, 2).available(), dec!(40.0));
+    }
+
+    #[test]
+    fn test_transfer_insufficient_funds_changes_nothing() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        let result = db.process(&setup_transfer_transaction(2, 1, 2, dec!(40.0)));
+        assert!(matches!(
+            result,
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+
+        assert_eq!(account(&db, 1).available(), dec!(10.0));
+        assert!(db.account(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_transfer_to_locked_account_does_not_debit_source() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(2, 2, dec!(5.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 2)).unwrap();
+        db.process(&setup_chargeback_transaction(2, 2)).unwrap();
+
+        let result = db.process(&setup_transfer_transaction(3, 1, 2, dec!(40.0)));
+        assert!(matches!(
+            result,
+            Err(TransactionError::AccountError(AccountError::Locked))
+        ));
+        assert_eq!(account(&db, 1).available(), dec!(100.0));
+        assert_eq!(account(&db, 2).available(), dec!(0.0));
+    }
+
+    #[test]
+    fn test_transfer_requires_other_destination() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        let mut transfer = setup_transfer_transaction(2, 1, 1, dec!(1.0));
+        assert!(matches!(
+            db.process(&transfer),
+            Err(TransactionError::InvalidTransfer)
+        ));
+        transfer.to_client = None;
+        assert!(matches!(
+            db.process(&transfer),
+            Err(TransactionError::MissingDestination)
+        ));
+    }
+
+    #[test]
+    fn test_transfer_cannot_be_disputed() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_transfer_transaction(2, 1, 2, dec!(40.0)))
+            .unwrap();
+        let result = db.process(&setup_dispute_transaction(2, 1));
+        assert!(matches!(result, Err(TransactionError::InvalidDispute)));
+    }
+
+    fn lock_client_one(db: &mut Database) {
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+    }
+
+    fn setup_unlock_transaction(tx: TransactionID, client: ClientID) -> Transaction {
+        Transaction::new(TransactionType::Unlock, client, tx, None)
+    }
+
+    #[test]
+    fn test_unknown_type_is_rejected() {
+        let mut db = Database::default();
+        let transaction = Transaction::new(
+            TransactionType::Other("refund".to_string()),
+            1,
+            1,
+            Some(dec!(1.0)),
+        );
+        assert!(matches!(
+            db.process(&transaction),
+            Err(TransactionError::UnknownType)
+        ));
+        assert!(db.accounts().next().is_none());
+    }
+
+    #[test]
+    fn test_unlock_transaction_requires_admin_ops() {
+        let mut db = Database::default();
+        lock_client_one(&mut db);
+        let result = db.process(&setup_unlock_transaction(2, 1));
+        assert!(matches!(result, Err(TransactionError::AdminOpsDisabled)));
+        assert!(account(&db, 1).is_locked());
+        assert!(db.audit_log().is_empty());
+    }
+
+    #[test]
+    fn test_unlock_transaction_releases_account_and_audits() {
+        let mut db = Database::default().with_admin_ops(true);
+        lock_client_one(&mut db);
+        db.process(&setup_unlock_transaction(2, 1)).unwrap();
+        assert!(!account(&db, 1).is_locked());
+        assert_eq!(
+            db.audit_log(),
+            &[AuditEntry {
+                action: AdminAction::Unlock,
+                client: 1,
+                tx: Some(2),
+            }]
+        );
+
+        // The account is usable again
+        db.process(&setup_deposit_transaction(3, 1, dec!(5.0)))
+            .unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(5.0));
+    }
+
+    #[test]
+    fn test_unlock_api_rejects_unlocked_or_unknown_accounts() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        assert!(matches!(
+            db.unlock(1),
+            Err(TransactionError::AccountError(AccountError::NotLocked))
+        ));
+        assert!(matches!(
+            db.unlock(2),
+            Err(TransactionError::AccountNotFound)
+        ));
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+        db.unlock(1).unwrap();
+        assert_eq!(db.audit_log()[0].tx, None);
+    }
+
+    #[test]
+    fn test_overflowing_deposit_is_rejected() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, Decimal::MAX))
+            .unwrap();
+        let result = db.process(&setup_deposit_transaction(2, 1, dec!(1.0)));
+        assert!(matches!(
+            result,
+            Err(TransactionError::AccountError(AccountError::Overflow))
+        ));
+        assert_eq!(account(&db, 1).available(), Decimal::MAX);
+    }
+
+    #[test]
+    fn test_dispute_after_withdrawal_needs_negative_funding() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(80.0)))
+            .unwrap();
+        let result = db.process(&setup_dispute_transaction(1, 1));
+        assert!(matches!(
+            result,
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+
+        let mut db = Database::default().with_dispute_funding(DisputeFunding::AllowNegative);
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(80.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+        let acc = account(&db, 1);
+        assert_eq!(acc.available(), dec!(-80.0));
+        assert_eq!(acc.held(), dec!(0.0));
+        assert_eq!(acc.get_total(), dec!(-80.0));
+        assert!(acc.is_locked());
+    }
+
+    #[test]
+    fn test_open_disputes_settle_on_locked_account_only_by_policy() {
+        for policy in [
+            LockedAccountPolicy::FreezeEverything,
+            LockedAccountPolicy::SettleOpenDisputes,
+        ] {
+            let mut db = Database::default().with_locked_policy(policy);
+            for tx in 1..=4 {
+                db.process(&setup_deposit_transaction(tx, 1, dec!(10.0)))
+                    .unwrap();
+            }
+            db.process(&setup_dispute_transaction(1, 1)).unwrap();
+            db.process(&setup_dispute_transaction(2, 1)).unwrap();
+            db.process(&setup_dispute_transaction(3, 1)).unwrap();
+            db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+            let resolve = db.process(&Transaction::new(TransactionType::Resolve, 1, 2, None));
+            let chargeback = db.process(&setup_chargeback_transaction(3, 1));
+            match policy {
+                LockedAccountPolicy::FreezeEverything => {
+                    assert!(resolve.is_err());
+                    assert!(chargeback.is_err());
+                    assert_eq!(account(&db, 1).held(), dec!(20.0));
+                }
+                LockedAccountPolicy::SettleOpenDisputes => {
+                    resolve.unwrap();
+                    chargeback.unwrap();
+                    let acc = account(&db, 1);
+                    assert_eq!(acc.available(), dec!(20.0));
+                    assert_eq!(acc.held(), dec!(0.0));
+                    assert!(acc.is_locked());
+                }
+            }
+            // Everything else stays blocked either way
+            assert!(db.process(&setup_dispute_transaction(4, 1)).is_err());
+            assert!(
+                db.process(&setup_deposit_transaction(5, 1, dec!(1.0)))
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_amounts_are_rounded_on_ingest() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(1.00005)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        assert_eq!(account(&db, 1).held(), dec!(1.0000));
+
+        // Rounds to zero so it is not a positive amount anymore
+        let result = db.process(&setup_deposit_transaction(2, 1, dec!(0.00004)));
+        assert!(matches!(result, Err(TransactionError::NegativeAmount)));
+    }
+
+    #[test]
+    fn test_accounts_iterates_every_client() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(2, 7, dec!(20.0)))
+            .unwrap();
+
+        let mut clients: Vec<ClientID> = db.accounts().map(|entry| entry.unwrap().0).collect();
+        clients.sort();
+        assert_eq!(clients, vec![1, 7]);
+    }
+
+    #[test]
+    fn test_ledger_records_accepted_mutations() {
+        let mut db = Database::default().with_ledger(Ledger::new());
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        assert!(
+            db.process(&setup_withdrawal_transaction(2, 1, dec!(50.0)))
+                .is_err()
+        );
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+        let events = db.ledger().unwrap().events();
+        assert_eq!(events[0], LedgerEvent::AccountOpened { client: 1 });
+        assert_eq!(
+            events[1],
+            LedgerEvent::FundsCredited {
+                client: 1,
+                currency: None,
+                amount: dec!(10.0)
+            }
+        );
+        assert!(matches!(
+            events[2],
+            LedgerEvent::RecordWritten { tx: 1, .. }
+        ));
+        // The rejected withdrawal only counted against the client
+        assert_eq!(
+            events[3],
+            LedgerEvent::RiskChanged {
+                client: 1,
+                risk: RiskCounters {
+                    rejected_withdrawals: 1,
+                    ..RiskCounters::default()
+                }
+            }
+        );
+        assert_eq!(
+            events[4..6],
+            [
+                LedgerEvent::FundsDebited {
+                    client: 1,
+                    currency: None,
+                    amount: dec!(10.0)
+                },
+                LedgerEvent::FundsHeld {
+                    client: 1,
+                    currency: None,
+                    amount: dec!(10.0)
+                },
+            ]
+        );
+        assert_eq!(
+            events[8..11],
+            [
+                LedgerEvent::FundsReleased {
+                    client: 1,
+                    currency: None,
+                    amount: dec!(10.0)
+                },
+                LedgerEvent::AccountLocked { client: 1 },
+                LedgerEvent::RiskChanged {
+                    client: 1,
+                    risk: RiskCounters {
+                        disputes: 1,
+                        chargebacks: 1,
+                        rejected_withdrawals: 1,
+                    }
+                },
+            ]
+        );
+        assert_eq!(events.len(), 12);
+    }
+
+    #[test]
+    fn test_replay_rebuilds_state() {
+        let mut db = Database::default()
+            .with_dispute_policy(DisputePolicy::DepositsAndWithdrawals)
+            .with_admin_ops(true)
+            .with_ledger(Ledger::new());
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(30.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        db.process(&setup_transfer_transaction(3, 1, 2, dec!(20.0)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(4, 3, dec!(5.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(4, 3)).unwrap();
+        db.process(&setup_chargeback_transaction(4, 3)).unwrap();
+        db.process(&setup_unlock_transaction(5, 3)).unwrap();
+        assert!(
+            db.process(&setup_withdrawal_transaction(6, 4, dec!(1.0)))
+                .is_err()
+        );
+
+        let mut replayed =
+            Database::default().with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        replayed.replay(db.ledger().unwrap().clone()).unwrap();
+        for client in 1..=4 {
+            let (expected, actual) = (account(&db, client), account(&replayed, client));
+            assert_eq!(actual.available(), expected.available());
+            assert_eq!(actual.held(), expected.held());
+            assert_eq!(actual.is_locked(), expected.is_locked());
+        }
+        for tx in 1..=4 {
+            assert_eq!(
+                replayed.storage.record(RecordKey::from(tx)).unwrap(),
+                db.storage.record(RecordKey::from(tx)).unwrap()
+            );
+        }
+        // Replayed records can still be disputed
+        replayed
+            .process(&setup_chargeback_transaction(2, 1))
+            .unwrap();
+        assert!(account(&replayed, 1).is_locked());
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_transaction_history() {
+        let mut db = Database::default().with_history(History::new());
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_resolve(1, 1)).unwrap();
+        assert!(db.process(&setup_resolve(1, 1)).is_err());
+        assert!(
+            db.process(&setup_withdrawal_transaction(2, 1, dec!(50.0)))
+                .is_err()
+        );
+
+        let history = db.transaction_history(1);
+        assert_eq!(
+            history
+                .iter()
+                .map(|entry| (entry.transaction.tx_type.clone(), entry.is_accepted()))
+                .collect::<Vec<_>>(),
+            [
+                (TransactionType::Deposit, true),
+                (TransactionType::Dispute, true),
+                (TransactionType::Resolve, true),
+                (TransactionType::Resolve, false),
+            ]
+        );
+        let delta = |available, held| BalanceDelta {
+            client: 1,
+            currency: None,
+            available,
+            held,
+        };
+        assert_eq!(history[0].deltas, [delta(dec!(10.0), Decimal::ZERO)]);
+        assert_eq!(history[1].deltas, [delta(dec!(-10.0), dec!(10.0))]);
+        assert_eq!(history[2].deltas, [delta(dec!(10.0), dec!(-10.0))]);
+        assert_eq!(history[3].error.as_deref(), Some("invalid_dispute"));
+        assert!(history[3].deltas.is_empty());
+        assert_eq!(
+            db.transaction_history(2)[0].error.as_deref(),
+            Some("insufficient_funds")
+        );
+        assert!(Database::default().transaction_history(1).is_empty());
+
+        // Kept in snapshots for databases recording history
+        let mut bytes = Vec::new();
+        db.write_snapshot(&mut bytes).unwrap();
+        let mut restored = Database::default().with_history(History::new());
+        restored.restore_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(restored.transaction_history(1), db.transaction_history(1));
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_snapshot_round_trip() {
+        let mut db = Database::default().with_admin_ops(true);
+        db.process(&setup_deposit_transaction(1, 1, dec!(100.1234)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(2, 1, dec!(5.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        lock_client_two(&mut db);
+        db.process(&setup_unlock_transaction(9, 2)).unwrap();
+        db.process(&Transaction::new(TransactionType::Open, 4, 10, None))
+            .unwrap();
+
+        let mut bytes = Vec::new();
+        db.write_snapshot(&mut bytes).unwrap();
+        let mut restored = Database::default();
+        restored.restore_snapshot(bytes.as_slice()).unwrap();
+
+        let acc = account(&restored, 1);
+        assert_eq!(acc.available(), dec!(100.1234));
+        assert_eq!(acc.held(), dec!(5.0));
+        assert!(!account(&restored, 2).is_locked());
+        assert_eq!(account(&restored, 4).status(), AccountStatus::Open);
+        assert_eq!(restored.audit_log(), db.audit_log());
+        // Dispute flags survive, so the dispute can be settled after resuming
+        restored
+            .process(&setup_chargeback_transaction(2, 1))
+            .unwrap();
+        assert!(account(&restored, 1).is_locked());
+        assert!(matches!(
+            restored.process(&setup_deposit_transaction(1, 3, dec!(1.0))),
+            Err(TransactionError::Duplicate)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_replays_are_skipped_once_applied() {
+        let new = || {
+            Database::default()
+                .with_max_disputes_per_tx(1)
+                .with_skip_replays(true)
+        };
+        let mut db = new();
+        let deposit = setup_deposit_transaction(1, 1, dec!(10.0));
+        db.process(&deposit).unwrap();
+        db.process(&setup_deposit_transaction(2, 1, dec!(5.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        db.process(&setup_resolve(2, 1)).unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+
+        // The whole input again, from a snapshot as a new run would
+        let mut bytes = Vec::new();
+        db.write_snapshot(&mut bytes).unwrap();
+        let mut db = new();
+        db.restore_snapshot(bytes.as_slice()).unwrap();
+        db.process(&deposit).unwrap();
+        db.process(&setup_deposit_transaction(2, 1, dec!(5.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        db.process(&setup_resolve(2, 1)).unwrap();
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+        assert_eq!(db.replays_skipped(), 6);
+        let acc = account(&db, 1);
+        assert_eq!((acc.available(), acc.held()), (dec!(5.0), dec!(0)));
+        assert!(acc.is_locked());
+
+        // Same id, different transaction
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(1, 1, dec!(11.0))),
+            Err(TransactionError::Duplicate)
+        ));
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 2)),
+            Err(TransactionError::InvalidDispute)
+        ));
+        assert_eq!(db.replays_skipped(), 6);
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_replays_of_rejected_transactions_are_skipped() {
+        let new = || Database::default().with_skip_replays(true);
+        let feed = [
+            setup_withdrawal_transaction(1, 1, dec!(5)),
+            setup_deposit_transaction(2, 1, dec!(10)),
+        ];
+        let mut db = new();
+        assert!(matches!(
+            db.process(&feed[0]),
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+        db.process(&feed[1]).unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(10));
+
+        let mut bytes = Vec::new();
+        db.write_snapshot(&mut bytes).unwrap();
+        let mut db = new();
+        db.restore_snapshot(bytes.as_slice()).unwrap();
+        for transaction in &feed {
+            db.process(transaction).unwrap();
+        }
+        assert_eq!(db.replays_skipped(), 2);
+        assert_eq!(account(&db, 1).available(), dec!(10));
+        // The id stays taken, and there is nothing to dispute
+        assert!(matches!(
+            db.process(&setup_withdrawal_transaction(1, 1, dec!(6))),
+            Err(TransactionError::Duplicate)
+        ));
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::ReferenceNotFound)
+        ));
+        db.check_invariants().unwrap();
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn lock_client_two(db: &mut Database) {
+        db.process(&setup_deposit_transaction(3, 2, dec!(1.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(3, 2)).unwrap();
+        db.process(&setup_chargeback_transaction(3, 2)).unwrap();
+    }
+
+    #[test]
+    fn test_reversals() {
+        let reversal = |tx| Transaction::new(TransactionType::Reversal, 1, tx, None);
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(30)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(3, 1, dec!(50)))
+            .unwrap();
+        db.process(&reversal(2)).unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(150));
+        assert!(matches!(
+            db.process(&reversal(2)),
+            Err(TransactionError::InvalidReversal)
+        ));
+        // A reversed deposit is out of the dispute lifecycle, and a disputed one can't be reversed
+        db.process(&reversal(1)).unwrap();
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::InvalidDispute)
+        ));
+        db.process(&setup_dispute_transaction(3, 1)).unwrap();
+        assert!(matches!(
+            db.process(&reversal(3)),
+            Err(TransactionError::InvalidReversal)
+        ));
+        let account = account(&db, 1);
+        assert_eq!((account.available(), account.held()), (dec!(0), dec!(50)));
+        assert!(!account.is_locked());
+
+        // Reversing a deposit takes funds that are available
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(5)))
+            .unwrap();
+        assert!(matches!(
+            db.process(&reversal(1)),
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_hook_verdicts() {
+        // Rejects client 9, tags deposits over 100 and halves withdrawals
+        #[derive(Debug)]
+        struct Compliance;
+        impl TransactionHook for Compliance {
+            fn inspect(
+                &mut self,
+                transaction: &Transaction,
+                _account: Option<&Account>,
+            ) -> HookVerdict {
+                let amount = transaction.amount.unwrap_or_default();
+                match transaction.tx_type {
+                    _ if transaction.client == 9 => HookVerdict::Reject("sanctioned".to_string()),
+                    TransactionType::Withdrawal => HookVerdict::Transform {
+                        transaction: Transaction {
+                            amount: Some(amount / dec!(2)),
+                            ..transaction.clone()
+                        },
+                        tags: Vec::new(),
+                    },
+                    _ if amount > dec!(100) => HookVerdict::Accept {
+                        tags: vec!["large".to_string()],
+                    },
+                    _ => HookVerdict::Accept { tags: Vec::new() },
+                }
+            }
+        }
+        let mut db = Database::default().with_hook(Compliance);
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(1, 9, dec!(10))),
+            Err(TransactionError::RejectedByHook(reason)) if reason == "sanctioned"
+        ));
+        assert!(db.account(9).unwrap().is_none());
+        db.process(&setup_deposit_transaction(2, 1, dec!(500)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(3, 1, dec!(100)))
+            .unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(450));
+        // Tags only count once the transaction is accepted
+        assert!(
+            db.process(&setup_deposit_transaction(2, 1, dec!(500)))
+                .is_err()
+        );
+        assert_eq!(db.tags().get("large"), Some(&1));
+    }
+
+    #[test]
+    fn test_prepare_then_commit_or_abort() {
+        let mut db = Database::default()
+            .with_require_monotonic_time(true)
+            .with_history(History::new());
+        let deposit = |tx, amount, timestamp| {
+            setup_deposit_transaction(tx, 1, amount).with_timestamp(Some(timestamp))
+        };
+
+        // Nothing shows until committed, and an abort leaves no trace
+        let prepared = db.prepare(&deposit(1, dec!(10), 5)).unwrap();
+        assert_eq!(prepared.transaction().tx, 1);
+        prepared.abort();
+        assert!(db.account(1).unwrap().is_none());
+        db.process(&deposit(2, dec!(3), 1)).unwrap();
+        drop(db.prepare(&deposit(1, dec!(10), 5)).unwrap());
+        assert_eq!(account(&db, 1).available(), dec!(3));
+
+        db.prepare(&deposit(1, dec!(10), 5))
+            .unwrap()
+            .commit()
+            .unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(13));
+        assert!(db.transaction_history(1)[0].is_accepted());
+        assert!(matches!(
+            db.prepare(&deposit(1, dec!(10), 6)),
+            Err(TransactionError::Duplicate)
+        ));
+        assert!(matches!(
+            db.prepare(&deposit(3, dec!(10), 4)),
+            Err(TransactionError::OutOfOrder)
+        ));
+
+        // A rejected withdrawal bumps no risk counter when only prepared
+        let withdrawal = setup_withdrawal_transaction(4, 1, dec!(100)).with_timestamp(Some(7));
+        assert!(db.prepare(&withdrawal).is_err());
+        assert_eq!(account(&db, 1).risk.rejected_withdrawals, 0);
+    }
+
+    // Memory storage failing the next account write of the client set in fail_for
+    #[derive(Debug, Default)]
+    struct FailingStorage {
+        inner: MemoryStorage,
+        fail_for: std::sync::Arc<std::sync::Mutex<Option<ClientID>>>,
+    }
+
+    impl StorageBackend for FailingStorage {
+        fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+            self.inner.account(client)
+        }
+        fn put_account(&mut self, client: ClientID, account: &Account) -> StorageResult<()> {
+            let mut fail_for = self.fail_for.lock().unwrap();
+            if *fail_for == Some(client) {
+                *fail_for = None;
+                return Err(StorageError::Backend("disk full".to_string()));
+            }
+            self.inner.put_account(client, account)
+        }
+        fn record(&self, key: RecordKey) -> StorageResult<Option<TransactionRecord>> {
+            self.inner.record(key)
+        }
+        fn put_record(&mut self, key: RecordKey, record: &TransactionRecord) -> StorageResult<()> {
+            self.inner.put_record(key, record)
+        }
+        fn accounts(&self) -> AccountEntries<'_> {
+            self.inner.accounts()
+        }
+        fn records(&self) -> crate::storage::RecordEntries<'_> {
+            self.inner.records()
+        }
+        fn prune_record(&mut self, key: RecordKey) -> StorageResult<()> {
+            self.inner.prune_record(key)
+        }
+        fn is_pruned(&self, key: RecordKey) -> StorageResult<bool> {
+            self.inner.is_pruned(key)
+        }
+        fn pruned(&self) -> crate::storage::PrunedEntries<'_> {
+            self.inner.pruned()
+        }
+        fn remove_account(&mut self, client: ClientID) -> StorageResult<()> {
+            self.inner.remove_account(client)
+        }
+        fn remove_record(&mut self, key: RecordKey) -> StorageResult<()> {
+            self.inner.remove_record(key)
+        }
+    }
+
+    #[test]
+    fn test_commit_failing_part_way_changes_nothing() {
+        let storage = FailingStorage::default();
+        let fail_for = storage.fail_for.clone();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.wal");
+        let (wal, _) = Wal::open(&path, 0).unwrap();
+        let mut db = Database::with_storage(storage)
+            .with_history(History::new())
+            .with_wal(wal);
+        db.process(&setup_deposit_transaction(1, 1, dec!(10)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(2, 2, dec!(5)))
+            .unwrap();
+
+        // The source is debited, then crediting the destination fails
+        *fail_for.lock().unwrap() = Some(2);
+        let transfer = setup_transfer_transaction(3, 1, 2, dec!(4));
+        let err = db.prepare(&transfer).unwrap().commit().unwrap_err();
+        assert!(matches!(err, TransactionError::Storage(_)));
+        assert_eq!(account(&db, 1).available(), dec!(10));
+        assert_eq!(account(&db, 2).available(), dec!(5));
+        assert!(db.transaction_history(3).is_empty());
+        let logged = || std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(logged(), 3);
+
+        // Nor is the id taken, the transfer goes through once the storage recovers
+        db.prepare(&transfer).unwrap().commit().unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(6));
+        assert_eq!(account(&db, 2).available(), dec!(9));
+        assert!(db.transaction_history(3)[0].is_accepted());
+        assert_eq!(logged(), 4);
+    }
+
+    #[test]
+    fn test_settle_reports_period_deltas() {
+        let mut db = Database::default().with_settlement(true);
+        db.process(&setup_deposit_transaction(1, 1, dec!(100)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(2, 2, dec!(20)))
+            .unwrap();
+        let deltas = db.settle().unwrap();
+        assert_eq!(
+            deltas
+                .iter()
+                .map(|delta| (delta.client, delta.available, delta.held))
+                .collect::<Vec<_>>(),
+            [(1, dec!(100), dec!(0)), (2, dec!(20), dec!(0))]
+        );
+
+        // Only what moved since is reported, balances carry over
+        db.process(&setup_withdrawal_transaction(3, 1, dec!(30)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(4, 1, dec!(10)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(4, 1)).unwrap();
+        let deltas = db.settle().unwrap();
+        assert_eq!(deltas.len(), 1);
+        assert_eq!((deltas[0].available, deltas[0].held), (dec!(-30), dec!(10)));
+        assert_eq!(
+            account(&db, 1).available() + account(&db, 1).held(),
+            dec!(80)
+        );
+        assert!(db.settle().unwrap().is_empty());
+
+        // Settle rows themselves move nothing
+        let settle = Transaction::new(TransactionType::Settle, 0, 0, None);
+        db.process(&settle).unwrap();
+        assert!(db.settle().unwrap().is_empty());
+        assert!(Database::default().settle().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let partial = |amount| Transaction {
+            amount: Some(amount),
+            ..setup_dispute_transaction(1, 1)
+        };
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(100)))
+            .unwrap();
+        db.process(&partial(dec!(30))).unwrap();
+        db.process(&partial(dec!(20))).unwrap();
+        assert_eq!(account(&db, 1).held(), dec!(50));
+        assert!(matches!(
+            db.process(&partial(dec!(50.01))),
+            Err(TransactionError::DisputeTooLarge)
+        ));
+        // A dispute of the whole amount can't join a partial one
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::InvalidDispute)
+        ));
+        db.process(&setup_chargeback_transaction(1, 1)).unwrap();
+        let account = account(&db, 1);
+        assert_eq!((account.available(), account.held()), (dec!(50), dec!(0)));
+        assert!(account.is_locked());
+        let record = db.storage.record(RecordKey::from(1)).unwrap().unwrap();
+        assert_eq!(record.dispute_count(), 1);
+        assert!(record.was_charged_back());
+    }
+
+    #[test]
+    fn test_block_policy() {
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10)))
+            .unwrap();
+        db.set_block_policy(BlockPolicy::Block([1].into()));
+        assert!(matches!(
+            db.process(&setup_withdrawal_transaction(2, 1, dec!(5))),
+            Err(TransactionError::ClientBlocked)
+        ));
+        db.process(&setup_deposit_transaction(3, 2, dec!(10)))
+            .unwrap();
+        // Either side of a transfer
+        let transfer = Transaction {
+            tx_type: TransactionType::Transfer,
+            to_client: Some(1),
+            ..setup_withdrawal_transaction(4, 2, dec!(1))
+        };
+        assert!(matches!(
+            db.process(&transfer),
+            Err(TransactionError::ClientBlocked)
+        ));
+
+        db.set_block_policy(BlockPolicy::Allow([1].into()));
+        db.process(&setup_withdrawal_transaction(5, 1, dec!(5)))
+            .unwrap();
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(6, 3, dec!(1))),
+            Err(TransactionError::ClientBlocked)
+        ));
+        assert_eq!(account(&db, 1).available(), dec!(5));
+    }
+
+    #[test]
+    fn test_limits() {
+        let deposit = |tx, client, amount, timestamp| {
+            setup_deposit_transaction(tx, client, amount).with_timestamp(Some(timestamp))
+        };
+        let mut db = Database::default().with_limits(Limits::new(vec![
+            LimitRule {
+                id: "daily-deposits".to_string(),
+                tx_type: Some(TransactionType::Deposit),
+                max_amount: None,
+                max_count: Some(2),
+                max_total: Some(dec!(100)),
+                window: Some(Duration::from_secs(86_400)),
+            },
+            LimitRule {
+                id: "max-withdrawal".to_string(),
+                tx_type: Some(TransactionType::Withdrawal),
+                max_amount: Some(dec!(50)),
+                max_count: None,
+                max_total: None,
+                window: None,
+            },
+        ]));
+        let exceeded = |result: TransactionResult| match result {
+            Err(TransactionError::LimitExceeded(id)) => id,
+            other => panic!("{:?}", other),
+        };
+        db.process(&deposit(1, 1, dec!(60), 10)).unwrap();
+        assert_eq!(
+            exceeded(db.process(&deposit(2, 1, dec!(50), 20))),
+            "daily-deposits"
+        );
+        db.process(&deposit(3, 1, dec!(40), 30)).unwrap();
+        assert_eq!(
+            exceeded(db.process(&deposit(4, 1, dec!(1), 40))),
+            "daily-deposits"
+        );
+        // Other clients and the next day start afresh
+        db.process(&deposit(5, 2, dec!(100), 40)).unwrap();
+        db.process(&deposit(6, 1, dec!(100), 86_400)).unwrap();
+        assert_eq!(
+            exceeded(db.process(&setup_withdrawal_transaction(7, 1, dec!(50.01)))),
+            "max-withdrawal"
+        );
+        db.process(&setup_withdrawal_transaction(8, 1, dec!(50)))
+            .unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(150));
+    }
+
+    #[test]
+    fn test_amount_limits() {
+        let mut db = Database::default().with_amount_limits(AmountLimits {
+            max_amount: Some(dec!(1000)),
+            max_precision: Some(4),
+        });
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(1, 1, dec!(1000.01))),
+            Err(TransactionError::AmountTooLarge)
+        ));
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(2, 1, dec!(1.000000000001))),
+            Err(TransactionError::AmountTooPrecise)
+        ));
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(3, 1, dec!(-5))),
+            Err(TransactionError::NegativeAmount)
+        ));
+        // Trailing zeros don't count
+        db.process(&setup_deposit_transaction(4, 1, dec!(1000.00000000)))
+            .unwrap();
+        assert!(matches!(
+            db.process(&setup_withdrawal_transaction(5, 1, dec!(1e28))),
+            Err(TransactionError::AmountTooLarge)
+        ));
+        assert_eq!(account(&db, 1).available(), dec!(1000));
+
+        // Without limits, extra decimal places are rounded away as before
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(1.000000000001)))
+            .unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(1));
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_undisputable_records_are_not_kept() {
+        let mut db = Database::default().with_retention(RetentionPolicy {
+            disputable_only: true,
+            ..RetentionPolicy::default()
+        });
+        db.process(&setup_deposit_transaction(1, 1, dec!(10)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(4)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(3, 1, dec!(2)))
+            .unwrap();
+        assert_eq!(db.records_pruned(), 1);
+        assert!(matches!(
+            db.process(&setup_withdrawal_transaction(2, 1, dec!(1))),
+            Err(TransactionError::Duplicate)
+        ));
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(2, 1)),
+            Err(TransactionError::ReferencePruned)
+        ));
+        db.process(&setup_dispute_transaction(3, 1)).unwrap();
+
+        // Pruned ids stay taken after a snapshot
+        let mut bytes = Vec::new();
+        db.write_snapshot(&mut bytes).unwrap();
+        let mut restored = Database::default();
+        restored.restore_snapshot(bytes.as_slice()).unwrap();
+        assert!(matches!(
+            restored.process(&setup_deposit_transaction(2, 1, dec!(1))),
+            Err(TransactionError::Duplicate)
+        ));
+    }
+
+    #[test]
+    fn test_prune_keeps_newest_records_per_client() {
+        let mut db = Database::default().with_retention(RetentionPolicy {
+            max_per_client: Some(1),
+            ..RetentionPolicy::default()
+        });
+        for tx in 1..=4 {
+            db.process(&setup_deposit_transaction(tx, 1, dec!(1)))
+                .unwrap();
+        }
+        db.process(&setup_deposit_transaction(5, 2, dec!(1)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        // The disputed record stays, so the dispute can still be settled
+        assert_eq!(db.prune().unwrap(), 2);
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::ReferencePruned)
+        ));
+        db.process(&setup_chargeback_transaction(2, 1)).unwrap();
+        db.process(&setup_dispute_transaction(5, 2)).unwrap();
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(9, 1)),
+            Err(TransactionError::ReferenceNotFound)
+        ));
+
+        // Settled records are no longer held on to
+        db.process(&Transaction::new(TransactionType::Resolve, 2, 5, None))
+            .unwrap();
+        db.process(&setup_deposit_transaction(6, 2, dec!(1)))
+            .unwrap();
+        assert_eq!(db.prune().unwrap(), 2);
+        assert_eq!(db.records_pruned(), 4);
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(5, 2)),
+            Err(TransactionError::ReferencePruned)
+        ));
+        db.process(&setup_dispute_transaction(6, 2)).unwrap();
+    }
+
+    #[test]
+    fn test_prune_drops_records_past_the_dispute_window() {
+        let mut db = Database::default()
+            .with_dispute_window(Duration::from_secs(100))
+            .with_retention(RetentionPolicy {
+                past_dispute_window: true,
+                ..RetentionPolicy::default()
+            });
+        for (tx, timestamp) in [(1, 1000), (2, 1050), (3, 1120)] {
+            db.process(&setup_deposit_transaction(tx, 1, dec!(1)).with_timestamp(Some(timestamp)))
+                .unwrap();
+        }
+        assert_eq!(db.prune().unwrap(), 1);
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::ReferencePruned)
+        ));
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_restore_rejects_unknown_snapshot_version() {
+        let bytes = bincode::serialize(&99u32).unwrap();
+        assert!(matches!(
+            Database::default().restore_snapshot(bytes.as_slice()),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+        // A valid version header followed by garbage
+        let mut bytes = Vec::new();
+        Database::default().write_snapshot(&mut bytes).unwrap();
+        bytes.truncate(4);
+        bytes.extend_from_slice(b"garbage");
+        assert!(matches!(
+            Database::default().restore_snapshot(bytes.as_slice()),
+            Err(SnapshotError::Encoding(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_restore_reads_older_snapshot_versions() {
+        // Written by version 1: five records with optional amounts, one of them the transfer of
+        // tx 4, and the audit entry of unlocking client 2 after a chargeback
+        let mut db = Database::default();
+        db.restore_snapshot(&include_bytes!("../../fixtures/snapshot_v1.bin")[..])
+            .unwrap();
+        let balances = |db: &Database, client| {
+            let acc = account(db, client);
+            (acc.available(), acc.held(), acc.is_locked())
+        };
+        assert_eq!(balances(&db, 1), (dec!(2.5), dec!(0), false));
+        assert_eq!(balances(&db, 2), (dec!(1), dec!(0), false));
+        assert_eq!(balances(&db, 3), (dec!(0), dec!(2), false));
+        assert_eq!(account(&db, 1).status(), AccountStatus::Implicit);
+        assert_eq!(db.audit_log().len(), 1);
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(4, 1, dec!(1))),
+            Err(TransactionError::Duplicate)
+        ));
+        db.process(&setup_resolve(5, 3)).unwrap();
+        assert_eq!(balances(&db, 3), (dec!(2), dec!(0), false));
+        db.check_invariants().unwrap();
+
+        // Written by version 10, with types as variant indices, history and pruned records
+        let mut db = Database::default().with_history(History::new());
+        db.restore_snapshot(&include_bytes!("../../fixtures/snapshot_v10.bin")[..])
+            .unwrap();
+        assert_eq!(balances(&db, 1), (dec!(8.25), dec!(0), false));
+        assert_eq!(balances(&db, 2), (dec!(1), dec!(3), false));
+        assert_eq!(
+            db.transaction_history(6)[0].transaction.tx_type,
+            TransactionType::Transfer
+        );
+        assert_eq!(
+            db.transaction_history(4)[0].error.as_deref(),
+            Some("insufficient_funds")
+        );
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::ReferencePruned)
+        ));
+        db.process(&setup_resolve(2, 2)).unwrap();
+        assert_eq!(balances(&db, 2), (dec!(4), dec!(0), false));
+        db.check_invariants().unwrap();
+    }
+
+    fn at(timestamp: Option<Timestamp>, transaction: Transaction) -> Transaction {
+        Transaction {
+            timestamp,
+            ..transaction
+        }
+    }
+
+    #[test]
+    fn test_require_monotonic_time() {
+        let mut db = Database::default().with_require_monotonic_time(true);
+        db.process(&at(Some(10), setup_deposit_transaction(1, 1, dec!(5.0))))
+            .unwrap();
+        // Equal timestamps are fine
+        db.process(&at(Some(10), setup_deposit_transaction(2, 1, dec!(1.0))))
+            .unwrap();
+        assert!(matches!(
+            db.process(&at(Some(9), setup_deposit_transaction(3, 1, dec!(1.0)))),
+            Err(TransactionError::OutOfOrder)
+        ));
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(4, 1, dec!(1.0))),
+            Err(TransactionError::MissingTimestamp)
+        ));
+        // A rejected transaction still moves the clock forward
+        assert!(matches!(
+            db.process(&at(
+                Some(20),
+                setup_withdrawal_transaction(5, 1, dec!(100.0))
+            )),
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+        assert!(matches!(
+            db.process(&at(Some(15), setup_deposit_transaction(6, 1, dec!(1.0)))),
+            Err(TransactionError::OutOfOrder)
+        ));
+        assert_eq!(account(&db, 1).available(), dec!(6.0));
+        assert_eq!(
+            db.storage
+                .record(RecordKey::from(1))
+                .unwrap()
+                .unwrap()
+                .timestamp(),
+            Some(10)
+        );
+
+        // Without the option timestamps are only recorded
+        let mut db = Database::default();
+        db.process(&at(Some(10), setup_deposit_transaction(1, 1, dec!(5.0))))
+            .unwrap();
+        db.process(&at(Some(9), setup_deposit_transaction(2, 1, dec!(1.0))))
+            .unwrap();
+        db.process(&setup_deposit_transaction(3, 1, dec!(1.0)))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let day = 24 * 60 * 60;
+        let mut db = Database::default().with_dispute_window(Duration::from_secs(90 * day));
+        db.process(&at(Some(0), setup_deposit_transaction(1, 1, dec!(5.0))))
+            .unwrap();
+        db.process(&at(Some(0), setup_deposit_transaction(2, 1, dec!(5.0))))
+            .unwrap();
+        db.process(&setup_deposit_transaction(3, 1, dec!(5.0)))
+            .unwrap();
+        assert!(matches!(
+            db.process(&at(Some(91 * day), setup_dispute_transaction(1, 1))),
+            Err(TransactionError::DisputeWindowExpired)
+        ));
+        db.process(&at(Some(90 * day), setup_dispute_transaction(2, 1)))
+            .unwrap();
+        // Settling is not bound by the window
+        db.process(&Transaction {
+            tx_type: TransactionType::Resolve,
+            ..at(Some(200 * day), setup_dispute_transaction(2, 1))
+        })
+        .unwrap();
+        // Without timestamps on both sides the window can't be enforced
+        db.process(&at(Some(200 * day), setup_dispute_transaction(3, 1)))
+            .unwrap();
+        assert_eq!(account(&db, 1).held(), dec!(5.0));
+    }
+
+    fn in_currency(code: &str, transaction: Transaction) -> Transaction {
+        transaction.with_currency(Currency::new(code))
+    }
+
+    #[test]
+    #[cfg(feature = "snapshot")]
+    fn test_currencies_are_kept_apart() {
+        let (eur, usd) = (Currency::new("EUR"), Currency::new("USD"));
+        let mut db = Database::default();
+        db.process(&in_currency(
+            "EUR",
+            setup_deposit_transaction(1, 1, dec!(10.0)),
+        ))
+        .unwrap();
+        db.process(&in_currency(
+            "USD",
+            setup_deposit_transaction(2, 1, dec!(5.0)),
+        ))
+        .unwrap();
+        assert!(matches!(
+            db.process(&in_currency(
+                "USD",
+                setup_withdrawal_transaction(3, 1, dec!(6.0))
+            )),
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+        assert!(matches!(
+            db.process(&in_currency("USD", setup_dispute_transaction(1, 1))),
+            Err(TransactionError::CurrencyMismatch)
+        ));
+        // The dispute doesn't have to name the currency, the disputed one applies
+        db.process(&setup_dispute_transaction(1, 1)).unwrap();
+        let acc = account(&db, 1);
+        assert_eq!(acc.balance(eur).held(), dec!(10.0));
+        assert_eq!(acc.balance(usd).available(), dec!(5.0));
+        assert_eq!(acc.get_total(), Decimal::ZERO);
+
+        let mut bytes = Vec::new();
+        db.write_snapshot(&mut bytes).unwrap();
+        let mut restored = Database::default();
+        restored.restore_snapshot(bytes.as_slice()).unwrap();
+        restored
+            .process(&in_currency("EUR", setup_resolve(1, 1)))
+            .unwrap();
+        let acc = account(&restored, 1);
+        assert_eq!(acc.balance(eur).available(), dec!(10.0));
+        assert_eq!(acc.balance(usd).available(), dec!(5.0));
+    }
+
+    fn setup_resolve(tx: TransactionID, client: ClientID) -> Transaction {
+        Transaction::new(TransactionType::Resolve, client, tx, None)
+    }
+
+    fn setup_convert(tx: TransactionID, amount: Decimal, to: &str, rate: Decimal) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Convert,
+            to_currency: Currency::new(to),
+            rate: Some(rate),
+            ..in_currency("EUR", setup_deposit_transaction(tx, 1, amount))
+        }
+    }
+
+    #[test]
+    fn test_convert_moves_funds_between_currencies() {
+        let (eur, usd) = (Currency::new("EUR"), Currency::new("USD"));
+        let mut db = Database::default();
+        db.process(&in_currency(
+            "EUR",
+            setup_deposit_transaction(1, 1, dec!(100.0)),
+        ))
+        .unwrap();
+        db.process(&setup_convert(2, dec!(40.0), "USD", dec!(1.08345)))
+            .unwrap();
+        let acc = account(&db, 1);
+        assert_eq!(acc.balance(eur).available(), dec!(60.0));
+        assert_eq!(acc.balance(usd).available(), dec!(43.3380));
+
+        for (convert, code) in [
+            (
+                setup_convert(3, dec!(61.0), "USD", dec!(1)),
+                "insufficient_funds",
+            ),
+            (setup_convert(3, dec!(1.0), "USD", dec!(0)), "invalid_rate"),
+            (
+                setup_convert(3, dec!(1.0), "USD", dec!(-1.1)),
+                "invalid_rate",
+            ),
+            (
+                setup_convert(3, dec!(1.0), "EUR", dec!(1)),
+                "invalid_transfer",
+            ),
+            (
+                setup_convert(3, dec!(1.0), "USD", dec!(0.00001)),
+                "negative_amount",
+            ),
+            (setup_convert(2, dec!(1.0), "USD", dec!(1)), "duplicate"),
+            (
+                setup_convert(3, dec!(1.0), "USD", dec!(1)).with_rate(None),
+                "invalid_rate",
+            ),
+        ] {
+            assert_eq!(db.process(&convert).unwrap_err().code(), code);
+        }
+        let acc = account(&db, 1);
+        assert_eq!(acc.balance(eur).available(), dec!(60.0));
+        assert_eq!(acc.balance(usd).available(), dec!(43.3380));
+        // Converts can't be disputed
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(2, 1)),
+            Err(TransactionError::InvalidDispute)
+        ));
+    }
+
+    // Withdrawals are disputable and a resolved dispute closes the transaction for good
+    #[derive(Debug)]
+    struct HouseRules;
+
+    impl DisputeRules for HouseRules {
+        fn is_disputable(&self, tx_type: &TransactionType) -> bool {
+            matches!(
+                tx_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        }
+
+        fn allows_negative_available(&self) -> bool {
+            false
+        }
+
+        fn allows_redispute(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_custom_dispute_rules() {
+        let mut db = Database::default().with_dispute_rules(HouseRules);
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(4.0)))
+            .unwrap();
+        db.process(&setup_deposit_transaction(3, 1, dec!(2.0)))
+            .unwrap();
+        db.process(&setup_dispute_transaction(2, 1)).unwrap();
+        db.process(&setup_dispute_transaction(3, 1)).unwrap();
+        db.process(&setup_resolve(3, 1)).unwrap();
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(3, 1)),
+            Err(TransactionError::InvalidDispute)
+        ));
+
+        // The standard rules let a resolved transaction be disputed again
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        for _ in 0..2 {
+            db.process(&setup_dispute_transaction(1, 1)).unwrap();
+            db.process(&setup_resolve(1, 1)).unwrap();
+        }
+        assert!(db.dispute_rules().allows_redispute());
+    }
+
+    #[test]
+    fn test_max_disputes_per_tx() {
+        let mut db = Database::default().with_max_disputes_per_tx(2);
+        db.process(&setup_deposit_transaction(1, 1, dec!(10.0)))
+            .unwrap();
+        for _ in 0..2 {
+            db.process(&setup_dispute_transaction(1, 1)).unwrap();
+            db.process(&setup_resolve(1, 1)).unwrap();
+        }
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(1, 1)),
+            Err(TransactionError::DisputeLimitReached)
+        ));
+        assert_eq!(
+            db.storage
+                .record(RecordKey::from(1))
+                .unwrap()
+                .unwrap()
+                .dispute_count(),
+            2
+        );
+        assert_eq!(account(&db, 1).available(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_open_and_close() {
+        let status = |tx_type, tx, client| Transaction {
+            tx_type,
+            ..setup_dispute_transaction(tx, client)
+        };
+        let mut db = Database::default().with_require_open(true);
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(1, 1, dec!(5.0))),
+            Err(TransactionError::AccountNotOpen)
+        ));
+        assert!(matches!(
+            db.process(&status(TransactionType::Close, 2, 1)),
+            Err(TransactionError::AccountNotOpen)
+        ));
+        db.process(&status(TransactionType::Open, 3, 1)).unwrap();
+        db.process(&setup_deposit_transaction(4, 1, dec!(5.0)))
+            .unwrap();
+        let transfer = Transaction {
+            tx_type: TransactionType::Transfer,
+            to_client: Some(2),
+            ..setup_deposit_transaction(5, 1, dec!(5.0))
+        };
+        assert!(matches!(
+            db.process(&transfer),
+            Err(TransactionError::AccountNotOpen)
+        ));
+        assert!(matches!(
+            db.process(&status(TransactionType::Close, 6, 1)),
+            Err(TransactionError::AccountError(AccountError::NonZeroBalance))
+        ));
+        db.process(&setup_withdrawal_transaction(7, 1, dec!(5.0)))
+            .unwrap();
+        db.process(&status(TransactionType::Close, 8, 1)).unwrap();
+        assert_eq!(account(&db, 1).status(), AccountStatus::Closed);
+        assert!(matches!(
+            db.process(&setup_deposit_transaction(9, 1, dec!(1.0))),
+            Err(TransactionError::AccountError(AccountError::Closed))
+        ));
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(4, 1)),
+            Err(TransactionError::AccountError(AccountError::Closed))
+        ));
+
+        // Without --require-open accounts still open implicitly, and can be closed
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(5.0)))
+            .unwrap();
+        assert_eq!(account(&db, 1).status(), AccountStatus::Implicit);
+        db.process(&status(TransactionType::Open, 2, 1)).unwrap();
+        assert!(matches!(
+            db.process(&status(TransactionType::Open, 3, 1)),
+            Err(TransactionError::AccountError(AccountError::AlreadyOpen))
+        ));
+        db.process(&status(TransactionType::Close, 4, 2)).unwrap();
+        assert_eq!(account(&db, 2).status(), AccountStatus::Closed);
+    }
+
+    #[test]
+    fn test_overdraft_limit() {
+        let mut db = Database::default().with_overdraft_limit(dec!(100.0));
+        db.process(&setup_deposit_transaction(1, 1, dec!(20.0)))
+            .unwrap();
+        db.process(&setup_withdrawal_transaction(2, 1, dec!(70.0)))
+            .unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(-50.0));
+        let transfer = Transaction {
+            tx_type: TransactionType::Transfer,
+            to_client: Some(2),
+            ..setup_deposit_transaction(3, 1, dec!(50.0))
+        };
+        db.process(&transfer).unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(-100.0));
+        assert!(matches!(
+            db.process(&setup_withdrawal_transaction(4, 1, dec!(0.5))),
+            Err(TransactionError::AccountError(
+                AccountError::InsufficientFunds
+            ))
+        ));
+        assert_eq!(account(&db, 1).available(), dec!(-100.0));
+    }
+
+    #[test]
+    fn test_fee_floor() {
+        let fee = |tx, amount| Transaction {
+            tx_type: TransactionType::Fee,
+            ..setup_deposit_transaction(tx, 1, amount)
+        };
+        let mut db = Database::default();
+        db.process(&setup_deposit_transaction(1, 1, dec!(2.0)))
+            .unwrap();
+        assert!(matches!(
+            db.process(&fee(2, dec!(3.0))),
+            Err(TransactionError::AccountError(
+                AccountError::FeeFloorExceeded
+            ))
+        ));
+        db.process(&fee(3, dec!(2.0))).unwrap();
+        assert_eq!(account(&db, 1).available(), Decimal::ZERO);
+
+        let mut db = Database::default().with_fee_floor(dec!(-5.0));
+        db.process(&setup_deposit_transaction(1, 1, dec!(2.0)))
+            .unwrap();
+        db.process(&fee(2, dec!(3.0))).unwrap();
+        assert_eq!(account(&db, 1).available(), dec!(-1.0));
+        assert!(matches!(
+            db.process(&fee(2, dec!(1.0))),
+            Err(TransactionError::Duplicate)
+        ));
+        assert!(matches!(
+            db.process(&setup_dispute_transaction(2, 1)),
+            Err(TransactionError::InvalidDispute)
+        ));
+    }
+
+    #[test]
+    fn test_tx_id_scope() {
+        let deposits = [
+            setup_deposit_transaction(1, 1, Decimal::from(5)),
+            setup_deposit_transaction(1, 2, Decimal::from(7)),
+        ];
+        let mut db = Database::default();
+        assert!(db.process(&deposits[0]).is_ok());
+        assert!(matches!(
+            db.process(&deposits[1]),
+            Err(TransactionError::Duplicate)
+        ));
+
+        let mut db = Database::default().with_tx_id_scope(TxIdScope::PerClient);
+        for deposit in &deposits {
+            db.process(deposit).unwrap();
+        }
+        assert!(matches!(
+            db.process(&deposits[0]),
+            Err(TransactionError::Duplicate)
+        ));
+        db.process(&setup_dispute_transaction(1, 2)).unwrap();
+        assert_eq!(account(&db, 1).balance(None).held(), Decimal::ZERO);
+        assert_eq!(account(&db, 2).balance(None).held(), Decimal::from(7));
+        db.check_invariants().unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::account::Account;
+use super::currency::Currency;
+use super::database::TransactionResult;
+use super::transaction::{ClientID, Transaction, TransactionID, TransactionType};
+
+// How one transaction moved one of a client's balances
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceDelta {
+    pub client: ClientID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+// What an accepted transaction did to one account, as returned by
+// Database::process_with_effect. An account without balances before is an empty Account.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransactionEffect {
+    pub client: ClientID,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub prior: Account,
+    pub new: Account,
+}
+
+impl TransactionEffect {
+    // The balances that moved, by how much
+    pub fn deltas(&self) -> Vec<BalanceDelta> {
+        deltas(self.client, &self.prior, &self.new).collect()
+    }
+}
+
+// A processed transaction, whether it was accepted or not
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    pub transaction: Transaction,
+    // The error code of a rejected transaction, None once accepted
+    pub error: Option<String>,
+    // Empty for most rejected transactions, though a client is registered even if its first
+    // transaction is rejected
+    pub deltas: Vec<BalanceDelta>,
+}
+
+impl HistoryEntry {
+    pub fn is_accepted(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+// Every processed transaction by id, so disputes, resolves and chargebacks show up next to the
+// transaction they refer to, oldest first
+#[derive(Debug, Default, Clone)]
+pub struct History {
+    entries: HashMap<TransactionID, Vec<HistoryEntry>>,
+    // Deltas of the transaction being processed
+    pending: Vec<BalanceDelta>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self, tx: TransactionID) -> &[HistoryEntry] {
+        self.entries.get(&tx).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Every entry, for snapshots
+    #[cfg(feature = "snapshot")]
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (TransactionID, &HistoryEntry)> {
+        self.entries
+            .iter()
+            .flat_map(|(tx, entries)| entries.iter().map(|entry| (*tx, entry)))
+    }
+
+    pub(crate) fn push(&mut self, tx: TransactionID, entry: HistoryEntry) {
+        self.entries.entry(tx).or_default().push(entry);
+    }
+
+    pub(crate) fn extend(&mut self, other: History) {
+        for (tx, entries) in other.entries {
+            self.entries.entry(tx).or_default().extend(entries);
+        }
+    }
+
+    // Drops deltas left over by writes outside of a transaction, such as a replay
+    pub(crate) fn begin(&mut self) {
+        self.pending.clear();
+    }
+
+    pub(crate) fn record_account(
+        &mut self,
+        client: ClientID,
+        before: Option<&Account>,
+        after: &Account,
+    ) {
+        let empty = Account::new();
+        self.pending
+            .extend(deltas(client, before.unwrap_or(&empty), after));
+    }
+
+    pub(crate) fn finish(&mut self, transaction: &Transaction, result: &TransactionResult) {
+        let entry = HistoryEntry {
+            transaction: transaction.clone(),
+            error: result.as_ref().err().map(|err| err.code().to_string()),
+            deltas: std::mem::take(&mut self.pending),
+        };
+        self.push(transaction.tx, entry);
+    }
+}
+
+// A balance missing from after can't have moved: balances are never removed
+pub(crate) fn deltas<'a>(
+    client: ClientID,
+    before: &'a Account,
+    after: &'a Account,
+) -> impl Iterator<Item = BalanceDelta> + 'a {
+    after
+        .balances
+        .iter()
+        .filter_map(move |(&currency, balance)| {
+            let previous = before.balance(currency);
+            let (available, held) = (
+                balance.available.saturating_sub(previous.available),
+                balance.held.saturating_sub(previous.held),
+            );
+            (available != Decimal::ZERO || held != Decimal::ZERO).then_some(BalanceDelta {
+                client,
+                currency,
+                available,
+                held,
+            })
+        })
+}
@@ -0,0 +1,26 @@
+use std::fmt::Debug;
+
+use super::account::Account;
+use super::transaction::Transaction;
+
+// Runs before each transaction is applied, for compliance rules the engine doesn't have. Install
+// it with Database::with_hook. The account is the client's as it stands, None before its first
+// transaction.
+pub trait TransactionHook: Debug + Send {
+    fn inspect(&mut self, transaction: &Transaction, account: Option<&Account>) -> HookVerdict;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookVerdict {
+    // Applied as is, the tags being counted by the Database
+    Accept {
+        tags: Vec<String>,
+    },
+    // Rejected with TransactionError::RejectedByHook and the reason
+    Reject(String),
+    // Applied in place of the transaction given
+    Transform {
+        transaction: Transaction,
+        tags: Vec<String>,
+    },
+}
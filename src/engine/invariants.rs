@@ -0,0 +1,252 @@
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+
+use super::currency::Currency;
+use super::transaction::{ClientID, TransactionID};
+use crate::storage::{AccountEntries, RecordEntries, StorageError};
+
+// A state the engine should never get into, found by Database::check_invariants
+#[derive(Debug)]
+pub enum InvariantViolation {
+    // available + held doesn't fit in a Decimal, so the total can't be reported
+    TotalOverflow {
+        client: ClientID,
+        currency: Option<Currency>,
+    },
+    // Only possible when disputes are allowed to drive balances negative
+    NegativeHeld {
+        client: ClientID,
+        currency: Option<Currency>,
+        held: Decimal,
+    },
+    // Held funds should be exactly what the client's open disputes hold
+    HeldMismatch {
+        client: ClientID,
+        currency: Option<Currency>,
+        held: Decimal,
+        disputed: Decimal,
+    },
+    // A transaction record for a client without an account
+    OrphanRecord {
+        tx: TransactionID,
+    },
+    Storage(StorageError),
+}
+
+impl InvariantViolation {
+    // Stable identifier for reports and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            InvariantViolation::TotalOverflow { .. } => "total_overflow",
+            InvariantViolation::NegativeHeld { .. } => "negative_held",
+            InvariantViolation::HeldMismatch { .. } => "held_mismatch",
+            InvariantViolation::OrphanRecord { .. } => "orphan_record",
+            InvariantViolation::Storage(_) => "storage",
+        }
+    }
+}
+
+impl From<StorageError> for InvariantViolation {
+    fn from(err: StorageError) -> Self {
+        InvariantViolation::Storage(err)
+    }
+}
+
+pub(crate) fn check(
+    accounts: AccountEntries<'_>,
+    records: RecordEntries<'_>,
+    allows_negative_held: bool,
+) -> Result<(), InvariantViolation> {
+    let mut disputed: HashMap<(ClientID, Option<Currency>), Decimal> = HashMap::new();
+    let mut clients = HashSet::new();
+    let mut recorded_clients = Vec::new();
+    for entry in records {
+        let (key, record) = entry?;
+        // Only keeps its id taken, a refused transfer may not even have created its client
+        if record.was_rejected() {
+            continue;
+        }
+        recorded_clients.push((key.tx, record.client()));
+        if record.is_disputed() {
+            *disputed
+                .entry((record.client(), record.currency()))
+                .or_default() += record.disputed_amount();
+        }
+    }
+    for entry in accounts {
+        let (client, account) = entry?;
+        clients.insert(client);
+        for (currency, balance) in &account.balances {
+            let (available, held) = (balance.available, balance.held);
+            if available.checked_add(held).is_none() {
+                return Err(InvariantViolation::TotalOverflow {
+                    client,
+                    currency: *currency,
+                });
+            }
+            if held < Decimal::ZERO && !allows_negative_held {
+                return Err(InvariantViolation::NegativeHeld {
+                    client,
+                    currency: *currency,
+                    held,
+                });
+            }
+            let disputed = disputed.remove(&(client, *currency)).unwrap_or_default();
+            if held != disputed {
+                return Err(InvariantViolation::HeldMismatch {
+                    client,
+                    currency: *currency,
+                    held,
+                    disputed,
+                });
+            }
+        }
+    }
+    // Disputes left over belong to balances that don't exist, which hold nothing
+    if let Some(((client, currency), disputed)) = disputed.into_iter().next() {
+        return Err(InvariantViolation::HeldMismatch {
+            client,
+            currency,
+            held: Decimal::ZERO,
+            disputed,
+        });
+    }
+    match recorded_clients
+        .into_iter()
+        .find(|(_, client)| !clients.contains(client))
+    {
+        Some((tx, _)) => Err(InvariantViolation::OrphanRecord { tx }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        Account, Balance, Database, DisputeFunding, DisputePolicy, Transaction, TransactionType,
+    };
+    use proptest::prelude::*;
+
+    fn transaction_type() -> impl Strategy<Value = TransactionType> {
+        prop_oneof![
+            3 => Just(TransactionType::Deposit),
+            2 => Just(TransactionType::Withdrawal),
+            2 => Just(TransactionType::Dispute),
+            1 => Just(TransactionType::Resolve),
+            1 => Just(TransactionType::Chargeback),
+            1 => Just(TransactionType::Transfer),
+            1 => Just(TransactionType::Fee),
+            1 => Just(TransactionType::Reversal),
+        ]
+    }
+
+    // Few clients and transaction ids, so sequences keep running into each other
+    fn transaction() -> impl Strategy<Value = Transaction> {
+        (
+            transaction_type(),
+            1..4u16,
+            1..24u32,
+            proptest::option::weighted(0.95, (1..100_000i64).prop_map(|n| Decimal::new(n, 2))),
+            1..4u16,
+        )
+            .prop_map(|(tx_type, client, tx, amount, to_client)| {
+                Transaction::new(tx_type, client, tx, amount).with_to_client(Some(to_client))
+            })
+    }
+
+    fn balances(db: &Database, client: ClientID) -> Option<Vec<(Option<Currency>, Balance)>> {
+        db.account(client)
+            .unwrap()
+            .map(|account| account.balances().collect())
+    }
+
+    fn is_locked(db: &Database, client: ClientID) -> bool {
+        db.account(client)
+            .unwrap()
+            .as_ref()
+            .is_some_and(Account::is_locked)
+    }
+
+    // Runs the sequence, checking the invariants after every step
+    fn run(mut db: Database, transactions: &[Transaction]) -> Result<(), TestCaseError> {
+        let mut accepted = HashSet::new();
+        for transaction in transactions {
+            let clients = [transaction.client, transaction.to_client.unwrap()];
+            let locked = clients.map(|client| is_locked(&db, client));
+            let before = clients.map(|client| balances(&db, client));
+            let result = db.process(transaction);
+            if let Err(violation) = db.check_invariants() {
+                return Err(TestCaseError::fail(format!(
+                    "{:?} after {:?}",
+                    violation, transaction
+                )));
+            }
+            for (i, client) in clients.into_iter().enumerate() {
+                if locked[i] {
+                    prop_assert_eq!(&before[i], &balances(&db, client));
+                }
+            }
+            let recorded = matches!(
+                transaction.tx_type,
+                TransactionType::Deposit
+                    | TransactionType::Withdrawal
+                    | TransactionType::Transfer
+                    | TransactionType::Fee
+            );
+            // A transaction id is only ever applied once
+            if recorded && result.is_ok() {
+                prop_assert!(accepted.insert(transaction.tx), "{:?}", transaction);
+            }
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn invariants_hold_with_default_rules(
+            transactions in proptest::collection::vec(transaction(), 1..64)
+        ) {
+            run(Database::default(), &transactions)?;
+        }
+
+        #[test]
+        fn invariants_hold_with_permissive_rules(
+            transactions in proptest::collection::vec(transaction(), 1..64)
+        ) {
+            let db = Database::default()
+                .with_dispute_policy(DisputePolicy::DepositsAndWithdrawals)
+                .with_dispute_funding(DisputeFunding::AllowNegative)
+                .with_overdraft_limit(Decimal::from(50));
+            run(db, &transactions)?;
+        }
+    }
+
+    #[test]
+    fn test_held_funds_must_match_open_disputes() {
+        let mut db = Database::default();
+        db.process(&Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(Decimal::from(5)),
+        ))
+        .unwrap();
+        db.check_invariants().unwrap();
+        let mut account = db.account(1).unwrap().unwrap();
+        account.balances.insert(
+            None,
+            Balance {
+                available: Decimal::from(5),
+                held: Decimal::ONE,
+            },
+        );
+        let accounts: AccountEntries = Box::new(std::iter::once(Ok((1, account))));
+        assert_eq!(
+            check(accounts, Box::new(std::iter::empty()), false)
+                .unwrap_err()
+                .code(),
+            "held_mismatch"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use rust_decimal::Decimal;
+
+use super::account::{Account, AccountStatus, Balance, RiskCounters};
+use super::currency::Currency;
+use super::transaction::{ClientID, TransactionID, TransactionRecord};
+
+// A single accepted state mutation. Events carry their full effect, so replaying them needs
+// neither the original transactions nor the policies that accepted them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerEvent {
+    // The client was seen for the first time
+    AccountOpened {
+        client: ClientID,
+    },
+    // Available funds went up, e.g. a deposit was applied or a transfer received
+    FundsCredited {
+        client: ClientID,
+        currency: Option<Currency>,
+        amount: Decimal,
+    },
+    // Available funds went down, e.g. a withdrawal or a dispute
+    FundsDebited {
+        client: ClientID,
+        currency: Option<Currency>,
+        amount: Decimal,
+    },
+    // Held funds went up through a dispute
+    FundsHeld {
+        client: ClientID,
+        currency: Option<Currency>,
+        amount: Decimal,
+    },
+    // Held funds went down, through a resolve or a chargeback
+    FundsReleased {
+        client: ClientID,
+        currency: Option<Currency>,
+        amount: Decimal,
+    },
+    AccountLocked {
+        client: ClientID,
+    },
+    AccountUnlocked {
+        client: ClientID,
+    },
+    // Through an open or close transaction
+    StatusChanged {
+        client: ClientID,
+        status: AccountStatus,
+    },
+    // A dispute or chargeback was counted against the client, or a withdrawal rejected
+    RiskChanged {
+        client: ClientID,
+        risk: RiskCounters,
+    },
+    // A deposit, withdrawal or transfer was recorded, or its dispute state changed
+    RecordWritten {
+        tx: TransactionID,
+        record: TransactionRecord,
+    },
+}
+
+impl LedgerEvent {
+    // The account an event applies to, None for record events
+    pub fn client(&self) -> Option<ClientID> {
+        match self {
+            LedgerEvent::AccountOpened { client }
+            | LedgerEvent::FundsCredited { client, .. }
+            | LedgerEvent::FundsDebited { client, .. }
+            | LedgerEvent::FundsHeld { client, .. }
+            | LedgerEvent::FundsReleased { client, .. }
+            | LedgerEvent::AccountLocked { client }
+            | LedgerEvent::AccountUnlocked { client }
+            | LedgerEvent::StatusChanged { client, .. }
+            | LedgerEvent::RiskChanged { client, .. } => Some(*client),
+            LedgerEvent::RecordWritten { .. } => None,
+        }
+    }
+}
+
+// Append-only log of every event applied to a Database, oldest first
+#[derive(Debug, Default, Clone)]
+pub struct Ledger {
+    events: Vec<LedgerEvent>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> &[LedgerEvent] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, event: LedgerEvent) {
+        self.events.push(event);
+    }
+
+    pub(crate) fn extend(&mut self, other: Ledger) {
+        self.events.extend(other.events);
+    }
+
+    // Appends the events turning 'before' into 'after', None meaning the account is new
+    pub(crate) fn record_account(
+        &mut self,
+        client: ClientID,
+        before: Option<&Account>,
+        after: &Account,
+    ) {
+        let before = match before {
+            Some(before) => before.clone(),
+            None => {
+                self.push(LedgerEvent::AccountOpened { client });
+                Account::new()
+            }
+        };
+        // Every currency either side has a balance in
+        let mut currencies = before
+            .balances
+            .keys()
+            .chain(after.balances.keys())
+            .collect::<Vec<_>>();
+        currencies.sort_unstable();
+        currencies.dedup();
+        for &currency in currencies {
+            self.record_balance(
+                client,
+                currency,
+                before.balance(currency),
+                after.balance(currency),
+            );
+        }
+        match (before.locked, after.locked) {
+            (false, true) => self.push(LedgerEvent::AccountLocked { client }),
+            (true, false) => self.push(LedgerEvent::AccountUnlocked { client }),
+            _ => (),
+        }
+        if before.status != after.status {
+            self.push(LedgerEvent::StatusChanged {
+                client,
+                status: after.status,
+            });
+        }
+        if before.risk != after.risk {
+            self.push(LedgerEvent::RiskChanged {
+                client,
+                risk: after.risk,
+            });
+        }
+    }
+
+    fn record_balance(
+        &mut self,
+        client: ClientID,
+        currency: Option<Currency>,
+        before: Balance,
+        after: Balance,
+    ) {
+        if after.available > before.available {
+            self.push(LedgerEvent::FundsCredited {
+                client,
+                currency,
+                amount: after.available - before.available,
+            });
+        } else if after.available < before.available {
+            self.push(LedgerEvent::FundsDebited {
+                client,
+                currency,
+                amount: before.available - after.available,
+            });
+        }
+        if after.held > before.held {
+            self.push(LedgerEvent::FundsHeld {
+                client,
+                currency,
+                amount: after.held - before.held,
+            });
+        } else if after.held < before.held {
+            self.push(LedgerEvent::FundsReleased {
+                client,
+                currency,
+                amount: before.held - after.held,
+            });
+        }
+    }
+}
+
+impl IntoIterator for Ledger {
+    type Item = LedgerEvent;
+    type IntoIter = std::vec::IntoIter<LedgerEvent>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.events.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_record_account_emits_differences() {
+        let mut ledger = Ledger::new();
+        let mut account = Account::new();
+        ledger.record_account(1, None, &account);
+        assert_eq!(ledger.events(), [LedgerEvent::AccountOpened { client: 1 }]);
+
+        let before = account.clone();
+        account.deposit(None, dec!(5.0)).unwrap();
+        account.dispute(None, dec!(2.0)).unwrap();
+        ledger.record_account(1, Some(&before), &account);
+        assert_eq!(
+            ledger.events()[1..],
+            [
+                LedgerEvent::FundsCredited {
+                    client: 1,
+                    currency: None,
+                    amount: dec!(3.0)
+                },
+                LedgerEvent::FundsHeld {
+                    client: 1,
+                    currency: None,
+                    amount: dec!(2.0)
+                },
+                LedgerEvent::RiskChanged {
+                    client: 1,
+                    risk: RiskCounters {
+                        disputes: 1,
+                        ..RiskCounters::default()
+                    }
+                },
+            ]
+        );
+
+        let before = account.clone();
+        ledger.record_account(1, Some(&before), &account);
+        assert_eq!(ledger.len(), 4);
+
+        let eur = Currency::new("EUR");
+        account.deposit(eur, dec!(1.0)).unwrap();
+        ledger.record_account(1, Some(&before), &account);
+        assert_eq!(
+            ledger.events()[4..],
+            [LedgerEvent::FundsCredited {
+                client: 1,
+                currency: eur,
+                amount: dec!(1.0)
+            }]
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde::de::{Deserializer, Error, Unexpected};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::database::TransactionError;
+use super::transaction::{ClientID, Timestamp, Transaction, TransactionType};
+
+// A bound on one client's transactions, rejecting those that would break it with
+// TransactionError::LimitExceeded naming the rule. Rules apply to the transactions of their type,
+// or to all of them when it is left out. Amounts are taken as given, and only transactions the
+// engine accepted count towards the window they fall in.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitRule {
+    pub id: String,
+    #[serde(default, rename = "type")]
+    pub tx_type: Option<TransactionType>,
+    // Largest amount of a single transaction
+    #[serde(default)]
+    pub max_amount: Option<Decimal>,
+    // Most transactions, and largest sum of their amounts, within a window
+    #[serde(default)]
+    pub max_count: Option<u32>,
+    #[serde(default)]
+    pub max_total: Option<Decimal>,
+    // Windows are fixed, 'day' starting at midnight UTC, and transactions without a timestamp
+    // all fall in the same one. None is a single window for the whole run.
+    #[serde(default, rename = "per", deserialize_with = "window")]
+    pub window: Option<Duration>,
+}
+
+impl LimitRule {
+    fn applies_to(&self, transaction: &Transaction) -> bool {
+        self.tx_type
+            .as_ref()
+            .is_none_or(|tx_type| *tx_type == transaction.tx_type)
+    }
+
+    fn window_start(&self, transaction: &Transaction) -> Timestamp {
+        let timestamp = transaction.timestamp.unwrap_or_default();
+        match self.window {
+            Some(window) => timestamp - timestamp % window.as_secs(),
+            None => 0,
+        }
+    }
+}
+
+// 'minute', 'hour', 'day', 'week' or a number of seconds
+fn window<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Per {
+        Seconds(u64),
+        Named(String),
+    }
+    let expected = &"minute, hour, day, week or a positive number of seconds";
+    let secs = match Per::deserialize(deserializer)? {
+        Per::Seconds(0) => return Err(D::Error::invalid_value(Unexpected::Unsigned(0), expected)),
+        Per::Seconds(secs) => secs,
+        Per::Named(name) => match name.as_str() {
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            _ => return Err(D::Error::invalid_value(Unexpected::Str(&name), expected)),
+        },
+    };
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+// The limit rules with what each client did in their current windows
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    rules: Vec<LimitRule>,
+    // By rule index and client
+    windows: HashMap<(usize, ClientID), Window>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Window {
+    start: Timestamp,
+    count: u32,
+    total: Decimal,
+}
+
+impl Limits {
+    pub fn new(rules: Vec<LimitRule>) -> Self {
+        Limits {
+            rules,
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &[LimitRule] {
+        &self.rules
+    }
+
+    // The first rule, in the order given, the transaction would break
+    pub(crate) fn check(&self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let amount = transaction.amount.unwrap_or_default();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.applies_to(transaction) {
+                continue;
+            }
+            let (count, total) = match self.windows.get(&(i, transaction.client)) {
+                Some(window) if window.start == rule.window_start(transaction) => {
+                    (window.count, window.total)
+                }
+                _ => (0, Decimal::ZERO),
+            };
+            if rule.max_amount.is_some_and(|max| amount > max)
+                || rule.max_count.is_some_and(|max| count >= max)
+                || rule.max_total.is_some_and(|max| total + amount > max)
+            {
+                return Err(TransactionError::LimitExceeded(rule.id.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn record(&mut self, transaction: &Transaction) {
+        let amount = transaction.amount.unwrap_or_default();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.applies_to(transaction) {
+                continue;
+            }
+            let start = rule.window_start(transaction);
+            let window = self.windows.entry((i, transaction.client)).or_default();
+            if window.start != start {
+                *window = Window {
+                    start,
+                    ..Window::default()
+                };
+            }
+            window.count = window.count.saturating_add(1);
+            window.total = window.total.saturating_add(amount);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_rules_from_toml() {
+        #[derive(Deserialize)]
+        struct File {
+            rule: Vec<LimitRule>,
+        }
+        let file: File = toml::from_str(
+            "[[rule]]\nid = 'deposits'\ntype = 'deposit'\nmax_count = 3\nper = 'day'\n\
+             [[rule]]\nid = 'big'\nmax_amount = '1000.5'\nper = 90\n",
+        )
+        .unwrap();
+        assert_eq!(file.rule[0].tx_type, Some(TransactionType::Deposit));
+        assert_eq!(file.rule[0].window, Some(Duration::from_secs(86_400)));
+        assert_eq!(file.rule[1].max_amount, Some(dec!(1000.5)));
+        assert_eq!(file.rule[1].window, Some(Duration::from_secs(90)));
+        for bad in ["per = 'fortnight'", "per = 0", "max_deposits = 1"] {
+            assert!(toml::from_str::<File>(&format!("[[rule]]\nid = 'x'\n{}", bad)).is_err());
+        }
+    }
+}
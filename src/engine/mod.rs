@@ -0,0 +1,64 @@
+mod account;
+#[cfg(feature = "async")]
+mod actors;
+mod audit;
+mod concurrent;
+mod csv_columns;
+mod currency;
+mod database;
+mod history;
+mod hook;
+// Walks the whole state, so only built into debug builds
+#[cfg(debug_assertions)]
+mod invariants;
+mod ledger;
+mod limits;
+#[cfg(feature = "wasm")]
+mod plugin;
+mod policy;
+mod reorder;
+mod retention;
+#[cfg(feature = "rules")]
+mod rules;
+mod sharded;
+#[cfg(feature = "snapshot")]
+mod snapshot;
+#[cfg(feature = "async")]
+mod streaming;
+mod transaction;
+mod wal;
+
+pub use account::{
+    Account, AccountError, AccountResult, AccountRow, AccountStatus, Balance, RiskCounters,
+};
+#[cfg(feature = "async")]
+pub use actors::ActorDatabase;
+pub use audit::{AdminAction, AuditEntry};
+pub use concurrent::ConcurrentDatabase;
+pub use csv_columns::{AmountFormat, CsvColumns, DecimalSeparator, lenient_column};
+pub use currency::Currency;
+pub use database::{Database, Prepared, TransactionError, TransactionResult};
+pub use history::{BalanceDelta, History, HistoryEntry, TransactionEffect};
+pub use hook::{HookVerdict, TransactionHook};
+#[cfg(debug_assertions)]
+pub use invariants::InvariantViolation;
+pub use ledger::{Ledger, LedgerEvent};
+pub use limits::{LimitRule, Limits};
+#[cfg(feature = "wasm")]
+pub use plugin::{PluginError, WasmPlugin};
+pub use policy::{
+    AmountLimits, BlockPolicy, DisputeFunding, DisputePolicy, DisputeRules, LockedAccountPolicy,
+    PrecisionPolicy, RetentionPolicy, Rounding, StandardDisputeRules, TxIdScope,
+};
+pub use reorder::ReorderBuffer;
+#[cfg(feature = "rules")]
+pub use rules::{Rules, RulesError};
+pub use sharded::{ErrorHandler, ShardError, ShardedDatabase};
+#[cfg(feature = "snapshot")]
+pub use snapshot::SnapshotError;
+#[cfg(feature = "async")]
+pub use streaming::{AsyncDatabase, AsyncHandle};
+pub use transaction::{
+    ClientID, RecordKey, Timestamp, Transaction, TransactionID, TransactionRecord, TransactionType,
+};
+pub use wal::Wal;
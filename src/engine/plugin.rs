@@ -0,0 +1,260 @@
+use serde::Deserialize;
+use std::fmt::{self, Debug};
+use std::path::Path;
+use wasmi::{Config, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use super::account::Account;
+use super::hook::{HookVerdict, TransactionHook};
+use super::transaction::Transaction;
+
+// Fuel a plugin may burn on one transaction, roughly one unit per instruction, so a plugin stuck
+// in a loop fails the transaction rather than hanging the engine
+const FUEL_PER_TRANSACTION: u64 = 10_000_000;
+
+#[derive(Debug)]
+pub enum PluginError {
+    Io(std::io::Error),
+    // Not a valid module, or one without the exports plugins have
+    Invalid(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::Io(err) => write!(f, "{}", err),
+            PluginError::Invalid(reason) => write!(f, "invalid plugin: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+// A TransactionHook running a WebAssembly module, one instance for the plugin's whole life so it
+// can keep state between transactions. The module exports its 'memory' and two functions:
+//
+//   alloc(len: i32) -> i32, where the engine may write len bytes
+//   on_transaction(ptr: i32, len: i32) -> i64
+//
+// on_transaction gets the transaction as JSON, with the fields of the CSV input, and returns 0 to
+// accept it as is. Anything else is the address of a JSON response in its memory, in the high 32
+// bits, and its length, in the low 32: {"reject": "reason"} rejects the transaction,
+// {"transaction": {...}} replaces it, and {"tags": ["..."]}, alone or next to "transaction", tags
+// it. The account isn't passed on. The module may not import anything. A plugin that traps, runs out of fuel or answers
+// nonsense rejects the transaction.
+pub struct WasmPlugin {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_transaction: TypedFunc<(i32, i32), i64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Response {
+    #[serde(default)]
+    reject: Option<String>,
+    #[serde(default)]
+    transaction: Option<Transaction>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+impl WasmPlugin {
+    pub fn new(wasm: &[u8]) -> Result<Self, PluginError> {
+        let invalid = |err: wasmi::Error| PluginError::Invalid(err.to_string());
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(invalid)?;
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(invalid)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| PluginError::Invalid("no 'memory' export".to_string()))?;
+        let alloc = instance.get_typed_func(&store, "alloc").map_err(invalid)?;
+        let on_transaction = instance
+            .get_typed_func(&store, "on_transaction")
+            .map_err(invalid)?;
+        Ok(WasmPlugin {
+            store,
+            memory,
+            alloc,
+            on_transaction,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PluginError> {
+        WasmPlugin::new(&std::fs::read(path).map_err(PluginError::Io)?)
+    }
+
+    fn call(&mut self, transaction: &Transaction) -> Result<HookVerdict, String> {
+        let input = serde_json::to_vec(transaction).map_err(|e| e.to_string())?;
+        let len = i32::try_from(input.len()).map_err(|e| e.to_string())?;
+        self.store
+            .set_fuel(FUEL_PER_TRANSACTION)
+            .map_err(|e| e.to_string())?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|e| e.to_string())?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, &input)
+            .map_err(|e| e.to_string())?;
+        let answer = self
+            .on_transaction
+            .call(&mut self.store, (ptr, len))
+            .map_err(|e| e.to_string())?;
+        if answer == 0 {
+            return Ok(HookVerdict::Accept { tags: Vec::new() });
+        }
+        let (ptr, len) = ((answer as u64 >> 32) as usize, answer as u32 as usize);
+        let mut output = vec![0; len];
+        self.memory
+            .read(&self.store, ptr, &mut output)
+            .map_err(|e| e.to_string())?;
+        let response: Response = serde_json::from_slice(&output).map_err(|e| e.to_string())?;
+        Ok(match response {
+            Response {
+                reject: Some(reason),
+                ..
+            } => HookVerdict::Reject(reason),
+            Response {
+                transaction: Some(transaction),
+                tags,
+                ..
+            } => HookVerdict::Transform { transaction, tags },
+            Response { tags, .. } => HookVerdict::Accept { tags },
+        })
+    }
+}
+
+impl TransactionHook for WasmPlugin {
+    fn inspect(&mut self, transaction: &Transaction, _account: Option<&Account>) -> HookVerdict {
+        self.call(transaction)
+            .unwrap_or_else(|err| HookVerdict::Reject(format!("plugin failed: {}", err)))
+    }
+}
+
+impl Debug for WasmPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmPlugin").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::transaction::TransactionType;
+    use rust_decimal::dec;
+
+    fn leb(mut value: i64, signed: bool, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            let done = match signed {
+                true => (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0),
+                false => value == 0,
+            };
+            out.push(if done { byte } else { byte | 0x80 });
+            if done {
+                return;
+            }
+        }
+    }
+
+    fn section(id: u8, content: &[u8], out: &mut Vec<u8>) {
+        out.push(id);
+        leb(content.len() as i64, false, out);
+        out.extend_from_slice(content);
+    }
+
+    // A plugin whose alloc hands out address 1024, and whose on_transaction runs `body` with
+    // `data` at address 0
+    fn module(body: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        section(
+            1,
+            b"\x02\x60\x01\x7f\x01\x7f\x60\x02\x7f\x7f\x01\x7e",
+            &mut wasm,
+        );
+        section(3, b"\x02\x00\x01", &mut wasm);
+        section(5, b"\x01\x00\x01", &mut wasm);
+        section(
+            7,
+            b"\x03\x06memory\x02\x00\x05alloc\x00\x00\x0eon_transaction\x00\x01",
+            &mut wasm,
+        );
+        let alloc = b"\x00\x41\x80\x08\x0b";
+        let mut code = vec![0x02, alloc.len() as u8];
+        code.extend_from_slice(alloc);
+        leb(body.len() as i64 + 2, false, &mut code);
+        code.push(0x00);
+        code.extend_from_slice(body);
+        code.push(0x0b);
+        section(10, &code, &mut wasm);
+        let mut segment = b"\x01\x00\x41\x00\x0b".to_vec();
+        leb(data.len() as i64, false, &mut segment);
+        segment.extend_from_slice(data);
+        section(11, &segment, &mut wasm);
+        wasm
+    }
+
+    // A plugin answering every transaction with `response`
+    fn answering(response: &str) -> WasmPlugin {
+        let mut body = vec![0x42];
+        leb(response.len() as i64, true, &mut body);
+        WasmPlugin::new(&module(&body, response.as_bytes())).unwrap()
+    }
+
+    fn deposit() -> Transaction {
+        Transaction::new(TransactionType::Deposit, 1, 1, Some(dec!(5)))
+    }
+
+    #[test]
+    fn test_plugin_verdicts() {
+        let mut plugin = WasmPlugin::new(&module(b"\x42\x00", b"")).unwrap();
+        assert_eq!(
+            plugin.inspect(&deposit(), None),
+            HookVerdict::Accept { tags: Vec::new() }
+        );
+        assert_eq!(
+            answering(r#"{"reject": "sanctioned"}"#).inspect(&deposit(), None),
+            HookVerdict::Reject("sanctioned".to_string())
+        );
+        assert_eq!(
+            answering(r#"{"tags": ["large"]}"#).inspect(&deposit(), None),
+            HookVerdict::Accept {
+                tags: vec!["large".to_string()]
+            }
+        );
+        let transform = r#"{"transaction": {"type": "deposit", "client": 1, "tx": 1, "amount": "4.5"}, "tags": ["fee"]}"#;
+        assert_eq!(
+            answering(transform).inspect(&deposit(), None),
+            HookVerdict::Transform {
+                transaction: Transaction {
+                    amount: Some(dec!(4.5)),
+                    ..deposit()
+                },
+                tags: vec!["fee".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_failing_plugins_reject() {
+        let rejects = |mut plugin: WasmPlugin| matches!(plugin.inspect(&deposit(), None), HookVerdict::Reject(reason) if reason.starts_with("plugin failed"));
+        assert!(rejects(answering("{nonsense")));
+        // unreachable, and a loop running out of fuel
+        assert!(rejects(WasmPlugin::new(&module(b"\x00", b"")).unwrap()));
+        assert!(rejects(
+            WasmPlugin::new(&module(b"\x03\x40\x0c\x00\x0b\x42\x00", b"")).unwrap()
+        ));
+        assert!(matches!(
+            WasmPlugin::new(b"not wasm"),
+            Err(PluginError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,272 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+use super::transaction::{ClientID, RecordKey, TransactionID, TransactionType};
+
+// Which kinds of transactions a dispute may reference
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DisputePolicy {
+    #[default]
+    DepositsOnly,
+    DepositsAndWithdrawals,
+}
+
+impl DisputePolicy {
+    pub fn allows(&self, tx_type: &TransactionType) -> bool {
+        match tx_type {
+            TransactionType::Deposit => true,
+            TransactionType::Withdrawal => *self == DisputePolicy::DepositsAndWithdrawals,
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Transfer
+            | TransactionType::Convert
+            | TransactionType::Fee
+            | TransactionType::Unlock
+            | TransactionType::Open
+            | TransactionType::Close
+            | TransactionType::Reversal
+            | TransactionType::Settle
+            | TransactionType::Other(_) => false,
+        }
+    }
+}
+
+// What a dispute does when the client no longer has the disputed funds available, e.g. because
+// they were withdrawn after the deposit
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DisputeFunding {
+    // Reject the dispute with InsufficientFunds
+    #[default]
+    RequireAvailable,
+    // Hold the funds anyway, available goes negative and reflects the client's debt, so the
+    // chargeback can always complete
+    AllowNegative,
+}
+
+// House rules for disputes. Implement it to change what the engine accepts without touching
+// the dispute handling itself, and install it with Database::with_dispute_rules.
+pub trait DisputeRules: Debug + Send {
+    // Whether a transaction of this type may be disputed at all
+    fn is_disputable(&self, tx_type: &TransactionType) -> bool;
+
+    // Whether a dispute may hold funds the client no longer has available, driving available
+    // negative
+    fn allows_negative_available(&self) -> bool;
+
+    // Whether a transaction whose dispute was resolved may be disputed again
+    fn allows_redispute(&self) -> bool;
+}
+
+// The rules used unless others are installed, set up through with_dispute_policy and
+// with_dispute_funding
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StandardDisputeRules {
+    pub policy: DisputePolicy,
+    pub funding: DisputeFunding,
+}
+
+impl DisputeRules for StandardDisputeRules {
+    fn is_disputable(&self, tx_type: &TransactionType) -> bool {
+        self.policy.allows(tx_type)
+    }
+
+    fn allows_negative_available(&self) -> bool {
+        self.funding == DisputeFunding::AllowNegative
+    }
+
+    fn allows_redispute(&self) -> bool {
+        true
+    }
+}
+
+// Which transaction records are dropped to bound memory, none by default. A pruned record leaves
+// only its key behind: its id still counts as a duplicate, and disputes of it fail with
+// ReferencePruned. Records under dispute are never pruned.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    // Records of types the dispute rules don't allow disputing are not kept at all
+    pub disputable_only: bool,
+    // Records older than the dispute window, as of the latest timestamp processed
+    pub past_dispute_window: bool,
+    // Only the records with the highest transaction ids of each client are kept
+    pub max_per_client: Option<usize>,
+}
+
+impl RetentionPolicy {
+    // Whether Database::prune has anything to sweep
+    pub fn sweeps(&self) -> bool {
+        self.past_dispute_window || self.max_per_client.is_some()
+    }
+}
+
+// Which clients may transact. A transfer is rejected when either side is blocked.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum BlockPolicy {
+    #[default]
+    AllowAll,
+    // Every client but these
+    Block(HashSet<ClientID>),
+    // Only these clients
+    Allow(HashSet<ClientID>),
+}
+
+impl BlockPolicy {
+    pub fn blocks(&self, client: ClientID) -> bool {
+        match self {
+            BlockPolicy::AllowAll => false,
+            BlockPolicy::Block(clients) => clients.contains(&client),
+            BlockPolicy::Allow(clients) => !clients.contains(&client),
+        }
+    }
+}
+
+// Bounds on the amounts transactions may carry, checked as given, before rounding to the
+// precision. None lifts a bound, both are lifted by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AmountLimits {
+    pub max_amount: Option<Decimal>,
+    // Decimal places, trailing zeros not counting
+    pub max_precision: Option<u32>,
+}
+
+// What a locked account still accepts
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum LockedAccountPolicy {
+    // Nothing at all, so disputes that were open when the account got locked stay open forever
+    #[default]
+    FreezeEverything,
+    // Open disputes can still be resolved or charged back, new disputes, deposits and
+    // withdrawals remain blocked
+    SettleOpenDisputes,
+}
+
+// Whether transaction ids are unique across the whole input, or only within each client's
+// transactions so two clients may use the same id
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TxIdScope {
+    #[default]
+    Global,
+    PerClient,
+}
+
+impl TxIdScope {
+    pub fn key(self, client: ClientID, tx: TransactionID) -> RecordKey {
+        match self {
+            TxIdScope::Global => RecordKey::from(tx),
+            TxIdScope::PerClient => RecordKey {
+                client: Some(client),
+                tx,
+            },
+        }
+    }
+}
+
+// Amounts are rounded to this many decimal places on ingest, and output is always formatted
+// with exactly this many decimal places, both rounding the same way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrecisionPolicy {
+    pub decimal_places: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for PrecisionPolicy {
+    fn default() -> Self {
+        PrecisionPolicy {
+            decimal_places: 4,
+            rounding: Rounding::default(),
+        }
+    }
+}
+
+// How amounts with more decimal places than the precision are rounded
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    // Banker's rounding, a tie goes to the even digit
+    #[default]
+    HalfEven,
+    // A tie goes away from zero
+    HalfUp,
+    // The extra decimal places are dropped
+    Truncate,
+}
+
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+impl PrecisionPolicy {
+    // The most decimal places a Decimal can represent
+    pub const MAX_DECIMAL_PLACES: u32 = 28;
+
+    pub fn normalize(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.decimal_places, self.rounding.strategy())
+    }
+
+    // Rounded and scaled to exactly decimal_places, so it displays with that many
+    pub fn fixed(&self, amount: Decimal) -> Decimal {
+        let mut amount = self.normalize(amount);
+        amount.rescale(self.decimal_places);
+        amount
+    }
+
+    pub fn format(&self, amount: Decimal) -> String {
+        format!(
+            "{:.*}",
+            self.decimal_places as usize,
+            self.normalize(amount)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_normalize_rounds_half_even() {
+        let precision = PrecisionPolicy::default();
+        assert_eq!(precision.normalize(dec!(1.23455)), dec!(1.2346));
+        assert_eq!(precision.normalize(dec!(1.23465)), dec!(1.2346));
+        assert_eq!(precision.normalize(dec!(1.2)), dec!(1.2));
+    }
+
+    #[test]
+    fn test_format_pads_to_exact_decimal_places() {
+        let precision = PrecisionPolicy::default();
+        assert_eq!(precision.format(dec!(70)), "70.0000");
+        assert_eq!(precision.format(dec!(1.23456)), "1.2346");
+        let precision = PrecisionPolicy {
+            decimal_places: 2,
+            ..PrecisionPolicy::default()
+        };
+        assert_eq!(precision.format(dec!(0.005)), "0.00");
+    }
+
+    #[test]
+    fn test_rounding_strategies() {
+        let precision = |rounding| PrecisionPolicy {
+            decimal_places: 2,
+            rounding,
+        };
+        for (amount, half_even, half_up, truncate) in [
+            (dec!(1.005), dec!(1.00), dec!(1.01), dec!(1.00)),
+            (dec!(1.015), dec!(1.02), dec!(1.02), dec!(1.01)),
+            (dec!(1.019), dec!(1.02), dec!(1.02), dec!(1.01)),
+            (dec!(-1.005), dec!(-1.00), dec!(-1.01), dec!(-1.00)),
+        ] {
+            assert_eq!(precision(Rounding::HalfEven).normalize(amount), half_even);
+            assert_eq!(precision(Rounding::HalfUp).normalize(amount), half_up);
+            assert_eq!(precision(Rounding::Truncate).normalize(amount), truncate);
+        }
+        assert_eq!(precision(Rounding::HalfUp).format(dec!(0.005)), "0.01");
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+
+use super::database::{Database, TransactionError};
+use super::transaction::{Transaction, TransactionID, TransactionType};
+
+struct Parked<C> {
+    transaction: Transaction,
+    context: C,
+    // Position of the transaction in the feed, for expiry
+    position: u64,
+}
+
+// Parks disputes, resolves and chargebacks whose transaction hasn't been seen yet, for feeds
+// that deliver them a little ahead of it. They are retried as soon as a transaction with the id
+// they refer to is accepted, and rejected with ReferenceNotFound once 'window' more
+// transactions went by without it. C is whatever the caller needs to report a rejection, such
+// as where the transaction came from.
+pub struct ReorderBuffer<C> {
+    window: u64,
+    position: u64,
+    parked: VecDeque<Parked<C>>,
+}
+
+impl<C> ReorderBuffer<C> {
+    // A window of 0 parks nothing, every transaction is processed as is
+    pub fn new(window: u64) -> Self {
+        ReorderBuffer {
+            window,
+            position: 0,
+            parked: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.parked.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parked.is_empty()
+    }
+
+    // on_reject gets every transaction rejected for good, whether right away, after a retry or
+    // once it expired
+    pub fn process(
+        &mut self,
+        db: &mut Database,
+        transaction: Transaction,
+        context: C,
+        mut on_reject: impl FnMut(Transaction, C, TransactionError),
+    ) {
+        self.position += 1;
+        match db.process(&transaction) {
+            Err(TransactionError::ReferenceNotFound)
+                if self.window > 0 && refers_to_another(&transaction.tx_type) =>
+            {
+                self.parked.push_back(Parked {
+                    transaction,
+                    context,
+                    position: self.position,
+                });
+            }
+            Err(err) => on_reject(transaction, context, err),
+            Ok(()) if !self.parked.is_empty() => self.retry(db, transaction.tx, &mut on_reject),
+            Ok(()) => (),
+        }
+        while self
+            .parked
+            .front()
+            .is_some_and(|parked| self.position - parked.position >= self.window)
+        {
+            if let Some(parked) = self.parked.pop_front() {
+                on_reject(
+                    parked.transaction,
+                    parked.context,
+                    TransactionError::ReferenceNotFound,
+                );
+            }
+        }
+    }
+
+    // Oldest first, so a parked dispute goes through before a parked resolve of the same
+    // transaction
+    fn retry(
+        &mut self,
+        db: &mut Database,
+        tx: TransactionID,
+        on_reject: &mut impl FnMut(Transaction, C, TransactionError),
+    ) {
+        let mut i = 0;
+        while i < self.parked.len() {
+            if self.parked[i].transaction.tx != tx {
+                i += 1;
+                continue;
+            }
+            match db.process(&self.parked[i].transaction) {
+                // Still missing, e.g. another client's transaction with ids scoped per client
+                Err(TransactionError::ReferenceNotFound) => i += 1,
+                result => {
+                    if let (Some(parked), Err(err)) = (self.parked.remove(i), result) {
+                        on_reject(parked.transaction, parked.context, err);
+                    }
+                }
+            }
+        }
+    }
+
+    // Rejects whatever is still parked, once the feed is exhausted
+    pub fn finish(&mut self, mut on_reject: impl FnMut(Transaction, C, TransactionError)) {
+        for parked in self.parked.drain(..) {
+            on_reject(
+                parked.transaction,
+                parked.context,
+                TransactionError::ReferenceNotFound,
+            );
+        }
+    }
+}
+
+fn refers_to_another(tx_type: &TransactionType) -> bool {
+    matches!(
+        tx_type,
+        TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Reversal
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ClientID;
+    use rust_decimal::Decimal;
+
+    fn transaction(
+        tx_type: TransactionType,
+        client: ClientID,
+        tx: TransactionID,
+        amount: Option<Decimal>,
+    ) -> Transaction {
+        Transaction::new(tx_type, client, tx, amount)
+    }
+
+    #[test]
+    fn test_early_disputes_are_retried() {
+        let mut db = Database::default();
+        let mut buffer = ReorderBuffer::new(3);
+        let mut rejected = Vec::new();
+        for (line, transaction) in [
+            transaction(TransactionType::Dispute, 1, 1, None),
+            transaction(TransactionType::Chargeback, 1, 1, None),
+            // Never arrives
+            transaction(TransactionType::Dispute, 1, 9, None),
+            transaction(TransactionType::Deposit, 1, 1, Some(Decimal::from(5))),
+            transaction(TransactionType::Deposit, 2, 2, Some(Decimal::from(1))),
+            transaction(TransactionType::Deposit, 2, 3, Some(Decimal::from(1))),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            buffer.process(&mut db, transaction, line, |transaction, line, err| {
+                rejected.push((line, transaction.tx, err.code()))
+            });
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(rejected, [(2, 9, "reference_not_found")]);
+        let account = db.account(1).unwrap().unwrap();
+        assert!(account.is_locked());
+        assert_eq!(account.balance(None).total(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_finish_rejects_parked() {
+        let mut db = Database::default();
+        let mut buffer = ReorderBuffer::new(100);
+        buffer.process(
+            &mut db,
+            transaction(TransactionType::Resolve, 1, 1, None),
+            (),
+            |_, _, _| panic!("parked transactions are not rejected yet"),
+        );
+        assert_eq!(buffer.len(), 1);
+        let mut rejected = 0;
+        buffer.finish(|_, _, _| rejected += 1);
+        assert_eq!(rejected, 1);
+    }
+}
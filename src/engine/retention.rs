@@ -0,0 +1,94 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::transaction::{ClientID, Timestamp, TransactionID, TransactionRecord};
+
+// The kept records a sweep of the retention policy goes through, updated as records are written
+// so that a sweep only looks at the ones that may have to go: the oldest, and those of clients
+// written to since the last sweep. Database::prune builds it from the storage the first time.
+#[derive(Debug, Default)]
+pub(super) struct RetentionIndex {
+    // Transaction ids of each client's kept records
+    by_client: HashMap<ClientID, BTreeSet<TransactionID>>,
+    // Clients with records written since the last sweep
+    touched: HashSet<ClientID>,
+    // Kept records with a timestamp, oldest first
+    by_age: BTreeSet<(Timestamp, ClientID, TransactionID)>,
+}
+
+impl RetentionIndex {
+    // Also called for updates of a record, which can make it prunable again, e.g. a resolve
+    pub(super) fn insert(&mut self, tx: TransactionID, record: &TransactionRecord) {
+        let client = record.client();
+        self.by_client.entry(client).or_default().insert(tx);
+        self.touched.insert(client);
+        if let Some(timestamp) = record.timestamp() {
+            self.by_age.insert((timestamp, client, tx));
+        }
+    }
+
+    pub(super) fn remove(&mut self, tx: TransactionID, record: &TransactionRecord) {
+        let client = record.client();
+        if let Some(txs) = self.by_client.get_mut(&client) {
+            txs.remove(&tx);
+            if txs.is_empty() {
+                self.by_client.remove(&client);
+            }
+        }
+        if let Some(timestamp) = record.timestamp() {
+            self.by_age.remove(&(timestamp, client, tx));
+        }
+    }
+
+    pub(super) fn newest(&self) -> Option<Timestamp> {
+        self.by_age.last().map(|(timestamp, ..)| *timestamp)
+    }
+
+    // Records timestamped before cutoff, oldest first
+    pub(super) fn older_than(&self, cutoff: Timestamp) -> Vec<(ClientID, TransactionID)> {
+        self.by_age
+            .range(..(cutoff, 0, 0))
+            .map(|&(_, client, tx)| (client, tx))
+            .collect()
+    }
+
+    // The clients written to since the last call that have more than max records kept, each
+    // with their transaction ids in ascending order
+    pub(super) fn over(&mut self, max: usize) -> Vec<(ClientID, Vec<TransactionID>)> {
+        self.touched
+            .drain()
+            .filter_map(|client| {
+                let txs = self.by_client.get(&client)?;
+                (txs.len() > max).then(|| (client, txs.iter().copied().collect()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use rust_decimal::dec;
+
+    fn record(client: ClientID, timestamp: Timestamp) -> TransactionRecord {
+        TransactionRecord::new(&TransactionType::Deposit, client, dec!(1))
+            .with_timestamp(Some(timestamp))
+    }
+
+    #[test]
+    fn test_only_clients_written_to_are_looked_at_again() {
+        let mut index = RetentionIndex::default();
+        for (tx, client, timestamp) in [(3, 1, 30), (1, 1, 10), (2, 2, 20), (4, 1, 40)] {
+            index.insert(tx, &record(client, timestamp));
+        }
+        assert_eq!(index.newest(), Some(40));
+        assert_eq!(index.older_than(30), vec![(1, 1), (2, 2)]);
+        assert_eq!(index.over(2), vec![(1, vec![1, 3, 4])]);
+        assert!(index.over(2).is_empty());
+
+        index.remove(1, &record(1, 10));
+        index.insert(5, &record(2, 50));
+        assert_eq!(index.older_than(30), vec![(2, 2)]);
+        assert_eq!(index.over(1), vec![(2, vec![2, 5])]);
+    }
+}
@@ -0,0 +1,239 @@
+use rhai::{AST, Dynamic, Engine, Scope};
+use std::fmt;
+use std::path::Path;
+
+use super::account::Account;
+use super::hook::{HookVerdict, TransactionHook};
+use super::transaction::Transaction;
+
+// Operations a rule may run on one transaction before it fails
+const MAX_OPERATIONS: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RulesError {
+    // Line of the rules file, None when it couldn't be read
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for RulesError {}
+
+// A TransactionHook checking each transaction against rules written in Rhai, one per line:
+//
+//   reject if amount > 10_000 && type == "withdrawal"
+//   reject overdrawn if type == "withdrawal" && amount > available
+//   tag large if amount >= 1_000
+//
+// A rule rejects or tags the transaction when its condition holds, the reason of a rejection
+// being the name given after 'reject', or the rule's line. Rules run in order and the first
+// rejection wins. Conditions see the transaction as type, client, tx, amount (zero when there is
+// none), to_client and timestamp (() when absent) and currency ("" when absent), and the client's
+// account as available, held and total in the transaction's currency and locked. Numbers with a
+// fractional part are decimals, like amounts. Blank lines and lines starting with '#' are skipped.
+// A condition that fails or isn't a boolean rejects the transaction.
+pub struct Rules {
+    engine: Engine,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    line: usize,
+    action: Action,
+    condition: AST,
+}
+
+enum Action {
+    Reject(Option<String>),
+    Tag(String),
+}
+
+impl Rules {
+    pub fn parse(text: &str) -> Result<Self, RulesError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| RulesError {
+                line: Some(i + 1),
+                message,
+            };
+            let (head, condition) = line
+                .split_once(" if ")
+                .ok_or_else(|| error("expected 'reject [NAME] if' or 'tag NAME if'".to_string()))?;
+            let action = match head.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["reject"] => Action::Reject(None),
+                ["reject", name] => Action::Reject(Some(name.to_string())),
+                ["tag", name] => Action::Tag(name.to_string()),
+                _ => return Err(error(format!("unknown action '{}'", head))),
+            };
+            let condition = engine
+                .compile_expression(condition)
+                .map_err(|e| error(e.to_string()))?;
+            rules.push(Rule {
+                line: i + 1,
+                action,
+                condition,
+            });
+        }
+        Ok(Rules { engine, rules })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RulesError> {
+        let text = std::fs::read_to_string(path).map_err(|e| RulesError {
+            line: None,
+            message: e.to_string(),
+        })?;
+        Rules::parse(&text)
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn scope(transaction: &Transaction, account: Option<&Account>) -> Scope<'static> {
+        let optional = |value: Option<i64>| value.map_or(Dynamic::UNIT, Dynamic::from);
+        let balance = account
+            .map(|account| account.balance(transaction.currency))
+            .unwrap_or_default();
+        let mut scope = Scope::new();
+        scope.push("type", transaction.tx_type.name().to_string());
+        scope.push("client", transaction.client as i64);
+        scope.push("tx", transaction.tx as i64);
+        scope.push("amount", transaction.amount.unwrap_or_default());
+        scope.push_dynamic("to_client", optional(transaction.to_client.map(i64::from)));
+        scope.push_dynamic(
+            "timestamp",
+            optional(
+                transaction
+                    .timestamp
+                    .map(|timestamp| timestamp.min(i64::MAX as u64) as i64),
+            ),
+        );
+        scope.push(
+            "currency",
+            transaction
+                .currency
+                .map(|currency| currency.to_string())
+                .unwrap_or_default(),
+        );
+        scope.push("available", balance.available());
+        scope.push("held", balance.held());
+        scope.push("total", balance.total());
+        scope.push("locked", account.is_some_and(Account::is_locked));
+        scope
+    }
+}
+
+impl TransactionHook for Rules {
+    fn inspect(&mut self, transaction: &Transaction, account: Option<&Account>) -> HookVerdict {
+        let scope = Rules::scope(transaction, account);
+        let mut tags = Vec::new();
+        for rule in &self.rules {
+            let holds = self
+                .engine
+                .eval_ast_with_scope::<bool>(&mut scope.clone(), &rule.condition);
+            match (holds, &rule.action) {
+                (Ok(false), _) => {}
+                (Ok(true), Action::Reject(Some(name))) => return HookVerdict::Reject(name.clone()),
+                (Ok(true), Action::Reject(None)) => {
+                    return HookVerdict::Reject(format!("rule at line {}", rule.line));
+                }
+                (Ok(true), Action::Tag(tag)) => tags.push(tag.clone()),
+                (Err(e), _) => {
+                    return HookVerdict::Reject(format!(
+                        "rule at line {} failed: {}",
+                        rule.line, e
+                    ));
+                }
+            }
+        }
+        HookVerdict::Accept { tags }
+    }
+}
+
+impl fmt::Debug for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rules")
+            .field("rules", &self.rules.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::transaction::TransactionType;
+    use rust_decimal::{Decimal, dec};
+
+    fn transaction(tx_type: TransactionType, amount: Decimal) -> Transaction {
+        Transaction::new(tx_type, 1, 1, Some(amount))
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut rules = Rules::parse(
+            "# house rules\n\
+             reject if amount > 10_000 && type == \"withdrawal\"\n\
+             \n\
+             reject overdrawn if type == \"withdrawal\" && amount > available + 0.5\n\
+             tag large if amount >= 1_000\n\
+             tag new if total == 0 && !locked\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+        let mut account = Account::default();
+        account.deposit(None, dec!(20_000)).unwrap();
+        let withdrawal = |amount| transaction(TransactionType::Withdrawal, amount);
+        assert_eq!(
+            rules.inspect(&withdrawal(dec!(10_001)), Some(&account)),
+            HookVerdict::Reject("rule at line 2".to_string())
+        );
+        assert_eq!(
+            rules.inspect(&withdrawal(dec!(600)), None),
+            HookVerdict::Reject("overdrawn".to_string())
+        );
+        assert_eq!(
+            rules.inspect(&withdrawal(dec!(1_000)), Some(&account)),
+            HookVerdict::Accept {
+                tags: vec!["large".to_string()]
+            }
+        );
+        assert_eq!(
+            rules.inspect(&transaction(TransactionType::Deposit, dec!(1_000)), None),
+            HookVerdict::Accept {
+                tags: vec!["large".to_string(), "new".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_bad_rules() {
+        let error = Rules::parse("tag large if amount >\n").unwrap_err();
+        assert_eq!(error.line, Some(1));
+        assert!(Rules::parse("\nallow if true").unwrap_err().line == Some(2));
+        assert!(Rules::parse("reject amount > 1").is_err());
+        // Conditions that don't give a boolean reject at run time
+        let mut rules = Rules::parse("reject if amount + \"x\"\nreject if to_client > 3").unwrap();
+        assert!(matches!(
+            rules.inspect(&transaction(TransactionType::Deposit, dec!(1)), None),
+            HookVerdict::Reject(reason) if reason.starts_with("rule at line 1 failed")
+        ));
+    }
+}
@@ -0,0 +1,409 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::database::{Database, TransactionError};
+use super::policy::TxIdScope;
+use super::transaction::{ClientID, Transaction, TransactionID};
+use crate::storage::{StorageError, StorageResult};
+
+// How many transactions may queue up for a worker before the router blocks
+const SHARD_QUEUE_CAPACITY: usize = 4096;
+
+// Ids per page of IdOwners
+const ID_PAGE_BITS: u32 = 12;
+
+#[derive(Debug)]
+pub enum ShardError {
+    WorkerPanicked,
+    Storage(StorageError),
+}
+
+// Receives every rejected transaction along with the metadata it was submitted with
+pub type ErrorHandler<M = ()> = Arc<dyn Fn(&Transaction, &M, TransactionError) + Send + Sync>;
+
+// What the router queues up for a worker
+enum Job<M> {
+    Process(Transaction, M),
+    // A transfer to a client of another shard, applied with the Database of that shard, lent by
+    // its worker and handed back once done
+    Transfer {
+        transaction: Transaction,
+        meta: M,
+        recipient: ClientID,
+        lent: Receiver<Database>,
+        give_back: Sender<Database>,
+    },
+    // Lends the worker's Database to the shard applying a transfer to one of its clients. The
+    // worker waits for it to come back, so nothing else of the shard applies meanwhile.
+    Lend(Sender<Database>, Receiver<Database>),
+    // Whether a record of the shard holds the id, answered once everything before applied
+    HoldsId(TransactionID, Sender<StorageResult<bool>>),
+    // A record of another shard holds the id
+    IdTaken(TransactionID),
+}
+
+// Routes transactions to one worker thread per shard by 'client % N'. Disputes always reference
+// a transaction of the same client, so every shard can run its own Database, and the shards
+// only need each other for transfers and ids:
+// - A transfer to a client of another shard waits in the sender's shard until the recipient's
+//   shard got through everything routed to it before, then applies with the recipient's account
+//   lent by that shard, which doesn't apply anything else until it gets the account back.
+// - With TxIdScope::Global the router remembers which shard took each id last. A transaction
+//   taking an id another shard took asks that shard whether it still holds the id, which is a
+//   round trip but only happens for ids used twice, and if so the id is a duplicate here too.
+// Accounts and rejections then come out as they would from a single Database processing the
+// input in order, except that a dispute, resolve, chargeback or reversal of another client's
+// transaction fails with reference_not_found rather than invalid_dispute or invalid_reversal,
+// and that timestamps are only required to be monotonic, and the dispute window swept, within
+// a shard.
+// 'M' is caller metadata (e.g. the input line) travelling with each transaction to the ErrorHandler.
+pub struct ShardedDatabase<M = ()> {
+    senders: Vec<SyncSender<Job<M>>>,
+    workers: Vec<JoinHandle<Database>>,
+    on_error: ErrorHandler<M>,
+    // Of the first shard, every shard is expected to be configured alike
+    tx_id_scope: TxIdScope,
+    owners: Mutex<IdOwners>,
+}
+
+impl<M: Send + 'static> ShardedDatabase<M> {
+    pub fn new(
+        shards: usize,
+        mut make_db: impl FnMut() -> Database,
+        on_error: ErrorHandler<M>,
+    ) -> Self {
+        let mut tx_id_scope = TxIdScope::default();
+        let (senders, workers) = (0..shards.max(1))
+            .map(|shard| {
+                let (sender, receiver) = mpsc::sync_channel(SHARD_QUEUE_CAPACITY);
+                let db = make_db();
+                if shard == 0 {
+                    tx_id_scope = db.tx_id_scope();
+                }
+                let on_error = Arc::clone(&on_error);
+                (sender, thread::spawn(move || work(db, receiver, on_error)))
+            })
+            .unzip();
+        ShardedDatabase {
+            senders,
+            workers,
+            on_error,
+            tx_id_scope,
+            owners: Mutex::new(IdOwners::default()),
+        }
+    }
+
+    pub fn submit(&self, transaction: Transaction, meta: M) {
+        let shards = self.senders.len();
+        let shard = shard_of(transaction.client, shards);
+        if let Err(err) = self.claim_id(&transaction, shard) {
+            return (self.on_error)(&transaction, &meta, err);
+        }
+        let recipient = transaction
+            .to_client
+            .filter(|to_client| shard_of(*to_client, shards) != shard);
+        // A send only fails if the worker panicked, which finish() reports
+        match recipient {
+            Some(recipient) => {
+                let (lend, lent) = mpsc::channel();
+                let (give_back, given_back) = mpsc::channel();
+                let job = Job::Transfer {
+                    transaction,
+                    meta,
+                    recipient,
+                    lent,
+                    give_back,
+                };
+                let _ = self.senders[shard].send(job);
+                let _ = self.senders[shard_of(recipient, shards)].send(Job::Lend(lend, given_back));
+            }
+            None => {
+                let _ = self.senders[shard].send(Job::Process(transaction, meta));
+            }
+        }
+    }
+
+    // Takes the transaction's id for its shard, unless another shard still holds it
+    fn claim_id(&self, transaction: &Transaction, shard: usize) -> Result<(), TransactionError> {
+        if self.tx_id_scope != TxIdScope::Global || !transaction.tx_type.takes_id() {
+            return Ok(());
+        }
+        let mut owners = self.owners.lock().unwrap();
+        if let Some(owner) = owners.get(transaction.tx).filter(|owner| *owner != shard) {
+            let (reply, held) = mpsc::channel();
+            let _ = self.senders[owner].send(Job::HoldsId(transaction.tx, reply));
+            if held.recv().map_err(|_| TransactionError::EngineStopped)?? {
+                let _ = self.senders[shard].send(Job::IdTaken(transaction.tx));
+                return Ok(());
+            }
+        }
+        owners.set(transaction.tx, shard);
+        Ok(())
+    }
+
+    // Waits for every shard to drain its queue and merges the partitions into one Database
+    pub fn finish(self) -> Result<Database, ShardError> {
+        drop(self.senders);
+        merge_partitions(
+            self.workers
+                .into_iter()
+                .map(|worker| worker.join().map_err(|_| ShardError::WorkerPanicked)),
+        )
+    }
+}
+
+fn work<M>(mut db: Database, jobs: Receiver<Job<M>>, on_error: ErrorHandler<M>) -> Database {
+    for job in jobs {
+        match job {
+            Job::Process(transaction, meta) => {
+                if let Err(err) = db.process(&transaction) {
+                    on_error(&transaction, &meta, err)
+                }
+            }
+            Job::Transfer {
+                transaction,
+                meta,
+                recipient,
+                lent,
+                give_back,
+            } => {
+                let result = match lent.recv() {
+                    Ok(mut lent) => {
+                        let result = with_recipient(&mut db, &mut lent, recipient, |db| {
+                            db.process(&transaction)
+                        });
+                        let _ = give_back.send(lent);
+                        result
+                    }
+                    Err(_) => Err(TransactionError::EngineStopped),
+                };
+                if let Err(err) = result {
+                    on_error(&transaction, &meta, err)
+                }
+            }
+            Job::Lend(lend, given_back) => {
+                db = match lend.send(db) {
+                    // The Database is gone if the borrowing worker panicked, so this one stops too
+                    Ok(()) => given_back.recv().expect("the shard borrowing it panicked"),
+                    Err(mpsc::SendError(db)) => db,
+                }
+            }
+            Job::HoldsId(tx, reply) => {
+                let _ = reply.send(db.holds_id(tx));
+            }
+            Job::IdTaken(tx) => db.take_id_elsewhere(tx),
+        }
+    }
+    db
+}
+
+// ConcurrentDatabase splits clients over shards the same way, and merges them alike
+pub(super) fn shard_of(client: ClientID, shards: usize) -> usize {
+    client as usize % shards
+}
+
+// Runs `f`, such as processing a transfer, on the shard of the sender with the recipient's
+// account moved over from its own shard, and moved back once done, so both accounts are there
+// as in a single Database
+pub(super) fn with_recipient<R>(
+    sender: &mut Database,
+    recipient: &mut Database,
+    client: ClientID,
+    f: impl FnOnce(&mut Database) -> Result<R, TransactionError>,
+) -> Result<R, TransactionError> {
+    let account = recipient.take_account(client)?;
+    if let Err(err) = sender.give_account(client, account.as_ref()) {
+        recipient.give_account(client, account.as_ref())?;
+        return Err(err.into());
+    }
+    let result = f(sender);
+    let account = sender.take_account(client)?;
+    recipient.give_account(client, account.as_ref())?;
+    result
+}
+
+// Which shard took each global transaction id last, in pages of ids allocated as ids in their
+// range turn up, two bytes per id. Fewer than 65535 shards are expected.
+#[derive(Default)]
+pub(super) struct IdOwners {
+    pages: Vec<Option<Box<[u16]>>>,
+}
+
+impl IdOwners {
+    pub(super) fn get(&self, tx: TransactionID) -> Option<usize> {
+        let page = self.pages.get((tx >> ID_PAGE_BITS) as usize)?.as_ref()?;
+        match page[(tx & ((1 << ID_PAGE_BITS) - 1)) as usize] {
+            0 => None,
+            owner => Some(owner as usize - 1),
+        }
+    }
+
+    pub(super) fn set(&mut self, tx: TransactionID, shard: usize) {
+        let index = (tx >> ID_PAGE_BITS) as usize;
+        if self.pages.len() <= index {
+            self.pages.resize_with(index + 1, || None);
+        }
+        let page =
+            self.pages[index].get_or_insert_with(|| vec![0; 1 << ID_PAGE_BITS].into_boxed_slice());
+        page[(tx & ((1 << ID_PAGE_BITS) - 1)) as usize] = shard as u16 + 1;
+    }
+}
+
+pub(super) fn merge_partitions(
+    partitions: impl IntoIterator<Item = Result<Database, ShardError>>,
+) -> Result<Database, ShardError> {
+    let mut partitions = partitions.into_iter();
+    // Merging into the first partition keeps its policies for the merged Database
+    let mut merged = partitions.next().ok_or(ShardError::WorkerPanicked)??;
+    for partition in partitions {
+        merged.merge(partition?).map_err(ShardError::Storage)?;
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use rust_decimal::{Decimal, dec};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_sharded_results_match_single_threaded() {
+        let transactions: Vec<Transaction> =
+            (1..=40)
+                .map(|tx| {
+                    Transaction::new(
+                        TransactionType::Deposit,
+                        (tx % 7) as u16,
+                        tx,
+                        Some(dec!(1.5)),
+                    )
+                })
+                .chain((1..=10).map(|tx| {
+                    Transaction::new(TransactionType::Dispute, (tx % 7) as u16, tx, None)
+                }))
+                .collect();
+
+        let mut single = Database::default();
+        for transaction in &transactions {
+            single.process(transaction).unwrap();
+        }
+
+        let errors = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&errors);
+        let sharded = ShardedDatabase::new(
+            3,
+            Database::default,
+            Arc::new(move |_, _: &(), _| *counter.lock().unwrap() += 1),
+        );
+        for transaction in transactions {
+            sharded.submit(transaction, ());
+        }
+        let merged = sharded.finish().unwrap();
+
+        assert_eq!(*errors.lock().unwrap(), 0);
+        assert_eq!(merged.accounts().count(), single.accounts().count());
+        for entry in single.accounts() {
+            let (cid, acc) = entry.unwrap();
+            let other = merged.account(cid).unwrap().unwrap();
+            assert_eq!(acc.available(), other.available());
+            assert_eq!(acc.held(), other.held());
+        }
+    }
+
+    // Transactions of ten clients from a fixed seed, with ids reused across clients, transfers
+    // between any two of them, refused withdrawals and chargebacks locking accounts. Disputes,
+    // resolves and chargebacks come from the client that took the id. The first two take an id
+    // in one shard and leave it to another.
+    fn mixed_feed() -> Vec<Transaction> {
+        let mut seed = 7u64;
+        let mut next = move |n: u64| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        let mut takers = HashMap::new();
+        let mut feed = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1000, Some(dec!(-1))),
+            Transaction::new(TransactionType::Deposit, 2, 1000, Some(dec!(5))),
+        ];
+        for _ in 0..2000 {
+            let client = next(10) as ClientID;
+            let tx = next(400) as u32 + 1;
+            let amount = Some(Decimal::new(next(5000) as i64 + 1, 2));
+            let taker = |tx_type| {
+                let taker = takers.get(&tx).copied().unwrap_or(client);
+                Transaction::new(tx_type, taker, tx, None)
+            };
+            let transaction = match next(12) {
+                0..=3 => Transaction::new(TransactionType::Deposit, client, tx, amount),
+                4..=5 => Transaction::new(TransactionType::Withdrawal, client, tx, amount),
+                6..=7 => Transaction::new(TransactionType::Transfer, client, tx, amount)
+                    .with_to_client(Some((client + 1 + next(9) as ClientID) % 10)),
+                8..=9 => taker(TransactionType::Dispute),
+                10 => taker(TransactionType::Resolve),
+                _ => taker(TransactionType::Chargeback),
+            };
+            if transaction.tx_type.takes_id() {
+                takers.entry(tx).or_insert(client);
+            }
+            feed.push(transaction);
+        }
+        feed
+    }
+
+    type Rejection = (u32, ClientID, String, &'static str);
+
+    fn rejection(transaction: &Transaction, err: &TransactionError) -> Rejection {
+        let tx_type = transaction.tx_type.name().to_string();
+        (transaction.tx, transaction.client, tx_type, err.code())
+    }
+
+    #[test]
+    fn test_transfers_and_ids_across_shards_match_single_threaded() {
+        let feed = mixed_feed();
+        let mut single = Database::default();
+        let mut expected = Vec::new();
+        for transaction in &feed {
+            if let Err(err) = single.process(transaction) {
+                expected.push(rejection(transaction, &err));
+            }
+        }
+
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let collected = Arc::clone(&rejected);
+        let sharded = ShardedDatabase::new(
+            3,
+            Database::default,
+            Arc::new(move |transaction, _: &(), err| {
+                collected.lock().unwrap().push(rejection(transaction, &err))
+            }),
+        );
+        for transaction in feed {
+            sharded.submit(transaction, ());
+        }
+        let merged = sharded.finish().unwrap();
+
+        let mut rejected = rejected.lock().unwrap().clone();
+        rejected.sort();
+        expected.sort();
+        assert_eq!(rejected, expected);
+        assert!(expected.iter().any(|(.., code)| *code == "duplicate"));
+        let accounts = |db: &Database| {
+            let mut accounts = db
+                .accounts()
+                .map(Result::unwrap)
+                .map(|(client, account)| {
+                    let balances = account.balances().collect::<Vec<_>>();
+                    (client, balances, account.is_locked())
+                })
+                .collect::<Vec<_>>();
+            accounts.sort_by_key(|(client, ..)| *client);
+            accounts
+        };
+        assert_eq!(accounts(&merged), accounts(&single));
+    }
+}
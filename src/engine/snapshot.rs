@@ -0,0 +1,455 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::io::{Read, Write};
+
+use super::account::{Account, AccountStatus, Balance, RiskCounters};
+use super::audit::AuditEntry;
+use super::currency::Currency;
+use super::history::{BalanceDelta, HistoryEntry};
+use super::transaction::{
+    ClientID, RecordKey, Timestamp, Transaction, TransactionID, TransactionRecord, TransactionType,
+};
+use crate::storage::StorageError;
+
+// Bumped whenever the encoding below changes. Older snapshots are still read by LegacyReader,
+// newer ones are refused.
+const SNAPSHOT_VERSION: u32 = 15;
+
+// Transaction types by the variant index bincode wrote them as, before version 11 wrote their
+// names. Variants were only ever appended until then.
+const LEGACY_TYPES: [TransactionType; 11] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Transfer,
+    TransactionType::Convert,
+    TransactionType::Fee,
+    TransactionType::Unlock,
+    TransactionType::Open,
+    TransactionType::Close,
+];
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Encoding(bincode::Error),
+    UnsupportedVersion(u32),
+    Storage(StorageError),
+}
+
+impl From<bincode::Error> for SnapshotError {
+    fn from(err: bincode::Error) -> Self {
+        SnapshotError::Encoding(err)
+    }
+}
+
+impl From<StorageError> for SnapshotError {
+    fn from(err: StorageError) -> Self {
+        SnapshotError::Storage(err)
+    }
+}
+
+// Everything a Database needs to carry on where it stopped. Decimals are kept as strings, the
+// default Decimal encoding can't be read back by bincode.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    version: u32,
+    accounts: Vec<AccountState>,
+    records: Vec<RecordState>,
+    pub(crate) audit_log: Vec<AuditEntry>,
+    pub(crate) last_timestamp: Option<Timestamp>,
+    history: Vec<HistoryState>,
+    // Keys of the records the retention policy dropped
+    pub(crate) pruned: Vec<RecordKey>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountState {
+    client: ClientID,
+    balances: Vec<BalanceState>,
+    locked: bool,
+    status: AccountStatus,
+    risk: RiskCounters,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BalanceState {
+    currency: Option<Currency>,
+    #[serde(with = "rust_decimal::serde::str")]
+    available: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    held: Decimal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordState {
+    tx: TransactionID,
+    tx_type: TransactionType,
+    client: ClientID,
+    #[serde(with = "rust_decimal::serde::str")]
+    amount: Decimal,
+    timestamp: Option<Timestamp>,
+    currency: Option<Currency>,
+    is_disputed: bool,
+    was_resolved: bool,
+    was_charged_back: bool,
+    was_reversed: bool,
+    was_rejected: bool,
+    dispute_count: u8,
+    #[serde(with = "rust_decimal::serde::str")]
+    disputed_amount: Decimal,
+}
+
+// A HistoryEntry with the transaction's fields inlined
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryState {
+    tx: TransactionID,
+    tx_type: TransactionType,
+    client: ClientID,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    amount: Option<Decimal>,
+    to_client: Option<ClientID>,
+    timestamp: Option<Timestamp>,
+    currency: Option<Currency>,
+    to_currency: Option<Currency>,
+    #[serde(with = "rust_decimal::serde::str_option")]
+    rate: Option<Decimal>,
+    error: Option<String>,
+    deltas: Vec<DeltaState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeltaState {
+    client: ClientID,
+    currency: Option<Currency>,
+    #[serde(with = "rust_decimal::serde::str")]
+    available: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    held: Decimal,
+}
+
+impl Snapshot {
+    pub(crate) fn new() -> Self {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            ..Snapshot::default()
+        }
+    }
+
+    pub(crate) fn push_account(&mut self, client: ClientID, account: &Account) {
+        self.accounts.push(AccountState {
+            client,
+            balances: account
+                .balances
+                .iter()
+                .map(|(currency, balance)| BalanceState {
+                    currency: *currency,
+                    available: balance.available,
+                    held: balance.held,
+                })
+                .collect(),
+            locked: account.locked,
+            status: account.status,
+            risk: account.risk,
+        });
+    }
+
+    pub(crate) fn push_record(&mut self, tx: TransactionID, record: &TransactionRecord) {
+        self.records.push(RecordState {
+            tx,
+            tx_type: record.tx_type(),
+            client: record.client(),
+            amount: record.amount(),
+            timestamp: record.timestamp(),
+            currency: record.currency(),
+            is_disputed: record.is_disputed(),
+            was_resolved: record.was_resolved(),
+            was_charged_back: record.was_charged_back(),
+            was_reversed: record.was_reversed(),
+            was_rejected: record.was_rejected(),
+            dispute_count: record.dispute_count(),
+            disputed_amount: record.disputed_amount(),
+        });
+    }
+
+    pub(crate) fn push_history(&mut self, tx: TransactionID, entry: &HistoryEntry) {
+        let transaction = &entry.transaction;
+        self.history.push(HistoryState {
+            tx,
+            tx_type: transaction.tx_type.clone(),
+            client: transaction.client,
+            amount: transaction.amount,
+            to_client: transaction.to_client,
+            timestamp: transaction.timestamp,
+            currency: transaction.currency,
+            to_currency: transaction.to_currency,
+            rate: transaction.rate,
+            error: entry.error.clone(),
+            deltas: entry
+                .deltas
+                .iter()
+                .map(|delta| DeltaState {
+                    client: delta.client,
+                    currency: delta.currency,
+                    available: delta.available,
+                    held: delta.held,
+                })
+                .collect(),
+        });
+    }
+
+    pub(crate) fn accounts(&self) -> impl Iterator<Item = (ClientID, Account)> + '_ {
+        self.accounts.iter().map(|state| {
+            (
+                state.client,
+                Account {
+                    balances: state
+                        .balances
+                        .iter()
+                        .map(|balance| {
+                            let (available, held) = (balance.available, balance.held);
+                            (balance.currency, Balance { available, held })
+                        })
+                        .collect(),
+                    locked: state.locked,
+                    status: state.status,
+                    risk: state.risk,
+                },
+            )
+        })
+    }
+
+    pub(crate) fn records(&self) -> impl Iterator<Item = (TransactionID, TransactionRecord)> + '_ {
+        self.records.iter().map(|state| {
+            let mut record = TransactionRecord::new(&state.tx_type, state.client, state.amount)
+                .with_timestamp(state.timestamp)
+                .with_currency(state.currency);
+            record.set_disputed(state.is_disputed);
+            record.set_disputed_amount(state.disputed_amount);
+            if state.was_resolved {
+                record.set_resolved();
+            }
+            if state.was_charged_back {
+                record.set_charged_back();
+            }
+            if state.was_reversed {
+                record.set_reversed();
+            }
+            if state.was_rejected {
+                record.set_rejected();
+            }
+            record.set_dispute_count(state.dispute_count);
+            (state.tx, record)
+        })
+    }
+
+    pub(crate) fn history(&self) -> impl Iterator<Item = (TransactionID, HistoryEntry)> + '_ {
+        self.history.iter().map(|state| {
+            let transaction =
+                Transaction::new(state.tx_type.clone(), state.client, state.tx, state.amount)
+                    .with_to_client(state.to_client)
+                    .with_timestamp(state.timestamp)
+                    .with_currency(state.currency)
+                    .with_to_currency(state.to_currency)
+                    .with_rate(state.rate);
+            let deltas = state
+                .deltas
+                .iter()
+                .map(|delta| BalanceDelta {
+                    client: delta.client,
+                    currency: delta.currency,
+                    available: delta.available,
+                    held: delta.held,
+                })
+                .collect();
+            let error = state.error.clone();
+            (
+                state.tx,
+                HistoryEntry {
+                    transaction,
+                    error,
+                    deltas,
+                },
+            )
+        })
+    }
+
+    pub(crate) fn write(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        Ok(bincode::serialize_into(writer, self)?)
+    }
+
+    pub(crate) fn read(mut reader: impl Read) -> Result<Snapshot, SnapshotError> {
+        // The version leads the encoding, so it can be checked before decoding anything else
+        let version: u32 = bincode::deserialize_from(&mut reader)?;
+        match version {
+            SNAPSHOT_VERSION => (),
+            1..SNAPSHOT_VERSION => return LegacyReader { reader, version }.snapshot(),
+            _ => return Err(SnapshotError::UnsupportedVersion(version)),
+        }
+        let (accounts, records, audit_log, last_timestamp, history, pruned) =
+            bincode::deserialize_from(&mut reader)?;
+        Ok(Snapshot {
+            version,
+            accounts,
+            records,
+            audit_log,
+            last_timestamp,
+            history,
+            pruned,
+        })
+    }
+}
+
+// Decimals as the string every version wrote them as, outside of the states above
+#[derive(Deserialize)]
+struct LegacyDecimal(#[serde(with = "rust_decimal::serde::str")] Decimal);
+
+#[derive(Deserialize)]
+struct LegacyOptionalDecimal(#[serde(with = "rust_decimal::serde::str_option")] Option<Decimal>);
+
+// Reads a snapshot of an older version field by field into the current states, defaulting what
+// that version didn't have yet: no status, risk counters, history or pruned records, and
+// disputes holding the whole amount
+struct LegacyReader<R> {
+    reader: R,
+    version: u32,
+}
+
+impl<R: Read> LegacyReader<R> {
+    fn snapshot(mut self) -> Result<Snapshot, SnapshotError> {
+        let accounts = self.seq(Self::account)?;
+        let records = self.seq(Self::record)?;
+        let audit_log = self.next()?;
+        let last_timestamp = self.since(3)?;
+        let history = match self.version >= 8 {
+            true => self.seq(Self::history)?,
+            false => Vec::new(),
+        };
+        let pruned = self.since(10)?;
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            accounts,
+            records,
+            audit_log,
+            last_timestamp,
+            history,
+            pruned,
+        })
+    }
+
+    fn account(&mut self) -> Result<AccountState, SnapshotError> {
+        let client = self.next()?;
+        // A single balance until version 4 brought currencies
+        let balances = match self.version >= 4 {
+            true => self.next()?,
+            false => vec![BalanceState {
+                currency: None,
+                available: self.decimal()?,
+                held: self.decimal()?,
+            }],
+        };
+        Ok(AccountState {
+            client,
+            balances,
+            locked: self.next()?,
+            status: self.since(7)?,
+            risk: self.since(12)?,
+        })
+    }
+
+    fn record(&mut self) -> Result<RecordState, SnapshotError> {
+        let tx = self.next()?;
+        let tx_type = self.tx_type()?;
+        let client = self.next()?;
+        // Version 1 kept the amount optional, followed by a transfer's recipient
+        let amount = match self.version >= 2 {
+            true => self.decimal()?,
+            false => {
+                let amount = self.next::<LegacyOptionalDecimal>()?.0;
+                self.next::<Option<ClientID>>()?;
+                amount.ok_or_else(|| {
+                    StorageError::Corrupt(format!("record {} without an amount", tx))
+                })?
+            }
+        };
+        let timestamp = self.since(3)?;
+        let currency = self.since(4)?;
+        let is_disputed = self.next()?;
+        let was_resolved = self.since(5)?;
+        let was_charged_back = self.since(9)?;
+        let was_reversed = self.since(14)?;
+        let dispute_count = self.since(6)?;
+        let disputed_amount = match self.version >= 13 {
+            true => self.decimal()?,
+            false => amount,
+        };
+        Ok(RecordState {
+            tx,
+            tx_type,
+            client,
+            amount,
+            timestamp,
+            currency,
+            is_disputed,
+            was_resolved,
+            was_charged_back,
+            was_reversed,
+            was_rejected: false,
+            dispute_count,
+            disputed_amount,
+        })
+    }
+
+    fn history(&mut self) -> Result<HistoryState, SnapshotError> {
+        Ok(HistoryState {
+            tx: self.next()?,
+            tx_type: self.tx_type()?,
+            client: self.next()?,
+            amount: self.next::<LegacyOptionalDecimal>()?.0,
+            to_client: self.next()?,
+            timestamp: self.next()?,
+            currency: self.next()?,
+            to_currency: self.next()?,
+            rate: self.next::<LegacyOptionalDecimal>()?.0,
+            error: self.next()?,
+            deltas: self.next()?,
+        })
+    }
+
+    fn tx_type(&mut self) -> Result<TransactionType, SnapshotError> {
+        if self.version >= 11 {
+            return self.next();
+        }
+        let index: u32 = self.next()?;
+        let tx_type = LEGACY_TYPES
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(|| StorageError::Corrupt(format!("unknown transaction type {}", index)))?;
+        Ok(tx_type)
+    }
+
+    fn decimal(&mut self) -> Result<Decimal, SnapshotError> {
+        Ok(self.next::<LegacyDecimal>()?.0)
+    }
+
+    // A field the encoding gained in the given version, defaulted in older snapshots
+    fn since<T: DeserializeOwned + Default>(&mut self, version: u32) -> Result<T, SnapshotError> {
+        match self.version >= version {
+            true => self.next(),
+            false => Ok(T::default()),
+        }
+    }
+
+    fn seq<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, SnapshotError>,
+    ) -> Result<Vec<T>, SnapshotError> {
+        let len: u64 = self.next()?;
+        (0..len).map(|_| item(self)).collect()
+    }
+
+    fn next<T: DeserializeOwned>(&mut self) -> Result<T, SnapshotError> {
+        Ok(bincode::deserialize_from(&mut self.reader)?)
+    }
+}
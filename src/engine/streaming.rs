@@ -0,0 +1,209 @@
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_stream::{Stream, StreamExt};
+
+use super::database::{Database, TransactionError, TransactionResult};
+use super::transaction::Transaction;
+
+// How many transactions may queue up for the engine before submitters have to wait, by default
+const ENGINE_QUEUE_CAPACITY: usize = 4096;
+
+pub(crate) type Request = (Transaction, oneshot::Sender<TransactionResult>);
+
+// Drives a Database from async code. The Database lives on a blocking thread of the tokio
+// runtime, so slow storage never stalls the executor, and any number of tasks can feed it
+// through cheap AsyncHandle clones. Transactions from one handle are applied in order.
+pub struct AsyncDatabase {
+    handle: AsyncHandle,
+    worker: JoinHandle<Database>,
+}
+
+#[derive(Clone)]
+pub struct AsyncHandle {
+    sender: mpsc::Sender<Request>,
+}
+
+impl AsyncDatabase {
+    // Must be called from within a tokio runtime
+    pub fn spawn(db: Database) -> Self {
+        AsyncDatabase::spawn_with_capacity(db, ENGINE_QUEUE_CAPACITY)
+    }
+
+    // Like spawn, with at most `capacity` transactions waiting for the engine. Past that,
+    // process waits for room and try_process fails with TransactionError::QueueFull.
+    pub fn spawn_with_capacity(mut db: Database, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Request>(capacity.max(1));
+        let worker = tokio::task::spawn_blocking(move || {
+            while let Some((transaction, reply)) = receiver.blocking_recv() {
+                // The submitter may have stopped waiting, the transaction is applied regardless
+                let _ = reply.send(db.process(&transaction));
+            }
+            db
+        });
+        AsyncDatabase {
+            handle: AsyncHandle { sender },
+            worker,
+        }
+    }
+
+    pub fn handle(&self) -> AsyncHandle {
+        self.handle.clone()
+    }
+
+    pub async fn process(&self, transaction: Transaction) -> TransactionResult {
+        self.handle.process(transaction).await
+    }
+
+    pub async fn process_stream(
+        &self,
+        transactions: impl Stream<Item = Transaction>,
+        on_error: impl FnMut(&Transaction, TransactionError),
+    ) {
+        self.handle.process_stream(transactions, on_error).await
+    }
+
+    // Waits for every queued transaction and hands the Database back. Outstanding handles keep
+    // the engine running, so they have to be dropped first.
+    pub async fn finish(self) -> Result<Database, TransactionError> {
+        drop(self.handle);
+        self.worker
+            .await
+            .map_err(|_| TransactionError::EngineStopped)
+    }
+}
+
+impl AsyncHandle {
+    pub async fn process(&self, transaction: Transaction) -> TransactionResult {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send((transaction, reply))
+            .await
+            .map_err(|_| TransactionError::EngineStopped)?;
+        result.await.map_err(|_| TransactionError::EngineStopped)?
+    }
+
+    // Transactions waiting for the engine, for callers that want to shed load before the queue
+    // fills up
+    pub fn queued(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    // Like process, but fails at once rather than waiting when the queue is full
+    pub async fn try_process(&self, transaction: Transaction) -> TransactionResult {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .try_send((transaction, reply))
+            .map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => TransactionError::QueueFull,
+                mpsc::error::TrySendError::Closed(_) => TransactionError::EngineStopped,
+            })?;
+        result.await.map_err(|_| TransactionError::EngineStopped)?
+    }
+
+    // Applies every transaction of the stream in order, reporting the rejected ones
+    pub async fn process_stream(
+        &self,
+        transactions: impl Stream<Item = Transaction>,
+        mut on_error: impl FnMut(&Transaction, TransactionError),
+    ) {
+        let mut transactions = std::pin::pin!(transactions);
+        while let Some(transaction) = transactions.next().await {
+            match self.process(transaction.clone()).await {
+                Ok(()) => continue,
+                Err(err) => on_error(&transaction, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Account, HookVerdict, TransactionHook, TransactionType};
+    use rust_decimal::dec;
+
+    fn deposit(client: u16, tx: u32) -> Transaction {
+        Transaction::new(TransactionType::Deposit, client, tx, Some(dec!(1.0)))
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_streams_share_one_database() {
+        let engine = AsyncDatabase::spawn(Database::default());
+        let sources = (0..8u32)
+            .map(|source| {
+                let handle = engine.handle();
+                tokio::spawn(async move {
+                    let transactions = (0..50).map(move |i| deposit(1, source * 50 + i));
+                    let mut rejected = 0;
+                    handle
+                        .process_stream(tokio_stream::iter(transactions), |_, _| rejected += 1)
+                        .await;
+                    rejected
+                })
+            })
+            .collect::<Vec<_>>();
+        for source in sources {
+            assert_eq!(source.await.unwrap(), 0);
+        }
+        assert!(matches!(
+            engine.process(deposit(2, 0)).await,
+            Err(TransactionError::Duplicate)
+        ));
+
+        let db = engine.finish().await.unwrap();
+        let acc = db.account(1).unwrap().unwrap();
+        assert_eq!(acc.available(), dec!(400.0));
+    }
+
+    // Holds the engine on each transaction until the test lets it go
+    #[derive(Debug)]
+    struct Gate {
+        entered: mpsc::UnboundedSender<()>,
+        release: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl TransactionHook for Gate {
+        fn inspect(&mut self, _: &Transaction, _: Option<&Account>) -> HookVerdict {
+            let _ = self.entered.send(());
+            let _ = self.release.recv();
+            HookVerdict::Accept { tags: Vec::new() }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_full_queue_pushes_back() {
+        let (entered, mut entering) = mpsc::unbounded_channel();
+        let (release, gate) = std::sync::mpsc::channel();
+        let db = Database::default().with_hook(Gate {
+            entered,
+            release: gate,
+        });
+        let engine = AsyncDatabase::spawn_with_capacity(db, 1);
+        let handle = engine.handle();
+        let first = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.process(deposit(1, 1)).await }
+        });
+        entering.recv().await.unwrap();
+        let second = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.process(deposit(1, 2)).await }
+        });
+        while handle.queued() < 1 {
+            tokio::task::yield_now().await;
+        }
+        assert!(matches!(
+            handle.try_process(deposit(1, 3)).await,
+            Err(TransactionError::QueueFull)
+        ));
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+        assert_eq!(handle.queued(), 0);
+        drop(handle);
+        let db = engine.finish().await.unwrap();
+        assert_eq!(db.account(1).unwrap().unwrap().available(), dec!(2.0));
+    }
+}
@@ -0,0 +1,611 @@
+use rust_decimal::Decimal;
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{Error, Unexpected, Visitor},
+};
+use std::{fmt, str::FromStr};
+
+use super::currency::Currency;
+
+pub type ClientID = u16;
+pub type TransactionID = u32;
+// Seconds since the Unix epoch
+pub type Timestamp = u64;
+
+// Serialized as its lowercase name, in every format
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Transfer,
+    // Exchanges amount from one of the client's currencies into another at the given rate
+    Convert,
+    // A platform fee, debited even if that drives available negative, down to the fee floor
+    Fee,
+    // Administrative, only accepted when the Database allows admin ops
+    Unlock,
+    // Explicitly starts an account's life, required first with --require-open
+    Open,
+    // Ends it for good, refused while anything is available or held
+    Close,
+    // Undoes the deposit or withdrawal with the same tx, for corrections by operations
+    Reversal,
+    // Closes a settlement period. It moves no money, front-ends act on it through
+    // Database::settle.
+    Settle,
+    // A type this version doesn't know, such as one upstream added since, kept by its name so
+    // it can be reported. The Database rejects it.
+    Other(String),
+}
+
+impl TransactionType {
+    pub fn name(&self) -> &str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Convert => "convert",
+            TransactionType::Fee => "fee",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Open => "open",
+            TransactionType::Close => "close",
+            TransactionType::Reversal => "reversal",
+            TransactionType::Settle => "settle",
+            TransactionType::Other(name) => name,
+        }
+    }
+
+    // Whether the transaction is kept as a record under its own id, which takes the id whether
+    // the accounts accepted it or not
+    pub(crate) fn takes_id(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Convert
+                | TransactionType::Fee
+        )
+    }
+
+    // What a lenient reader takes an unknown type for, ignoring case and separators and accepting
+    // 'withdraw', so that DEPOSIT and charge_back are known types
+    pub fn lenient(self) -> Self {
+        let TransactionType::Other(name) = &self else {
+            return self;
+        };
+        let normalized: String = name
+            .chars()
+            .filter(|c| !matches!(c, '_' | '-' | ' '))
+            .flat_map(char::to_lowercase)
+            .collect();
+        match normalized.as_str() {
+            "withdraw" => TransactionType::Withdrawal,
+            normalized => match TransactionType::from_name(normalized) {
+                TransactionType::Other(_) => self,
+                known => known,
+            },
+        }
+    }
+
+    fn from_name(name: &str) -> Self {
+        match name {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            "transfer" => TransactionType::Transfer,
+            "convert" => TransactionType::Convert,
+            "fee" => TransactionType::Fee,
+            "unlock" => TransactionType::Unlock,
+            "open" => TransactionType::Open,
+            "close" => TransactionType::Close,
+            "reversal" => TransactionType::Reversal,
+            "settle" => TransactionType::Settle,
+            other => TransactionType::Other(other.to_string()),
+        }
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(TypeVisitor)
+    }
+}
+
+struct TypeVisitor;
+
+impl Visitor<'_> for TypeVisitor {
+    type Value = TransactionType;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a transaction type")
+    }
+
+    // A missing type is still unparsable rather than unknown
+    fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<TransactionType, E> {
+        match name {
+            "" => Err(E::invalid_value(serde::de::Unexpected::Str(name), &self)),
+            name => Ok(TransactionType::from_name(name)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub client: ClientID,
+    pub tx: TransactionID,
+    #[serde(default, deserialize_with = "amount")]
+    pub amount: Option<Decimal>, // Optional because not all transaction types include amount
+    // Destination of a transfer, 'client' being the source. The column may be absent entirely.
+    pub to_client: Option<ClientID>,
+    // When the transaction happened. Optional like to_client, the column may be absent.
+    pub timestamp: Option<Timestamp>,
+    // Which of the client's balances the transaction applies to, None for feeds without currencies
+    pub currency: Option<Currency>,
+    // Target currency and rate of a convert, the amount being in 'currency'
+    pub to_currency: Option<Currency>,
+    #[serde(default, deserialize_with = "rate")]
+    pub rate: Option<Decimal>,
+}
+
+impl Transaction {
+    // A transaction without the optional columns, which the with_ methods below fill in
+    pub fn new(
+        tx_type: TransactionType,
+        client: ClientID,
+        tx: TransactionID,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount,
+            to_client: None,
+            timestamp: None,
+            currency: None,
+            to_currency: None,
+            rate: None,
+        }
+    }
+
+    pub fn with_to_client(mut self, to_client: Option<ClientID>) -> Self {
+        self.to_client = to_client;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: Option<Timestamp>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn with_currency(mut self, currency: Option<Currency>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    pub fn with_to_currency(mut self, to_currency: Option<Currency>) -> Self {
+        self.to_currency = to_currency;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: Option<Decimal>) -> Self {
+        self.rate = rate;
+        self
+    }
+}
+
+fn amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+    deserializer.deserialize_option(DecimalVisitor { field: "amount" })
+}
+
+fn rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+    deserializer.deserialize_option(DecimalVisitor { field: "rate" })
+}
+
+// Decimals as Decimal reads them, plain or in scientific notation, strings or numbers, but
+// failing with the field and the text that didn't parse rather than a generic message. Quoted
+// ones such as "2500.00" are rejected, see AmountFormat for CSV inputs that have them.
+struct DecimalVisitor {
+    field: &'static str,
+}
+
+impl<'de> Visitor<'de> for DecimalVisitor {
+    type Value = Option<Decimal>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a decimal {} such as 12.50", self.field)
+    }
+
+    fn visit_none<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_i64<E: Error>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(Some(Decimal::from(value)))
+    }
+
+    fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(Some(Decimal::from(value)))
+    }
+
+    fn visit_f64<E: Error>(self, value: f64) -> Result<Self::Value, E> {
+        match Decimal::from_str(&value.to_string()) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(E::invalid_value(Unexpected::Float(value), &self)),
+        }
+    }
+
+    fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+        let quoted = value.len() >= 2
+            && (value.starts_with('"') && value.ends_with('"')
+                || value.starts_with('\'') && value.ends_with('\''));
+        match Decimal::from_str(value).or_else(|_| Decimal::from_scientific(value)) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) if quoted => Err(E::custom(format!("{} {} is quoted", self.field, value))),
+            Err(_) => Err(E::custom(format!(
+                "{} '{}' is not a decimal number such as 12.50",
+                self.field, value
+            ))),
+        }
+    }
+}
+
+// The key a TransactionRecord is stored under. The client is only part of it when transaction
+// ids are scoped per client, see TxIdScope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RecordKey {
+    pub client: Option<ClientID>,
+    pub tx: TransactionID,
+}
+
+impl From<TransactionID> for RecordKey {
+    fn from(tx: TransactionID) -> Self {
+        RecordKey { client: None, tx }
+    }
+}
+
+// What the Database remembers about a deposit, withdrawal or transfer it accepted, or that the
+// accounts refused, which is flagged as rejected and only keeps its id taken. There is one per
+// such transaction ever processed so it is kept small: the id is the storage key, and the type and
+// dispute state are packed into flags. A missing timestamp is stored as NO_TIMESTAMP rather than
+// an Option, which would double the size of the field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransactionRecord {
+    client: ClientID,
+    amount: Decimal,
+    timestamp: Timestamp,
+    currency: Option<Currency>,
+    flags: u8,
+    // How many times the transaction was disputed, saturating
+    disputes: u8,
+    // What an open partial dispute holds, zero while the whole amount or nothing is disputed
+    partial: Decimal,
+}
+
+const NO_TIMESTAMP: Timestamp = Timestamp::MAX;
+
+// The three low bits hold the type, deposits being 0
+const TYPE_MASK: u8 = 0b111;
+const WITHDRAWAL: u8 = 1;
+const TRANSFER: u8 = 2;
+const CONVERT: u8 = 3;
+const FEE: u8 = 4;
+const DISPUTED: u8 = 1 << 3;
+// A dispute of the transaction was resolved at some point
+const RESOLVED: u8 = 1 << 4;
+const CHARGED_BACK: u8 = 1 << 5;
+const REVERSED: u8 = 1 << 6;
+const REJECTED: u8 = 1 << 7;
+
+impl TransactionRecord {
+    // Only deposits, withdrawals, transfers, converts and fees are recorded, anything else is kept
+    // as a deposit
+    pub fn new(tx_type: &TransactionType, client: ClientID, amount: Decimal) -> Self {
+        let flags = match tx_type {
+            TransactionType::Withdrawal => WITHDRAWAL,
+            TransactionType::Transfer => TRANSFER,
+            TransactionType::Convert => CONVERT,
+            TransactionType::Fee => FEE,
+            _ => 0,
+        };
+        TransactionRecord {
+            client,
+            amount,
+            timestamp: NO_TIMESTAMP,
+            currency: None,
+            flags,
+            disputes: 0,
+            partial: Decimal::ZERO,
+        }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: Option<Timestamp>) -> Self {
+        self.timestamp = timestamp.unwrap_or(NO_TIMESTAMP);
+        self
+    }
+
+    pub fn with_currency(mut self, currency: Option<Currency>) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    pub fn client(&self) -> ClientID {
+        self.client
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn tx_type(&self) -> TransactionType {
+        match self.flags & TYPE_MASK {
+            WITHDRAWAL => TransactionType::Withdrawal,
+            TRANSFER => TransactionType::Transfer,
+            CONVERT => TransactionType::Convert,
+            FEE => TransactionType::Fee,
+            _ => TransactionType::Deposit,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self.timestamp {
+            NO_TIMESTAMP => None,
+            timestamp => Some(timestamp),
+        }
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    pub fn is_disputed(&self) -> bool {
+        self.flags & DISPUTED != 0
+    }
+
+    pub fn was_resolved(&self) -> bool {
+        self.flags & RESOLVED != 0
+    }
+
+    pub fn was_charged_back(&self) -> bool {
+        self.flags & CHARGED_BACK != 0
+    }
+
+    // The part of the amount held by the open dispute, zero without one
+    pub fn disputed_amount(&self) -> Decimal {
+        match (self.is_disputed(), self.partial.is_zero()) {
+            (false, _) => Decimal::ZERO,
+            (true, true) => self.amount,
+            (true, false) => self.partial,
+        }
+    }
+
+    // Restores what a stored dispute holds, on a record already set disputed. Only snapshots and
+    // the persistent storages restore records.
+    #[cfg_attr(
+        not(any(
+            feature = "snapshot",
+            feature = "sled",
+            feature = "sqlite",
+            feature = "postgres"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn set_disputed_amount(&mut self, amount: Decimal) {
+        self.partial = match amount < self.amount {
+            true => amount,
+            false => Decimal::ZERO,
+        };
+    }
+
+    pub fn was_reversed(&self) -> bool {
+        self.flags & REVERSED != 0
+    }
+
+    // Refused when processed, nothing was applied
+    pub fn was_rejected(&self) -> bool {
+        self.flags & REJECTED != 0
+    }
+
+    pub fn dispute_count(&self) -> u8 {
+        self.disputes
+    }
+
+    #[cfg_attr(
+        not(any(
+            feature = "snapshot",
+            feature = "sled",
+            feature = "sqlite",
+            feature = "postgres"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn set_dispute_count(&mut self, disputes: u8) {
+        self.disputes = disputes;
+    }
+
+    // Opens a dispute of the whole amount or of a part of it, or adds a part to the open partial
+    // dispute. Only opening one counts as a dispute.
+    pub(crate) fn dispute(&mut self, part: Option<Decimal>) {
+        let held = self.disputed_amount();
+        if !self.is_disputed() {
+            self.disputes = self.disputes.saturating_add(1);
+        }
+        self.set_disputed(true);
+        self.partial = match part {
+            Some(part) => held + part,
+            None => Decimal::ZERO,
+        };
+    }
+
+    pub(crate) fn set_resolved(&mut self) {
+        self.flags |= RESOLVED;
+    }
+
+    pub(crate) fn set_charged_back(&mut self) {
+        self.flags |= CHARGED_BACK;
+    }
+
+    pub(crate) fn set_reversed(&mut self) {
+        self.flags |= REVERSED;
+    }
+
+    pub(crate) fn set_rejected(&mut self) {
+        self.flags |= REJECTED;
+    }
+
+    pub(crate) fn set_disputed(&mut self, disputed: bool) {
+        match disputed {
+            true => self.flags |= DISPUTED,
+            false => {
+                self.flags &= !DISPUTED;
+                self.partial = Decimal::ZERO;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_unknown_types_are_kept_by_name() {
+        let tx_type: TransactionType = serde_json::from_str("\"refund\"").unwrap();
+        assert_eq!(tx_type, TransactionType::Other("refund".to_string()));
+        assert_eq!(serde_json::to_string(&tx_type).unwrap(), "\"refund\"");
+        let tx_type: TransactionType = serde_json::from_str("\"chargeback\"").unwrap();
+        assert_eq!(tx_type, TransactionType::Chargeback);
+        assert_eq!(tx_type.name(), "chargeback");
+        assert!(serde_json::from_str::<TransactionType>("\"\"").is_err());
+    }
+
+    #[test]
+    fn test_amounts_fail_with_the_text_that_did_not_parse() {
+        let amount = |json: &str| {
+            let transaction = format!(
+                r#"{{"type":"deposit","client":1,"tx":1,"amount":{}}}"#,
+                json
+            );
+            serde_json::from_str::<Transaction>(&transaction).map(|transaction| transaction.amount)
+        };
+        assert_eq!(amount(r#""1.5""#).unwrap(), Some(dec!(1.5)));
+        assert_eq!(amount("2").unwrap(), Some(dec!(2)));
+        assert_eq!(amount(r#""2.5e2""#).unwrap(), Some(dec!(250)));
+        assert_eq!(amount("null").unwrap(), None);
+        let e = amount(r#""1,5""#).unwrap_err().to_string();
+        assert!(
+            e.starts_with("amount '1,5' is not a decimal number"),
+            "{}",
+            e
+        );
+        let e = amount(r#""\"1.5\"""#).unwrap_err().to_string();
+        assert!(e.starts_with(r#"amount "1.5" is quoted"#), "{}", e);
+        // The column may be left out entirely
+        let transaction: Transaction =
+            serde_json::from_str(r#"{"type":"dispute","client":1,"tx":1}"#).unwrap();
+        assert_eq!((transaction.amount, transaction.rate), (None, None));
+    }
+
+    #[test]
+    fn test_lenient_types() {
+        let lenient = |name: &str| TransactionType::Other(name.to_string()).lenient();
+        assert_eq!(lenient("DEPOSIT"), TransactionType::Deposit);
+        assert_eq!(lenient("withdraw"), TransactionType::Withdrawal);
+        assert_eq!(lenient("Charge_Back"), TransactionType::Chargeback);
+        assert_eq!(
+            lenient("refund"),
+            TransactionType::Other("refund".to_string())
+        );
+        assert_eq!(TransactionType::Fee.lenient(), TransactionType::Fee);
+    }
+
+    #[test]
+    fn test_record_packs_type_and_dispute_state() {
+        let mut record = TransactionRecord::new(&TransactionType::Withdrawal, 3, dec!(1.5));
+        assert_eq!(record.tx_type(), TransactionType::Withdrawal);
+        assert!(!record.is_disputed());
+        record.set_disputed(true);
+        assert!(record.is_disputed());
+        assert_eq!(record.tx_type(), TransactionType::Withdrawal);
+        record.set_disputed(false);
+        assert!(!record.is_disputed());
+        assert!(!record.was_resolved());
+        record.set_resolved();
+        assert!(record.was_resolved() && !record.is_disputed());
+        assert_eq!(record.dispute_count(), 0);
+        record.dispute(None);
+        assert!(record.is_disputed());
+        assert_eq!(record.dispute_count(), 1);
+        record.set_dispute_count(u8::MAX);
+        record.dispute(None);
+        assert_eq!(record.dispute_count(), u8::MAX);
+        assert_eq!((record.client(), record.amount()), (3, dec!(1.5)));
+        for tx_type in [
+            TransactionType::Transfer,
+            TransactionType::Convert,
+            TransactionType::Fee,
+        ] {
+            let mut record = TransactionRecord::new(&tx_type, 1, dec!(1));
+            record.set_disputed(true);
+            assert_eq!(record.tx_type(), tx_type);
+        }
+    }
+
+    #[test]
+    fn test_record_timestamp_is_optional() {
+        let record = TransactionRecord::new(&TransactionType::Deposit, 1, dec!(1));
+        assert_eq!(record.timestamp(), None);
+        assert_eq!(record.with_timestamp(Some(0)).timestamp(), Some(0));
+        assert_eq!(
+            record.with_timestamp(Some(1_700_000_000)).timestamp(),
+            Some(1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn test_partial_disputes_add_up() {
+        let mut record = TransactionRecord::new(&TransactionType::Deposit, 1, dec!(10));
+        assert_eq!(record.disputed_amount(), dec!(0));
+        record.dispute(Some(dec!(3)));
+        record.dispute(Some(dec!(2.5)));
+        assert_eq!(record.disputed_amount(), dec!(5.5));
+        assert_eq!(record.dispute_count(), 1);
+        record.set_disputed(false);
+        assert_eq!(record.disputed_amount(), dec!(0));
+        record.dispute(None);
+        assert_eq!(record.disputed_amount(), dec!(10));
+        assert_eq!(record.dispute_count(), 2);
+    }
+
+    #[test]
+    fn test_record_stays_compact() {
+        // The held part of partial disputes takes it from 32 bytes
+        assert!(std::mem::size_of::<TransactionRecord>() <= 48);
+    }
+}
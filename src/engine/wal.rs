@@ -0,0 +1,150 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::transaction::Transaction;
+
+const MAGIC: &str = "octopus-wal";
+
+// An append-only log of the transactions a Database processes, installed with
+// Database::with_wal. Every transaction is written and synced to disk before it is applied, so
+// after a crash the state it was started over plus the log gives back the state at the crash.
+// Rejected transactions are logged too and are rejected again when the log is replayed.
+//
+// The first line names the base the log applies over, usually Wal::checksum() of the snapshot the
+// run resumed from. A log whose base doesn't match is stale, its transactions being part of the
+// base already, and starts over. Transactions follow as JSON, one per line.
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    base: u64,
+}
+
+impl Wal {
+    // Opens the log at path along with the transactions it holds over base, which are to be
+    // replayed before anything else is processed. A line cut short by a crash was never applied
+    // and is dropped.
+    pub fn open(path: impl AsRef<Path>, base: u64) -> io::Result<(Self, Vec<Transaction>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+        let mut end = reader.read_line(&mut line)? as u64;
+        let mut tail = Vec::new();
+        if line == header(base) {
+            loop {
+                line.clear();
+                let read = reader.read_line(&mut line)?;
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+                let transaction = serde_json::from_str(&line).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("WAL entry {}: {}", tail.len() + 1, e),
+                    )
+                })?;
+                tail.push(transaction);
+                end += read as u64;
+            }
+            file.set_len(end)?;
+            file.seek(SeekFrom::End(0))?;
+            file.sync_data()?;
+            Ok((Wal { file, base }, tail))
+        } else {
+            let mut wal = Wal { file, base };
+            wal.reset(base)?;
+            Ok((wal, tail))
+        }
+    }
+
+    // FNV-1a, identifying a snapshot as the base of a log
+    pub fn checksum(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+        })
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn append(&mut self, transaction: &Transaction) -> io::Result<()> {
+        let mut line = serde_json::to_vec(transaction)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+
+    // Where the next entry starts
+    pub fn end(&mut self) -> io::Result<u64> {
+        self.file.stream_position()
+    }
+
+    // Cuts the log back to an end() it had, taking out the entries appended since. Only for a
+    // transaction that turned out not to be applied after all, see Prepared::commit.
+    pub fn truncate(&mut self, end: u64) -> io::Result<()> {
+        self.file.set_len(end)?;
+        self.file.seek(SeekFrom::Start(end))?;
+        self.file.sync_data()
+    }
+
+    // Empties the log once its transactions are part of a new base, e.g. after a snapshot
+    pub fn reset(&mut self, base: u64) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(header(base).as_bytes())?;
+        self.base = base;
+        self.file.sync_data()
+    }
+}
+
+fn header(base: u64) -> String {
+    format!("{} {:016x}\n", MAGIC, base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use rust_decimal::dec;
+    use std::io::Read;
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction::new(TransactionType::Deposit, 1, tx, Some(dec!(1.5)))
+    }
+
+    #[test]
+    fn test_tail_is_recovered_over_its_base_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.wal");
+        let (mut wal, tail) = Wal::open(&path, 7).unwrap();
+        assert!(tail.is_empty());
+        wal.append(&deposit(1)).unwrap();
+        wal.append(&deposit(2)).unwrap();
+        // A crash in the middle of the third entry
+        wal.file.write_all(b"{\"type\":\"dep").unwrap();
+        drop(wal);
+
+        let (mut wal, tail) = Wal::open(&path, 7).unwrap();
+        assert_eq!(tail, vec![deposit(1), deposit(2)]);
+        wal.append(&deposit(3)).unwrap();
+        drop(wal);
+        let (_, tail) = Wal::open(&path, 7).unwrap();
+        assert_eq!(tail.len(), 3);
+
+        // Another base, the log is stale and starts over
+        let (_, tail) = Wal::open(&path, 8).unwrap();
+        assert!(tail.is_empty());
+        let mut contents = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, header(8));
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{self, Write};
+
+use crate::cli::GenerateOptions;
+
+// Disputes are settled this many transactions later on average, so some are still open at the
+// end of the file
+const SETTLE_AFTER: u64 = 64;
+
+// xorshift64*, seeded through splitmix64 so nearby seeds give unrelated sequences. Reproducible
+// across platforms, which is all a generator needs.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        // xorshift gets stuck on zero
+        Rng((z ^ (z >> 31)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    // Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Writes 'transactions' rows of deposits and withdrawals spread over the clients, a share of the
+// deposits disputed and most disputes later resolved or charged back. Disputes and their
+// settlements count towards the rows.
+pub fn generate(options: &GenerateOptions, output: impl Write) -> io::Result<()> {
+    let mut output = io::BufWriter::new(output);
+    let mut rng = Rng::new(options.seed);
+    let total = options.transactions as u64;
+    // (client, tx, row it is due at) of every dispute still open
+    let mut open_disputes: Vec<(u16, u32, u64)> = Vec::new();
+    let mut next_tx: u32 = 1;
+    let mut rows = 0;
+    writeln!(output, "type,client,tx,amount")?;
+    while rows < total {
+        rows += 1;
+        if let Some(i) = open_disputes.iter().position(|&(_, _, due)| due <= rows) {
+            let (client, tx, _) = open_disputes.swap_remove(i);
+            let settle = match rng.below(10) {
+                0 => "chargeback",
+                _ => "resolve",
+            };
+            writeln!(output, "{},{},{},", settle, client, tx)?;
+            continue;
+        }
+        let client = rng.below(options.clients as u64) as u16 + 1;
+        let tx = next_tx;
+        next_tx += 1;
+        // Up to 10,000.0000, with four decimal places
+        let amount = rng.below(100_000_000) + 1;
+        let amount = format!("{}.{:04}", amount / 10_000, amount % 10_000);
+        match rng.below(10) {
+            0..7 => {
+                writeln!(output, "deposit,{},{},{}", client, tx, amount)?;
+                if rows < total && rng.unit() < options.dispute_rate {
+                    rows += 1;
+                    writeln!(output, "dispute,{},{},", client, tx)?;
+                    open_disputes.push((client, tx, rows + 1 + rng.below(SETTLE_AFTER * 2)));
+                }
+            }
+            _ => writeln!(output, "withdrawal,{},{},{}", client, tx, amount)?,
+        }
+    }
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(options: &GenerateOptions) -> String {
+        let mut output = Vec::new();
+        generate(options, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_generates_reproducible_rows() {
+        let options = GenerateOptions {
+            clients: 10,
+            transactions: 5_000,
+            dispute_rate: 0.2,
+            seed: 42,
+        };
+        let generated = csv(&options);
+        assert_eq!(generated, csv(&options));
+        assert_ne!(
+            generated,
+            csv(&GenerateOptions {
+                seed: 43,
+                ..options
+            })
+        );
+        let rows = generated.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 5_000);
+        for kind in ["deposit,", "withdrawal,", "dispute,", "resolve,"] {
+            assert!(rows.iter().any(|row| row.starts_with(kind)), "{}", kind);
+        }
+        assert!(rows.iter().all(|row| {
+            let client: u16 = row.split(',').nth(1).unwrap().parse().unwrap();
+            (1..=10).contains(&client)
+        }));
+    }
+
+    #[test]
+    fn test_no_disputes_at_zero_rate() {
+        let generated = csv(&GenerateOptions {
+            dispute_rate: 0.0,
+            transactions: 1_000,
+            ..GenerateOptions::default()
+        });
+        assert!(!generated.contains("dispute"));
+    }
+}
@@ -0,0 +1,32 @@
+// Payments engine library. The `octopus` binary is a thin CSV front-end over this crate.
+pub mod engine;
+#[cfg(any(
+    feature = "grpc",
+    feature = "http",
+    feature = "tcp",
+    feature = "redis",
+    feature = "kafka"
+))]
+pub mod server;
+pub mod storage;
+
+#[cfg(debug_assertions)]
+pub use engine::InvariantViolation;
+pub use engine::{
+    Account, AccountError, AccountResult, AccountRow, AccountStatus, AdminAction, AmountFormat,
+    AmountLimits, AuditEntry, Balance, BalanceDelta, BlockPolicy, ClientID, ConcurrentDatabase,
+    CsvColumns, Currency, Database, DecimalSeparator, DisputeFunding, DisputePolicy, DisputeRules,
+    ErrorHandler, History, HistoryEntry, HookVerdict, Ledger, LedgerEvent, LimitRule, Limits,
+    LockedAccountPolicy, PrecisionPolicy, Prepared, RecordKey, ReorderBuffer, RetentionPolicy,
+    RiskCounters, Rounding, ShardError, ShardedDatabase, StandardDisputeRules, Timestamp,
+    Transaction, TransactionEffect, TransactionError, TransactionHook, TransactionID,
+    TransactionRecord, TransactionResult, TransactionType, TxIdScope, Wal, lenient_column,
+};
+#[cfg(feature = "async")]
+pub use engine::{ActorDatabase, AsyncDatabase, AsyncHandle};
+#[cfg(feature = "snapshot")]
+pub use engine::SnapshotError;
+#[cfg(feature = "wasm")]
+pub use engine::{PluginError, WasmPlugin};
+#[cfg(feature = "rules")]
+pub use engine::{Rules, RulesError};
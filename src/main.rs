@@ -1,606 +1,1261 @@
+mod avro_input;
+mod bom;
+mod cli;
+mod config;
+mod control;
+mod diff;
+mod generate;
+mod parallel_csv;
+mod parquet_input;
+mod parquet_output;
+mod progress;
+mod proto_input;
+mod report;
+mod schema_registry;
+mod settlement;
+mod statement;
+mod stats;
+mod validate;
+
+use cli::{
+    Command, Compression, DiffOptions, InputFormat, LogFormat, Options, OutputFormat, QueryOptions,
+    ServeOptions, StateStore, UnknownTypePolicy,
+};
 use csv::ReaderBuilder;
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use octopus::{
+    AccountRow, AmountFormat, ClientID, ConcurrentDatabase, CsvColumns, Database, History, Limits,
+    ReorderBuffer, RiskCounters, Rules, ShardedDatabase, Transaction, TransactionError,
+    TransactionType, Wal, WasmPlugin,
+    server::{
+        SharedDatabase,
+        admission::{Admission, DEFAULT_QUEUE_CAPACITY},
+        dead_letter::DeadLetters,
+        events::AccountEvents,
+        grpc, http, kafka,
+        metrics::Metrics,
+        mirror, tcp,
+    },
+    storage::{
+        AccountEntries, MemoryStorage, PostgresStorage, SledStorage, SpillStorage, SqliteStorage,
+    },
+};
 
+use bom::SkipBom;
+use control::Control;
+use diff::{AccountsDiff, Change};
+use progress::Progress;
+use report::{ErrorReporter, Location, Outcome, RawRecord};
+use rust_decimal::Decimal;
+use schema_registry::SchemaRegistry;
+use serde::Serialize;
+use settlement::Settlements;
+use statement::Statement;
+use stats::Stats;
 use std::{
-    collections::HashMap,
     env,
     fs::File,
-    io::{self},
+    io::{self, BufReader, BufWriter, IsTerminal, Read, Write},
+    path::Path,
+    sync::Arc,
+};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
 };
+use validate::Validator;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Get the input file path from the first command-line argument
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Requires exactly one command line argument. Example: 'cargo run -- test.csv' ");
-        std::process::exit(1);
+    let options = match Options::parse(env::args()) {
+        Ok(options) => options,
+        // --help and --version print to stdout and succeed, bad flags exit with 1 rather than
+        // clap's 2, which means rejected rows
+        Err(err) => {
+            let _ = err.print();
+            std::process::exit(if err.use_stderr() { 1 } else { 0 });
+        }
+    };
+    init_logging(&options);
+
+    match &options.command {
+        Command::Process => match process(&options)?.exit_code() {
+            0 => Ok(()),
+            code => std::process::exit(code),
+        },
+        Command::Validate => match validate(&options)?.exit_code() {
+            0 => Ok(()),
+            code => std::process::exit(code),
+        },
+        Command::Serve(serve) => self::serve(&options, serve),
+        Command::Generate(generate) => Ok(generate::generate(generate, io::stdout().lock())?),
+        Command::Query(query) => self::query(&options, query),
+        Command::Statement { client } => self::statement(&options, *client),
+        Command::Diff(diff) => self::diff(&options, diff),
     }
+}
 
-    // We get the second arg here because the first arg is always the destination folder for compilation
-    let path = &args[1];
-    let file = File::open(path)?;
-    //trims whitespace and header
-    let mut rdr = ReaderBuilder::new().trim(csv::Trim::All).from_reader(file);
-
-    let mut db = Database::default();
-
-    for result in rdr.deserialize::<Transaction>() {
-        match result {
-            Ok(transaction) => match db.process(&transaction) {
-                Ok(()) => continue,
-                Err(err) => {
-                    eprintln!(
-                        " {:#?} Transaction {}, for Client {}, failed with error: {:#?}",
-                        &transaction.tx_type, &transaction.tx, &transaction.client, err
-                    )
-                }
-            },
-            Err(e) => eprintln!("Failed to deserialize transaction: {}", e),
+// Everything is logged to stderr, stdout being reserved for the accounts
+fn init_logging(options: &Options) {
+    let logger = tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_max_level(options.log_level);
+    // Migrations and server notices of --state postgres:// are chatty at info
+    let quiet = options.log_level.min(LevelFilter::WARN);
+    let targets = Targets::new()
+        .with_default(options.log_level)
+        .with_target("refinery_core", quiet)
+        .with_target("postgres", quiet);
+    match options.log_format {
+        LogFormat::Text => logger.finish().with(targets).init(),
+        LogFormat::Json => logger.json().finish().with(targets).init(),
+    }
+}
+
+// Builds a single Database honouring the storage and engine flags
+fn open_database(options: &Options) -> Result<Database, Box<dyn std::error::Error>> {
+    let db = match &options.state {
+        Some(StateStore::Sled(dir)) => {
+            Database::with_storage(SledStorage::open(dir).map_err(|e| format!("{}: {:?}", dir, e))?)
         }
+        Some(StateStore::Sqlite(path)) => Database::with_storage(
+            SqliteStorage::open(path).map_err(|e| format!("{}: {:?}", path, e))?,
+        ),
+        // The URL may hold a password, so it is kept out of the error
+        Some(StateStore::Postgres(url)) => Database::with_storage(
+            PostgresStorage::open(url).map_err(|e| format!("PostgreSQL: {:?}", e))?,
+        ),
+        None => spill_database(options.max_memory, options.expected_transactions)?,
+    };
+    let mut db = configure(db, options)?;
+    if let Some(path) = &options.resume_from {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        db.restore_snapshot(BufReader::new(file))
+            .map_err(|e| format!("Failed to resume from {}: {:?}", path, e))?;
+    }
+    match &options.wal {
+        Some(path) => recover(db, path, options.resume_from.as_deref()),
+        None => Ok(db),
     }
+}
 
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-    wtr.write_record(&["client", "available", "held", "total", "locked"])?;
-    for (client_id, acc) in db.account_map.iter() {
-        wtr.write_record(&[
-            client_id.to_string(),
-            acc.available.to_string(),
-            acc.held.to_string(),
-            acc.get_total().to_string(),
-            acc.locked.to_string(),
-        ])?;
+// Replays what the WAL holds over the snapshot resumed from, then logs to it from there on
+fn recover(
+    mut db: Database,
+    path: &str,
+    resumed_from: Option<&str>,
+) -> Result<Database, Box<dyn std::error::Error>> {
+    let base = match resumed_from {
+        Some(snapshot) => Wal::checksum(&std::fs::read(snapshot)?),
+        None => 0,
+    };
+    let (wal, tail) = Wal::open(path, base).map_err(|e| format!("{}: {}", path, e))?;
+    if !tail.is_empty() {
+        tracing::info!(
+            transactions = tail.len(),
+            wal = path,
+            "recovering from the WAL"
+        );
+    }
+    for transaction in &tail {
+        // Rejections were reported by the run that logged them
+        let _ = db.process(transaction);
     }
-    wtr.flush()?;
+    Ok(db.with_wal(wal))
+}
 
+// In memory, paging cold transaction records to disk if a memory budget is given. Without
+// one, room for the expected number of records is reserved up front.
+fn spill_database(
+    max_memory: Option<usize>,
+    expected_transactions: usize,
+) -> Result<Database, Box<dyn std::error::Error>> {
+    Ok(match max_memory {
+        Some(bytes) => Database::with_storage(
+            SpillStorage::open(bytes)
+                .map_err(|e| format!("Failed to open spill storage: {:?}", e))?,
+        ),
+        None => Database::with_storage(MemoryStorage::with_capacity(expected_transactions)),
+    })
+}
+
+// Written next to the destination first, so an interrupted run never leaves a truncated snapshot.
+// The file is synced before the rename and its directory after, so once this returns the
+// snapshot survives a crash of the machine too, which Kafka offsets are committed against.
+fn write_snapshot(db: &Database, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = format!("{}.tmp", path);
+    let mut writer = BufWriter::new(File::create(&tmp).map_err(|e| format!("{}: {}", tmp, e))?);
+    db.write_snapshot(&mut writer)
+        .map_err(|e| format!("Failed to write snapshot {}: {:?}", path, e))?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
     Ok(())
 }
 
-type ClientID = u16;
-type TransactionID = u32;
-
-#[derive(Debug, Deserialize, Clone, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
-#[derive(Debug, Deserialize, Clone)]
-struct Transaction {
-    #[serde(rename = "type")]
-    tx_type: TransactionType,
-    client: ClientID,
-    tx: TransactionID,
-    amount: Option<Decimal>, // Optional because not all transaction types include amount
-}
-#[derive(Debug)]
-struct TransactionRecord {
-    transaction: Transaction,
-    is_disputed: bool,
-}
-#[derive(Debug, Default)]
-struct Database {
-    transaction_map: TransactionMap,
-    account_map: AccountMap,
-}
-type TransactionMap = HashMap<TransactionID, TransactionRecord>;
-type AccountMap = HashMap<ClientID, Account>;
-trait AccountAccess {
-    fn get_or_create_new_acc(&mut self, cid: ClientID) -> &mut Account;
-}
-impl AccountAccess for AccountMap {
-    fn get_or_create_new_acc(&mut self, cid: ClientID) -> &mut Account {
-        self.entry(cid).or_insert_with(Account::new)
-    }
-}
-
-#[derive(Debug)]
-pub enum TransactionError {
-    NegativeAmount,
-    Duplicate,
-    AccountError(AccountError),
-    MissingAmount,
-    InvalidDispute,
-    ReferenceNotFound,
-}
-pub type TransactionResult = Result<(), TransactionError>;
-
-impl Database {
-    fn handle_amount_transaction(
-        &mut self,
-        transaction: &Transaction,
-        action: impl Fn(&mut Account, Decimal) -> AccountResult,
-    ) -> TransactionResult {
-        match transaction.amount {
-            Some(amount) => {
-                if amount <= Decimal::ZERO {
-                    Err(TransactionError::NegativeAmount)
-                } else if self.transaction_map.contains_key(&transaction.tx) {
-                    Err(TransactionError::Duplicate)
-                } else {
-                    let account = self.account_map.get_or_create_new_acc(transaction.client);
-                    match action(account, amount) {
-                        Ok(()) => {
-                            self.transaction_map.insert(
-                                transaction.tx,
-                                TransactionRecord {
-                                    transaction: transaction.clone(),
-                                    is_disputed: false,
-                                },
-                            );
-                            Ok(())
-                        }
-                        Err(err) => Err(TransactionError::AccountError(err)),
+// Applies the engine policies from the command line
+fn configure(mut db: Database, options: &Options) -> Result<Database, Box<dyn std::error::Error>> {
+    db.set_block_policy(options.block_policy.clone());
+    let db = db
+        .with_precision(options.precision)
+        .with_admin_ops(options.allow_admin_ops)
+        .with_dispute_policy(options.dispute_policy)
+        .with_dispute_funding(options.dispute_funding)
+        .with_locked_policy(options.locked_policy)
+        .with_require_monotonic_time(options.require_monotonic_time)
+        .with_fee_floor(options.fee_floor)
+        .with_overdraft_limit(options.overdraft_limit)
+        .with_require_open(options.require_open)
+        .with_tx_id_scope(options.tx_id_scope)
+        .with_skip_replays(options.skip_replays)
+        .with_retention(options.retention)
+        .with_amount_limits(options.amount_limits())
+        .with_limits(Limits::new(options.limits.clone()))
+        .with_settlement(options.settlement_dir.is_some());
+    let db = match options.history {
+        true => db.with_history(History::new()),
+        false => db,
+    };
+    let db = match options.dispute_window {
+        Some(window) => db.with_dispute_window(window),
+        None => db,
+    };
+    let db = match options.max_disputes_per_tx {
+        Some(max) => db.with_max_disputes_per_tx(max),
+        None => db,
+    };
+    // Loaded for every Database, each shard running its own instance. Rules run first, so the
+    // plugin sees what they let through.
+    let db = match &options.rules {
+        Some(path) => db.with_hook(Rules::load(path).map_err(|e| format!("{}: {}", path, e))?),
+        None => db,
+    };
+    Ok(match &options.plugin {
+        Some(path) => db.with_hook(WasmPlugin::load(path).map_err(|e| format!("{}: {}", path, e))?),
+        None => db,
+    })
+}
+
+type ServeError = Box<dyn std::error::Error + Send + Sync>;
+
+fn serve(options: &Options, serve: &ServeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let db: SharedDatabase = Arc::new(ConcurrentDatabase::try_new(
+        serve.shards.unwrap_or(1),
+        || open_database(options),
+    )?);
+    let metrics = Arc::new(Metrics::new());
+    let events = AccountEvents::new();
+    let admission = Admission::new(serve.queue_capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY));
+    let dead_letters = match &serve.dead_letter {
+        Some(path) => DeadLetters::open(path).map_err(|e| format!("{}: {}", path, e))?,
+        None => DeadLetters::default(),
+    };
+    // The first SIGINT or SIGTERM stops every front-end gracefully, a second one exits at once
+    let (stop, stopped) = tokio::sync::watch::channel(false);
+    ctrlc::set_handler(move || {
+        if stop.send_replace(true) {
+            std::process::exit(130);
+        }
+        tracing::info!("shutting down");
+    })?;
+    let shutdown = || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.wait_for(|&stopped| stopped).await;
+        }
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    // Run every requested front-end on the same Database, stopping if any of them fails
+    runtime
+        .block_on(async {
+            let grpc = async {
+                match serve.grpc {
+                    Some(addr) => {
+                        tracing::info!(%addr, "serving gRPC");
+                        grpc::serve(
+                            Arc::clone(&db),
+                            Arc::clone(&metrics),
+                            events.clone(),
+                            admission.clone(),
+                            addr,
+                            shutdown(),
+                        )
+                        .await
+                        .map_err(ServeError::from)
                     }
+                    None => Ok(()),
                 }
-            }
-            None => Err(TransactionError::MissingAmount),
+            };
+            let http = async {
+                match serve.http {
+                    Some(addr) => {
+                        tracing::info!(%addr, "serving HTTP");
+                        http::serve(
+                            Arc::clone(&db),
+                            Arc::clone(&metrics),
+                            events.clone(),
+                            admission.clone(),
+                            addr,
+                            shutdown(),
+                        )
+                        .await
+                        .map_err(ServeError::from)
+                    }
+                    None => Ok(()),
+                }
+            };
+            let tcp = async {
+                match serve.tcp {
+                    Some(addr) => {
+                        tracing::info!(%addr, "serving TCP");
+                        tcp::serve(
+                            Arc::clone(&db),
+                            Arc::clone(&metrics),
+                            events.clone(),
+                            admission.clone(),
+                            dead_letters.clone(),
+                            addr,
+                            shutdown(),
+                        )
+                        .await
+                        .map_err(ServeError::from)
+                    }
+                    None => Ok(()),
+                }
+            };
+            let redis = async {
+                match &serve.redis {
+                    Some(url) => {
+                        tracing::info!("mirroring balances to Redis");
+                        // The URL may hold a password, so it is kept out of the error
+                        mirror::mirror(Arc::clone(&db), events.clone(), url, shutdown())
+                            .await
+                            .map_err(|e| ServeError::from(format!("Redis: {}", e)))
+                    }
+                    None => Ok(()),
+                }
+            };
+            let kafka = async {
+                match &serve.kafka {
+                    Some(source) => {
+                        tracing::info!(topics = ?source.topics, "consuming from Kafka");
+                        let consumer =
+                            kafka::consumer(&source.brokers, &source.group, &source.topics)
+                                .map_err(|e| ServeError::from(format!("Kafka: {}", e)))?;
+                        let mut registry =
+                            source.schema_registry.as_deref().map(SchemaRegistry::new);
+                        kafka::consume(
+                            Arc::clone(&db),
+                            Arc::clone(&metrics),
+                            events.clone(),
+                            dead_letters.clone(),
+                            consumer,
+                            async |value: &[u8]| match &mut registry {
+                                Some(registry) => registry.decode(value).await,
+                                None => kafka::decode(value),
+                            },
+                            source.commit_every,
+                            |db| checkpoint(db, options),
+                            shutdown(),
+                        )
+                        .await
+                        .map_err(|e| ServeError::from(format!("Kafka: {}", e)))
+                    }
+                    None => Ok(()),
+                }
+            };
+            tokio::try_join!(grpc, http, tcp, redis, kafka)
+        })
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    // Dropping the runtime cancels connections still open, between two transactions
+    drop(runtime);
+    dead_letters
+        .flush()
+        .map_err(|e| format!("Failed to write dead letters: {}", e))?;
+
+    let mut db = Arc::into_inner(db)
+        .ok_or("database still in use")?
+        .into_database()
+        .map_err(|e| format!("Failed to merge the shards: {:?}", e))?;
+    db.flush()
+        .map_err(|e| format!("Failed to flush state: {:?}", e))?;
+    if let Some(path) = &options.snapshot_out {
+        write_snapshot(&db, path)?;
+        if let Some(wal) = db.wal_mut() {
+            wal.reset(Wal::checksum(&std::fs::read(path)?))?;
         }
     }
-    fn handle_dispute_like(
-        &mut self,
-        transaction: &Transaction,
-        condition: impl Fn(&TransactionRecord) -> bool,
-        action: impl Fn(&mut Account, Decimal) -> AccountResult,
-        new_disputed_state: bool,
-    ) -> TransactionResult {
-        match self.transaction_map.get_mut(&transaction.tx) {
-            Some(record)
-                if record.transaction.client == transaction.client
-                    && record.transaction.tx_type == TransactionType::Deposit
-                    && condition(record) =>
-            {
-                match record.transaction.amount {
-                    Some(amount) => {
-                        let account = self.account_map.get_or_create_new_acc(transaction.client);
-                        match action(account, amount) {
-                            Ok(()) => {
-                                record.is_disputed = new_disputed_state;
-                                Ok(())
+    Ok(())
+}
+
+// Makes everything applied so far durable, before --kafka commits the offsets it covers. The
+// WAL already holds every transaction applied and a state store only needs flushing, otherwise
+// the snapshot is written. --kafka takes a single shard.
+fn checkpoint(db: &ConcurrentDatabase, options: &Options) -> Result<(), String> {
+    match (&options.wal, &options.state, &options.snapshot_out) {
+        (Some(_), _, _) => Ok(()),
+        (None, Some(_), _) => db
+            .flush()
+            .map_err(|e| format!("Failed to flush state: {:?}", e)),
+        (None, None, Some(path)) => db
+            .with_client(0, |db| write_snapshot(db, path).map_err(|e| e.to_string()))
+            .unwrap_or_else(|| Err("database unavailable after a panic".to_string())),
+        (None, None, None) => Err("nothing to checkpoint to".to_string()),
+    }
+}
+
+// Prints every transaction processed under the id as JSON, from a snapshot with history
+fn query(options: &Options, query: &QueryOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let path = &query.state;
+    let mut db = configure(Database::default(), options)?.with_history(History::new());
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    db.restore_snapshot(BufReader::new(file))
+        .map_err(|e| format!("Failed to read {}: {:?}", path, e))?;
+    let history = db.transaction_history(query.tx);
+    if history.is_empty() {
+        return Err(format!(
+            "No history for transaction {} in {}, was it written with --history?",
+            query.tx, path
+        )
+        .into());
+    }
+    serde_json::to_writer_pretty(io::stdout().lock(), history)?;
+    println!();
+    Ok(())
+}
+
+// An input and where it comes from, for error reports
+type Input = (Arc<str>, InputReader);
+
+enum InputReader {
+    Csv(Box<dyn Read>),
+    Avro(Box<dyn Read>),
+    Protobuf(Box<dyn Read>),
+    // Parquet is read out of order, footer first, so it needs the file itself
+    Parquet(File),
+}
+
+// Every input file is processed in order into the same database, no files means stdin. Every
+// file is opened up front so a typo in the last path doesn't leave us half processed.
+fn open_inputs(options: &Options, progress: Option<&Progress>) -> io::Result<Vec<Input>> {
+    let sources = match options.inputs.is_empty() {
+        true => vec!["-".to_string()],
+        false => options.inputs.clone(),
+    };
+    sources
+        .iter()
+        .map(|path| {
+            Ok((
+                Arc::from(path.as_str()),
+                match options
+                    .format
+                    .unwrap_or_else(|| InputFormat::from_path(path))
+                {
+                    InputFormat::Csv => InputReader::Csv(open_input(
+                        path,
+                        options.compression,
+                        options.mmap,
+                        progress,
+                    )?),
+                    InputFormat::Avro => InputReader::Avro(open_input(
+                        path,
+                        options.compression,
+                        options.mmap,
+                        progress,
+                    )?),
+                    InputFormat::Protobuf => InputReader::Protobuf(open_input(
+                        path,
+                        options.compression,
+                        options.mmap,
+                        progress,
+                    )?),
+                    InputFormat::Parquet => InputReader::Parquet(open_parquet(path)?),
+                },
+            ))
+        })
+        .collect()
+}
+
+// Processes the input like a batch run, following one client through it
+fn statement(options: &Options, client: ClientID) -> Result<(), Box<dyn std::error::Error>> {
+    let inputs = open_inputs(options, None)?;
+    let reporter = ErrorReporter::new(options.error_report.as_deref(), options.max_errors)?;
+    let stats = Stats::new();
+    let mut db = open_database(options)?.with_history(History::new());
+    let mut statement = Statement::new(client);
+    let mut failed = None;
+    for (source, input) in inputs {
+        process_input(
+            &source,
+            input,
+            options,
+            &reporter,
+            &stats,
+            |transaction, location| {
+                if let Err(err) = db.process(&transaction) {
+                    reporter.rejected(&transaction, &location, &err)
+                }
+                if let Err(err) = statement.record(&db, &transaction) {
+                    failed.get_or_insert(err);
+                }
+            },
+        );
+    }
+    if let Some(err) = failed {
+        return Err(format!("Failed to read account: {:?}", err).into());
+    }
+    reporter.flush()?;
+    statement
+        .finish(&db)
+        .map_err(|e| format!("Failed to read account: {:?}", e))?;
+    statement.write(options.output_format, io::stdout().lock())?;
+    Ok(())
+}
+
+// Compares two accounts files, the differences going to stdout and a summary to the log
+fn diff(options: &Options, diff: &DiffOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let open = |path: &str| File::open(path).map_err(|e| format!("{}: {}", path, e));
+    let accounts = AccountsDiff::compare(
+        BufReader::new(open(&diff.old)?),
+        BufReader::new(open(&diff.new)?),
+        options.precision,
+    )
+    .map_err(|e| format!("Failed to compare {} and {}: {}", diff.old, diff.new, e))?;
+    tracing::info!(
+        new = accounts.count(Change::New),
+        removed = accounts.count(Change::Removed),
+        changed = accounts.count(Change::Changed),
+        newly_locked = accounts.newly_locked(),
+        "accounts compared"
+    );
+    accounts.write(options.output_format, io::stdout().lock())?;
+    Ok(())
+}
+
+// --validate: checks every input without a Database, the report going to stdout unless
+// --error-report says otherwise. Its outcome decides the exit code as a run's does.
+fn validate(options: &Options) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let progress = options.progress.then(|| Progress::new(&options.inputs));
+    let inputs = open_inputs(options, progress.as_ref())?;
+    let reporter = ErrorReporter::new(
+        Some(options.error_report.as_deref().unwrap_or("-")),
+        options.max_errors,
+    )?;
+    let stats = Stats::new();
+    let mut validator = Validator::new(options.precision, options.tx_id_scope);
+    for (source, input) in inputs {
+        process_input(
+            &source,
+            input,
+            options,
+            &reporter,
+            &stats,
+            |transaction, location| {
+                if let Some(progress) = &progress {
+                    progress.row()
+                }
+                if let Err(err) = validator.check(&transaction) {
+                    stats.rejected(&transaction.tx_type);
+                    reporter.rejected(&transaction, &location, &err)
+                }
+            },
+        );
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    reporter.flush()?;
+    if let Some(path) = &options.run_summary {
+        reporter
+            .write_summary(path, stats.counts())
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let counts = stats.counts();
+    tracing::info!(
+        rows = counts.processed,
+        invalid = counts.rejected,
+        unparsable = counts.unparsable,
+        "validation finished"
+    );
+    Ok(reporter.outcome())
+}
+
+// The outcome decides the exit code, errors that stop the run before processing exit with 1
+fn process(options: &Options) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let progress = options.progress.then(|| Progress::new(&options.inputs));
+    let inputs = open_inputs(options, progress.as_ref())?;
+    let row = || {
+        if let Some(progress) = &progress {
+            progress.row()
+        }
+    };
+
+    let reporter = Arc::new(ErrorReporter::new(
+        options.error_report.as_deref(),
+        options.max_errors,
+    )?);
+    let stats = Arc::new(Stats::new());
+    // The first SIGINT or SIGTERM stops reading the inputs, the run then ending normally with
+    // its output and snapshot. A second one exits at once.
+    let stopping = Arc::clone(&reporter);
+    ctrlc::set_handler(move || {
+        if stopping.stopped() {
+            std::process::exit(130);
+        }
+        tracing::info!("stopping once the row being read is processed");
+        stopping.stop();
+    })?;
+
+    let mut db = match options.threads.get() {
+        1 => {
+            let mut db = open_database(options)?;
+            let control = match &options.control {
+                Some(path) => Some(
+                    Control::start(path, Arc::clone(&stats), Arc::clone(&reporter))
+                        .map_err(|e| format!("{}: {}", path, e))?,
+                ),
+                None => None,
+            };
+            let mut settlements = match &options.settlement_dir {
+                Some(dir) => Some(
+                    Settlements::new(dir, options.settle_every)
+                        .map_err(|e| format!("{}: {}", dir, e))?,
+                ),
+                None => None,
+            };
+            // Stops the run, settlement files missing a period being of no use
+            let mut settlement_failed = None;
+            let mut reorder = ReorderBuffer::new(options.reorder_window);
+            let reject = |transaction: Transaction, location: Location, err: TransactionError| {
+                stats.rejected(&transaction.tx_type);
+                reporter.rejected(&transaction, &location, &err)
+            };
+            for (source, input) in inputs {
+                process_input(
+                    &source,
+                    input,
+                    options,
+                    &reporter,
+                    &stats,
+                    |transaction, location| {
+                        row();
+                        if let Some(settlements) = &mut settlements {
+                            match settlements.before(&mut db, &transaction) {
+                                Ok(true) => {}
+                                Ok(false) => return,
+                                Err(e) => {
+                                    settlement_failed.get_or_insert(e);
+                                    reporter.stop();
+                                    return;
+                                }
                             }
-                            Err(err) => Err(TransactionError::AccountError(err)),
                         }
-                    }
-                    None => Err(TransactionError::MissingAmount),
-                }
+                        reorder.process(&mut db, transaction, location, reject);
+                        for request in control.iter().flat_map(Control::pending) {
+                            answer(&mut db, &reporter, request)
+                        }
+                    },
+                );
             }
-            Some(_) => {
-                return Err(TransactionError::InvalidDispute);
+            reorder.finish(reject);
+            if let Some(e) = settlement_failed {
+                return Err(format!("Failed to write settlement: {}", e).into());
             }
-            None => {
-                return Err(TransactionError::ReferenceNotFound);
+            if let Some(settlements) = &mut settlements {
+                settlements
+                    .finish(&mut db)
+                    .map_err(|e| format!("Failed to write settlement: {}", e))?;
             }
+            db.flush()
+                .map_err(|e| format!("Failed to flush state: {:?}", e))?;
+            db
         }
+        threads => {
+            let worker_reporter = Arc::clone(&reporter);
+            let worker_stats = Arc::clone(&stats);
+            // The memory budget and expected transactions are split evenly between the shards
+            let mut partitions = (0..threads)
+                .map(|_| {
+                    configure(
+                        spill_database(
+                            options.max_memory.map(|bytes| bytes / threads),
+                            options.expected_transactions / threads,
+                        )?,
+                        options,
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter();
+            let sharded = ShardedDatabase::new(
+                threads,
+                || partitions.next().unwrap_or_default(),
+                Arc::new(move |transaction, location, err| {
+                    worker_stats.rejected(&transaction.tx_type);
+                    worker_reporter.rejected(transaction, location, &err)
+                }),
+            );
+            for (source, input) in inputs {
+                process_input(
+                    &source,
+                    input,
+                    options,
+                    &reporter,
+                    &stats,
+                    |transaction, location| {
+                        row();
+                        sharded.submit(transaction, location)
+                    },
+                );
+            }
+            sharded
+                .finish()
+                .map_err(|e| format!("Failed to merge shards: {:?}", e))?
+        }
+    };
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    reporter.flush()?;
+    if let Some(path) = &options.reconcile {
+        reconcile(
+            &db,
+            path,
+            options.reconcile_tolerance.unwrap_or_default(),
+            &reporter,
+        )?;
+    }
+    if let Some(path) = &options.run_summary {
+        reporter
+            .write_summary(path, stats.counts())
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if reporter.stopped() {
+        tracing::info!("inputs left unread after a shutdown request");
+    }
+    if reporter.exhausted() {
+        tracing::error!(
+            errors = reporter.error_count(),
+            max_errors = options.max_errors.unwrap_or_default(),
+            "aborted, more errors than --max-errors allows"
+        );
+        // Nothing of this run is kept, neither is its WAL
+        if let Some(wal) = db.wal_mut() {
+            wal.reset(wal.base())?;
+        }
+        return Ok(Outcome::Fatal);
+    }
+    if let Some(path) = &options.snapshot_out {
+        write_snapshot(&db, path)?;
+        if let Some(wal) = db.wal_mut() {
+            wal.reset(Wal::checksum(&std::fs::read(path)?))?;
+        }
+    }
+    for entry in db.audit_log() {
+        tracing::info!(
+            action = ?entry.action,
+            client = entry.client,
+            tx = entry.tx,
+            "admin operation"
+        );
+    }
+    for (tag, count) in db.tags() {
+        tracing::info!(tag, transactions = count, "tagged by the rules or plugin");
+    }
+    if db.replays_skipped() > 0 {
+        tracing::info!(
+            replays = db.replays_skipped(),
+            "transactions already applied were skipped"
+        );
+    }
+    if db.records_pruned() > 0 {
+        tracing::info!(
+            records = db.records_pruned(),
+            "transaction records pruned by the retention policy"
+        );
     }
 
-    fn process(&mut self, transaction: &Transaction) -> TransactionResult {
-        match transaction.tx_type {
-            TransactionType::Deposit => {
-                self.handle_amount_transaction(transaction, Account::deposit)
-            }
-            TransactionType::Withdrawal => {
-                self.handle_amount_transaction(transaction, Account::withdraw)
-            }
-            TransactionType::Dispute => self.handle_dispute_like(
-                transaction,
-                |record| !record.is_disputed,
-                Account::dispute,
-                true,
-            ),
-            TransactionType::Resolve => self.handle_dispute_like(
-                transaction,
-                |record| record.is_disputed,
-                Account::resolve,
-                false,
-            ),
-            TransactionType::Chargeback => self.handle_dispute_like(
-                transaction,
-                |record| record.is_disputed,
-                Account::chargeback,
-                false,
-            ),
+    match options.output.as_deref() {
+        Some(path) if path.ends_with(".parquet") => {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            parquet_output::write(
+                db.precision(),
+                account_entries(&db, options.order),
+                BufWriter::with_capacity(options.buffer_size, file),
+            )?
+        }
+        Some(path) => {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            write_accounts(&db, options, file)?
         }
+        None => write_accounts(&db, options, io::stdout().lock())?,
     }
-}
 
-#[derive(Debug, Serialize)]
-struct Account {
-    available: Decimal,
-    held: Decimal,
-    locked: bool,
+    if options.stats {
+        stats.write(&db, io::stderr())?;
+    }
+    if let Some(path) = &options.stats_file {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        stats.write(&db, BufWriter::new(file))?;
+    }
+
+    io::stdout().flush()?;
+    Ok(reporter.outcome())
 }
 
-#[derive(Debug)]
-pub enum AccountError {
-    Locked,
-    InsufficientFunds,
+// Logs every balance that differs from the expected ones, an expected balance the run doesn't
+// have counting as missing and one it has but isn't expected as unexpected
+fn reconcile(
+    db: &Database,
+    path: &str,
+    tolerance: Decimal,
+    reporter: &ErrorReporter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let diff = AccountsDiff::reconcile(BufReader::new(file), db, tolerance)
+        .map_err(|e| format!("Failed to reconcile with {}: {}", path, e))?;
+    for line in &diff.lines {
+        tracing::warn!(
+            client = line.client,
+            currency = line.currency.map(|currency| currency.to_string()),
+            mismatch = match line.change {
+                Change::New => "unexpected",
+                Change::Removed => "missing",
+                Change::Changed => "differs",
+            },
+            available_diff = line.available,
+            held_diff = line.held,
+            total_diff = line.total,
+            locked = line.locked,
+            "balance does not reconcile"
+        );
+    }
+    match diff.lines.len() {
+        0 => tracing::info!(expected = path, "balances reconcile"),
+        mismatches => tracing::error!(mismatches, expected = path, "balances do not reconcile"),
+    }
+    reporter.reconciled(diff.lines.len() as u64);
+    Ok(())
 }
-pub type AccountResult = Result<(), AccountError>;
 
-impl Account {
-    fn new() -> Self {
-        Account {
-            available: Decimal::ZERO,
-            held: Decimal::ZERO,
-            locked: false,
+// Answers a --control command that needs the Database
+fn answer(db: &mut Database, reporter: &ErrorReporter, request: control::Request) {
+    let (reply, answer) = match request {
+        control::Request::Snapshot(reply) => {
+            let mut out = Vec::new();
+            let answer = match write_rows(
+                db,
+                cli::OutputOrder::Client,
+                OutputFormat::Csv,
+                None,
+                8 << 10,
+                &mut out,
+            ) {
+                Ok(()) => String::from_utf8_lossy(&out).into_owned() + "\n",
+                Err(e) => format!("error {}\n", e),
+            };
+            (reply, answer)
         }
-    }
+        control::Request::Flush(reply) => {
+            let flushed = db
+                .flush()
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|()| reporter.flush().map_err(|e| e.to_string()));
+            let answer = match flushed {
+                Ok(()) => "ok\n".to_string(),
+                Err(e) => format!("error {}\n", e),
+            };
+            (reply, answer)
+        }
+    };
+    // The connection may be gone already
+    let _ = reply.send(answer);
+}
 
-    fn deposit(&mut self, amount: Decimal) -> AccountResult {
-        if self.locked {
-            return Err(AccountError::Locked);
+// '-' reads the transaction CSV from stdin. Compressed inputs are decompressed on the fly.
+// With --mmap files are memory mapped and parsed from the mapping, without a read syscall
+// per buffer.
+fn open_input(
+    path: &str,
+    compression: Option<Compression>,
+    mmap: bool,
+    progress: Option<&Progress>,
+) -> io::Result<Box<dyn Read>> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+    let input: Box<dyn Read> = match (path, mmap) {
+        ("-", _) => Box::new(io::stdin()),
+        (_, true) => {
+            let file = File::open(path).map_err(with_path)?;
+            // Safety: the mapping is only read, and inputs aren't expected to change while a
+            // run reads them. One that is truncated under us fails the run with SIGBUS.
+            let map = unsafe { memmap2::Mmap::map(&file) }.map_err(with_path)?;
+            let _ = map.advise(memmap2::Advice::Sequential);
+            Box::new(io::Cursor::new(map))
         }
-        self.available += amount;
-        Ok(())
+        (_, false) => Box::new(File::open(path).map_err(with_path)?),
+    };
+    // Counted before decompression, the total being the size on disk
+    let input = match progress {
+        Some(progress) => progress.wrap(input),
+        None => input,
+    };
+    Ok(
+        match compression.unwrap_or_else(|| Compression::from_path(path)) {
+            Compression::None => input,
+            // Multi-member so concatenated dumps ('cat a.gz b.gz') are read in full
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(input).map_err(with_path)?),
+        },
+    )
+}
+
+fn open_parquet(path: &str) -> io::Result<File> {
+    match path {
+        "-" => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Parquet input cannot be read from stdin",
+        )),
+        _ => File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e))),
     }
+}
 
-    fn withdraw(&mut self, amount: Decimal) -> AccountResult {
-        if self.locked {
-            return Err(AccountError::Locked);
+// Rows reaching submit count as processed, rows of unknown types only unless skipped
+fn process_input(
+    source: &Arc<str>,
+    input: InputReader,
+    options: &Options,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    mut submit: impl FnMut(Transaction, Location),
+) {
+    let (parse_threads, lenient) = (options.parse_threads, options.lenient);
+    let header = CsvHeader::new(options);
+    let submit = |mut transaction: Transaction, location: Location| {
+        if lenient {
+            transaction.tx_type = transaction.tx_type.lenient();
         }
-        if self.available < amount {
-            return Err(AccountError::InsufficientFunds);
+        if let TransactionType::Other(name) = &transaction.tx_type
+            && options.on_unknown_type == UnknownTypePolicy::Skip
+        {
+            tracing::debug!(tx_type = %name, line = ?location.line, "unknown type skipped");
+            return stats.skipped();
         }
-        self.available -= amount;
-        Ok(())
-    }
-
-    fn dispute(&mut self, amount: Decimal) -> AccountResult {
-        if self.locked {
-            return Err(AccountError::Locked);
+        stats.submitted(&transaction.tx_type);
+        submit(transaction, location)
+    };
+    match input {
+        InputReader::Csv(input) if parse_threads.get() > 1 => parallel_csv::process(
+            source,
+            input,
+            parse_threads,
+            &header,
+            reporter,
+            stats,
+            submit,
+        ),
+        InputReader::Csv(input) => process_csv(source, input, &header, reporter, stats, submit),
+        InputReader::Avro(input) => {
+            avro_input::process(source, input, lenient, reporter, stats, submit)
         }
-        if self.available < amount {
-            return Err(AccountError::InsufficientFunds);
+        InputReader::Protobuf(input) => {
+            // Lengths are read a byte at a time
+            let input = BufReader::new(input);
+            proto_input::process(source, input, reporter, stats, submit)
+        }
+        InputReader::Parquet(input) => {
+            parquet_input::process(source, input, lenient, reporter, stats, submit)
         }
-        self.available -= amount;
-        self.held += amount;
-        Ok(())
     }
+}
 
-    fn resolve(&mut self, amount: Decimal) -> AccountResult {
-        if self.locked {
-            return Err(AccountError::Locked);
+// Where the columns of CSV inputs come from
+enum CsvHeader {
+    // The first line of every input
+    FirstLine {
+        lenient: bool,
+        amount_format: AmountFormat,
+    },
+    // Nowhere, inputs are headerless and these are their columns
+    Columns(CsvColumns),
+}
+
+impl CsvHeader {
+    fn new(options: &Options) -> Self {
+        match options.headerless_columns() {
+            Some(columns) => CsvHeader::Columns(
+                CsvColumns::new(&columns.into()).with_amount_format(options.amount_format()),
+            ),
+            None => CsvHeader::FirstLine {
+                lenient: options.lenient,
+                amount_format: options.amount_format(),
+            },
         }
-        if self.held < amount {
-            return Err(AccountError::InsufficientFunds);
+    }
+
+    // The columns of an input whose header line is `headers`
+    fn read(&self, headers: &csv::ByteRecord) -> CsvColumns {
+        match *self {
+            CsvHeader::FirstLine {
+                lenient: true,
+                amount_format,
+            } => CsvColumns::lenient(headers).with_amount_format(amount_format),
+            CsvHeader::FirstLine {
+                lenient: false,
+                amount_format,
+            } => CsvColumns::new(headers).with_amount_format(amount_format),
+            CsvHeader::Columns(ref columns) => columns.clone(),
         }
-        self.held -= amount;
-        self.available += amount;
-        Ok(())
     }
+}
 
-    fn chargeback(&mut self, amount: Decimal) -> AccountResult {
-        if self.locked {
-            return Err(AccountError::Locked);
+fn process_csv(
+    source: &Arc<str>,
+    input: impl Read,
+    header: &CsvHeader,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    mut submit: impl FnMut(Transaction, Location),
+) {
+    let _span = tracing::info_span!("input", source = %source).entered();
+    let mut rows: u64 = 0;
+    //trims whitespace and header
+    // Same terminator as parallel_csv::reader, so CRLF rows report their own
+    // line and byte.
+    let mut rdr = ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .has_headers(matches!(header, CsvHeader::FirstLine { .. }))
+        .from_reader(SkipBom::new(input));
+    let columns = match header {
+        CsvHeader::Columns(columns) => Ok(columns.clone()),
+        CsvHeader::FirstLine { .. } => rdr.byte_headers().map(|headers| header.read(headers)),
+    };
+    let columns = match columns {
+        Ok(columns) => columns,
+        Err(e) => {
+            let location = Location {
+                source: Arc::clone(source),
+                line: Some(1),
+            };
+            stats.unparsable();
+            reporter.input_failed();
+            return reporter.unparsable(&location, &e, &RawRecord::default());
+        }
+    };
+
+    // Records are read one by one rather than through deserialize() so we know their line, and
+    // as bytes so no row allocates
+    let mut record = csv::ByteRecord::new();
+    while !reporter.halted() {
+        let result = rdr.read_byte_record(&mut record);
+        let position = match &result {
+            Ok(_) => record.position(),
+            Err(e) => e.position(),
         }
-        if self.held < amount {
-            return Err(AccountError::InsufficientFunds);
+        .cloned();
+        let location = Location {
+            source: Arc::clone(source),
+            line: position.as_ref().map(|pos| pos.line()),
+        };
+        match result.and_then(|more| match more {
+            true => columns.deserialize(&record).map(Some),
+            false => Ok(None),
+        }) {
+            Ok(Some(transaction)) => {
+                rows += 1;
+                submit(transaction, location)
+            }
+            Ok(None) => break,
+            Err(e) => {
+                stats.unparsable();
+                if e.is_io_error() {
+                    reporter.unparsable(&location, &e, &RawRecord::default());
+                    reporter.input_failed();
+                    return;
+                }
+                // Offsets are in the file, a byte order mark included
+                let byte = position.map(|pos| pos.byte() + rdr.get_ref().skipped());
+                reporter.unparsable(&location, &e, &RawRecord::new(&record, byte));
+            }
         }
-        self.held -= amount;
-        self.locked = true;
-        Ok(())
     }
+    tracing::debug!(rows, "input processed");
+}
 
-    fn get_total(&self) -> Decimal {
-        self.available + self.held
+// Unsorted streams straight from storage, as does sorted unless the backend lists accounts out
+// of order and they have to be sorted in memory first
+fn account_entries(db: &Database, order: cli::OutputOrder) -> AccountEntries<'_> {
+    match order {
+        cli::OutputOrder::Client => db.accounts_by_client(),
+        cli::OutputOrder::Unsorted => db.accounts(),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rust_decimal::*;
+fn write_accounts(
+    db: &Database,
+    options: &Options,
+    output: impl io::Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let risk = options.extended_output.then_some(options.max_chargebacks);
+    write_rows(
+        db,
+        options.order,
+        options.output_format,
+        risk,
+        options.buffer_size,
+        output,
+    )
+}
 
-    fn setup_deposit_transaction(
-        tx: TransactionID,
-        client: ClientID,
-        amount: Decimal,
-    ) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Deposit,
-            client,
-            tx,
-            amount: Some(amount),
-        }
-    }
+// An output row of --extended-output
+#[derive(Serialize)]
+struct ExtendedRow {
+    #[serde(flatten)]
+    row: AccountRow,
+    #[serde(flatten)]
+    risk: RiskCounters,
+    risky: bool,
+}
 
-    fn setup_dispute_transaction(tx: TransactionID, client: ClientID) -> Transaction {
-        Transaction {
-            tx_type: TransactionType::Dispute,
-            client,
-            tx,
-            amount: None,
-        }
+// Rows are written as they are read, through a buffer of buffer_size bytes flushed whenever
+// it fills, so memory stays bounded however many accounts there are. With `risk`, rows carry
+// the risk counters too, and are flagged risky past the chargebacks it holds.
+fn write_rows(
+    db: &Database,
+    order: cli::OutputOrder,
+    format: OutputFormat,
+    risk: Option<Option<u32>>,
+    buffer_size: usize,
+    output: impl io::Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let precision = db.precision();
+    // CSV output has a currency column only if some balance has a currency, so feeds without
+    // currencies keep their five columns. Client ids being 16 bit, the extra pass is short.
+    let currency = format == OutputFormat::Csv
+        && db
+            .accounts()
+            .any(|entry| entry.is_ok_and(|(_, acc)| acc.has_currency()));
+    let rows = account_entries(db, order);
+
+    // One row per client per currency
+    let rows = rows.flat_map(|entry| match entry {
+        Ok((client_id, acc)) => AccountRow::rows(precision, client_id, &acc)
+            .map(|row| Ok((row, acc.risk())))
+            .collect::<Vec<_>>(),
+        Err(e) => vec![Err(format!("Failed to read account: {:?}", e))],
+    });
+    let Some(max_chargebacks) = risk else {
+        return write_rows_as(
+            format,
+            currency,
+            buffer_size,
+            output,
+            rows.map(|row| row.map(|(row, _)| row)),
+        );
+    };
+    let rows = rows.map(|row| {
+        row.map(|(row, risk)| ExtendedRow {
+            row,
+            risk,
+            risky: max_chargebacks.is_some_and(|max| risk.exceeds(max)),
+        })
+    });
+    write_rows_as(format, currency, buffer_size, output, rows)
+}
+
+// The columns of a CSV output row, written as AccountRow::write_csv writes them
+trait CsvRow: Serialize {
+    const HEADER: &[&str];
+
+    fn write(&self, wtr: &mut csv::Writer<impl io::Write>, currency: bool) -> csv::Result<()>;
+}
+
+impl CsvRow for AccountRow {
+    const HEADER: &[&str] = AccountRow::CSV_HEADER;
+
+    fn write(&self, wtr: &mut csv::Writer<impl io::Write>, currency: bool) -> csv::Result<()> {
+        self.write_csv(wtr, currency)
     }
+}
 
-    #[test]
-    fn test_deposit_increases_available_balance() {
-        let mut db = Database::default();
-        let tx = setup_deposit_transaction(1, 1, dec!(100.00));
-        db.process(&tx);
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(100.00));
-        assert_eq!(acc.held, dec!(0.00));
-        assert_eq!(acc.locked, false);
+impl CsvRow for ExtendedRow {
+    const HEADER: &[&str] = &[
+        "client",
+        "currency",
+        "available",
+        "held",
+        "total",
+        "locked",
+        "disputes",
+        "chargebacks",
+        "rejected_withdrawals",
+        "risky",
+    ];
+
+    fn write(&self, wtr: &mut csv::Writer<impl io::Write>, currency: bool) -> csv::Result<()> {
+        let row = &self.row;
+        wtr.serialize((
+            row.client,
+            currency.then_some(row.currency).as_slice(),
+            row.available,
+            row.held,
+            row.total,
+            row.locked,
+            self.risk.disputes,
+            self.risk.chargebacks,
+            self.risk.rejected_withdrawals,
+            self.risky,
+        ))
     }
+}
 
-    #[test]
-    fn test_withdrawal_reduces_balance() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)));
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(30.00)),
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(70.00));
-        assert_eq!(acc.get_total(), dec!(70.00));
-    }
-
-    #[test]
-    fn test_withdrawal_insufficient_funds_does_not_change_balance() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(50.00)));
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(100.00)),
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(50.00)); // unchanged
-    }
-
-    #[test]
-    fn test_dispute_moves_funds_to_held() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)));
-        db.process(&setup_dispute_transaction(1, 1));
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(0.00));
-        assert_eq!(acc.held, dec!(100.00));
-    }
-
-    #[test]
-    fn test_resolve_returns_held_funds_to_available() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)));
-        db.process(&setup_dispute_transaction(1, 1));
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(100.00));
-        assert_eq!(acc.held, dec!(0.00));
-    }
-
-    #[test]
-    fn test_chargeback_removes_held_funds_and_locks_account() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)));
-        db.process(&setup_dispute_transaction(1, 1));
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(0.00));
-        assert_eq!(acc.held, dec!(0.00));
-        assert_eq!(acc.locked, true);
-    }
-
-    #[test]
-    fn test_cannot_deposit_to_locked_account() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)));
-        db.process(&setup_dispute_transaction(1, 1));
-        db.process(&Transaction {
-            tx_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        db.process(&setup_deposit_transaction(2, 1, dec!(50.00)));
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(0.00)); // deposit rejected
-    }
-
-    #[test]
-    fn test_cannot_withdraw_from_locked_account() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.00)));
-        db.process(&setup_dispute_transaction(1, 1));
-        db.process(&Transaction {
-            tx_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: Some(dec!(50.00)),
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(0.00)); // withdrawal ignored
-    }
-
-    #[test]
-    fn test_deposit_increases_available_and_total() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(10.5));
-        assert_eq!(acc.available, dec!(10.5));
-        assert_eq!(acc.get_total(), dec!(10.5));
-    }
-
-    #[test]
-    fn test_withdraw_succeeds_when_sufficient_funds() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(10.0));
-        acc.withdraw(dec!(4.0));
-        assert_eq!(acc.available, dec!(6.0));
-        assert_eq!(acc.get_total(), dec!(6.0));
-    }
-
-    #[test]
-    fn test_withdraw_does_nothing_if_insufficient_funds() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(5.0));
-        acc.withdraw(dec!(10.0));
-        assert_eq!(acc.available, dec!(5.0));
-        assert_eq!(acc.get_total(), dec!(5.0));
-    }
-
-    #[test]
-    fn test_withdraw_does_nothing_if_account_locked() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(5.0));
-        acc.locked = true;
-        acc.withdraw(dec!(2.0));
-        assert_eq!(acc.available, dec!(5.0));
-    }
-
-    #[test]
-    fn test_dispute_moves_funds_from_available_to_held() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(10.0));
-        acc.dispute(dec!(4.0));
-        assert_eq!(acc.available, dec!(6.0));
-        assert_eq!(acc.held, dec!(4.0));
-        assert_eq!(acc.get_total(), dec!(10.0));
-    }
-
-    #[test]
-    fn test_resolve_returns_held_to_available() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(10.0));
-        acc.dispute(dec!(3.0));
-        acc.resolve(dec!(3.0));
-        assert_eq!(acc.available, dec!(10.0));
-        assert_eq!(acc.held, dec!(0.0));
-    }
-
-    #[test]
-    fn test_chargeback_removes_held_and_locks_account() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(10.0));
-        acc.dispute(dec!(7.0));
-        acc.chargeback(dec!(7.0));
-        assert_eq!(acc.held, dec!(0.0));
-        assert_eq!(acc.available, dec!(3.0));
-        assert_eq!(acc.get_total(), dec!(3.0));
-        assert!(acc.locked);
-    }
-
-    #[test]
-    fn test_total_is_sum_of_available_and_held() {
-        let mut acc = Account::new();
-        acc.deposit(dec!(10.0));
-        acc.dispute(dec!(4.0));
-        assert_eq!(acc.get_total(), dec!(10.0));
-    }
-    #[test]
-    fn test_withdrawal_missing_amount_is_ignored() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(50.00)));
-        db.process(&Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 2,
-            amount: None,
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(50.00)); // unchanged
-    }
-
-    #[test]
-    fn test_chargeback_without_dispute_does_nothing() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)));
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Chargeback,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(100.0));
-        assert_eq!(acc.held, dec!(0.0));
-        assert_eq!(acc.locked, false);
-    }
-    #[test]
-    fn test_resolve_non_disputed_does_nothing() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)));
-
-        db.process(&Transaction {
-            tx_type: TransactionType::Resolve,
-            client: 1,
-            tx: 1,
-            amount: None,
-        });
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(100.0));
-        assert_eq!(acc.held, dec!(0.0));
-    }
-    #[test]
-    fn test_double_dispute_does_nothing() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)));
-        db.process(&setup_dispute_transaction(1, 1));
-        db.process(&setup_dispute_transaction(1, 1)); // again
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.held, dec!(100.0));
-        assert_eq!(acc.available, dec!(0.0));
-    }
-    #[test]
-    fn test_dispute_wrong_client_id() {
-        let mut db = Database::default();
-        db.process(&setup_deposit_transaction(1, 1, dec!(100.0)));
-        db.process(&setup_dispute_transaction(1, 2)); // wrong client ID
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.held, dec!(0.0)); // should not be disputed
-    }
-
-    #[test]
-    fn test_duplicate_deposit_is_ignored() {
-        let mut db = Database::default();
-        let tx = setup_deposit_transaction(1, 1, dec!(100.00));
-        db.process(&tx);
-        db.process(&tx); // duplicate tx_id
-
-        let acc = db.account_map.get(&1).unwrap();
-        assert_eq!(acc.available, dec!(100.00)); // second deposit ignored
+fn write_rows_as<R: CsvRow>(
+    format: OutputFormat,
+    currency: bool,
+    buffer_size: usize,
+    output: impl io::Write,
+    mut rows: impl Iterator<Item = Result<R, String>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .buffer_capacity(buffer_size)
+                .from_writer(output);
+            wtr.write_record(AccountRow::csv_header(R::HEADER, currency))?;
+            for row in rows {
+                row?.write(&mut wtr, currency)?;
+            }
+            wtr.flush()?;
+        }
+        // Written row by row so unsorted output still streams
+        OutputFormat::Json => {
+            let mut out = BufWriter::with_capacity(buffer_size, output);
+            write!(out, "[")?;
+            if let Some(first) = rows.next() {
+                serde_json::to_writer(&mut out, &first?)?;
+            }
+            for row in rows {
+                write!(out, ",")?;
+                serde_json::to_writer(&mut out, &row?)?;
+            }
+            writeln!(out, "]")?;
+            out.flush()?;
+        }
+        OutputFormat::Ndjson => {
+            let mut out = BufWriter::with_capacity(buffer_size, output);
+            for row in rows {
+                serde_json::to_writer(&mut out, &row?)?;
+                writeln!(out)?;
+            }
+            out.flush()?;
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,435 @@
+use csv::{ByteRecord, ReaderBuilder};
+use octopus::{CsvColumns, Transaction};
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    mem,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+
+use crate::CsvHeader;
+use crate::bom::SkipBom;
+use crate::report::{ErrorReporter, Location, RawRecord};
+use crate::stats::Stats;
+
+// Bytes of input handed to a parser at a time, cut at the end of the record they end in
+const CHUNK_SIZE: usize = 1 << 20;
+
+// Rows parsed from a chunk with their line, in input order. Those that fail come with the row.
+type Parsed = Vec<(Option<u64>, Result<Transaction, (csv::Error, RawRecord)>)>;
+
+// Reads a transaction CSV like the sequential reader, but splits it into chunks on record
+// boundaries and parses and deserializes the chunks on `parsers` threads. Rows still reach
+// `submit` one by one in input order, on the calling thread, with the same lines and errors.
+pub fn process(
+    source: &Arc<str>,
+    input: impl Read,
+    parsers: NonZeroUsize,
+    header: &CsvHeader,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    submit: impl FnMut(Transaction, Location),
+) {
+    process_chunked(
+        source, input, parsers, CHUNK_SIZE, header, reporter, stats, submit,
+    )
+}
+
+// process with the chunk size left to the caller, for tests
+#[allow(clippy::too_many_arguments)]
+fn process_chunked(
+    source: &Arc<str>,
+    input: impl Read,
+    parsers: NonZeroUsize,
+    chunk_size: usize,
+    header: &CsvHeader,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    mut submit: impl FnMut(Transaction, Location),
+) {
+    let _span = tracing::info_span!("input", source = %source).entered();
+    let location = |line| Location {
+        source: Arc::clone(source),
+        line,
+    };
+    let fail = |line, e: csv::Error| {
+        stats.unparsable();
+        reporter.input_failed();
+        reporter.unparsable(&location(line), &e, &RawRecord::default());
+    };
+    let mut chunks = Chunks::new(SkipBom::new(input));
+    // Every chunk is parsed behind the header line, so records of the wrong length are caught
+    // as they are by the sequential reader. Headerless inputs get one written from --columns.
+    let (header, columns) = match header {
+        CsvHeader::Columns(columns) => (header_line(columns.headers()), columns.clone()),
+        CsvHeader::FirstLine { .. } => {
+            let line = match chunks.take(1) {
+                Ok(Some(chunk)) => chunk.bytes,
+                Ok(None) => return,
+                Err(e) => return fail(Some(1), e.into()),
+            };
+            match reader(line.as_slice()).byte_headers() {
+                Ok(headers) => (line.clone(), header.read(headers)),
+                Err(e) => return fail(Some(1), e),
+            }
+        }
+    };
+
+    let mut rows: u64 = 0;
+    let mut deliver = |parsed: Parsed| {
+        for (line, result) in parsed {
+            if reporter.halted() {
+                return;
+            }
+            match result {
+                Ok(transaction) => {
+                    rows += 1;
+                    submit(transaction, location(line))
+                }
+                Err((e, raw)) => {
+                    stats.unparsable();
+                    reporter.unparsable(&location(line), &e, &raw);
+                }
+            }
+        }
+    };
+    let (work, jobs) = mpsc::sync_channel::<(Chunk, mpsc::Sender<Parsed>)>(parsers.get());
+    let jobs = Mutex::new(jobs);
+    thread::scope(|scope| {
+        for _ in 0..parsers.get() {
+            scope.spawn(|| {
+                while let Ok((chunk, reply)) = jobs.lock().unwrap().recv() {
+                    // Gone once the reader stopped early
+                    let _ = reply.send(parse(&header, &columns, chunk));
+                }
+            });
+        }
+
+        // At most two chunks per parser are held, parsed or not
+        let mut pending = VecDeque::new();
+        let mut failed = None;
+        while !reporter.halted() {
+            match chunks.take(chunk_size) {
+                Ok(Some(mut chunk)) => {
+                    // Offsets are in the file, a byte order mark included
+                    chunk.byte += chunks.input.skipped();
+                    let (reply, parsed) = mpsc::channel();
+                    if work.send((chunk, reply)).is_err() {
+                        break;
+                    }
+                    pending.push_back(parsed);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    failed = Some((chunks.line, e));
+                    break;
+                }
+            }
+            while pending.len() > 2 * parsers.get() {
+                deliver(
+                    pending
+                        .pop_front()
+                        .and_then(|parsed| parsed.recv().ok())
+                        .unwrap_or_default(),
+                );
+            }
+        }
+        drop(work);
+        for parsed in pending {
+            deliver(parsed.recv().unwrap_or_default());
+        }
+        if let Some((line, e)) = failed {
+            fail(Some(line), e.into());
+        }
+    });
+    tracing::debug!(rows, "input processed");
+}
+
+fn reader<R: Read>(input: R) -> csv::Reader<R> {
+    // Records end at '\n' only: csv's default CRLF terminator places a record
+    // after "\r\n" at the '\n' and on the previous line. Trim drops the '\r'.
+    ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_reader(input)
+}
+
+fn header_line(headers: &ByteRecord) -> Vec<u8> {
+    let mut line = csv::Writer::from_writer(Vec::new());
+    line.write_byte_record(headers)
+        .expect("writing to a Vec doesn't fail");
+    line.into_inner().expect("writing to a Vec doesn't fail")
+}
+
+// Deserializes the records of a chunk, giving lines and offsets of the whole input
+fn parse(header: &[u8], columns: &CsvColumns, chunk: Chunk) -> Parsed {
+    let mut rdr = reader(header.chain(chunk.bytes.as_slice()));
+    let mut parsed = Vec::new();
+    let mut record = ByteRecord::new();
+    // The chunk's first record is line 2, right behind the header
+    let line = |pos: &csv::Position| pos.line() - 2 + chunk.line;
+    let byte = |pos: &csv::Position| pos.byte() - header.len() as u64 + chunk.byte;
+    loop {
+        let result = rdr.read_byte_record(&mut record);
+        let position = match &result {
+            Ok(_) => record.position(),
+            Err(e) => e.position(),
+        }
+        .cloned();
+        let line = position.as_ref().map(line);
+        match result.and_then(|more| match more {
+            true => columns.deserialize(&record).map(Some),
+            false => Ok(None),
+        }) {
+            Ok(Some(transaction)) => parsed.push((line, Ok(transaction))),
+            Ok(None) => break,
+            Err(e) if e.is_io_error() => {
+                parsed.push((line, Err((e, RawRecord::default()))));
+                break;
+            }
+            Err(e) => {
+                let raw = RawRecord::new(&record, position.as_ref().map(byte));
+                parsed.push((line, Err((e, raw))));
+            }
+        }
+    }
+    parsed
+}
+
+struct Chunk {
+    bytes: Vec<u8>,
+    // Line of the input the chunk starts on, and the offset of its first byte
+    line: u64,
+    byte: u64,
+}
+
+// Cuts the input after record terminators, outside quoted fields
+struct Chunks<R> {
+    input: R,
+    buf: Vec<u8>,
+    // How far buf was searched for the end of a record, and whether that is inside quotes
+    scanned: usize,
+    quoted: bool,
+    eof: bool,
+    line: u64,
+    byte: u64,
+}
+
+impl<R: Read> Chunks<R> {
+    fn new(input: R) -> Self {
+        Chunks {
+            input,
+            buf: Vec::new(),
+            scanned: 0,
+            quoted: false,
+            eof: false,
+            line: 1,
+            byte: 0,
+        }
+    }
+
+    // The next chunk of at least `size` bytes, unless the input ends first
+    fn take(&mut self, size: usize) -> io::Result<Option<Chunk>> {
+        loop {
+            if let Some(end) = self.record_end(size) {
+                return Ok(Some(self.split(end)));
+            }
+            if self.eof {
+                return Ok(match self.buf.is_empty() {
+                    true => None,
+                    false => Some(self.split(self.buf.len())),
+                });
+            }
+            let read = (&mut self.input)
+                .take(size.max(CHUNK_SIZE) as u64)
+                .read_to_end(&mut self.buf)?;
+            self.eof = read == 0;
+        }
+    }
+
+    // Just past the first record terminator at or beyond `size` bytes
+    fn record_end(&mut self, size: usize) -> Option<usize> {
+        for i in self.scanned..self.buf.len() {
+            match self.buf[i] {
+                b'"' => self.quoted = !self.quoted,
+                b'\n' if !self.quoted && i + 1 >= size => return Some(i + 1),
+                _ => {}
+            }
+        }
+        self.scanned = self.buf.len();
+        None
+    }
+
+    fn split(&mut self, end: usize) -> Chunk {
+        let rest = self.buf.split_off(end);
+        let bytes = mem::replace(&mut self.buf, rest);
+        let (line, byte) = (self.line, self.byte);
+        self.line += bytes.iter().filter(|&&byte| byte == b'\n').count() as u64;
+        self.byte += bytes.len() as u64;
+        (self.scanned, self.quoted) = (0, false);
+        Chunk { bytes, line, byte }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octopus::AmountFormat;
+
+    #[test]
+    fn test_chunks_end_outside_quoted_fields() {
+        let mut chunks = Chunks::new(&b"a,b\n\"x\ny\",1\nz,2\n"[..]);
+        let header = chunks.take(1).unwrap().unwrap();
+        assert_eq!((header.bytes.as_slice(), header.line), (&b"a,b\n"[..], 1));
+        let chunk = chunks.take(3).unwrap().unwrap();
+        assert_eq!(
+            (chunk.bytes.as_slice(), chunk.line),
+            (&b"\"x\ny\",1\n"[..], 2)
+        );
+        let chunk = chunks.take(3).unwrap().unwrap();
+        assert_eq!((chunk.bytes.as_slice(), chunk.line), (&b"z,2\n"[..], 4));
+        assert!(chunks.take(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rows_arrive_in_order_with_their_lines() {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for tx in 1..=50 {
+            input += &format!("deposit, 1, {}, 1.0\n", tx);
+        }
+        input += "deposit,1\nwithdrawal,1,51,0.5";
+
+        let (reporter, stats) = (ErrorReporter::new(None, None).unwrap(), Stats::new());
+        let mut transactions = Vec::new();
+        process_chunked(
+            &Arc::from("payments.csv"),
+            input.as_bytes(),
+            NonZeroUsize::new(3).unwrap(),
+            16,
+            &CsvHeader::FirstLine {
+                lenient: false,
+                amount_format: AmountFormat::default(),
+            },
+            &reporter,
+            &stats,
+            |transaction, location| transactions.push((transaction.tx, location.line)),
+        );
+
+        let expected = (1..=50)
+            .map(|tx| (tx, Some(tx as u64 + 1)))
+            .chain([(51, Some(53))])
+            .collect::<Vec<_>>();
+        assert_eq!(transactions, expected);
+        // The short row fails as it does for the sequential reader
+        assert_eq!(stats.counts().unparsable, 1);
+    }
+
+    #[test]
+    fn test_excel_export_parses_like_plain_csv() {
+        let excel = include_bytes!("../fixtures/excel_transactions.csv");
+        let (reporter, stats) = (ErrorReporter::new(None, None).unwrap(), Stats::new());
+        let mut transactions = Vec::new();
+        process_chunked(
+            &Arc::from("excel.csv"),
+            &excel[..],
+            NonZeroUsize::new(2).unwrap(),
+            8,
+            &CsvHeader::FirstLine {
+                lenient: false,
+                amount_format: AmountFormat::default(),
+            },
+            &reporter,
+            &stats,
+            |transaction, location| {
+                transactions.push((transaction.tx, transaction.amount, location.line))
+            },
+        );
+        let amount = |amount: &str| Some(amount.parse::<rust_decimal::Decimal>().unwrap());
+        assert_eq!(
+            transactions,
+            [
+                (1, amount("1.5"), Some(2)),
+                (2, amount("2"), Some(3)),
+                (3, amount("0.25"), Some(4)),
+                (2, None, Some(5)),
+            ]
+        );
+        assert_eq!(stats.counts().unparsable, 0);
+    }
+
+    #[test]
+    fn test_headerless_rows_start_on_line_one() {
+        let columns = CsvColumns::new(&ByteRecord::from(vec!["tx", "type", "client", "amount"]));
+        let (reporter, stats) = (ErrorReporter::new(None, None).unwrap(), Stats::new());
+        let mut transactions = Vec::new();
+        process_chunked(
+            &Arc::from("payments.csv"),
+            &b"1,deposit,3,1.0\n2,withdrawal,3,0.5\n"[..],
+            NonZeroUsize::new(2).unwrap(),
+            8,
+            &CsvHeader::Columns(columns),
+            &reporter,
+            &stats,
+            |transaction, location| transactions.push((transaction.tx, location.line)),
+        );
+        assert_eq!(transactions, [(1, Some(1)), (2, Some(2))]);
+    }
+
+    #[test]
+    fn test_unparsable_rows_are_reported_as_by_the_sequential_reader() {
+        let input = b"\xef\xbb\xbftype,client,tx,amount\r\ndeposit,1,1,1.0\r\ndeposit,1\r\n\
+                      deposit,1,3,abc\r\ndeposit,1,4,2\r\n";
+        let header = CsvHeader::FirstLine {
+            lenient: false,
+            amount_format: AmountFormat::default(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let run = |name: &str, chunked: bool| {
+            let path = dir.path().join(format!("{}.csv", name));
+            let reporter = ErrorReporter::new(path.to_str(), None).unwrap();
+            let source = Arc::from("payments.csv");
+            match chunked {
+                true => process_chunked(
+                    &source,
+                    &input[..],
+                    NonZeroUsize::new(2).unwrap(),
+                    8,
+                    &header,
+                    &reporter,
+                    &Stats::new(),
+                    |_, _| {},
+                ),
+                false => crate::process_csv(
+                    &source,
+                    &input[..],
+                    &header,
+                    &reporter,
+                    &Stats::new(),
+                    |_, _| {},
+                ),
+            }
+            reporter.flush().unwrap();
+            std::fs::read_to_string(&path).unwrap()
+        };
+
+        let report = run("sequential", false);
+        assert_eq!(report, run("chunked", true));
+        // Offsets are in the file, the byte order mark included
+        let mut rows = report.lines();
+        assert_eq!(
+            rows.next(),
+            Some("source,line,byte,tx,client,type,error_code,record")
+        );
+        assert_eq!(
+            rows.next(),
+            Some("payments.csv,3,43,,,,deserialize,\"deposit,1\"")
+        );
+        assert_eq!(
+            rows.next(),
+            Some("payments.csv,4,54,,,,deserialize,\"deposit,1,3,abc\"")
+        );
+        assert_eq!(rows.next(), None);
+    }
+}
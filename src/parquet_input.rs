@@ -0,0 +1,158 @@
+use arrow_array::{Array, RecordBatch, StringArray, cast::AsArray};
+use arrow_schema::{ArrowError, DataType};
+use csv::StringRecord;
+use octopus::{Transaction, lenient_column};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::{fs::File, io, sync::Arc};
+
+use crate::report::{ErrorReporter, Location, RawRecord};
+use crate::stats::Stats;
+
+// Reads a Parquet file of transactions, batch by batch. Columns are named like the CSV header
+// and every value is turned into its string form, then deserialized like a CSV row, so both
+// formats accept exactly the same transactions. Integer, decimal, float and string columns all
+// work, nulls being empty fields. Locations are row numbers, 1 being the first row.
+pub fn process(
+    source: &Arc<str>,
+    input: File,
+    lenient: bool,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    mut submit: impl FnMut(Transaction, Location),
+) {
+    let _span = tracing::info_span!("input", source = %source).entered();
+    let location = |line| Location {
+        source: Arc::clone(source),
+        line,
+    };
+    let fail = |line, e: String| {
+        stats.unparsable();
+        reporter.input_failed();
+        reporter.unparsable(
+            &location(line),
+            &csv::Error::from(io::Error::other(e)),
+            &RawRecord::default(),
+        );
+    };
+    let batches = match ParquetRecordBatchReaderBuilder::try_new(input).and_then(|b| b.build()) {
+        Ok(batches) => batches,
+        Err(e) => return fail(None, e.to_string()),
+    };
+
+    let mut rows: u64 = 0;
+    for batch in batches {
+        let (headers, columns) = match batch.and_then(|batch| as_strings(&batch)) {
+            Ok(columns) => columns,
+            Err(e) => return fail(Some(rows + 1), e.to_string()),
+        };
+        let headers = match lenient {
+            true => headers.iter().map(lenient_column).collect(),
+            false => headers,
+        };
+        let mut record = StringRecord::new();
+        for row in 0..columns.first().map_or(0, |column| column.len()) {
+            if reporter.halted() {
+                return;
+            }
+            rows += 1;
+            record.clear();
+            for column in &columns {
+                record.push_field(match column.is_null(row) {
+                    true => "",
+                    false => column.value(row),
+                });
+            }
+            record.trim();
+            match record.deserialize::<Transaction>(Some(&headers)) {
+                Ok(transaction) => submit(transaction, location(Some(rows))),
+                Err(e) => {
+                    stats.unparsable();
+                    let raw = RawRecord::new(record.as_byte_record(), None);
+                    reporter.unparsable(&location(Some(rows)), &e, &raw);
+                }
+            }
+        }
+    }
+    tracing::debug!(rows, "input processed");
+}
+
+// The column names, as a header, and every column cast to strings
+fn as_strings(batch: &RecordBatch) -> Result<(StringRecord, Vec<StringArray>), ArrowError> {
+    let headers = batch
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| field.name().as_str())
+        .collect();
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| {
+            arrow_cast::cast(column, &DataType::Utf8).map(|column| column.as_string().clone())
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((headers, columns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Decimal128Array, UInt16Array, UInt32Array};
+    use octopus::TransactionType;
+    use parquet::arrow::ArrowWriter;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_parquet_rows_become_transactions() {
+        let batch = RecordBatch::try_from_iter([
+            (
+                // Read leniently
+                "Type",
+                Arc::new(StringArray::from(vec![
+                    Some("deposit"),
+                    Some("dispute"),
+                    None,
+                ])) as _,
+            ),
+            ("client", Arc::new(UInt16Array::from(vec![1, 1, 2])) as _),
+            ("tx", Arc::new(UInt32Array::from(vec![1, 1, 2])) as _),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![Some(15_000), None, Some(1)])
+                        .with_precision_and_scale(10, 4)
+                        .unwrap(),
+                ) as _,
+            ),
+        ])
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("day.parquet");
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let (reporter, stats) = (ErrorReporter::new(None, None).unwrap(), Stats::new());
+        let mut transactions = Vec::new();
+        process(
+            &Arc::from("day.parquet"),
+            File::open(&path).unwrap(),
+            true,
+            &reporter,
+            &stats,
+            |transaction, location| transactions.push((transaction, location.line)),
+        );
+
+        assert_eq!(transactions.len(), 2);
+        let (deposit, line) = &transactions[0];
+        assert_eq!(*line, Some(1));
+        assert_eq!(deposit.tx_type, TransactionType::Deposit);
+        assert_eq!(deposit.amount, Some(Decimal::new(15, 1)));
+        let (dispute, line) = &transactions[1];
+        assert_eq!(*line, Some(2));
+        assert_eq!(dispute.tx_type, TransactionType::Dispute);
+        assert_eq!(dispute.amount, None);
+        assert_eq!(stats.counts().unparsable, 1);
+    }
+}
@@ -0,0 +1,137 @@
+use arrow_array::{
+    ArrayRef, RecordBatch,
+    builder::{ArrayBuilder, BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder},
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use octopus::{AccountRow, PrecisionPolicy, storage::AccountEntries};
+use parquet::arrow::ArrowWriter;
+use std::{error::Error, io::Write, sync::Arc};
+
+// Rows per record batch, so huge account counts are written without holding them all
+const BATCH_ROWS: usize = 8192;
+
+// Enough digits for any Decimal
+const DECIMAL_PRECISION: u8 = 38;
+
+// Writes the accounts as Parquet, one row per client per currency like the CSV output. Amounts
+// are decimal columns scaled to --precision, so Spark or DuckDB load them without going through
+// floats.
+pub fn write(
+    precision: PrecisionPolicy,
+    accounts: AccountEntries,
+    output: impl Write + Send,
+) -> Result<(), Box<dyn Error>> {
+    let mut columns = Columns::new(precision);
+    let mut writer = ArrowWriter::try_new(output, Arc::clone(&columns.schema), None)?;
+    for entry in accounts {
+        let (client, account) = entry.map_err(|e| format!("Failed to read account: {:?}", e))?;
+        for row in AccountRow::rows(precision, client, &account) {
+            // Rows are scaled to --precision already, like the column type
+            columns.client.append_value(row.client);
+            columns
+                .currency
+                .append_option(row.currency.map(|currency| currency.to_string()));
+            columns.available.append_value(row.available.mantissa());
+            columns.held.append_value(row.held.mantissa());
+            columns.total.append_value(row.total.mantissa());
+            columns.locked.append_value(row.locked);
+            if columns.client.len() == BATCH_ROWS {
+                writer.write(&columns.finish()?)?;
+            }
+        }
+    }
+    if !columns.client.is_empty() {
+        writer.write(&columns.finish()?)?;
+    }
+    writer.close()?;
+    Ok(())
+}
+
+struct Columns {
+    schema: SchemaRef,
+    client: UInt16Builder,
+    currency: StringBuilder,
+    available: Decimal128Builder,
+    held: Decimal128Builder,
+    total: Decimal128Builder,
+    locked: BooleanBuilder,
+}
+
+impl Columns {
+    fn new(precision: PrecisionPolicy) -> Self {
+        let amount = DataType::Decimal128(DECIMAL_PRECISION, precision.decimal_places as i8);
+        let decimal = || Decimal128Builder::new().with_data_type(amount.clone());
+        Columns {
+            schema: Arc::new(Schema::new(vec![
+                Field::new("client", DataType::UInt16, false),
+                // Null for the balance without a currency
+                Field::new("currency", DataType::Utf8, true),
+                Field::new("available", amount.clone(), false),
+                Field::new("held", amount.clone(), false),
+                Field::new("total", amount.clone(), false),
+                Field::new("locked", DataType::Boolean, false),
+            ])),
+            client: UInt16Builder::new(),
+            currency: StringBuilder::new(),
+            available: decimal(),
+            held: decimal(),
+            total: decimal(),
+            locked: BooleanBuilder::new(),
+        }
+    }
+
+    // Takes the rows appended so far, leaving the builders empty
+    fn finish(&mut self) -> Result<RecordBatch, ArrowError> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.client.finish()),
+            Arc::new(self.currency.finish()),
+            Arc::new(self.available.finish()),
+            Arc::new(self.held.finish()),
+            Arc::new(self.total.finish()),
+            Arc::new(self.locked.finish()),
+        ];
+        RecordBatch::try_new(Arc::clone(&self.schema), columns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, cast::AsArray, types::Decimal128Type};
+    use octopus::{Database, Transaction, TransactionType};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rust_decimal::Decimal;
+    use std::fs::File;
+
+    #[test]
+    fn test_accounts_are_written_as_decimals() {
+        let mut db = Database::default();
+        db.process(&Transaction::new(
+            TransactionType::Deposit,
+            7,
+            1,
+            Some(Decimal::new(15, 1)),
+        ))
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.parquet");
+        write(db.precision(), db.accounts(), File::create(&path).unwrap()).unwrap();
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 1);
+        assert_eq!(
+            batch.schema().field(2).data_type(),
+            &DataType::Decimal128(38, 4)
+        );
+        assert!(batch.column(1).is_null(0));
+        let available = batch.column(2).as_primitive::<Decimal128Type>();
+        assert_eq!(available.value(0), 15_000);
+        assert_eq!(available.value_as_string(0), "1.5000");
+    }
+}
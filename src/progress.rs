@@ -0,0 +1,94 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+// How often the rows per second are refreshed, in rows
+const RATE_EVERY: u64 = 10_000;
+
+// --progress on stderr: bytes read out of the inputs and rows per second, with an ETA when every
+// input is a regular file. Compressed inputs count their compressed bytes.
+pub struct Progress {
+    bar: ProgressBar,
+    rows: AtomicU64,
+    started: Instant,
+}
+
+impl Progress {
+    // The total is the size of every input, unknown when one of them is stdin or not a file
+    pub fn new(inputs: &[String]) -> Self {
+        let sizes = inputs
+            .iter()
+            .map(|path| match path.as_str() {
+                "-" => None,
+                path => std::fs::metadata(path)
+                    .ok()
+                    .filter(|metadata| metadata.is_file())
+                    .map(|metadata| metadata.len()),
+            })
+            .collect::<Option<Vec<u64>>>()
+            .filter(|_| !inputs.is_empty());
+        let bar = match sizes {
+            Some(sizes) => ProgressBar::new(sizes.iter().sum()).with_style(
+                ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {msg} ETA {eta}")
+                    .unwrap_or_else(|_| ProgressStyle::default_bar()),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {bytes} {msg}")
+                    .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+            ),
+        };
+        Progress {
+            bar,
+            rows: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn wrap<'a>(&self, input: impl Read + 'a) -> Box<dyn Read + 'a> {
+        Box::new(self.bar.wrap_read(input))
+    }
+
+    pub fn row(&self) {
+        let rows = self.rows.fetch_add(1, Ordering::Relaxed) + 1;
+        if rows.is_multiple_of(RATE_EVERY) {
+            self.refresh(rows);
+        }
+    }
+
+    fn refresh(&self, rows: u64) {
+        let secs = self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        self.bar
+            .set_message(format!("{} rows, {:.0} rows/s", rows, rows as f64 / secs));
+    }
+
+    // Leaves the final counts on screen
+    pub fn finish(&self) {
+        self.refresh(self.rows.load(Ordering::Relaxed));
+        self.bar.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_total_is_known_for_regular_files_only() {
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string();
+        let size = std::fs::metadata(&manifest).unwrap().len();
+        assert_eq!(
+            Progress::new(std::slice::from_ref(&manifest)).bar.length(),
+            Some(size)
+        );
+        assert_eq!(
+            Progress::new(&[manifest.clone(), "-".to_string()])
+                .bar
+                .length(),
+            None
+        );
+        assert_eq!(Progress::new(&[]).bar.length(), None);
+    }
+}
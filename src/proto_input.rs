@@ -0,0 +1,129 @@
+use octopus::{Transaction, server::grpc::proto};
+use prost::Message;
+use std::{
+    io::{self, Read},
+    sync::Arc,
+};
+
+use crate::report::{ErrorReporter, Location, RawRecord};
+use crate::stats::Stats;
+
+// Reads a stream of Transaction messages from proto/octopus.proto, each prefixed by its length
+// as a varint, as written by prost's encode_length_delimited or protobuf-java's
+// writeDelimitedTo. Messages are converted like gRPC submissions. Locations are message numbers,
+// 1 being the first message.
+pub fn process(
+    source: &Arc<str>,
+    mut input: impl Read,
+    reporter: &ErrorReporter,
+    stats: &Stats,
+    mut submit: impl FnMut(Transaction, Location),
+) {
+    let _span = tracing::info_span!("input", source = %source).entered();
+    let mut rows: u64 = 0;
+    let mut buf = Vec::new();
+    while !reporter.halted() {
+        let location = Location {
+            source: Arc::clone(source),
+            line: Some(rows + 1),
+        };
+        match read_message(&mut input, &mut buf) {
+            Ok(true) => rows += 1,
+            Ok(false) => break,
+            // The framing is lost, nothing after this can be trusted
+            Err(e) => {
+                stats.unparsable();
+                reporter.input_failed();
+                let e = csv::Error::from(e);
+                return reporter.unparsable(&location, &e, &RawRecord::default());
+            }
+        }
+        let result = proto::Transaction::decode(buf.as_slice())
+            .map_err(|e| e.to_string())
+            .and_then(|message| {
+                Transaction::try_from(message).map_err(|status| status.message().to_string())
+            });
+        match result {
+            Ok(transaction) => submit(transaction, location),
+            Err(e) => {
+                stats.unparsable();
+                let e = csv::Error::from(io::Error::other(e));
+                reporter.unparsable(&location, &e, &RawRecord::default());
+            }
+        }
+    }
+    tracing::debug!(rows, "input processed");
+}
+
+// Reads the next message into buf, false once the stream ends cleanly between two messages
+fn read_message(input: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut len: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        if input.read(&mut byte)? == 0 {
+            return match shift {
+                0 => Ok(false),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        len |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            buf.clear();
+            input.take(len).read_to_end(buf)?;
+            if buf.len() as u64 != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            return Ok(true);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "message length is not a valid varint",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octopus::TransactionType;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_delimited_messages_become_transactions() {
+        let mut bytes = Vec::new();
+        for (r#type, client, amount) in [
+            (proto::TransactionType::Deposit, 1, Some("1.5")),
+            (proto::TransactionType::Deposit, 1, Some("lots")),
+            (proto::TransactionType::Withdrawal, 2, Some("0.5")),
+        ] {
+            proto::Transaction {
+                r#type: r#type.into(),
+                client,
+                tx: 1,
+                amount: amount.map(String::from),
+                ..Default::default()
+            }
+            .encode_length_delimited(&mut bytes)
+            .unwrap();
+        }
+        // A truncated message at the end fails the input
+        bytes.extend([0x05, 0x08]);
+
+        let (reporter, stats) = (ErrorReporter::new(None, None).unwrap(), Stats::new());
+        let mut transactions = Vec::new();
+        process(
+            &Arc::from("feed.pb"),
+            bytes.as_slice(),
+            &reporter,
+            &stats,
+            |transaction, location| transactions.push((transaction, location.line)),
+        );
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].0.amount, Some(Decimal::new(15, 1)));
+        assert_eq!(transactions[1].0.tx_type, TransactionType::Withdrawal);
+        assert_eq!(transactions[1].1, Some(3));
+        assert_eq!(stats.counts().unparsable, 2);
+        assert_eq!(reporter.outcome(), crate::report::Outcome::Fatal);
+    }
+}
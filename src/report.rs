@@ -0,0 +1,482 @@
+use csv::ByteRecord;
+use octopus::{ClientID, Transaction, TransactionError, TransactionID, TransactionType};
+use serde::Serialize;
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+};
+
+use crate::stats::Counts;
+
+// How many errors --run-summary lists, the error report has them all
+const SUMMARY_ERRORS: usize = 100;
+
+// How a batch run ended, which decides the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    // Every row was accepted
+    Clean,
+    // Processing completed, but some rows were rejected or unparsable
+    Rejects,
+    // An input could not be read to the end, or the error budget was exhausted
+    Fatal,
+    // Processing completed, but the balances don't match those of --reconcile
+    Mismatch,
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Clean => 0,
+            Outcome::Rejects => 2,
+            Outcome::Fatal => 3,
+            Outcome::Mismatch => 4,
+        }
+    }
+}
+
+// Where a transaction came from, so rejects can be traced back to the input
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub source: Arc<str>,
+    // 1-based, the header is line 1. Parquet inputs give the row number instead.
+    pub line: Option<u64>,
+}
+
+// The row an unparsable transaction was read from, so it can be found in an input too large to
+// search by hand. Empty when the input as a whole couldn't be read.
+#[derive(Debug, Default)]
+pub struct RawRecord {
+    // Offset of the row's first byte in the input, as decompressed
+    pub byte: Option<u64>,
+    // The row's fields as they were read, written back as a CSV line
+    pub text: Option<String>,
+}
+
+impl RawRecord {
+    pub fn new(record: &ByteRecord, byte: Option<u64>) -> Self {
+        // csv leaves records with the wrong field count untrimmed
+        let mut record = record.clone();
+        record.trim();
+        let mut line = csv::Writer::from_writer(Vec::new());
+        line.write_byte_record(&record)
+            .expect("writing to a Vec doesn't fail");
+        let mut line = line.into_inner().expect("writing to a Vec doesn't fail");
+        line.pop();
+        RawRecord {
+            byte,
+            text: Some(String::from_utf8_lossy(&line).into_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorRow<'a> {
+    source: &'a str,
+    line: Option<u64>,
+    byte: Option<u64>,
+    tx: Option<TransactionID>,
+    client: Option<ClientID>,
+    #[serde(rename = "type")]
+    tx_type: Option<&'a TransactionType>,
+    error_code: &'a str,
+    record: Option<&'a str>,
+}
+
+// An ErrorRow kept for --run-summary, with the error message
+#[derive(Debug, Serialize)]
+struct SummaryError {
+    source: String,
+    line: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    byte: Option<u64>,
+    tx: Option<TransactionID>,
+    client: Option<ClientID>,
+    #[serde(rename = "type")]
+    tx_type: Option<TransactionType>,
+    error_code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    outcome: Outcome,
+    exit_code: i32,
+    transactions: Counts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mismatches: Option<u64>,
+    errors: &'a [SummaryError],
+}
+
+// Logs rejected transactions as warnings and, with --error-report, writes one CSV row per reject.
+// Also keeps the error budget of --strict / --max-errors.
+// Shared between shard workers, hence the Mutex and atomics.
+pub struct ErrorReporter {
+    report: Option<Mutex<csv::Writer<Box<dyn Write + Send>>>>,
+    errors: AtomicU64,
+    max_errors: Option<u64>,
+    first_errors: Mutex<Vec<SummaryError>>,
+    fatal: AtomicBool,
+    // Set by a shutdown request, the inputs stop being read but the run ends normally
+    stopped: AtomicBool,
+    // Balances differing from --reconcile's, None without it
+    mismatches: Mutex<Option<u64>>,
+}
+
+impl ErrorReporter {
+    // A path of '-' writes the report to stdout
+    pub fn new(path: Option<&str>, max_errors: Option<u64>) -> io::Result<Self> {
+        let report = match path {
+            Some("-") => Some(Box::new(io::stdout()) as Box<dyn Write + Send>),
+            Some(path) => Some(Box::new(File::create(path)?) as Box<dyn Write + Send>),
+            None => None,
+        };
+        let report = report.map(|output| Mutex::new(csv::Writer::from_writer(output)));
+        Ok(ErrorReporter {
+            report,
+            errors: AtomicU64::new(0),
+            max_errors,
+            first_errors: Mutex::new(Vec::new()),
+            fatal: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            mismatches: Mutex::new(None),
+        })
+    }
+
+    pub fn error_count(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    // True once more errors were seen than tolerated, processing should stop
+    pub fn exhausted(&self) -> bool {
+        self.max_errors
+            .is_some_and(|max_errors| self.error_count() > max_errors)
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    // Input loops check this before every row
+    pub fn halted(&self) -> bool {
+        self.exhausted() || self.stopped()
+    }
+
+    // An input stopped being readable part way through
+    pub fn input_failed(&self) {
+        self.fatal.store(true, Ordering::Relaxed);
+    }
+
+    // Records how many balances --reconcile found to differ
+    pub fn reconciled(&self, mismatches: u64) {
+        *self
+            .mismatches
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(mismatches);
+    }
+
+    fn mismatches(&self) -> Option<u64> {
+        *self
+            .mismatches
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        match self.error_count() {
+            _ if self.exhausted() || self.fatal.load(Ordering::Relaxed) => Outcome::Fatal,
+            _ if self.mismatches().unwrap_or_default() > 0 => Outcome::Mismatch,
+            0 => Outcome::Clean,
+            _ => Outcome::Rejects,
+        }
+    }
+
+    pub fn rejected(&self, transaction: &Transaction, location: &Location, err: &TransactionError) {
+        tracing::warn!(
+            tx = transaction.tx,
+            client = transaction.client,
+            tx_type = ?transaction.tx_type,
+            error_code = err.code(),
+            source = %location.source,
+            line = location.line,
+            "transaction rejected"
+        );
+        self.write(
+            ErrorRow {
+                source: &location.source,
+                line: location.line,
+                byte: None,
+                tx: Some(transaction.tx),
+                client: Some(transaction.client),
+                tx_type: Some(&transaction.tx_type),
+                error_code: err.code(),
+                record: None,
+            },
+            || err.to_string(),
+        );
+    }
+
+    pub fn unparsable(&self, location: &Location, err: &csv::Error, raw: &RawRecord) {
+        let message = unparsable_message(err);
+        tracing::warn!(
+            error_code = "deserialize",
+            source = %location.source,
+            line = location.line,
+            byte = raw.byte,
+            record = raw.text.as_deref(),
+            error = %message,
+            "unparsable transaction"
+        );
+        self.write(
+            ErrorRow {
+                source: &location.source,
+                line: location.line,
+                byte: raw.byte,
+                tx: None,
+                client: None,
+                tx_type: None,
+                error_code: "deserialize",
+                record: raw.text.as_deref(),
+            },
+            || message,
+        );
+    }
+
+    fn write(&self, row: ErrorRow, message: impl FnOnce() -> String) {
+        if self.errors.fetch_add(1, Ordering::Relaxed) < SUMMARY_ERRORS as u64 {
+            self.first_errors
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(SummaryError {
+                    source: row.source.to_string(),
+                    line: row.line,
+                    byte: row.byte,
+                    tx: row.tx,
+                    client: row.client,
+                    tx_type: row.tx_type.cloned(),
+                    error_code: row.error_code.to_string(),
+                    message: message(),
+                    record: row.record.map(str::to_string),
+                });
+        }
+        if let Some(report) = &self.report {
+            let mut wtr = report
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            match wtr.serialize(row) {
+                Ok(()) => (),
+                Err(e) => tracing::error!(error = %e, "failed to write error report"),
+            }
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.report {
+            Some(report) => report
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .flush(),
+            None => Ok(()),
+        }
+    }
+
+    // The JSON document of --run-summary
+    pub fn write_summary(&self, path: &str, counts: Counts) -> io::Result<()> {
+        let outcome = self.outcome();
+        let first_errors = self
+            .first_errors
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(
+            &mut out,
+            &RunSummary {
+                outcome,
+                exit_code: outcome.exit_code(),
+                transactions: counts,
+                mismatches: self.mismatches(),
+                errors: &first_errors,
+            },
+        )?;
+        writeln!(out)?;
+        out.flush()
+    }
+}
+
+// The error without the record position csv puts in front of it, the line and byte reported
+// alongside being those in the whole input even when it was parsed in chunks
+fn unparsable_message(err: &csv::Error) -> String {
+    match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        csv::ErrorKind::UnequalLengths {
+            expected_len, len, ..
+        } => format!(
+            "found record with {} fields, but the previous record has {} fields",
+            len, expected_len
+        ),
+        _ => err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome_follows_errors() {
+        let location = Location {
+            source: Arc::from("test.csv"),
+            line: Some(2),
+        };
+        let transaction = Transaction::new(TransactionType::Withdrawal, 1, 1, None);
+        let reporter = ErrorReporter::new(None, Some(1)).unwrap();
+        assert_eq!(reporter.outcome(), Outcome::Clean);
+        reporter.rejected(&transaction, &location, &TransactionError::MissingAmount);
+        assert_eq!(reporter.outcome(), Outcome::Rejects);
+        reporter.rejected(&transaction, &location, &TransactionError::MissingAmount);
+        assert_eq!(reporter.outcome(), Outcome::Fatal);
+
+        let reporter = ErrorReporter::new(None, None).unwrap();
+        reporter.input_failed();
+        assert_eq!(reporter.outcome().exit_code(), 3);
+    }
+
+    #[test]
+    fn test_unparsable_rows_keep_their_record() {
+        let mut rdr = csv::Reader::from_reader(&b"type,client,tx\ndeposit,1\n"[..]);
+        let mut record = ByteRecord::new();
+        let err = rdr.read_byte_record(&mut record).unwrap_err();
+        let location = Location {
+            source: Arc::from("test.csv"),
+            line: err.position().map(|pos| pos.line()),
+        };
+        let reporter = ErrorReporter::new(None, None).unwrap();
+        let byte = err.position().map(|pos| pos.byte());
+        reporter.unparsable(&location, &err, &RawRecord::new(&record, byte));
+
+        let errors = reporter.first_errors.lock().unwrap();
+        assert_eq!((errors[0].line, errors[0].byte), (Some(2), Some(15)));
+        assert_eq!(errors[0].record.as_deref(), Some("deposit,1"));
+        // The position is in line and byte, not repeated in the message
+        assert_eq!(
+            errors[0].message,
+            "found record with 2 fields, but the previous record has 3 fields"
+        );
+    }
+
+    #[test]
+    fn test_report_has_a_row_per_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("errors.csv");
+        let reporter = ErrorReporter::new(path.to_str(), None).unwrap();
+        let location = Location {
+            source: Arc::from("day.csv"),
+            line: Some(3),
+        };
+        let transaction = Transaction::new(TransactionType::Withdrawal, 4, 9, None);
+        reporter.rejected(&transaction, &location, &TransactionError::MissingAmount);
+        let mut rdr = csv::Reader::from_reader(&b"type,client,tx,amount\ndeposit,1,x,2\n"[..]);
+        let err = rdr
+            .deserialize::<Transaction>()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        let raw = RawRecord {
+            byte: Some(22),
+            text: Some("deposit,1,x,2".to_string()),
+        };
+        reporter.unparsable(&location, &err, &raw);
+        reporter.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "source,line,byte,tx,client,type,error_code,record\n\
+             day.csv,3,,9,4,withdrawal,missing_amount,\n\
+             day.csv,3,22,,,,deserialize,\"deposit,1,x,2\"\n"
+        );
+        assert_eq!(reporter.error_count(), 2);
+    }
+
+    #[test]
+    fn test_stopping_halts_without_failing_the_run() {
+        let reporter = ErrorReporter::new(None, None).unwrap();
+        assert!(!reporter.halted());
+        reporter.stop();
+        assert!(reporter.stopped() && reporter.halted());
+        assert!(!reporter.exhausted());
+        assert_eq!(reporter.outcome(), Outcome::Clean);
+
+        // --strict tolerates no error at all
+        let reporter = ErrorReporter::new(None, Some(0)).unwrap();
+        let location = Location {
+            source: Arc::from("-"),
+            line: None,
+        };
+        let transaction = Transaction::new(TransactionType::Deposit, 1, 1, None);
+        reporter.rejected(&transaction, &location, &TransactionError::MissingAmount);
+        assert!(reporter.exhausted() && reporter.halted());
+        assert!(!reporter.stopped());
+    }
+
+    #[test]
+    fn test_mismatches_decide_the_outcome_unless_fatal() {
+        let reporter = ErrorReporter::new(None, None).unwrap();
+        reporter.reconciled(0);
+        assert_eq!(reporter.outcome(), Outcome::Clean);
+        reporter.reconciled(2);
+        assert_eq!(reporter.outcome(), Outcome::Mismatch);
+        assert_eq!(reporter.outcome().exit_code(), 4);
+        reporter.input_failed();
+        assert_eq!(reporter.outcome(), Outcome::Fatal);
+    }
+
+    #[test]
+    fn test_summary_lists_the_first_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.json");
+        let reporter = ErrorReporter::new(None, None).unwrap();
+        let location = Location {
+            source: Arc::from("day.csv"),
+            line: Some(2),
+        };
+        for tx in 0..150 {
+            let transaction = Transaction::new(TransactionType::Deposit, 1, tx, None);
+            reporter.rejected(&transaction, &location, &TransactionError::MissingAmount);
+        }
+        let counts = Counts {
+            processed: 150,
+            accepted: 0,
+            rejected: 150,
+            unparsable: 0,
+            skipped: 0,
+        };
+        reporter
+            .write_summary(path.to_str().unwrap(), counts)
+            .unwrap();
+
+        let summary: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(summary["outcome"], "rejects");
+        assert_eq!(summary["exit_code"], 2);
+        assert_eq!(summary["transactions"]["rejected"], 150);
+        assert!(summary.get("mismatches").is_none());
+        let errors = summary["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), SUMMARY_ERRORS);
+        assert_eq!(errors[0]["tx"], 0);
+        assert_eq!(errors[0]["error_code"], "missing_amount");
+        assert_eq!(
+            errors[0]["message"],
+            TransactionError::MissingAmount.to_string()
+        );
+        assert!(errors[0].get("record").is_none());
+    }
+}
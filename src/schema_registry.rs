@@ -0,0 +1,214 @@
+use apache_avro::{Schema, from_avro_datum, types::Value};
+use csv::StringRecord;
+use octopus::{
+    Transaction,
+    server::kafka::{self, DecodeError},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, time::Duration};
+
+use crate::avro_input;
+
+// First byte of a value in the Confluent wire format
+const MAGIC: u8 = 0;
+
+// How long fetching a schema may take, each message waiting on it is retried after that
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Decodes Kafka message values in the Confluent wire format, which producers using a schema
+// registry write: the magic byte, the id of the writer schema in the registry as a 4-byte
+// big-endian integer, then the record Avro-encoded with that schema. Record fields stand for
+// the CSV columns as in Avro container files. Each schema is fetched from the registry the
+// first time its id turns up and kept for the rest of the run.
+pub struct SchemaRegistry {
+    // Such as http://registry:8081
+    url: String,
+    agent: ureq::Agent,
+    schemas: HashMap<u32, WriterSchema>,
+}
+
+// A registered schema along with the header and decimal scales its records are read with
+struct WriterSchema {
+    schema: Schema,
+    headers: StringRecord,
+    scales: Vec<Option<u32>>,
+}
+
+impl SchemaRegistry {
+    pub fn new(url: &str) -> Self {
+        SchemaRegistry {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(CONNECT_TIMEOUT)
+                .timeout_read(READ_TIMEOUT)
+                .build(),
+            schemas: HashMap::new(),
+        }
+    }
+
+    // Values starting with the magic byte are in the wire format, others are taken as
+    // kafka::decode takes them, no CSV or JSON text starting with a zero byte. A schema the
+    // registry couldn't be asked for is a DecodeError::Retry, one it doesn't have is invalid.
+    pub async fn decode(&mut self, value: &[u8]) -> Result<Transaction, DecodeError> {
+        match value {
+            [MAGIC, a, b, c, d, datum @ ..] => {
+                let id = u32::from_be_bytes([*a, *b, *c, *d]);
+                if !self.schemas.contains_key(&id) {
+                    let writer = self.fetch(id).await?;
+                    self.schemas.insert(id, writer);
+                }
+                decode_datum(&self.schemas[&id], datum).map_err(DecodeError::Invalid)
+            }
+            [MAGIC, ..] => Err(DecodeError::Invalid(
+                "wire format value without a schema id".to_string(),
+            )),
+            _ => kafka::decode(value),
+        }
+    }
+
+    // The request blocks, so it runs on a thread of tokio's blocking pool rather than holding
+    // up the front-ends sharing the runtime
+    async fn fetch(&self, id: u32) -> Result<WriterSchema, DecodeError> {
+        #[derive(Deserialize)]
+        struct Registered {
+            schema: String,
+        }
+        let request = self.agent.get(&format!("{}/schemas/ids/{}", self.url, id));
+        let registered = tokio::task::spawn_blocking(move || {
+            let response = request.call().map_err(|e| match e {
+                ureq::Error::Status(404, _) => {
+                    DecodeError::Invalid(format!("schema {} is not registered", id))
+                }
+                ureq::Error::Status(status, _) => {
+                    DecodeError::Retry(format!("schema {}: the registry answered {}", id, status))
+                }
+                // The URL may hold a password, so it is kept out of the error
+                ureq::Error::Transport(e) => {
+                    DecodeError::Retry(format!("schema {}: {}", id, e.kind()))
+                }
+            })?;
+            response
+                .into_json::<Registered>()
+                .map_err(|e| DecodeError::Retry(format!("schema {}: {}", id, e)))
+        })
+        .await
+        .map_err(|e| DecodeError::Retry(format!("schema {}: {}", id, e)))??;
+        let invalid = |e: String| DecodeError::Invalid(format!("schema {}: {}", id, e));
+        let schema = Schema::parse_str(&registered.schema).map_err(|e| invalid(e.to_string()))?;
+        let (headers, scales) =
+            avro_input::fields(&schema).ok_or_else(|| invalid("not a record".to_string()))?;
+        Ok(WriterSchema {
+            schema,
+            headers,
+            scales,
+        })
+    }
+}
+
+fn decode_datum(writer: &WriterSchema, mut datum: &[u8]) -> Result<Transaction, String> {
+    let fields = match from_avro_datum(&writer.schema, &mut datum, None) {
+        Ok(Value::Record(fields)) => fields,
+        Ok(_) => return Err("expected a record".to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut row = StringRecord::new();
+    avro_input::row(&fields, &writer.scales, &mut row)?;
+    row.deserialize(Some(&writer.headers))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apache_avro::to_avro_datum;
+    use octopus::TransactionType;
+    use rust_decimal::Decimal;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    const SCHEMA: &str = r#"{
+        "type": "record",
+        "name": "Transaction",
+        "fields": [
+            {"name": "type", "type": "string"},
+            {"name": "client", "type": "int"},
+            {"name": "tx", "type": "long"},
+            {"name": "amount", "type": ["null", "string"]}
+        ]
+    }"#;
+
+    fn framed(id: u32, tx: i64) -> Vec<u8> {
+        let schema = Schema::parse_str(SCHEMA).unwrap();
+        let datum = to_avro_datum(
+            &schema,
+            Value::Record(vec![
+                ("type".into(), Value::String("deposit".into())),
+                ("client".into(), Value::Int(1)),
+                ("tx".into(), Value::Long(tx)),
+                (
+                    "amount".into(),
+                    Value::Union(1, Box::new(Value::String("2.5".into()))),
+                ),
+            ]),
+        )
+        .unwrap();
+        [&[MAGIC][..], &id.to_be_bytes(), &datum].concat()
+    }
+
+    // A registry answering a single request for schema 7, then going away
+    fn registry() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(&stream).lines();
+            let request = lines.next().unwrap().unwrap();
+            assert!(request.starts_with("GET /schemas/ids/7 "), "{}", request);
+            for line in lines {
+                if line.unwrap().is_empty() {
+                    break;
+                }
+            }
+            let body = serde_json::json!({ "schema": SCHEMA }).to_string();
+            write!(
+                &stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_wire_format_values_use_the_registered_schema() {
+        let mut registry = SchemaRegistry::new(&registry());
+        let transaction = registry.decode(&framed(7, 1)).await.unwrap();
+        assert_eq!(transaction.tx_type, TransactionType::Deposit);
+        assert_eq!((transaction.client, transaction.tx), (1, 1));
+        assert_eq!(transaction.amount, Some(Decimal::new(25, 1)));
+        // The registry is gone by now, the schema was kept
+        assert_eq!(registry.decode(&framed(7, 2)).await.unwrap().tx, 2);
+        assert!(registry.decode(&[MAGIC, 0, 0]).await.is_err());
+        assert_eq!(registry.decode(b"deposit,1,3,1").await.unwrap().tx, 3);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_registry_is_retried() {
+        // Nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let mut registry = SchemaRegistry::new(&url);
+        assert!(matches!(
+            registry.decode(&[MAGIC, 0, 0, 0, 7]).await,
+            Err(DecodeError::Retry(_))
+        ));
+        assert!(registry.schemas.is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+use crate::engine::TransactionError;
+
+// Transactions the front-ends hold in flight by default, like AsyncDatabase's queue
+pub const DEFAULT_QUEUE_CAPACITY: usize = 4096;
+
+// Bounds how many transactions the front-ends together may have waiting for the Database, so a
+// burst can't pile up requests in memory faster than the engine applies them. Each transaction
+// holds a permit until it is answered. HTTP and gRPC turn callers away with
+// TransactionError::QueueFull when there are none left, TCP stops reading the connection until
+// one frees up, which pushes back on the producer through TCP's own flow control.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    permits: Arc<Semaphore>,
+    capacity: usize,
+}
+
+impl Admission {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Admission {
+            permits: Arc::new(Semaphore::new(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Admits `count` transactions at once, or none of them. More than the capacity is never
+    // admitted.
+    pub fn try_admit(&self, count: usize) -> Result<OwnedSemaphorePermit, TransactionError> {
+        if count > self.capacity {
+            return Err(TransactionError::QueueFull);
+        }
+        match Arc::clone(&self.permits).try_acquire_many_owned(count as u32) {
+            Ok(permit) => Ok(permit),
+            Err(TryAcquireError::NoPermits) => Err(TransactionError::QueueFull),
+            Err(TryAcquireError::Closed) => Err(TransactionError::EngineStopped),
+        }
+    }
+
+    // Waits until one transaction can be admitted
+    pub async fn admit(&self) -> Result<OwnedSemaphorePermit, TransactionError> {
+        Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .map_err(|_| TransactionError::EngineStopped)
+    }
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Admission::new(DEFAULT_QUEUE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admission_is_bounded() {
+        let admission = Admission::new(3);
+        let batch = admission.try_admit(2).unwrap();
+        let one = admission.try_admit(1).unwrap();
+        assert!(matches!(
+            admission.try_admit(1),
+            Err(TransactionError::QueueFull)
+        ));
+        assert!(matches!(
+            admission.try_admit(4),
+            Err(TransactionError::QueueFull)
+        ));
+        drop(batch);
+        let _two = admission.try_admit(2).unwrap();
+
+        // A waiting producer gets in once a permit is released
+        let waiting = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit().await.map(drop) }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        drop(one);
+        assert!(waiting.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use crate::engine::{Transaction, TransactionError};
+
+const HEADER: [&str; 10] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "to_client",
+    "timestamp",
+    "currency",
+    "to_currency",
+    "rate",
+    "error_code",
+];
+
+// Where the TCP and Kafka front-ends write the transactions they reject, so they can be repaired
+// and fed again rather than just answered and forgotten. The file is CSV with the input's
+// columns and the error code last, a column inputs ignore, so it replays as is. Rows are handed
+// to a thread of their own that appends and flushes them one at a time, so the front-ends never
+// wait on the file; clones share the thread. The default writes nowhere.
+#[derive(Debug, Clone, Default)]
+pub struct DeadLetters {
+    sender: Option<Sender<Letter>>,
+}
+
+enum Letter {
+    Rejected(Transaction, &'static str),
+    // The values of an input that isn't a transaction, in the input's column order
+    Unparsable(Vec<String>),
+    // Answered once every row sent before is written
+    Flush(Sender<()>),
+}
+
+impl DeadLetters {
+    // Appends to the file, writing the header first if it is new or empty
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(BufWriter::new(file));
+        if empty {
+            writer.write_record(HEADER)?;
+            writer.flush()?;
+        }
+        let (sender, letters) = mpsc::channel();
+        thread::Builder::new()
+            .name("dead-letters".to_string())
+            .spawn(move || write_letters(writer, letters))?;
+        Ok(DeadLetters {
+            sender: Some(sender),
+        })
+    }
+
+    pub fn write(&self, transaction: &Transaction, err: &TransactionError) -> io::Result<()> {
+        self.send(Letter::Rejected(transaction.clone(), err.code()))
+    }
+
+    // An input the front-end couldn't read as a transaction, error code 'unparsable'. The values
+    // are those of the input columns in order, cut or padded to their number.
+    pub fn write_unparsable(&self, values: Vec<String>) -> io::Result<()> {
+        self.send(Letter::Unparsable(values))
+    }
+
+    // Blocks until every row written so far is in the file, such as before exiting
+    pub fn flush(&self) -> io::Result<()> {
+        if self.sender.is_none() {
+            return Ok(());
+        }
+        let (done, flushed) = mpsc::channel();
+        self.send(Letter::Flush(done))?;
+        flushed.recv().map_err(|_| stopped())
+    }
+
+    fn send(&self, letter: Letter) -> io::Result<()> {
+        match &self.sender {
+            Some(sender) => sender.send(letter).map_err(|_| stopped()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("dead-letter writer stopped")
+}
+
+// Runs until the last DeadLetters is dropped. A row that fails to be written is logged and the
+// next one tried, the file may be back by then.
+fn write_letters(mut writer: csv::Writer<BufWriter<File>>, letters: Receiver<Letter>) {
+    for letter in letters {
+        let written = match letter {
+            Letter::Rejected(transaction, code) => writer.serialize((transaction, code)),
+            Letter::Unparsable(mut values) => {
+                values.resize(HEADER.len() - 1, String::new());
+                values.push("unparsable".to_string());
+                writer.write_record(values)
+            }
+            Letter::Flush(done) => {
+                let _ = done.send(());
+                continue;
+            }
+        };
+        if let Err(err) = written
+            .map_err(io::Error::from)
+            .and_then(|()| writer.flush())
+        {
+            tracing::error!(%err, "dead-letter write failed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_rejects_are_appended() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.csv");
+        let withdrawal = Transaction::new(TransactionType::Withdrawal, 1, 2, Some(dec!(5.5)))
+            .with_timestamp(Some(7));
+        let dead_letters = DeadLetters::open(&path).unwrap();
+        dead_letters
+            .write(
+                &withdrawal,
+                &TransactionError::AccountError(crate::engine::AccountError::InsufficientFunds),
+            )
+            .unwrap();
+        dead_letters.flush().unwrap();
+        drop(dead_letters);
+        // Reopening appends below the rows already there
+        let dead_letters = DeadLetters::open(&path).unwrap();
+        dead_letters
+            .write(&withdrawal, &TransactionError::Duplicate)
+            .unwrap();
+        dead_letters
+            .write_unparsable(vec!["deposit".to_string(), "one".to_string()])
+            .unwrap();
+        dead_letters.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,to_client,timestamp,currency,to_currency,rate,error_code\n\
+             withdrawal,1,2,5.5,,7,,,,insufficient_funds\n\
+             withdrawal,1,2,5.5,,7,,,,duplicate\n\
+             deposit,one,,,,,,,,unparsable\n"
+        );
+        assert!(
+            DeadLetters::default()
+                .write(&withdrawal, &TransactionError::Duplicate)
+                .is_ok()
+        );
+        assert!(DeadLetters::default().flush().is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{future::Future, sync::Arc};
+use tokio::sync::{broadcast, watch};
+
+use crate::engine::{AccountRow, PrecisionPolicy, TransactionEffect};
+
+// Events a subscriber may fall behind by before it starts missing some
+const CAPACITY: usize = 1024;
+
+// Account changes, fanned out to every subscriber of GET /ws. One event is an account's
+// balance in one currency as it is after an accepted transaction.
+#[derive(Clone)]
+pub struct AccountEvents {
+    sender: broadcast::Sender<AccountRow>,
+    // Set on shutdown, so subscriptions end rather than keep the server up
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl Default for AccountEvents {
+    fn default() -> Self {
+        AccountEvents {
+            sender: broadcast::channel(CAPACITY).0,
+            closed: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl AccountEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AccountRow> {
+        self.sender.subscribe()
+    }
+
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    // Resolves once close() was called, even if that was before
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut closed = self.closed.subscribe();
+        async move {
+            let _ = closed.wait_for(|&closed| closed).await;
+        }
+    }
+
+    // Publishes every balance of the accounts an accepted transaction touched, as its effects
+    // left them. Rows are only built when someone is listening.
+    pub fn publish(&self, precision: PrecisionPolicy, effects: &[TransactionEffect]) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        for effect in effects {
+            for row in AccountRow::rows(precision, effect.client, &effect.new) {
+                // Fails only when nobody is subscribed
+                let _ = self.sender.send(row);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Database, Transaction, TransactionType};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn test_transfers_publish_both_accounts() {
+        let (events, mut db) = (AccountEvents::new(), Database::default());
+        let mut transaction =
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(Decimal::from(5)));
+        let effects = db.process_with_effect(&transaction).unwrap();
+        // Nobody listens yet, nothing is kept
+        events.publish(db.precision(), &effects);
+
+        let mut receiver = events.subscribe();
+        transaction.tx_type = TransactionType::Transfer;
+        transaction.tx = 2;
+        transaction.amount = Some(Decimal::from(2));
+        transaction.to_client = Some(2);
+        let effects = db.process_with_effect(&transaction).unwrap();
+        events.publish(db.precision(), &effects);
+
+        let source = receiver.try_recv().unwrap();
+        assert_eq!(
+            (source.client, source.available.to_string().as_str()),
+            (1, "3.0000")
+        );
+        let destination = receiver.try_recv().unwrap();
+        assert_eq!(
+            (destination.client, destination.total.to_string().as_str()),
+            (2, "2.0000")
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,381 @@
+// tonic::Status is large, but it is what every handler has to return anyway
+#![allow(clippy::result_large_err)]
+
+use rust_decimal::Decimal;
+use std::{future::Future, net::SocketAddr, str::FromStr, sync::Arc};
+use tonic::{Request, Response, Status, transport::Server};
+
+use super::{SharedDatabase, admission::Admission, events::AccountEvents, metrics::Metrics};
+use crate::engine::{
+    Account, AccountError, ClientID, Currency, PrecisionPolicy, Transaction, TransactionError,
+    TransactionType,
+};
+
+pub mod proto {
+    tonic::include_proto!("octopus");
+}
+
+use proto::payments_engine_server::{PaymentsEngine, PaymentsEngineServer};
+
+pub struct GrpcService {
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    admission: Admission,
+}
+
+impl GrpcService {
+    pub fn new(db: SharedDatabase) -> Self {
+        GrpcService {
+            db,
+            metrics: Arc::new(Metrics::new()),
+            events: AccountEvents::new(),
+            admission: Admission::default(),
+        }
+    }
+
+    // Counts transactions in metrics shared with the HTTP server
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Publishes account changes to the HTTP server's /ws subscribers
+    pub fn with_events(mut self, events: AccountEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    // Shares the bound on transactions in flight with the other front-ends
+    pub fn with_admission(mut self, admission: Admission) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    pub fn into_server(self) -> PaymentsEngineServer<Self> {
+        PaymentsEngineServer::new(self)
+    }
+}
+
+// Serves the PaymentsEngine service until shutdown resolves, letting calls in flight finish
+pub async fn serve(
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    admission: Admission,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send,
+) -> Result<(), tonic::transport::Error> {
+    let service = GrpcService::new(db)
+        .with_metrics(metrics)
+        .with_events(events)
+        .with_admission(admission);
+    Server::builder()
+        .add_service(service.into_server())
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+#[tonic::async_trait]
+impl PaymentsEngine for GrpcService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let transaction = Transaction::try_from(request.into_inner())?;
+        let _permit = self.admission.try_admit(1).map_err(|e| status_for(&e))?;
+        // Published under the shard's lock, so a client's updates go out in order
+        self.db
+            .with_transaction(&transaction, |db| {
+                let effects = self.metrics.process(db, &transaction)?;
+                self.events.publish(db.precision(), &effects);
+                Ok(())
+            })
+            .map_err(|err| status_for(&err))?;
+        Ok(Response::new(proto::SubmitTransactionResponse {}))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let request = request.into_inner();
+        let client = client_id(request.client)?;
+        let currency = request.currency.map(currency).transpose()?;
+        match self.db.account(client) {
+            Ok(Some(account)) => Ok(Response::new(to_proto(
+                self.db.precision(),
+                client,
+                currency,
+                &account,
+            ))),
+            Ok(None) => Err(Status::not_found(format!("client {} not found", client))),
+            Err(err) => Err(Status::internal(format!("{:?}", err))),
+        }
+    }
+
+    type StreamAccountsStream =
+        tokio_stream::Iter<std::vec::IntoIter<Result<proto::Account, Status>>>;
+
+    async fn stream_accounts(
+        &self,
+        _request: Request<proto::StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        // Read up front so the stream doesn't hold any lock while the client reads
+        let accounts = self
+            .db
+            .accounts()
+            .map_err(|err| Status::internal(format!("{:?}", err)))?;
+        let precision = self.db.precision();
+        let replies: Vec<_> = accounts
+            .iter()
+            .flat_map(|(client, account)| {
+                account
+                    .balances()
+                    .map(|(currency, _)| Ok(to_proto(precision, *client, currency, account)))
+            })
+            .collect();
+        Ok(Response::new(tokio_stream::iter(replies)))
+    }
+}
+
+fn client_id(client: u32) -> Result<ClientID, Status> {
+    ClientID::try_from(client)
+        .map_err(|_| Status::invalid_argument(format!("client {} is out of range", client)))
+}
+
+fn decimal(field: &str, value: String) -> Result<Decimal, Status> {
+    Decimal::from_str(&value)
+        .map_err(|_| Status::invalid_argument(format!("{} '{}' is not a decimal", field, value)))
+}
+
+fn currency(code: String) -> Result<Currency, Status> {
+    Currency::new(&code)
+        .ok_or_else(|| Status::invalid_argument(format!("currency '{}' is not a code", code)))
+}
+
+impl TryFrom<proto::Transaction> for Transaction {
+    type Error = Status;
+
+    fn try_from(message: proto::Transaction) -> Result<Self, Status> {
+        let tx_type = match proto::TransactionType::try_from(message.r#type) {
+            Ok(proto::TransactionType::Deposit) => TransactionType::Deposit,
+            Ok(proto::TransactionType::Withdrawal) => TransactionType::Withdrawal,
+            Ok(proto::TransactionType::Dispute) => TransactionType::Dispute,
+            Ok(proto::TransactionType::Resolve) => TransactionType::Resolve,
+            Ok(proto::TransactionType::Chargeback) => TransactionType::Chargeback,
+            Ok(proto::TransactionType::Transfer) => TransactionType::Transfer,
+            Ok(proto::TransactionType::Unlock) => TransactionType::Unlock,
+            Ok(proto::TransactionType::Convert) => TransactionType::Convert,
+            Ok(proto::TransactionType::Fee) => TransactionType::Fee,
+            Ok(proto::TransactionType::Open) => TransactionType::Open,
+            Ok(proto::TransactionType::Close) => TransactionType::Close,
+            Ok(proto::TransactionType::Reversal) => TransactionType::Reversal,
+            Ok(proto::TransactionType::Settle) => TransactionType::Settle,
+            Err(_) => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown transaction type {}",
+                    message.r#type
+                )));
+            }
+        };
+        Ok(Transaction {
+            tx_type,
+            client: client_id(message.client)?,
+            tx: message.tx,
+            amount: message
+                .amount
+                .map(|amount| decimal("amount", amount))
+                .transpose()?,
+            to_client: message.to_client.map(client_id).transpose()?,
+            timestamp: message.timestamp,
+            currency: message.currency.map(currency).transpose()?,
+            to_currency: message.to_currency.map(currency).transpose()?,
+            rate: message.rate.map(|rate| decimal("rate", rate)).transpose()?,
+        })
+    }
+}
+
+fn to_proto(
+    precision: PrecisionPolicy,
+    client: ClientID,
+    currency: Option<Currency>,
+    account: &Account,
+) -> proto::Account {
+    let balance = account.balance(currency);
+    proto::Account {
+        client: client.into(),
+        available: precision.format(balance.available()),
+        held: precision.format(balance.held()),
+        total: precision.format(balance.total()),
+        locked: account.is_locked(),
+        currency: currency.map(|currency| currency.to_string()),
+    }
+}
+
+// The status message is the engine's stable error code
+fn status_for(err: &TransactionError) -> Status {
+    let code = err.code();
+    match err {
+        TransactionError::NegativeAmount
+        | TransactionError::AmountTooLarge
+        | TransactionError::AmountTooPrecise
+        | TransactionError::UnknownType
+        | TransactionError::MissingAmount
+        | TransactionError::MissingDestination
+        | TransactionError::MissingTimestamp
+        | TransactionError::InvalidRate
+        | TransactionError::InvalidTransfer => Status::invalid_argument(code),
+        TransactionError::Duplicate => Status::already_exists(code),
+        TransactionError::ReferenceNotFound | TransactionError::AccountNotFound => {
+            Status::not_found(code)
+        }
+        TransactionError::ReferencePruned => Status::not_found(code),
+        TransactionError::AdminOpsDisabled | TransactionError::ClientBlocked => {
+            Status::permission_denied(code)
+        }
+        TransactionError::InvalidDispute
+        | TransactionError::CrossShard
+        | TransactionError::OutOfOrder
+        | TransactionError::DisputeWindowExpired
+        | TransactionError::DisputeLimitReached
+        | TransactionError::DisputeTooLarge
+        | TransactionError::InvalidReversal
+        | TransactionError::LimitExceeded(_)
+        | TransactionError::RejectedByHook(_)
+        | TransactionError::CurrencyMismatch
+        | TransactionError::AccountError(AccountError::Locked)
+        | TransactionError::AccountError(AccountError::InsufficientFunds)
+        | TransactionError::AccountError(AccountError::NotLocked)
+        | TransactionError::AccountError(AccountError::Overflow)
+        | TransactionError::AccountNotOpen
+        | TransactionError::AccountError(AccountError::FeeFloorExceeded)
+        | TransactionError::AccountError(AccountError::Closed)
+        | TransactionError::AccountError(AccountError::AlreadyOpen)
+        | TransactionError::AccountError(AccountError::NonZeroBalance) => {
+            Status::failed_precondition(code)
+        }
+        TransactionError::QueueFull => Status::resource_exhausted(code),
+        TransactionError::EngineStopped => Status::unavailable(code),
+        TransactionError::Storage(_) => Status::internal(code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ConcurrentDatabase, Database};
+    use tokio_stream::StreamExt;
+
+    fn service() -> GrpcService {
+        GrpcService::new(Arc::new(ConcurrentDatabase::new(1, Database::default)))
+    }
+
+    fn deposit(client: u32, tx: u32, amount: &str) -> Request<proto::Transaction> {
+        Request::new(proto::Transaction {
+            r#type: proto::TransactionType::Deposit.into(),
+            client,
+            tx,
+            amount: Some(amount.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_submit_and_get_account() {
+        let service = service();
+        service
+            .submit_transaction(deposit(1, 1, "10.5"))
+            .await
+            .unwrap();
+
+        let account = service
+            .get_account(Request::new(proto::GetAccountRequest {
+                client: 1,
+                currency: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(account.available, "10.5000");
+        assert_eq!(account.total, "10.5000");
+        assert!(!account.locked);
+    }
+
+    #[tokio::test]
+    async fn test_rejections_map_to_status_codes() {
+        let service = service();
+        service
+            .submit_transaction(deposit(1, 1, "1"))
+            .await
+            .unwrap();
+
+        let duplicate = service.submit_transaction(deposit(1, 1, "1")).await;
+        assert_eq!(duplicate.unwrap_err().code(), tonic::Code::AlreadyExists);
+        let bad_amount = service.submit_transaction(deposit(1, 2, "abc")).await;
+        assert_eq!(bad_amount.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let bad_client = service.submit_transaction(deposit(70_000, 3, "1")).await;
+        assert_eq!(bad_client.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let missing = service
+            .get_account(Request::new(proto::GetAccountRequest {
+                client: 2,
+                currency: None,
+            }))
+            .await;
+        assert_eq!(missing.unwrap_err().code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_stream_accounts_sorted_by_client() {
+        let service = service();
+        for (client, tx) in [(3, 1), (1, 2), (2, 3)] {
+            service
+                .submit_transaction(deposit(client, tx, "1"))
+                .await
+                .unwrap();
+        }
+        let stream = service
+            .stream_accounts(Request::new(proto::StreamAccountsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let clients: Vec<u32> = stream
+            .map(|account| account.unwrap().client)
+            .collect()
+            .await;
+        assert_eq!(clients, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_currencies_are_reported_separately() {
+        let service = service();
+        let mut eur = deposit(1, 1, "2");
+        eur.get_mut().currency = Some("eur".to_string());
+        service.submit_transaction(eur).await.unwrap();
+        service
+            .submit_transaction(deposit(1, 2, "1"))
+            .await
+            .unwrap();
+
+        let account = service
+            .get_account(Request::new(proto::GetAccountRequest {
+                client: 1,
+                currency: Some("EUR".to_string()),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(account.available, "2.0000");
+        assert_eq!(account.currency.as_deref(), Some("EUR"));
+        let stream = service
+            .stream_accounts(Request::new(proto::StreamAccountsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let currencies: Vec<Option<String>> = stream
+            .map(|account| account.unwrap().currency)
+            .collect()
+            .await;
+        assert_eq!(currencies, vec![None, Some("EUR".to_string())]);
+    }
+}
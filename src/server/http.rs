@@ -0,0 +1,462 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+
+use super::{SharedDatabase, admission::Admission, events::AccountEvents, metrics::Metrics};
+use crate::engine::{
+    AccountError, AccountRow, ClientID, Currency, Transaction, TransactionError, TransactionID,
+};
+use crate::storage::StorageError;
+use tokio::sync::broadcast::error::RecvError;
+
+// The outcome of one transaction of POST /transactions/batch, its error code if rejected
+#[derive(Debug, Serialize)]
+pub struct BatchItemJson {
+    pub tx: TransactionID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+// Rejections carry the engine's stable error code
+#[derive(Debug)]
+pub enum ApiError {
+    Transaction(TransactionError),
+    AccountNotFound,
+    // A batch larger than the ingestion queue, which no amount of waiting would admit
+    BatchTooLarge,
+}
+
+impl From<StorageError> for ApiError {
+    fn from(err: StorageError) -> Self {
+        ApiError::Transaction(TransactionError::Storage(err))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code) = match &self {
+            ApiError::Transaction(err) => (status_for(err), err.code()),
+            ApiError::AccountNotFound => (StatusCode::NOT_FOUND, "account_not_found"),
+            ApiError::BatchTooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "batch_too_large"),
+        };
+        (status, Json(serde_json::json!({ "error": code }))).into_response()
+    }
+}
+
+pub fn router(
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    admission: Admission,
+) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transaction))
+        .route("/transactions/batch", post(submit_batch))
+        .route("/accounts", get(list_accounts))
+        .route("/accounts/:client", get(get_account))
+        .route("/metrics", get(get_metrics))
+        .route("/ws", get(account_updates))
+        .layer(Extension(metrics))
+        .layer(Extension(events))
+        .layer(Extension(admission))
+        .with_state(db)
+}
+
+// Serves the REST API until shutdown resolves
+pub async fn serve(
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    admission: Admission,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let closing = events.clone();
+    // Requests in flight are answered, /ws subscriptions are closed
+    axum::serve(listener, router(db, metrics, events, admission))
+        .with_graceful_shutdown(async move {
+            shutdown.await;
+            closing.close();
+        })
+        .await
+}
+
+async fn submit_transaction(
+    State(db): State<SharedDatabase>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(events): Extension<AccountEvents>,
+    Extension(admission): Extension<Admission>,
+    Json(transaction): Json<Transaction>,
+) -> Result<StatusCode, ApiError> {
+    let _permit = admission.try_admit(1).map_err(ApiError::Transaction)?;
+    process(&db, &metrics, &events, &transaction).map_err(ApiError::Transaction)?;
+    Ok(StatusCode::CREATED)
+}
+
+// Changes are published under the shard's lock, so a client's updates go out in order
+fn process(
+    db: &SharedDatabase,
+    metrics: &Metrics,
+    events: &AccountEvents,
+    transaction: &Transaction,
+) -> Result<(), TransactionError> {
+    db.with_transaction(transaction, |db| {
+        let effects = metrics.process(db, transaction)?;
+        events.publish(db.precision(), &effects);
+        Ok(())
+    })
+}
+
+// Applies the transactions in order, answering 200 with one item per transaction at the same
+// index. The shards the batch touches stay locked until it is through, so no transaction of
+// another request applies in between. Rejections don't stop the rest of the batch. A batch is
+// admitted whole or answered 429, and one larger than the queue capacity is answered 413.
+async fn submit_batch(
+    State(db): State<SharedDatabase>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+    Extension(events): Extension<AccountEvents>,
+    Extension(admission): Extension<Admission>,
+    Json(transactions): Json<Vec<Transaction>>,
+) -> Result<Json<Vec<BatchItemJson>>, ApiError> {
+    if transactions.len() > admission.capacity() {
+        return Err(ApiError::BatchTooLarge);
+    }
+    let _permit = admission
+        .try_admit(transactions.len())
+        .map_err(ApiError::Transaction)?;
+    let results = db.with_batch(&transactions, |db, transaction| {
+        let effects = metrics.process(db, transaction)?;
+        events.publish(db.precision(), &effects);
+        Ok(())
+    });
+    let items = transactions
+        .iter()
+        .zip(results)
+        .map(|(transaction, result)| BatchItemJson {
+            tx: transaction.tx,
+            error: result.err().map(|err| err.code()),
+        })
+        .collect();
+    Ok(Json(items))
+}
+
+// Streams every account change as a JSON text message, like the rows of GET /accounts. A
+// subscriber too slow to keep up skips the changes it missed rather than holding up the engine.
+async fn account_updates(
+    ws: WebSocketUpgrade,
+    Extension(events): Extension<AccountEvents>,
+) -> Response {
+    let (receiver, closed) = (events.subscribe(), events.closed());
+    ws.on_upgrade(|socket| forward_updates(socket, receiver, closed))
+}
+
+async fn forward_updates(
+    mut socket: WebSocket,
+    mut receiver: tokio::sync::broadcast::Receiver<AccountRow>,
+    closed: impl Future<Output = ()>,
+) {
+    let mut closed = std::pin::pin!(closed);
+    loop {
+        let received = tokio::select! {
+            received = receiver.recv() => received,
+            () = &mut closed => {
+                let _ = socket.send(Message::Close(None)).await;
+                return;
+            }
+        };
+        let update = match received {
+            Ok(update) => update,
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "/ws subscriber lagging, account updates dropped");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let Ok(text) = serde_json::to_string(&update) else {
+            continue;
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountQuery {
+    // The balance to report, the one without a currency if absent
+    currency: Option<Currency>,
+}
+
+async fn get_account(
+    State(db): State<SharedDatabase>,
+    Path(client): Path<ClientID>,
+    Query(query): Query<AccountQuery>,
+) -> Result<Json<AccountRow>, ApiError> {
+    match db.account(client)? {
+        Some(account) => Ok(Json(AccountRow::new(
+            db.precision(),
+            client,
+            query.currency,
+            &account,
+        ))),
+        None => Err(ApiError::AccountNotFound),
+    }
+}
+
+// Every account sorted by client ID, once per currency it holds
+async fn list_accounts(
+    State(db): State<SharedDatabase>,
+) -> Result<Json<Vec<AccountRow>>, ApiError> {
+    let accounts = db.accounts()?;
+    Ok(Json(
+        accounts
+            .iter()
+            .flat_map(|(client, account)| AccountRow::rows(db.precision(), *client, account))
+            .collect(),
+    ))
+}
+
+// Prometheus text format
+async fn get_metrics(
+    State(db): State<SharedDatabase>,
+    Extension(metrics): Extension<Arc<Metrics>>,
+) -> Result<([(&'static str, &'static str); 1], String), ApiError> {
+    let rendered = metrics.render(&db)?;
+    Ok(([("content-type", "text/plain; version=0.0.4")], rendered))
+}
+
+fn status_for(err: &TransactionError) -> StatusCode {
+    match err {
+        TransactionError::NegativeAmount
+        | TransactionError::AmountTooLarge
+        | TransactionError::AmountTooPrecise
+        | TransactionError::UnknownType
+        | TransactionError::MissingAmount
+        | TransactionError::MissingDestination
+        | TransactionError::MissingTimestamp
+        | TransactionError::InvalidRate
+        | TransactionError::InvalidTransfer => StatusCode::BAD_REQUEST,
+        TransactionError::Duplicate => StatusCode::CONFLICT,
+        TransactionError::ReferenceNotFound | TransactionError::AccountNotFound => {
+            StatusCode::NOT_FOUND
+        }
+        TransactionError::ReferencePruned => StatusCode::GONE,
+        TransactionError::AdminOpsDisabled | TransactionError::ClientBlocked => {
+            StatusCode::FORBIDDEN
+        }
+        TransactionError::InvalidDispute
+        | TransactionError::CrossShard
+        | TransactionError::OutOfOrder
+        | TransactionError::DisputeWindowExpired
+        | TransactionError::DisputeLimitReached
+        | TransactionError::DisputeTooLarge
+        | TransactionError::InvalidReversal
+        | TransactionError::LimitExceeded(_)
+        | TransactionError::RejectedByHook(_)
+        | TransactionError::CurrencyMismatch
+        | TransactionError::AccountError(AccountError::Locked)
+        | TransactionError::AccountError(AccountError::InsufficientFunds)
+        | TransactionError::AccountError(AccountError::NotLocked)
+        | TransactionError::AccountError(AccountError::Overflow)
+        | TransactionError::AccountNotOpen
+        | TransactionError::AccountError(AccountError::FeeFloorExceeded)
+        | TransactionError::AccountError(AccountError::Closed)
+        | TransactionError::AccountError(AccountError::AlreadyOpen)
+        | TransactionError::AccountError(AccountError::NonZeroBalance) => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+        TransactionError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+        TransactionError::EngineStopped => StatusCode::SERVICE_UNAVAILABLE,
+        TransactionError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ConcurrentDatabase, Database};
+    use axum::body::{Body, to_bytes};
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    async fn send(router: &Router, method: &str, uri: &str, body: &str) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_post_transaction_and_get_account() {
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+        );
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"10.5"}"#;
+        assert_eq!(
+            send(&router, "POST", "/transactions", deposit).await.0,
+            StatusCode::CREATED
+        );
+
+        let (status, body) = send(&router, "GET", "/accounts/1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"{"client":1,"available":"10.5000","held":"0.0000","total":"10.5000","locked":false}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_map_to_status_codes() {
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+        );
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"1"}"#;
+        send(&router, "POST", "/transactions", deposit).await;
+
+        let (status, body) = send(&router, "POST", "/transactions", deposit).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body, r#"{"error":"duplicate"}"#);
+        let withdrawal = r#"{"type":"withdrawal","client":1,"tx":2,"amount":"5"}"#;
+        let (status, _) = send(&router, "POST", "/transactions", withdrawal).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            send(&router, "GET", "/accounts/2", "").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_answers_per_transaction() {
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+        );
+        let batch = r#"[
+            {"type":"deposit","client":1,"tx":1,"amount":"4"},
+            {"type":"withdrawal","client":1,"tx":2,"amount":"5"},
+            {"type":"withdrawal","client":1,"tx":3,"amount":"1.5"}
+        ]"#;
+        let (status, body) = send(&router, "POST", "/transactions/batch", batch).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            r#"[{"tx":1},{"tx":2,"error":"insufficient_funds"},{"tx":3}]"#
+        );
+        let (_, body) = send(&router, "GET", "/accounts/1", "").await;
+        assert!(body.contains(r#""available":"2.5000""#));
+    }
+
+    #[tokio::test]
+    async fn test_list_accounts_sorted() {
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+        );
+        for (client, tx) in [(3, 1), (1, 2)] {
+            let deposit = format!(
+                r#"{{"type":"deposit","client":{},"tx":{},"amount":"1"}}"#,
+                client, tx
+            );
+            send(&router, "POST", "/transactions", &deposit).await;
+        }
+        let (status, body) = send(&router, "GET", "/accounts", "").await;
+        assert_eq!(status, StatusCode::OK);
+        let accounts: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(accounts[0]["client"], 1);
+        assert_eq!(accounts[1]["client"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_currency_balances() {
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+        );
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"3","currency":"usd"}"#;
+        send(&router, "POST", "/transactions", deposit).await;
+
+        let (_, body) = send(&router, "GET", "/accounts/1?currency=USD", "").await;
+        assert_eq!(
+            body,
+            r#"{"client":1,"currency":"USD","available":"3.0000","held":"0.0000","total":"3.0000","locked":false}"#
+        );
+        let (_, body) = send(&router, "GET", "/accounts/1", "").await;
+        assert!(body.contains(r#""available":"0.0000""#));
+        let (status, _) = send(&router, "GET", "/accounts/1?currency=dollars", "").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_count_submissions() {
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+        );
+        let withdrawal = r#"{"type":"withdrawal","client":1,"tx":1,"amount":"5"}"#;
+        send(&router, "POST", "/transactions", withdrawal).await;
+
+        let (status, body) = send(&router, "GET", "/metrics", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(
+            r#"octopus_transactions_total{type="withdrawal",outcome="insufficient_funds"} 1"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_answers_429() {
+        let admission = Admission::new(2);
+        let router = router(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            admission.clone(),
+        );
+        let batch = r#"[
+            {"type":"deposit","client":1,"tx":1,"amount":"1"},
+            {"type":"deposit","client":1,"tx":2,"amount":"1"},
+            {"type":"deposit","client":1,"tx":3,"amount":"1"}
+        ]"#;
+        let (status, body) = send(&router, "POST", "/transactions/batch", batch).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body, r#"{"error":"batch_too_large"}"#);
+
+        // Other requests in flight hold the queue
+        let in_flight = admission.try_admit(2).unwrap();
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"1"}"#;
+        let (status, _) = send(&router, "POST", "/transactions", deposit).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        drop(in_flight);
+        let (status, _) = send(&router, "POST", "/transactions", deposit).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+}
@@ -0,0 +1,329 @@
+use rdkafka::{
+    ClientConfig, Message,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    error::{KafkaError, KafkaResult, RDKafkaErrorCode},
+};
+use std::{future::Future, sync::Arc, time::Duration};
+
+use super::{
+    SharedDatabase,
+    dead_letter::DeadLetters,
+    events::AccountEvents,
+    line::{columns, parse},
+    metrics::Metrics,
+};
+use crate::engine::{ConcurrentDatabase, Transaction, TransactionError};
+
+// Waits between two tries of a message that failed for a reason that may go away, doubling up
+// to the longest
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+// Why a message value couldn't be turned into a transaction
+#[derive(Debug)]
+pub enum DecodeError {
+    // The value isn't a transaction, it is skipped
+    Invalid(String),
+    // It may be one later, such as once a schema registry is reachable again, so the message is
+    // tried again
+    Retry(String),
+}
+
+// How applying a message failed, other than with a rejected transaction
+enum Failure {
+    Retry(String),
+    Stop(String),
+}
+
+// A consumer of the topics in the group that leaves committing offsets to consume(). Brokers
+// are host:port pairs separated by commas. A group new to the topics starts from their
+// earliest messages.
+pub fn consumer(brokers: &str, group: &str, topics: &[String]) -> KafkaResult<StreamConsumer> {
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", group)
+        .set("enable.auto.commit", "false")
+        .set("enable.auto.offset.store", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    consumer.subscribe(&topics.iter().map(String::as_str).collect::<Vec<_>>())?;
+    Ok(consumer)
+}
+
+// Applies the transactions of the consumer's messages until shutdown resolves, each message
+// value turned into a transaction by decode, such as decode() below. The messages of a
+// partition are applied in order. Those that aren't a transaction are logged and skipped, and
+// those rejected are logged, both going to the DeadLetters; values that aren't text, such as
+// Avro, are only logged.
+//
+// A message failing for a reason that may go away, a storage error or a DecodeError::Retry, is
+// tried again with a growing backoff until it goes through or shutdown resolves, holding up its
+// partition rather than skipping it. A stopped engine stops the consumer with an error.
+//
+// Offsets are only stored for messages done with, and committed every commit_every messages
+// and at shutdown, each time only once checkpoint has made what was applied up to them durable,
+// e.g. by flushing the state store or writing a snapshot. After a crash the group is
+// redelivered what was applied since the last commit, which Database::with_skip_replays accepts
+// without applying it twice.
+#[allow(clippy::too_many_arguments)]
+pub async fn consume(
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    dead_letters: DeadLetters,
+    consumer: StreamConsumer,
+    mut decode: impl AsyncFnMut(&[u8]) -> Result<Transaction, DecodeError>,
+    commit_every: usize,
+    mut checkpoint: impl FnMut(&ConcurrentDatabase) -> Result<(), String>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), String> {
+    let mut shutdown = std::pin::pin!(shutdown);
+    let mut uncommitted = 0;
+    'consume: loop {
+        let received = tokio::select! {
+            received = consumer.recv() => received,
+            () = &mut shutdown => break,
+        };
+        let message = match received {
+            Ok(message) => message,
+            // librdkafka recovers on its own, e.g. once an unreachable broker is back
+            Err(err) => {
+                tracing::warn!(%err, "Kafka consumer error");
+                continue;
+            }
+        };
+        let mut backoff = RETRY_BACKOFF;
+        loop {
+            match apply(&db, &metrics, &events, &dead_letters, &mut decode, &message).await {
+                Ok(()) => break,
+                Err(Failure::Retry(err)) => {
+                    tracing::warn!(
+                        topic = message.topic(),
+                        partition = message.partition(),
+                        offset = message.offset(),
+                        error = %err,
+                        retry_in = ?backoff,
+                        "Kafka message failed, retrying"
+                    );
+                    tokio::select! {
+                        () = tokio::time::sleep(backoff) => (),
+                        // Its offset isn't stored, the message is redelivered after a restart
+                        () = &mut shutdown => break 'consume,
+                    }
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                Err(Failure::Stop(err)) => return Err(err),
+            }
+        }
+        match consumer.store_offset_from_message(&message) {
+            Ok(()) => uncommitted += 1,
+            // The partition was taken away in a rebalance, its new owner is sent the message
+            // again and skips it as a replay
+            Err(KafkaError::StoreOffset(RDKafkaErrorCode::State)) => {
+                tracing::debug!(partition = message.partition(), "partition revoked");
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+        if uncommitted >= commit_every {
+            commit(&db, &consumer, &mut checkpoint)?;
+            uncommitted = 0;
+        }
+    }
+    match uncommitted {
+        0 => Ok(()),
+        _ => commit(&db, &consumer, &mut checkpoint),
+    }
+}
+
+async fn apply(
+    db: &SharedDatabase,
+    metrics: &Metrics,
+    events: &AccountEvents,
+    dead_letters: &DeadLetters,
+    decode: &mut impl AsyncFnMut(&[u8]) -> Result<Transaction, DecodeError>,
+    message: &impl Message,
+) -> Result<(), Failure> {
+    // Tombstones carry no value and are passed over
+    let Some(value) = message.payload() else {
+        return Ok(());
+    };
+    let written = match decode(value).await {
+        Ok(transaction) => match process(db, metrics, events, &transaction) {
+            Ok(()) => return Ok(()),
+            Err(err @ TransactionError::Storage(_)) => return Err(Failure::Retry(err.to_string())),
+            Err(TransactionError::EngineStopped) => {
+                return Err(Failure::Stop("the engine stopped".to_string()));
+            }
+            Err(err) => {
+                tracing::warn!(
+                    tx = transaction.tx,
+                    client = transaction.client,
+                    tx_type = ?transaction.tx_type,
+                    error_code = err.code(),
+                    "transaction rejected"
+                );
+                dead_letters.write(&transaction, &err)
+            }
+        },
+        Err(DecodeError::Retry(err)) => return Err(Failure::Retry(err)),
+        Err(DecodeError::Invalid(err)) => {
+            tracing::warn!(
+                error_code = "deserialize",
+                topic = message.topic(),
+                partition = message.partition(),
+                offset = message.offset(),
+                error = %err,
+                "unparsable transaction"
+            );
+            match std::str::from_utf8(value) {
+                Ok(text) if !text.contains(|c: char| c.is_control() && !c.is_whitespace()) => {
+                    dead_letters.write_unparsable(columns(text.trim()))
+                }
+                _ => Ok(()),
+            }
+        }
+    };
+    if let Err(err) = written {
+        tracing::error!(%err, "dead-letter write failed");
+    }
+    Ok(())
+}
+
+fn commit(
+    db: &ConcurrentDatabase,
+    consumer: &StreamConsumer,
+    checkpoint: &mut impl FnMut(&ConcurrentDatabase) -> Result<(), String>,
+) -> Result<(), String> {
+    checkpoint(db)?;
+    match consumer.commit_consumer_state(CommitMode::Sync) {
+        // The partitions were taken away in a rebalance, their messages will be redelivered
+        Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) | Ok(()) => (),
+        // Such as an unreachable broker. The offsets stay stored and go out with the next
+        // commit, until then a restart is redelivered a little more.
+        Err(err) => tracing::warn!(%err, "Kafka offset commit failed"),
+    }
+    Ok(())
+}
+
+fn process(
+    db: &SharedDatabase,
+    metrics: &Metrics,
+    events: &AccountEvents,
+    transaction: &Transaction,
+) -> Result<(), TransactionError> {
+    // Published under the shard's lock, so a client's updates go out in order
+    db.with_transaction(transaction, |db| {
+        let effects = metrics.process(db, transaction)?;
+        events.publish(db.precision(), &effects);
+        Ok(())
+    })
+}
+
+// A value holding a transaction as a --tcp line does (see line::parse)
+pub fn decode(value: &[u8]) -> Result<Transaction, DecodeError> {
+    let text = std::str::from_utf8(value).map_err(|e| DecodeError::Invalid(e.to_string()))?;
+    parse(text.trim()).map_err(|e| DecodeError::Invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Database, TransactionType};
+    use rdkafka::{Timestamp, message::OwnedMessage};
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_message_values_are_transactions() {
+        let transaction = decode(b"deposit, 1, 7, 2.5\n").unwrap();
+        assert_eq!(transaction.tx_type, TransactionType::Deposit);
+        assert_eq!((transaction.client, transaction.tx), (1, 7));
+        assert_eq!(transaction.amount, Some(dec!(2.5)));
+        let transaction =
+            decode(br#"{"type": "withdrawal", "client": 2, "tx": 8, "amount": "1"}"#).unwrap();
+        assert_eq!(transaction.tx_type, TransactionType::Withdrawal);
+        assert!(matches!(
+            decode(b"deposit,one,7,2.5"),
+            Err(DecodeError::Invalid(_))
+        ));
+        assert!(decode(&[0xff, 0xfe]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_checkpointed_before_a_message_arrives() {
+        // librdkafka connects lazily, so no broker is needed to stop before the first message
+        let consumer = consumer("127.0.0.1:1", "octopus-test", &["payments".to_string()]).unwrap();
+        let mut checkpoints = 0;
+        consume(
+            Arc::new(ConcurrentDatabase::new(1, Database::default)),
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            DeadLetters::default(),
+            consumer,
+            async |value: &[u8]| decode(value),
+            1,
+            |_| {
+                checkpoints += 1;
+                Ok(())
+            },
+            async {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(checkpoints, 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_messages_are_retried_or_dead_lettered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.csv");
+        let dead_letters = DeadLetters::open(&path).unwrap();
+        let db = Arc::new(ConcurrentDatabase::new(1, Database::default));
+        let (metrics, events) = (Metrics::new(), AccountEvents::new());
+        let message = |value: &[u8]| {
+            OwnedMessage::new(
+                Some(value.to_vec()),
+                None,
+                "payments".to_string(),
+                Timestamp::NotAvailable,
+                0,
+                0,
+                None,
+            )
+        };
+        let mut unreachable = true;
+        let mut decode = async |value: &[u8]| match value {
+            b"registered" if unreachable => {
+                unreachable = false;
+                Err(DecodeError::Retry("registry unreachable".to_string()))
+            }
+            b"registered" => decode(b"deposit,1,1,5"),
+            value => decode(value),
+        };
+        for (value, retried) in [
+            (&b"registered"[..], true),
+            (b"registered", false),
+            (b"withdrawal,1,2,10", false),
+            (b"deposit,1,oops", false),
+            (&[0, 1, 0xff], false),
+        ] {
+            let applied = apply(
+                &db,
+                &metrics,
+                &events,
+                &dead_letters,
+                &mut decode,
+                &message(value),
+            )
+            .await;
+            assert_eq!(matches!(applied, Err(Failure::Retry(_))), retried);
+        }
+        assert_eq!(db.account(1).unwrap().unwrap().get_total(), dec!(5));
+        dead_letters.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type,client,tx,amount,to_client,timestamp,currency,to_currency,rate,error_code\n\
+             withdrawal,1,2,10,,,,,,insufficient_funds\n\
+             deposit,1,oops,,,,,,,unparsable\n"
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use serde_json::Value;
+
+use crate::engine::Transaction;
+
+// Columns of a CSV line, trailing ones may be left out
+const COLUMNS: [&str; 9] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "to_client",
+    "timestamp",
+    "currency",
+    "to_currency",
+    "rate",
+];
+
+// A transaction as a CSV row in COLUMNS order or a JSON object like the HTTP API takes, the
+// lines of --tcp and the messages of --kafka
+pub(super) fn parse(line: &str) -> Result<Transaction, Box<dyn std::error::Error>> {
+    if line.starts_with('{') {
+        return Ok(serde_json::from_str(line)?);
+    }
+    let mut record = csv::StringRecord::new();
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes())
+        .read_record(&mut record)?;
+    Ok(record.deserialize(Some(&csv::StringRecord::from(&COLUMNS[..])))?)
+}
+
+// The values of a line parse() rejects, in COLUMNS order, for the DeadLetters: the fields of a
+// JSON object by name, otherwise the comma-separated fields
+pub(super) fn columns(line: &str) -> Vec<String> {
+    match serde_json::from_str::<serde_json::Map<String, Value>>(line) {
+        Ok(object) => COLUMNS
+            .iter()
+            .map(|column| match object.get(*column) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            })
+            .collect(),
+        Err(_) => csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(line.as_bytes())
+            .records()
+            .next()
+            .and_then(Result::ok)
+            .map(|record| record.iter().map(str::to_string).collect())
+            .unwrap_or_else(|| vec![line.to_string()]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unparsable_lines_keep_their_values() {
+        assert_eq!(columns("deposit, 1, oops"), ["deposit", "1", "oops"]);
+        assert_eq!(
+            columns(r#"{"type": "deposit", "client": 1, "tx": -1, "amount": null, "x": 2}"#),
+            ["deposit", "1", "-1", "", "", "", "", "", ""]
+        );
+        assert_eq!(columns("{oops"), ["{oops"]);
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::engine::{
+    ConcurrentDatabase, Currency, Database, Transaction, TransactionEffect, TransactionError,
+    TransactionType,
+};
+use crate::storage::StorageResult;
+
+// Upper bounds of the processing latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_01, 0.000_025, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.01, 0.1,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative counts are only computed when rendering
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    // (type, outcome), the outcome being 'accepted' or the error code
+    transactions: BTreeMap<(&'static str, &'static str), u64>,
+    latency: Histogram,
+}
+
+// Metrics of the transactions submitted through the servers, shared between gRPC and HTTP and
+// exposed by GET /metrics in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Processes the transaction, counting its outcome and timing it
+    pub fn process(
+        &self,
+        db: &mut Database,
+        transaction: &Transaction,
+    ) -> Result<Vec<TransactionEffect>, TransactionError> {
+        let started = Instant::now();
+        let result = db.process_with_effect(transaction);
+        self.observe(
+            &transaction.tx_type,
+            result.as_ref().err(),
+            started.elapsed(),
+        );
+        result
+    }
+
+    // error is None for an accepted transaction
+    pub fn observe(
+        &self,
+        tx_type: &TransactionType,
+        error: Option<&TransactionError>,
+        elapsed: Duration,
+    ) {
+        let outcome = error.map_or("accepted", TransactionError::code);
+        // A poisoned lock only means a scrape panicked, the counts are still good
+        let mut counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        *counters
+            .transactions
+            .entry((type_label(tx_type), outcome))
+            .or_default() += 1;
+        counters.latency.observe(elapsed);
+    }
+
+    // Gauges are read from the Database, which means a pass over every account per scrape
+    pub fn render(&self, db: &ConcurrentDatabase) -> StorageResult<String> {
+        let mut held = BTreeMap::<Option<Currency>, f64>::new();
+        let mut locked = 0u64;
+        for (_, account) in db.accounts()? {
+            for (currency, balance) in account.balances() {
+                *held.entry(currency).or_default() +=
+                    f64::try_from(balance.held()).unwrap_or_default();
+            }
+            locked += account.is_locked() as u64;
+        }
+
+        let counters = self.counters.lock().unwrap_or_else(|err| err.into_inner());
+        let mut out = String::new();
+        // Writing to a String can't fail
+        let _ = write_metrics(&mut out, &counters, &held, locked);
+        Ok(out)
+    }
+}
+
+fn write_metrics(
+    out: &mut String,
+    counters: &Counters,
+    held: &BTreeMap<Option<Currency>, f64>,
+    locked: u64,
+) -> std::fmt::Result {
+    writeln!(
+        out,
+        "# HELP octopus_transactions_total Transactions processed, by type and outcome"
+    )?;
+    writeln!(out, "# TYPE octopus_transactions_total counter")?;
+    for ((tx_type, outcome), count) in &counters.transactions {
+        writeln!(
+            out,
+            "octopus_transactions_total{{type=\"{}\",outcome=\"{}\"}} {}",
+            tx_type, outcome, count
+        )?;
+    }
+
+    writeln!(
+        out,
+        "# HELP octopus_held_funds Funds held by open disputes, by currency"
+    )?;
+    writeln!(out, "# TYPE octopus_held_funds gauge")?;
+    for (currency, amount) in held {
+        let currency = currency.map(|c| c.to_string()).unwrap_or_default();
+        writeln!(
+            out,
+            "octopus_held_funds{{currency=\"{}\"}} {}",
+            currency, amount
+        )?;
+    }
+
+    writeln!(out, "# HELP octopus_locked_accounts Locked accounts")?;
+    writeln!(out, "# TYPE octopus_locked_accounts gauge")?;
+    writeln!(out, "octopus_locked_accounts {}", locked)?;
+
+    writeln!(
+        out,
+        "# HELP octopus_processing_seconds Time taken to process a transaction"
+    )?;
+    writeln!(out, "# TYPE octopus_processing_seconds histogram")?;
+    let latency = &counters.latency;
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+        cumulative += count;
+        writeln!(
+            out,
+            "octopus_processing_seconds_bucket{{le=\"{}\"}} {}",
+            bound, cumulative
+        )?;
+    }
+    writeln!(
+        out,
+        "octopus_processing_seconds_bucket{{le=\"+Inf\"}} {}",
+        latency.count
+    )?;
+    writeln!(out, "octopus_processing_seconds_sum {}", latency.sum)?;
+    writeln!(out, "octopus_processing_seconds_count {}", latency.count)
+}
+
+// The name the transaction type has in inputs
+fn type_label(tx_type: &TransactionType) -> &'static str {
+    match tx_type {
+        TransactionType::Deposit => "deposit",
+        TransactionType::Withdrawal => "withdrawal",
+        TransactionType::Dispute => "dispute",
+        TransactionType::Resolve => "resolve",
+        TransactionType::Chargeback => "chargeback",
+        TransactionType::Transfer => "transfer",
+        TransactionType::Convert => "convert",
+        TransactionType::Fee => "fee",
+        TransactionType::Unlock => "unlock",
+        TransactionType::Open => "open",
+        TransactionType::Close => "close",
+        TransactionType::Reversal => "reversal",
+        TransactionType::Settle => "settle",
+        // Not by name, which would let inputs make up any number of labels
+        TransactionType::Other(_) => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn transaction(tx_type: TransactionType, tx: u32, amount: Option<Decimal>) -> Transaction {
+        Transaction::new(tx_type, 1, tx, amount)
+    }
+
+    #[test]
+    fn test_render_counts_outcomes_and_held_funds() {
+        let (metrics, db) = (
+            Metrics::new(),
+            ConcurrentDatabase::new(1, Database::default),
+        );
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, Some(Decimal::from(5))),
+            transaction(TransactionType::Deposit, 1, Some(Decimal::from(5))),
+            transaction(TransactionType::Dispute, 1, None),
+        ] {
+            let _ = db.with_transaction(&transaction, |db| metrics.process(db, &transaction));
+        }
+        let rendered = metrics.render(&db).unwrap();
+        for line in [
+            "octopus_transactions_total{type=\"deposit\",outcome=\"accepted\"} 1",
+            "octopus_transactions_total{type=\"deposit\",outcome=\"duplicate\"} 1",
+            "octopus_transactions_total{type=\"dispute\",outcome=\"accepted\"} 1",
+            "octopus_held_funds{currency=\"\"} 5",
+            "octopus_locked_accounts 0",
+            "octopus_processing_seconds_bucket{le=\"+Inf\"} 3",
+            "octopus_processing_seconds_count 3",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use redis::{Client, RedisResult, aio::MultiplexedConnection};
+use std::{future::Future, io};
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{SharedDatabase, events::AccountEvents};
+use crate::engine::AccountRow;
+
+// Balances are hashes under this prefix and the client, followed by ':' and the currency for
+// balances in one
+pub const KEY_PREFIX: &str = "octopus:account:";
+
+// Rows written per pipeline when the whole mirror is rewritten
+const SYNC_CHUNK: usize = 1000;
+
+// Mirrors every account balance into Redis until shutdown resolves, so other services can read
+// balances without going through the engine. Each balance is a hash of available, held, total
+// and locked, formatted like GET /accounts. The mirror follows the account events, so it never
+// holds up a transaction; if it falls behind them or Redis goes away, it is rewritten in full
+// on the next change Redis accepts. Redis has to be reachable at startup.
+pub async fn mirror(
+    db: SharedDatabase,
+    events: AccountEvents,
+    url: &str,
+    shutdown: impl Future<Output = ()>,
+) -> RedisResult<()> {
+    let client = Client::open(url)?;
+    // Subscribed before the first sync, so no change falls in between
+    let mut receiver = events.subscribe();
+    let mut conn = Some(connect(&client, &db).await?);
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let received = tokio::select! {
+            received = receiver.recv() => received,
+            () = &mut shutdown => return Ok(()),
+        };
+        let result = match (received, conn.as_mut()) {
+            (Err(RecvError::Closed), _) => return Ok(()),
+            (Ok(row), Some(conn)) => write(conn, &[row]).await,
+            (Err(RecvError::Lagged(missed)), Some(conn)) => {
+                tracing::warn!(missed, "Redis mirror lagging, rewriting it");
+                sync(conn, &db).await
+            }
+            (_, None) => connect(&client, &db).await.map(|connected| {
+                tracing::info!("Redis mirror reconnected");
+                conn = Some(connected);
+            }),
+        };
+        if let Err(err) = result {
+            tracing::warn!(%err, "Redis mirror failed, retrying on the next change");
+            conn = None;
+        }
+    }
+}
+
+pub fn key(row: &AccountRow) -> String {
+    match row.currency {
+        Some(currency) => format!("{}{}:{}", KEY_PREFIX, row.client, currency),
+        None => format!("{}{}", KEY_PREFIX, row.client),
+    }
+}
+
+async fn connect(client: &Client, db: &SharedDatabase) -> RedisResult<MultiplexedConnection> {
+    let mut conn = client.get_multiplexed_tokio_connection().await?;
+    sync(&mut conn, db).await?;
+    Ok(conn)
+}
+
+async fn sync(conn: &mut MultiplexedConnection, db: &SharedDatabase) -> RedisResult<()> {
+    for rows in accounts(db)?.chunks(SYNC_CHUNK) {
+        write(conn, rows).await?;
+    }
+    Ok(())
+}
+
+async fn write(conn: &mut MultiplexedConnection, rows: &[AccountRow]) -> RedisResult<()> {
+    let mut pipe = redis::pipe();
+    for row in rows {
+        pipe.hset_multiple(
+            key(row),
+            &[
+                ("available", row.available.to_string()),
+                ("held", row.held.to_string()),
+                ("total", row.total.to_string()),
+                ("locked", row.locked.to_string()),
+            ],
+        )
+        .ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+// Read up front, no lock is held while anything is sent
+fn accounts(db: &SharedDatabase) -> io::Result<Vec<AccountRow>> {
+    let precision = db.precision();
+    let accounts = db
+        .accounts()
+        .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+    Ok(accounts
+        .iter()
+        .flat_map(|(client, account)| AccountRow::rows(precision, *client, account))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ConcurrentDatabase, Currency, Database, Transaction, TransactionType};
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_every_balance_has_its_own_key() {
+        let db = ConcurrentDatabase::new(1, Database::default);
+        for (tx, currency) in [(1, None), (2, Currency::new("EUR"))] {
+            db.process(
+                &Transaction::new(TransactionType::Deposit, 7, tx, Some(Decimal::from(3)))
+                    .with_currency(currency),
+            )
+            .unwrap();
+        }
+        let rows = accounts(&Arc::new(db)).unwrap();
+        let keys = rows.iter().map(key).collect::<Vec<_>>();
+        assert_eq!(keys, ["octopus:account:7", "octopus:account:7:EUR"]);
+        assert_eq!(rows[1].available.to_string(), "3.0000");
+    }
+}
@@ -0,0 +1,25 @@
+// Long-running service front-ends over a shared Database
+pub mod admission;
+pub mod dead_letter;
+pub mod events;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(any(feature = "tcp", feature = "kafka"))]
+mod line;
+pub mod metrics;
+#[cfg(feature = "redis")]
+pub mod mirror;
+#[cfg(feature = "tcp")]
+pub mod tcp;
+
+use std::sync::Arc;
+
+use crate::engine::ConcurrentDatabase;
+
+// A request only locks the shard of the client it is about, so with more than one shard
+// (--shards) requests for clients of different shards are applied in parallel
+pub type SharedDatabase = Arc<ConcurrentDatabase>;
@@ -0,0 +1,185 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+use super::{
+    SharedDatabase,
+    admission::Admission,
+    dead_letter::DeadLetters,
+    events::AccountEvents,
+    line::{columns, parse},
+    metrics::Metrics,
+};
+use crate::engine::{AccountRow, Transaction, TransactionError};
+
+// Serves newline-delimited transactions over plain TCP until shutdown resolves. Every
+// connection is handled on its own task, see handle(). Lines are applied whole, so connections
+// dropped at shutdown lose nothing they were answered for.
+pub async fn serve(
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    admission: Admission,
+    dead_letters: DeadLetters,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let mut shutdown = std::pin::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            () = &mut shutdown => return Ok(()),
+        };
+        let (db, metrics, events) = (Arc::clone(&db), Arc::clone(&metrics), events.clone());
+        let (admission, dead_letters) = (admission.clone(), dead_letters.clone());
+        tokio::spawn(async move {
+            if let Err(err) = handle(db, metrics, events, admission, dead_letters, stream).await {
+                tracing::debug!(%peer, %err, "connection failed");
+            }
+        });
+    }
+}
+
+// Each line is a transaction, as a CSV row or a JSON object like the HTTP API takes (see
+// line::parse), and is answered with 'ok' or 'error <code>'. Lines are processed one at a time
+// in the order they arrive, so a producer sending all of a client's transactions over one
+// connection keeps them in order. 'SNAPSHOT' answers with the account table as CSV, sorted by
+// client and ended by an empty line. While the Admission has no room the connection isn't read
+// any further. Rejected transactions and lines that aren't one also go to the DeadLetters.
+pub async fn handle(
+    db: SharedDatabase,
+    metrics: Arc<Metrics>,
+    events: AccountEvents,
+    admission: Admission,
+    dead_letters: DeadLetters,
+    stream: impl AsyncRead + AsyncWrite,
+) -> io::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.trim() {
+            "" => continue,
+            command if command.eq_ignore_ascii_case("snapshot") => snapshot(&db),
+            // The parse error isn't Send, so it can't be held across the wait for admission
+            line => match parse(line).ok() {
+                Some(transaction) => {
+                    let processed = match admission.admit().await {
+                        Ok(_permit) => process(&db, &metrics, &events, &transaction),
+                        Err(err) => Err(err),
+                    };
+                    match processed {
+                        Ok(()) => "ok\n".to_string(),
+                        Err(err) => {
+                            if let Err(write_err) = dead_letters.write(&transaction, &err) {
+                                tracing::error!(%write_err, "dead-letter write failed");
+                            }
+                            format!("error {}\n", err.code())
+                        }
+                    }
+                }
+                None => {
+                    if let Err(write_err) = dead_letters.write_unparsable(columns(line)) {
+                        tracing::error!(%write_err, "dead-letter write failed");
+                    }
+                    "error unparsable\n".to_string()
+                }
+            },
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+    write.flush().await
+}
+
+fn process(
+    db: &SharedDatabase,
+    metrics: &Metrics,
+    events: &AccountEvents,
+    transaction: &Transaction,
+) -> Result<(), TransactionError> {
+    // Published under the shard's lock, so a client's updates go out in order
+    db.with_transaction(transaction, |db| {
+        let effects = metrics.process(db, transaction)?;
+        events.publish(db.precision(), &effects);
+        Ok(())
+    })
+}
+
+fn snapshot(db: &SharedDatabase) -> String {
+    let accounts = match db.accounts() {
+        Ok(accounts) => accounts,
+        Err(err) => return format!("error {}\n", TransactionError::Storage(err).code()),
+    };
+    // The columns of CSV output, the currency column only once some balance has a currency
+    let currency = accounts.iter().any(|(_, account)| account.has_currency());
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    // Writing to a Vec only fails to serialize, which the rows don't
+    let _ = wtr.write_record(AccountRow::csv_header(AccountRow::CSV_HEADER, currency));
+    for (client, account) in &accounts {
+        for row in AccountRow::rows(db.precision(), *client, account) {
+            let _ = row.write_csv(&mut wtr, currency);
+        }
+    }
+    let mut out = String::from_utf8_lossy(&wtr.into_inner().unwrap_or_default()).into_owned();
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ConcurrentDatabase, Database};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_lines_are_answered_in_order() {
+        let db: SharedDatabase = Arc::new(ConcurrentDatabase::new(1, Database::default));
+        let (client, server) = tokio::io::duplex(4096);
+        let dir = tempfile::tempdir().unwrap();
+        let dead_letter = dir.path().join("dead.csv");
+        let dead_letters = DeadLetters::open(&dead_letter).unwrap();
+        let connection = tokio::spawn(handle(
+            db,
+            Arc::new(Metrics::new()),
+            AccountEvents::new(),
+            Admission::default(),
+            dead_letters.clone(),
+            server,
+        ));
+
+        let (mut read, mut write) = tokio::io::split(client);
+        write
+            .write_all(
+                b"deposit,1,1,2.5\n\
+                  {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"5\"}\n\
+                  \n\
+                  deposit,1,oops\n\
+                  snapshot\n",
+            )
+            .await
+            .unwrap();
+        write.shutdown().await.unwrap();
+        connection.await.unwrap().unwrap();
+
+        let mut replies = String::new();
+        read.read_to_string(&mut replies).await.unwrap();
+        assert_eq!(
+            replies,
+            "ok\n\
+             error insufficient_funds\n\
+             error unparsable\n\
+             client,available,held,total,locked\n\
+             1,2.5000,0.0000,2.5000,false\n\
+             \n"
+        );
+        dead_letters.flush().unwrap();
+        assert!(std::fs::read_to_string(&dead_letter).unwrap().ends_with(
+            "\nwithdrawal,1,2,5,,,,,,insufficient_funds\n\
+             deposit,1,oops,,,,,,,unparsable\n"
+        ));
+    }
+}
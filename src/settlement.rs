@@ -0,0 +1,153 @@
+use octopus::{BalanceDelta, ClientID, Database, Timestamp, Transaction, TransactionType};
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// One row of a settlement file: how much a client's balance in one currency moved in the period
+#[derive(Debug, Serialize)]
+struct SettlementRow {
+    client: ClientID,
+    currency: String,
+    available: String,
+    held: String,
+    total: String,
+}
+
+// Closes settlement periods as the input goes, on settle rows and, with an interval, whenever a
+// transaction's timestamp falls in a later period than the open one. Each closed period is
+// written to its own numbered file in the directory. Transactions without a timestamp stay in
+// the open period.
+pub struct Settlements {
+    dir: PathBuf,
+    every: Option<Duration>,
+    // Start of the open period, once a timestamped transaction came by
+    period: Option<Timestamp>,
+    // Rows seen since the open period started, the statistics a close resets
+    rows: u64,
+    closed: u64,
+}
+
+impl Settlements {
+    pub fn new(dir: &str, every: Option<Duration>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Settlements {
+            dir: PathBuf::from(dir),
+            every,
+            period: None,
+            rows: 0,
+            closed: 0,
+        })
+    }
+
+    // To be called before each transaction is processed. Returns false for a settle row, which
+    // is not processed any further.
+    pub fn before(&mut self, db: &mut Database, transaction: &Transaction) -> io::Result<bool> {
+        if transaction.tx_type == TransactionType::Settle {
+            self.close(db)?;
+            return Ok(false);
+        }
+        if let (Some(every), Some(timestamp)) = (self.every, transaction.timestamp) {
+            let secs = every.as_secs().max(1);
+            let start = timestamp - timestamp % secs;
+            match self.period {
+                Some(period) if start > period => {
+                    self.close(db)?;
+                    self.period = Some(start);
+                }
+                Some(_) => {}
+                None => self.period = Some(start),
+            }
+        }
+        self.rows += 1;
+        Ok(true)
+    }
+
+    // Closes the last period, unless nothing came by since the previous one closed
+    pub fn finish(&mut self, db: &mut Database) -> io::Result<()> {
+        match self.rows {
+            0 => Ok(()),
+            _ => self.close(db),
+        }
+    }
+
+    fn close(&mut self, db: &mut Database) -> io::Result<()> {
+        let deltas = db
+            .settle()
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        self.closed += 1;
+        let path = self.dir.join(format!("settlement-{:04}.csv", self.closed));
+        write(&path, db, &deltas)?;
+        tracing::info!(
+            period = self.closed,
+            start = self.period,
+            rows = self.rows,
+            balances = deltas.len(),
+            path = %path.display(),
+            "settlement period closed"
+        );
+        self.rows = 0;
+        Ok(())
+    }
+}
+
+fn write(path: &Path, db: &Database, deltas: &[BalanceDelta]) -> io::Result<()> {
+    let precision = db.precision();
+    let mut writer = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+    for delta in deltas {
+        writer.serialize(SettlementRow {
+            client: delta.client,
+            currency: delta
+                .currency
+                .map(|currency| currency.to_string())
+                .unwrap_or_default(),
+            available: precision.format(delta.available),
+            held: precision.format(delta.held),
+            total: precision.format(delta.available + delta.held),
+        })?;
+    }
+    // Periods in which nothing moved still get a file, with just the header
+    if deltas.is_empty() {
+        writer.write_record(["client", "currency", "available", "held", "total"])?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(tx_type: TransactionType, tx: u32, timestamp: Option<Timestamp>) -> Transaction {
+        Transaction::new(tx_type, 1, tx, Some("1.5".parse().unwrap())).with_timestamp(timestamp)
+    }
+
+    #[test]
+    fn test_periods_close_on_markers_and_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("settlements");
+        let mut settlements =
+            Settlements::new(dir.to_str().unwrap(), Some(Duration::from_secs(86_400))).unwrap();
+        let mut db = Database::new().with_settlement(true);
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, Some(100)),
+            transaction(TransactionType::Settle, 0, None),
+            transaction(TransactionType::Deposit, 2, Some(200)),
+            transaction(TransactionType::Withdrawal, 3, Some(86_400 + 5)),
+        ] {
+            if settlements.before(&mut db, &transaction).unwrap() {
+                db.process(&transaction).unwrap();
+            }
+        }
+        settlements.finish(&mut db).unwrap();
+
+        let read = |n| fs::read_to_string(dir.join(format!("settlement-{:04}.csv", n))).unwrap();
+        let header = "client,currency,available,held,total\n";
+        assert_eq!(read(1), format!("{}1,,1.5000,0.0000,1.5000\n", header));
+        assert_eq!(read(2), format!("{}1,,1.5000,0.0000,1.5000\n", header));
+        assert_eq!(read(3), format!("{}1,,-1.5000,0.0000,-1.5000\n", header));
+        assert!(!dir.join("settlement-0004.csv").exists());
+    }
+}
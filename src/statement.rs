@@ -0,0 +1,235 @@
+use octopus::{
+    AccountRow, ClientID, Currency, Database, Transaction, TransactionID, TransactionType,
+    storage::StorageResult,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::{self, BufWriter, Write};
+
+use crate::cli::OutputFormat;
+
+// How one accepted transaction moved one of the client's balances, and the balance after it
+#[derive(Debug, Serialize)]
+pub struct StatementLine {
+    pub tx: TransactionID,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available_change: String,
+    pub held_change: String,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+}
+
+// Everything that happened to one client's money, in processing order
+#[derive(Debug, Serialize)]
+pub struct Statement {
+    pub client: ClientID,
+    pub lines: Vec<StatementLine>,
+    // One row per currency once every transaction is processed
+    #[serde(rename = "final")]
+    pub final_position: Vec<AccountRow>,
+}
+
+impl Statement {
+    pub fn new(client: ClientID) -> Self {
+        Statement {
+            client,
+            lines: Vec::new(),
+            final_position: Vec::new(),
+        }
+    }
+
+    // Adds the lines of a transaction the Database just processed, if it concerns the client and
+    // was accepted. The Database has to record history, that is where the changes come from.
+    pub fn record(&mut self, db: &Database, transaction: &Transaction) -> StorageResult<()> {
+        if transaction.client != self.client && transaction.to_client != Some(self.client) {
+            return Ok(());
+        }
+        let Some(account) = db.account(self.client)? else {
+            return Ok(());
+        };
+        let Some(entry) = db.transaction_history(transaction.tx).last() else {
+            return Ok(());
+        };
+        if !entry.is_accepted() {
+            return Ok(());
+        }
+        let mut changes = entry
+            .deltas
+            .iter()
+            .filter(|delta| delta.client == self.client)
+            .map(|delta| (delta.currency, delta.available, delta.held))
+            .collect::<Vec<_>>();
+        // Transactions moving no funds, such as an unlock, still get a line
+        if changes.is_empty() {
+            changes.push((transaction.currency, Decimal::ZERO, Decimal::ZERO));
+        }
+        let precision = db.precision();
+        for (currency, available_change, held_change) in changes {
+            let balance = account.balance(currency);
+            self.lines.push(StatementLine {
+                tx: transaction.tx,
+                tx_type: transaction.tx_type.clone(),
+                currency,
+                available_change: precision.format(available_change),
+                held_change: precision.format(held_change),
+                available: precision.format(balance.available()),
+                held: precision.format(balance.held()),
+                total: precision.format(balance.total()),
+                locked: account.is_locked(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self, db: &Database) -> StorageResult<()> {
+        if let Some(account) = db.account(self.client)? {
+            self.final_position = AccountRow::rows(db.precision(), self.client, &account).collect();
+        }
+        Ok(())
+    }
+
+    // CSV and NDJSON list the lines and then the final position, as rows of type 'final'
+    pub fn write(&self, format: OutputFormat, output: impl Write) -> io::Result<()> {
+        match format {
+            OutputFormat::Csv => {
+                let mut wtr = csv::Writer::from_writer(output);
+                wtr.write_record([
+                    "tx",
+                    "type",
+                    "currency",
+                    "available_change",
+                    "held_change",
+                    "available",
+                    "held",
+                    "total",
+                    "locked",
+                ])?;
+                let currency = |currency: Option<Currency>| {
+                    currency
+                        .map(|currency| currency.to_string())
+                        .unwrap_or_default()
+                };
+                for line in &self.lines {
+                    wtr.write_record([
+                        line.tx.to_string(),
+                        type_name(&line.tx_type)?,
+                        currency(line.currency),
+                        line.available_change.clone(),
+                        line.held_change.clone(),
+                        line.available.clone(),
+                        line.held.clone(),
+                        line.total.clone(),
+                        line.locked.to_string(),
+                    ])?;
+                }
+                for row in &self.final_position {
+                    wtr.write_record([
+                        String::new(),
+                        "final".to_string(),
+                        currency(row.currency),
+                        String::new(),
+                        String::new(),
+                        row.available.to_string(),
+                        row.held.to_string(),
+                        row.total.to_string(),
+                        row.locked.to_string(),
+                    ])?;
+                }
+                wtr.flush()
+            }
+            OutputFormat::Json => {
+                let mut out = BufWriter::new(output);
+                serde_json::to_writer(&mut out, self)?;
+                writeln!(out)?;
+                out.flush()
+            }
+            OutputFormat::Ndjson => {
+                let mut out = BufWriter::new(output);
+                for line in &self.lines {
+                    serde_json::to_writer(&mut out, line)?;
+                    writeln!(out)?;
+                }
+                for row in &self.final_position {
+                    serde_json::to_writer(
+                        &mut out,
+                        &FinalRow {
+                            tx_type: "final",
+                            row,
+                        },
+                    )?;
+                    writeln!(out)?;
+                }
+                out.flush()
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FinalRow<'a> {
+    #[serde(rename = "type")]
+    tx_type: &'static str,
+    #[serde(flatten)]
+    row: &'a AccountRow,
+}
+
+// The name the transaction type has in input CSVs
+fn type_name(tx_type: &TransactionType) -> io::Result<String> {
+    match serde_json::to_value(tx_type)? {
+        serde_json::Value::String(name) => Ok(name),
+        value => Ok(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octopus::History;
+
+    fn transaction(
+        tx_type: TransactionType,
+        client: ClientID,
+        tx: TransactionID,
+        amount: Option<Decimal>,
+    ) -> Transaction {
+        Transaction::new(tx_type, client, tx, amount)
+    }
+
+    #[test]
+    fn test_statement_keeps_a_running_balance() {
+        let mut db = Database::default().with_history(History::new());
+        let mut statement = Statement::new(1);
+        for transaction in [
+            transaction(TransactionType::Deposit, 1, 1, Some(Decimal::from(10))),
+            transaction(TransactionType::Deposit, 2, 2, Some(Decimal::from(5))),
+            transaction(TransactionType::Withdrawal, 1, 3, Some(Decimal::from(4))),
+            // Rejected, too little is left to hold
+            transaction(TransactionType::Dispute, 1, 1, None),
+            transaction(TransactionType::Deposit, 1, 4, Some(Decimal::from(3))),
+            transaction(TransactionType::Dispute, 1, 4, None),
+            transaction(TransactionType::Chargeback, 1, 4, None),
+        ] {
+            let _ = db.process(&transaction);
+            statement.record(&db, &transaction).unwrap();
+        }
+        statement.finish(&db).unwrap();
+
+        let mut csv = Vec::new();
+        statement.write(OutputFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "tx,type,currency,available_change,held_change,available,held,total,locked\n\
+             1,deposit,,10.0000,0.0000,10.0000,0.0000,10.0000,false\n\
+             3,withdrawal,,-4.0000,0.0000,6.0000,0.0000,6.0000,false\n\
+             4,deposit,,3.0000,0.0000,9.0000,0.0000,9.0000,false\n\
+             4,dispute,,-3.0000,3.0000,6.0000,3.0000,9.0000,false\n\
+             4,chargeback,,0.0000,-3.0000,6.0000,0.0000,6.0000,true\n\
+             ,final,,,,6.0000,0.0000,6.0000,true\n"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use octopus::{Currency, Database, TransactionType};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+const TYPES: [TransactionType; 13] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Transfer,
+    TransactionType::Convert,
+    TransactionType::Fee,
+    TransactionType::Unlock,
+    TransactionType::Open,
+    TransactionType::Close,
+    TransactionType::Reversal,
+    TransactionType::Settle,
+];
+
+fn index(tx_type: &TransactionType) -> usize {
+    match tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Transfer => 5,
+        TransactionType::Convert => 6,
+        TransactionType::Fee => 7,
+        TransactionType::Unlock => 8,
+        TransactionType::Open => 9,
+        TransactionType::Close => 10,
+        TransactionType::Reversal => 11,
+        TransactionType::Settle => 12,
+        // Unknown types share the slot after the known ones
+        TransactionType::Other(_) => TYPES.len(),
+    }
+}
+
+// Row counts of a run, as reported in --run-summary
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Counts {
+    pub processed: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub unparsable: u64,
+    // Rows of an unknown type dropped by --on-unknown-type skip, not counted as processed
+    pub skipped: u64,
+}
+
+// Counts of a batch run for --stats. Shared between shard workers, hence the atomics.
+pub struct Stats {
+    started: Instant,
+    submitted: [AtomicU64; TYPES.len() + 1],
+    rejected: [AtomicU64; TYPES.len() + 1],
+    unparsable: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            submitted: Default::default(),
+            rejected: Default::default(),
+            unparsable: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn submitted(&self, tx_type: &TransactionType) {
+        self.submitted[index(tx_type)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, tx_type: &TransactionType) {
+        self.rejected[index(tx_type)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unparsable(&self) {
+        self.unparsable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Counts {
+        let sum = |counts: &[AtomicU64]| {
+            counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .sum::<u64>()
+        };
+        let (processed, rejected) = (sum(&self.submitted), sum(&self.rejected));
+        Counts {
+            processed,
+            accepted: processed - rejected,
+            rejected,
+            unparsable: self.unparsable.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn accepted(&self, tx_type: &TransactionType) -> u64 {
+        let i = index(tx_type);
+        self.submitted[i].load(Ordering::Relaxed) - self.rejected[i].load(Ordering::Relaxed)
+    }
+
+    // Balances are taken from the final state, so this runs once processing is done
+    pub fn write(
+        &self,
+        db: &Database,
+        mut out: impl io::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let counts = self.counts();
+
+        // Held funds are only summed within a currency
+        let (mut held, mut locked) = (BTreeMap::<Option<Currency>, Decimal>::new(), 0);
+        held.insert(None, Decimal::ZERO);
+        for entry in db.accounts() {
+            let (_, account) = entry.map_err(|e| format!("Failed to read account: {:?}", e))?;
+            for (currency, balance) in account.balances() {
+                let total = held.entry(currency).or_default();
+                *total = total.saturating_add(balance.held());
+            }
+            locked += account.is_locked() as u64;
+        }
+
+        writeln!(
+            out,
+            "Transactions: {} processed, {} accepted, {} rejected, {} unparsable",
+            counts.processed, counts.accepted, counts.rejected, counts.unparsable
+        )?;
+        for tx_type in TYPES.iter() {
+            let i = index(tx_type);
+            if self.submitted[i].load(Ordering::Relaxed) > 0 {
+                writeln!(
+                    out,
+                    "  {}: {} accepted, {} rejected",
+                    format!("{:?}", tx_type).to_lowercase(),
+                    self.accepted(tx_type),
+                    self.rejected[i].load(Ordering::Relaxed)
+                )?;
+            }
+        }
+        let unknown = TYPES.len();
+        if self.submitted[unknown].load(Ordering::Relaxed) > 0 || counts.skipped > 0 {
+            writeln!(
+                out,
+                "  unknown types: {} rejected, {} skipped",
+                self.rejected[unknown].load(Ordering::Relaxed),
+                counts.skipped
+            )?;
+        }
+        writeln!(
+            out,
+            "Disputes: {} opened, {} resolved, {} charged back",
+            self.accepted(&TransactionType::Dispute),
+            self.accepted(&TransactionType::Resolve),
+            self.accepted(&TransactionType::Chargeback)
+        )?;
+        for (currency, held) in held {
+            match currency {
+                Some(currency) => writeln!(
+                    out,
+                    "Funds held ({}): {}",
+                    currency,
+                    db.precision().format(held)
+                )?,
+                None => writeln!(out, "Funds held: {}", db.precision().format(held))?,
+            }
+        }
+        writeln!(out, "Locked accounts: {}", locked)?;
+        writeln!(
+            out,
+            "Elapsed: {:.3}s ({:.0} tx/s)",
+            elapsed,
+            counts.processed as f64 / elapsed.max(f64::EPSILON)
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+
+use super::{AccountEntries, PrunedEntries, RecordEntries, StorageBackend, StorageResult};
+use crate::engine::{Account, ClientID, RecordKey, TransactionID, TransactionRecord};
+
+// Keys are small integers looked up once or more per transaction, so FxHash rather than the
+// default SipHash, which resists hash flooding at several times the cost
+type TransactionMap = FxHashMap<RecordKey, TransactionRecord>;
+
+// Accounts indexed by client id. With ClientID a u16 there are at most 65536 of them, so a
+// dense table takes hashing off the per-transaction path altogether. It only spans the clients
+// stored, from the lowest to the highest, so the Database of an ActorDatabase actor, holding one
+// client whatever its id, has a single slot.
+#[derive(Debug, Default)]
+pub(super) struct AccountTable {
+    // The client of the first slot
+    base: ClientID,
+    slots: Vec<Option<Account>>,
+}
+
+impl AccountTable {
+    pub(super) fn get(&self, client: ClientID) -> Option<&Account> {
+        let slot = client.checked_sub(self.base)?;
+        self.slots.get(usize::from(slot))?.as_ref()
+    }
+
+    pub(super) fn insert(&mut self, client: ClientID, account: Account) {
+        if self.slots.is_empty() {
+            self.base = client;
+        } else if client < self.base {
+            // Grown downwards by at least its length, so clients coming in descending order
+            // don't shift the table each
+            let len = ClientID::try_from(self.slots.len()).unwrap_or(ClientID::MAX);
+            let base = client.min(self.base.saturating_sub(len));
+            let added = usize::from(self.base - base);
+            self.slots
+                .splice(0..0, std::iter::repeat_with(|| None).take(added));
+            self.base = base;
+        }
+        let slot = usize::from(client - self.base);
+        if slot >= self.slots.len() {
+            self.slots.resize_with(slot + 1, || None);
+        }
+        self.slots[slot] = Some(account);
+    }
+
+    pub(super) fn remove(&mut self, client: ClientID) {
+        if let Some(slot) = client
+            .checked_sub(self.base)
+            .and_then(|slot| self.slots.get_mut(usize::from(slot)))
+        {
+            *slot = None;
+        }
+    }
+
+    // In client order
+    pub(super) fn iter(&self) -> impl Iterator<Item = (ClientID, &Account)> {
+        (self.base..=ClientID::MAX)
+            .zip(&self.slots)
+            .filter_map(|(client, slot)| slot.as_ref().map(|account| (client, account)))
+    }
+}
+
+// The keys of pruned records as runs of consecutive transaction ids, so that pruning a client's
+// oldest records, as retention does, extends one run rather than adding a key each. With ids
+// handed out densely a run only breaks at a record still kept or a run of another scope, so the
+// keys take no more room than the records kept do.
+#[derive(Debug, Default)]
+pub(super) struct PrunedKeys {
+    // The first id of each run to its last, by scope
+    runs: BTreeMap<(Option<ClientID>, TransactionID), TransactionID>,
+}
+
+impl PrunedKeys {
+    // The run holding tx or ending right before it, as its first and last id
+    fn run_until(&self, key: RecordKey) -> Option<(TransactionID, TransactionID)> {
+        self.runs
+            .range(..=(key.client, key.tx))
+            .next_back()
+            .filter(|((client, _), _)| *client == key.client)
+            .map(|(&(_, first), &last)| (first, last))
+    }
+
+    pub(super) fn contains(&self, key: RecordKey) -> bool {
+        self.run_until(key).is_some_and(|(_, last)| last >= key.tx)
+    }
+
+    pub(super) fn insert(&mut self, key: RecordKey) {
+        let (mut first, mut last) = (key.tx, key.tx);
+        match self.run_until(key) {
+            Some((_, end)) if end >= key.tx => return,
+            Some((start, end)) if end.checked_add(1) == Some(key.tx) => first = start,
+            _ => (),
+        }
+        if let Some(next) = key.tx.checked_add(1)
+            && let Some(end) = self.runs.remove(&(key.client, next))
+        {
+            last = end;
+        }
+        self.runs.insert((key.client, first), last);
+    }
+
+    pub(super) fn remove(&mut self, key: RecordKey) {
+        let Some((first, last)) = self.run_until(key).filter(|(_, last)| *last >= key.tx) else {
+            return;
+        };
+        self.runs.remove(&(key.client, first));
+        if first < key.tx {
+            self.runs.insert((key.client, first), key.tx - 1);
+        }
+        if key.tx < last {
+            self.runs.insert((key.client, key.tx + 1), last);
+        }
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = RecordKey> + '_ {
+        self.runs.iter().flat_map(|(&(client, first), &last)| {
+            (first..=last).map(move |tx| RecordKey { client, tx })
+        })
+    }
+}
+
+// Everything lives in HashMaps and is lost when the process exits
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    transaction_map: TransactionMap,
+    account_map: AccountTable,
+    pruned: PrunedKeys,
+}
+
+impl MemoryStorage {
+    // Reserves room for this many transaction records up front, so a big batch doesn't stall
+    // on the map growing
+    pub fn with_capacity(transactions: usize) -> Self {
+        let mut storage = MemoryStorage::default();
+        storage.transaction_map.reserve(transactions);
+        storage
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+        Ok(self.account_map.get(client).cloned())
+    }
+
+    fn put_account(&mut self, client: ClientID, account: &Account) -> StorageResult<()> {
+        self.account_map.insert(client, account.clone());
+        Ok(())
+    }
+
+    fn record(&self, key: RecordKey) -> StorageResult<Option<TransactionRecord>> {
+        Ok(self.transaction_map.get(&key).cloned())
+    }
+
+    fn put_record(&mut self, key: RecordKey, record: &TransactionRecord) -> StorageResult<()> {
+        self.transaction_map.insert(key, *record);
+        Ok(())
+    }
+
+    fn accounts(&self) -> AccountEntries<'_> {
+        Box::new(
+            self.account_map
+                .iter()
+                .map(|(cid, acc)| Ok((cid, acc.clone()))),
+        )
+    }
+
+    fn accounts_ordered(&self) -> bool {
+        true
+    }
+
+    fn records(&self) -> RecordEntries<'_> {
+        Box::new(
+            self.transaction_map
+                .iter()
+                .map(|(key, record)| Ok((*key, *record))),
+        )
+    }
+
+    fn prune_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.transaction_map.remove(&key);
+        self.pruned.insert(key);
+        Ok(())
+    }
+
+    fn is_pruned(&self, key: RecordKey) -> StorageResult<bool> {
+        Ok(self.pruned.contains(key))
+    }
+
+    fn pruned(&self) -> PrunedEntries<'_> {
+        Box::new(self.pruned.iter().map(Ok))
+    }
+
+    fn remove_account(&mut self, client: ClientID) -> StorageResult<()> {
+        self.account_map.remove(client);
+        Ok(())
+    }
+
+    fn remove_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.transaction_map.remove(&key);
+        self.pruned.remove(key);
+        Ok(())
+    }
+
+    fn contains_record(&self, key: RecordKey) -> StorageResult<bool> {
+        Ok(self.transaction_map.contains_key(&key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_table_lists_clients_in_order() {
+        let mut storage = MemoryStorage::default();
+        for client in [ClientID::MAX, 7, 0] {
+            storage.put_account(client, &Account::default()).unwrap();
+        }
+        assert!(storage.account(8).unwrap().is_none());
+        assert!(storage.account(ClientID::MAX).unwrap().is_some());
+        let clients = storage
+            .accounts()
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec![0, 7, ClientID::MAX]);
+    }
+
+    #[test]
+    fn test_account_table_spans_only_the_clients_stored() {
+        let mut table = AccountTable::default();
+        table.insert(ClientID::MAX, Account::default());
+        assert_eq!(table.slots.len(), 1);
+        table.insert(ClientID::MAX - 2, Account::default());
+        assert_eq!(table.slots.len(), 3);
+        // Room is made below the lowest client in one go rather than one slot at a time
+        table.insert(ClientID::MAX - 3, Account::default());
+        assert_eq!(table.slots.len(), 6);
+        for client in (ClientID::MAX - 1000..ClientID::MAX - 3).rev() {
+            table.insert(client, Account::default());
+        }
+        assert!(table.slots.len() < 2048);
+        assert!(table.get(ClientID::MAX - 1).is_none());
+        assert!(table.get(0).is_none());
+        table.remove(ClientID::MAX);
+        table.remove(0);
+        let clients = table.iter().map(|(client, _)| client).collect::<Vec<_>>();
+        assert_eq!(clients.len(), 999);
+        assert_eq!(clients.first(), Some(&(ClientID::MAX - 1000)));
+        assert_eq!(clients.last(), Some(&(ClientID::MAX - 2)));
+    }
+
+    #[test]
+    fn test_pruned_keys_merge_into_runs() {
+        let key = |client, tx| RecordKey { client, tx };
+        let mut pruned = PrunedKeys::default();
+        for tx in [3, 1, 2, 5, TransactionID::MAX] {
+            pruned.insert(key(Some(1), tx));
+        }
+        pruned.insert(key(None, 4));
+        pruned.insert(key(Some(1), 2));
+        assert_eq!(pruned.runs.len(), 4);
+        assert!(pruned.contains(key(Some(1), 2)));
+        assert!(!pruned.contains(key(Some(1), 4)));
+        assert!(!pruned.contains(key(Some(2), 2)));
+
+        // Filling the gap joins two runs, taking a key out splits one
+        pruned.insert(key(Some(1), 4));
+        assert_eq!(pruned.runs.get(&(Some(1), 1)), Some(&5));
+        pruned.remove(key(Some(1), 3));
+        assert!(!pruned.contains(key(Some(1), 3)));
+        assert_eq!(
+            pruned.iter().collect::<Vec<_>>(),
+            [
+                key(None, 4),
+                key(Some(1), 1),
+                key(Some(1), 2),
+                key(Some(1), 4),
+                key(Some(1), 5),
+                key(Some(1), TransactionID::MAX)
+            ]
+        );
+    }
+}
@@ -0,0 +1,84 @@
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sled")]
+mod spill;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod sql;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::fmt::{self, Debug};
+
+use crate::engine::{Account, ClientID, RecordKey, TransactionRecord};
+
+pub use memory::MemoryStorage;
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStorage;
+#[cfg(feature = "sled")]
+pub use sled::SledStorage;
+#[cfg(feature = "sled")]
+pub use spill::SpillStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Backend(String),
+    Corrupt(String),
+}
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Backend(message) => write!(f, "storage backend failed: {}", message),
+            StorageError::Corrupt(message) => write!(f, "stored state is corrupt: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+pub type AccountEntries<'a> = Box<dyn Iterator<Item = StorageResult<(ClientID, Account)>> + 'a>;
+pub type RecordEntries<'a> =
+    Box<dyn Iterator<Item = StorageResult<(RecordKey, TransactionRecord)>> + 'a>;
+pub type PrunedEntries<'a> = Box<dyn Iterator<Item = StorageResult<RecordKey>> + 'a>;
+
+// Where the Database keeps accounts and transaction records. Values are handed out by copy, so
+// the Database only writes an account back once an operation on it has succeeded.
+pub trait StorageBackend: Send + Debug {
+    fn account(&self, client: ClientID) -> StorageResult<Option<Account>>;
+    fn put_account(&mut self, client: ClientID, account: &Account) -> StorageResult<()>;
+    fn record(&self, key: RecordKey) -> StorageResult<Option<TransactionRecord>>;
+    fn put_record(&mut self, key: RecordKey, record: &TransactionRecord) -> StorageResult<()>;
+    fn accounts(&self) -> AccountEntries<'_>;
+    fn records(&self) -> RecordEntries<'_>;
+
+    // Drops a record for good, keeping only its key so the transaction id stays taken
+    fn prune_record(&mut self, key: RecordKey) -> StorageResult<()>;
+    fn is_pruned(&self, key: RecordKey) -> StorageResult<bool>;
+    fn pruned(&self) -> PrunedEntries<'_>;
+
+    // Forget an account, or a record along with its pruned key, as if it had never been written.
+    // Only used to undo the writes of a commit that failed part way.
+    fn remove_account(&mut self, client: ClientID) -> StorageResult<()>;
+    fn remove_record(&mut self, key: RecordKey) -> StorageResult<()>;
+
+    // Whether accounts() lists clients in ascending order, so sorted output can stream them
+    // rather than hold every account to sort it
+    fn accounts_ordered(&self) -> bool {
+        false
+    }
+
+    fn contains_record(&self, key: RecordKey) -> StorageResult<bool> {
+        self.record(key).map(|record| record.is_some())
+    }
+
+    // Makes every write so far durable, a no-op for volatile backends
+    fn flush(&mut self) -> StorageResult<()> {
+        Ok(())
+    }
+}
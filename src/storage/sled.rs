@@ -0,0 +1,536 @@
+use rust_decimal::Decimal;
+use std::path::Path;
+
+use super::{
+    AccountEntries, PrunedEntries, RecordEntries, StorageBackend, StorageError, StorageResult,
+};
+use crate::engine::{
+    Account, AccountStatus, Balance, ClientID, Currency, RecordKey, RiskCounters, TransactionID,
+    TransactionRecord, TransactionType,
+};
+
+const DECIMAL_LEN: usize = 16;
+// Accounts written before currencies, a single balance and the locked flag
+const LEGACY_ACCOUNT_LEN: usize = DECIMAL_LEN * 2 + 1;
+const BALANCE_LEN: usize = CURRENCY_LEN + DECIMAL_LEN * 2;
+const CURRENCY_LEN: usize = 3;
+// Disputes, chargebacks and rejected withdrawals, each a big-endian u32
+const RISK_LEN: usize = 4 * 3;
+// Flag of accounts whose risk counters follow the flags byte
+const HAS_RISK: u8 = 1 << 3;
+const RECORD_LEN: usize = 1 + 2 + 1 + DECIMAL_LEN + 1;
+// Records written before timestamps, currencies or dispute counts were stored end right before
+// them
+const TIMESTAMPED_RECORD_LEN: usize = RECORD_LEN + 8;
+const CURRENCY_RECORD_LEN: usize = TIMESTAMPED_RECORD_LEN + CURRENCY_LEN;
+const COUNTED_RECORD_LEN: usize = CURRENCY_RECORD_LEN + 1;
+// Records of partial disputes end with the amount held
+const PARTIAL_RECORD_LEN: usize = COUNTED_RECORD_LEN + DECIMAL_LEN;
+// Record key of a client and transaction id, when ids are scoped per client
+const SCOPED_KEY_LEN: usize = 2 + 4;
+
+// Persists accounts and transaction records in a sled database so state survives restarts and
+// transaction histories larger than RAM are paged from disk. Keys are big-endian so iteration
+// is ordered by client / transaction id. Records are keyed by the transaction id alone, or by
+// client and transaction id when ids are scoped per client. Pruned records leave their key
+// behind in a tree of their own.
+#[derive(Debug, Clone)]
+pub struct SledStorage {
+    db: sled::Db,
+    accounts: sled::Tree,
+    records: sled::Tree,
+    pruned: sled::Tree,
+}
+
+impl SledStorage {
+    pub fn open(path: impl AsRef<Path>) -> StorageResult<Self> {
+        let db = sled::open(path)?;
+        let accounts = db.open_tree("accounts")?;
+        let records = db.open_tree("records")?;
+        let pruned = db.open_tree("pruned")?;
+        Ok(SledStorage {
+            db,
+            accounts,
+            records,
+            pruned,
+        })
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(err: sled::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+impl StorageBackend for SledStorage {
+    fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+        self.accounts
+            .get(client.to_be_bytes())?
+            .map(|bytes| decode_account(&bytes))
+            .transpose()
+    }
+
+    fn put_account(&mut self, client: ClientID, account: &Account) -> StorageResult<()> {
+        self.accounts
+            .insert(client.to_be_bytes(), encode_account(account).as_slice())?;
+        Ok(())
+    }
+
+    fn record(&self, key: RecordKey) -> StorageResult<Option<TransactionRecord>> {
+        self.records
+            .get(encode_key(key))?
+            .map(|bytes| decode_record(&bytes))
+            .transpose()
+    }
+
+    fn put_record(&mut self, key: RecordKey, record: &TransactionRecord) -> StorageResult<()> {
+        self.records
+            .insert(encode_key(key), encode_record(record).as_slice())?;
+        Ok(())
+    }
+
+    fn accounts(&self) -> AccountEntries<'_> {
+        Box::new(self.accounts.iter().map(|entry| {
+            let (key, value) = entry?;
+            let client = ClientID::from_be_bytes(fixed(&key)?);
+            Ok((client, decode_account(&value)?))
+        }))
+    }
+
+    // Client keys are big endian, so byte order is client order
+    fn accounts_ordered(&self) -> bool {
+        true
+    }
+
+    fn records(&self) -> RecordEntries<'_> {
+        Box::new(self.records.iter().map(|entry| {
+            let (key, value) = entry?;
+            Ok((decode_key(&key)?, decode_record(&value)?))
+        }))
+    }
+
+    fn prune_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.records.remove(encode_key(key))?;
+        self.pruned.insert(encode_key(key), &[])?;
+        Ok(())
+    }
+
+    fn is_pruned(&self, key: RecordKey) -> StorageResult<bool> {
+        Ok(self.pruned.contains_key(encode_key(key))?)
+    }
+
+    fn pruned(&self) -> PrunedEntries<'_> {
+        Box::new(self.pruned.iter().keys().map(|key| decode_key(&key?)))
+    }
+
+    fn remove_account(&mut self, client: ClientID) -> StorageResult<()> {
+        self.accounts.remove(client.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn remove_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.records.remove(encode_key(key))?;
+        self.pruned.remove(encode_key(key))?;
+        Ok(())
+    }
+
+    fn contains_record(&self, key: RecordKey) -> StorageResult<bool> {
+        Ok(self.records.contains_key(encode_key(key))?)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+pub(super) fn encode_key(key: RecordKey) -> Vec<u8> {
+    match key.client {
+        Some(client) => [&client.to_be_bytes()[..], &key.tx.to_be_bytes()].concat(),
+        None => key.tx.to_be_bytes().to_vec(),
+    }
+}
+
+pub(super) fn decode_key(bytes: &[u8]) -> StorageResult<RecordKey> {
+    match bytes.len() {
+        SCOPED_KEY_LEN => {
+            let (client, tx) = bytes.split_at(SCOPED_KEY_LEN - 4);
+            Ok(RecordKey {
+                client: Some(ClientID::from_be_bytes(fixed(client)?)),
+                tx: TransactionID::from_be_bytes(fixed(tx)?),
+            })
+        }
+        _ => Ok(RecordKey::from(TransactionID::from_be_bytes(fixed(bytes)?))),
+    }
+}
+
+pub(super) fn fixed<const N: usize>(bytes: &[u8]) -> StorageResult<[u8; N]> {
+    bytes
+        .try_into()
+        .map_err(|_| StorageError::Corrupt(format!("expected {} bytes, got {}", N, bytes.len())))
+}
+
+fn decode_decimal(bytes: &[u8]) -> StorageResult<Decimal> {
+    Ok(Decimal::deserialize(fixed(bytes)?))
+}
+
+// A flags byte, the risk counters unless they are all zero, then every balance. Never as long as
+// a legacy account, whatever the number of balances. The flags hold the locked flag in bit 0, the
+// status in bits 1 and 2 and whether risk counters follow in bit 3, accounts written before
+// statuses reading as implicit and those written before risk counters as never at risk.
+fn encode_account(account: &Account) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + RISK_LEN + account.balances.len() * BALANCE_LEN);
+    let status = match account.status {
+        AccountStatus::Implicit => 0,
+        AccountStatus::Open => 1,
+        AccountStatus::Closed => 2,
+    };
+    let risk = account.risk != RiskCounters::default();
+    bytes.push(account.locked as u8 | status << 1 | if risk { HAS_RISK } else { 0 });
+    if risk {
+        for counter in [
+            account.risk.disputes,
+            account.risk.chargebacks,
+            account.risk.rejected_withdrawals,
+        ] {
+            bytes.extend_from_slice(&counter.to_be_bytes());
+        }
+    }
+    for (currency, balance) in &account.balances {
+        bytes.extend_from_slice(&encode_currency(*currency));
+        bytes.extend_from_slice(&balance.available.serialize());
+        bytes.extend_from_slice(&balance.held.serialize());
+    }
+    bytes
+}
+
+fn decode_account(bytes: &[u8]) -> StorageResult<Account> {
+    let mut account = Account::new();
+    if bytes.len() == LEGACY_ACCOUNT_LEN {
+        account
+            .balances
+            .insert(None, decode_balance(&bytes[..DECIMAL_LEN * 2])?);
+        account.locked = bytes[DECIMAL_LEN * 2] != 0;
+        return Ok(account);
+    }
+    let (flags, mut balances) = bytes
+        .split_first()
+        .ok_or_else(|| StorageError::Corrupt("empty account".to_string()))?;
+    if flags & HAS_RISK != 0 {
+        let (risk, rest) = balances
+            .split_at_checked(RISK_LEN)
+            .ok_or_else(|| StorageError::Corrupt("truncated risk counters".to_string()))?;
+        let counter = |i: usize| u32::from_be_bytes(risk[i * 4..i * 4 + 4].try_into().unwrap());
+        account.risk = RiskCounters {
+            disputes: counter(0),
+            chargebacks: counter(1),
+            rejected_withdrawals: counter(2),
+        };
+        balances = rest;
+    }
+    if balances.len() % BALANCE_LEN != 0 {
+        return Err(StorageError::Corrupt(format!(
+            "account of {} bytes",
+            bytes.len()
+        )));
+    }
+    account.locked = flags & 1 != 0;
+    account.status = match flags >> 1 & 0b11 {
+        0 => AccountStatus::Implicit,
+        1 => AccountStatus::Open,
+        2 => AccountStatus::Closed,
+        status => {
+            return Err(StorageError::Corrupt(format!(
+                "unknown account status {}",
+                status
+            )));
+        }
+    };
+    for balance in balances.chunks(BALANCE_LEN) {
+        let currency = decode_currency(fixed(&balance[..CURRENCY_LEN])?)?;
+        account
+            .balances
+            .insert(currency, decode_balance(&balance[CURRENCY_LEN..])?);
+    }
+    Ok(account)
+}
+
+fn decode_balance(bytes: &[u8]) -> StorageResult<Balance> {
+    Ok(Balance {
+        available: decode_decimal(&bytes[..DECIMAL_LEN])?,
+        held: decode_decimal(&bytes[DECIMAL_LEN..DECIMAL_LEN * 2])?,
+    })
+}
+
+// No currency is stored as zeros
+fn encode_currency(currency: Option<Currency>) -> [u8; CURRENCY_LEN] {
+    currency.map_or([0; CURRENCY_LEN], Currency::to_bytes)
+}
+
+fn decode_currency(bytes: [u8; CURRENCY_LEN]) -> StorageResult<Option<Currency>> {
+    match bytes {
+        [0, 0, 0] => Ok(None),
+        bytes => Currency::from_bytes(bytes)
+            .map(Some)
+            .ok_or_else(|| StorageError::Corrupt(format!("invalid currency {:?}", bytes))),
+    }
+}
+
+fn encode_tx_type(tx_type: &TransactionType) -> u8 {
+    match tx_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Transfer => 5,
+        TransactionType::Unlock => 6,
+        TransactionType::Convert => 7,
+        TransactionType::Fee => 8,
+        TransactionType::Open => 9,
+        TransactionType::Close => 10,
+        TransactionType::Reversal => 11,
+        TransactionType::Settle => 12,
+        // Never recorded, and refused by decode_tx_type
+        TransactionType::Other(_) => u8::MAX,
+    }
+}
+
+fn decode_tx_type(byte: u8) -> StorageResult<TransactionType> {
+    match byte {
+        0 => Ok(TransactionType::Deposit),
+        1 => Ok(TransactionType::Withdrawal),
+        2 => Ok(TransactionType::Dispute),
+        3 => Ok(TransactionType::Resolve),
+        4 => Ok(TransactionType::Chargeback),
+        5 => Ok(TransactionType::Transfer),
+        6 => Ok(TransactionType::Unlock),
+        7 => Ok(TransactionType::Convert),
+        8 => Ok(TransactionType::Fee),
+        9 => Ok(TransactionType::Open),
+        10 => Ok(TransactionType::Close),
+        11 => Ok(TransactionType::Reversal),
+        12 => Ok(TransactionType::Settle),
+        _ => Err(StorageError::Corrupt(format!(
+            "unknown transaction type {}",
+            byte
+        ))),
+    }
+}
+
+// The transaction id is the key, so it is not repeated in the value. The amount flag dates from
+// records holding an optional amount, it is kept so existing state directories stay readable.
+// The dispute byte holds the disputed flag in bit 0, the resolved flag in bit 1, the charged
+// back flag in bit 2, the reversed flag in bit 3 and the rejected flag in bit 4. A missing timestamp is encoded as u64::MAX.
+pub(super) fn encode_record(record: &TransactionRecord) -> Vec<u8> {
+    let mut bytes = vec![0; COUNTED_RECORD_LEN];
+    bytes[0] = encode_tx_type(&record.tx_type());
+    bytes[1..3].copy_from_slice(&record.client().to_be_bytes());
+    bytes[3] = 1;
+    bytes[4..4 + DECIMAL_LEN].copy_from_slice(&record.amount().serialize());
+    bytes[4 + DECIMAL_LEN] = record.is_disputed() as u8
+        | (record.was_resolved() as u8) << 1
+        | (record.was_charged_back() as u8) << 2
+        | (record.was_reversed() as u8) << 3
+        | (record.was_rejected() as u8) << 4;
+    bytes[RECORD_LEN..TIMESTAMPED_RECORD_LEN]
+        .copy_from_slice(&record.timestamp().unwrap_or(u64::MAX).to_be_bytes());
+    bytes[TIMESTAMPED_RECORD_LEN..CURRENCY_RECORD_LEN]
+        .copy_from_slice(&encode_currency(record.currency()));
+    bytes[CURRENCY_RECORD_LEN] = record.dispute_count();
+    if record.disputed_amount() != record.amount() && record.is_disputed() {
+        bytes.extend_from_slice(&record.disputed_amount().serialize());
+    }
+    bytes
+}
+
+pub(super) fn decode_record(bytes: &[u8]) -> StorageResult<TransactionRecord> {
+    let lengths = [
+        RECORD_LEN,
+        TIMESTAMPED_RECORD_LEN,
+        CURRENCY_RECORD_LEN,
+        COUNTED_RECORD_LEN,
+        PARTIAL_RECORD_LEN,
+    ];
+    if !lengths.contains(&bytes.len()) {
+        return Err(StorageError::Corrupt(format!(
+            "record of {} bytes",
+            bytes.len()
+        )));
+    }
+    let timestamp = match bytes.get(RECORD_LEN..TIMESTAMPED_RECORD_LEN) {
+        Some(timestamp) => match u64::from_be_bytes(fixed(timestamp)?) {
+            u64::MAX => None,
+            timestamp => Some(timestamp),
+        },
+        None => None,
+    };
+    let currency = match bytes.get(TIMESTAMPED_RECORD_LEN..CURRENCY_RECORD_LEN) {
+        Some(currency) => decode_currency(fixed(currency)?)?,
+        None => None,
+    };
+    let disputes = bytes.get(CURRENCY_RECORD_LEN).copied().unwrap_or_default();
+    let partial = match bytes.get(COUNTED_RECORD_LEN..) {
+        Some(partial) if !partial.is_empty() => Some(decode_decimal(partial)?),
+        _ => None,
+    };
+    let bytes: [u8; RECORD_LEN] = fixed(&bytes[..RECORD_LEN])?;
+    let amount = match bytes[3] {
+        0 => {
+            return Err(StorageError::Corrupt(
+                "record without an amount".to_string(),
+            ));
+        }
+        _ => decode_decimal(&bytes[4..4 + DECIMAL_LEN])?,
+    };
+    let mut record = TransactionRecord::new(
+        &decode_tx_type(bytes[0])?,
+        ClientID::from_be_bytes(fixed(&bytes[1..3])?),
+        amount,
+    )
+    .with_timestamp(timestamp)
+    .with_currency(currency);
+    record.set_disputed(bytes[4 + DECIMAL_LEN] & 1 != 0);
+    if bytes[4 + DECIMAL_LEN] & 2 != 0 {
+        record.set_resolved();
+    }
+    if bytes[4 + DECIMAL_LEN] & 4 != 0 {
+        record.set_charged_back();
+    }
+    if bytes[4 + DECIMAL_LEN] & 8 != 0 {
+        record.set_reversed();
+    }
+    if bytes[4 + DECIMAL_LEN] & 16 != 0 {
+        record.set_rejected();
+    }
+    record.set_dispute_count(disputes);
+    if let Some(partial) = partial {
+        record.set_disputed_amount(partial);
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_state_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut storage = SledStorage::open(dir.path()).unwrap();
+            let mut account = Account::new();
+            account.deposit(None, dec!(12.3456)).unwrap();
+            account.dispute(None, dec!(2.0)).unwrap();
+            storage.put_account(7, &account).unwrap();
+            let mut disputed_deposit =
+                TransactionRecord::new(&TransactionType::Deposit, 7, dec!(12.3456));
+            disputed_deposit.set_disputed(true);
+            storage
+                .put_record(RecordKey::from(99), &disputed_deposit)
+                .unwrap();
+            storage.flush().unwrap();
+            drop(storage);
+        }
+
+        // Writes sled handed to its thread pool hold the directory's lock a little past the drop
+        let mut tries = 0;
+        let storage = loop {
+            match SledStorage::open(dir.path()) {
+                Err(StorageError::Backend(err)) if err.contains("lock") && tries < 100 => {
+                    tries += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                opened => break opened.unwrap(),
+            }
+        };
+        let account = storage.account(7).unwrap().unwrap();
+        assert_eq!(account.available(), dec!(10.3456));
+        assert_eq!(account.held(), dec!(2.0));
+        let record = storage.record(RecordKey::from(99)).unwrap().unwrap();
+        assert_eq!(record.client(), 7);
+        assert_eq!(record.amount(), dec!(12.3456));
+        assert_eq!(record.tx_type(), TransactionType::Deposit);
+        assert!(record.is_disputed());
+        assert!(storage.account(8).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_record_keys_round_trip() {
+        for key in [
+            RecordKey::from(7),
+            RecordKey {
+                client: Some(3),
+                tx: 7,
+            },
+        ] {
+            assert_eq!(decode_key(&encode_key(key)).unwrap(), key);
+        }
+        // Keys written before ids could be scoped per client are the bare id
+        assert_eq!(decode_key(&7u32.to_be_bytes()).unwrap(), RecordKey::from(7));
+    }
+
+    #[test]
+    fn test_records_keep_timestamp_and_currency_and_read_legacy_layouts() {
+        let record = TransactionRecord::new(&TransactionType::Withdrawal, 3, dec!(1.5))
+            .with_timestamp(Some(42))
+            .with_currency(Currency::new("EUR"));
+        let mut record = record;
+        record.dispute(None);
+        record.set_charged_back();
+        let bytes = encode_record(&record);
+        assert_eq!(decode_record(&bytes).unwrap(), record);
+        let plain = TransactionRecord::new(&TransactionType::Deposit, 3, dec!(1.5));
+        assert_eq!(decode_record(&encode_record(&plain)).unwrap(), plain);
+        let mut reversed = plain;
+        reversed.set_reversed();
+        assert!(
+            decode_record(&encode_record(&reversed))
+                .unwrap()
+                .was_reversed()
+        );
+        let mut rejected = plain;
+        rejected.set_rejected();
+        assert_eq!(decode_record(&encode_record(&rejected)).unwrap(), rejected);
+        let mut partial = plain;
+        partial.dispute(Some(dec!(0.5)));
+        let bytes = encode_record(&partial);
+        assert_eq!(bytes.len(), PARTIAL_RECORD_LEN);
+        assert_eq!(decode_record(&bytes).unwrap().disputed_amount(), dec!(0.5));
+        // Records written before timestamps, currencies or dispute counts were stored
+        for len in [RECORD_LEN, TIMESTAMPED_RECORD_LEN, CURRENCY_RECORD_LEN] {
+            assert_eq!(decode_record(&encode_record(&plain)[..len]).unwrap(), plain);
+        }
+        assert!(decode_record(&bytes[..RECORD_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn test_accounts_keep_every_currency_and_read_legacy_layout() {
+        let mut account = Account::new();
+        account.deposit(None, dec!(1.0)).unwrap();
+        account.deposit(Currency::new("GBP"), dec!(2.5)).unwrap();
+        account.locked = true;
+        account.status = AccountStatus::Open;
+        let decoded = decode_account(&encode_account(&account)).unwrap();
+        assert_eq!(
+            decoded.balances().collect::<Vec<_>>(),
+            account.balances().collect::<Vec<_>>()
+        );
+        assert!(decoded.is_locked());
+        assert_eq!(decoded.status(), AccountStatus::Open);
+        assert_eq!(decoded.risk(), RiskCounters::default());
+        account.risk.chargebacks = 3;
+        account.risk.rejected_withdrawals = 70_000;
+        let decoded = decode_account(&encode_account(&account)).unwrap();
+        assert_eq!(decoded.risk(), account.risk);
+        assert_eq!(decoded.balances().count(), 2);
+
+        let mut legacy = [0; LEGACY_ACCOUNT_LEN];
+        legacy[..DECIMAL_LEN].copy_from_slice(&dec!(7.5).serialize());
+        let decoded = decode_account(&legacy).unwrap();
+        assert_eq!(decoded.available(), dec!(7.5));
+        assert!(!decoded.is_locked());
+    }
+}
@@ -0,0 +1,203 @@
+use hashlink::LruCache;
+use std::cell::RefCell;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::memory::{AccountTable, PrunedKeys};
+use super::sled::{decode_key, decode_record, encode_key, encode_record};
+use super::{AccountEntries, PrunedEntries, RecordEntries, StorageBackend, StorageResult};
+use crate::engine::{Account, ClientID, RecordKey, TransactionRecord};
+
+// Rough footprint of a hot record including the LRU's hashing and linked list overhead
+const HOT_RECORD_BYTES: usize = size_of::<(RecordKey, TransactionRecord)>() + 40;
+
+// Tells apart the spill files of several stores in one process, e.g. one per shard
+static SPILL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Keeps accounts and the most recently referenced transaction records in memory, and pages
+// colder records out to a temporary on-disk index once the memory budget is reached. A record
+// lives either in memory or on disk, reading a cold record moves it back into memory.
+// Accounts always stay in memory, there are at most 65536 of them.
+#[derive(Debug)]
+pub struct SpillStorage {
+    accounts: AccountTable,
+    // Reads promote records, which needs mutation behind StorageBackend's &self
+    hot: RefCell<LruCache<RecordKey, TransactionRecord>>,
+    hot_capacity: usize,
+    cold: sled::Tree,
+    // Keys only, these stay in memory
+    pruned: PrunedKeys,
+    // Owns the spill files, sled removes them when dropped
+    _spill: sled::Db,
+}
+
+impl SpillStorage {
+    // Spills under the system temporary directory once records take up about max_memory bytes
+    pub fn open(max_memory: usize) -> StorageResult<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "octopus-spill-{}-{}",
+            std::process::id(),
+            SPILL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let spill = sled::Config::new().path(dir).temporary(true).open()?;
+        Ok(SpillStorage {
+            accounts: AccountTable::default(),
+            hot: RefCell::new(LruCache::new_unbounded()),
+            hot_capacity: (max_memory / HOT_RECORD_BYTES).max(1),
+            cold: spill.open_tree("records")?,
+            pruned: PrunedKeys::default(),
+            _spill: spill,
+        })
+    }
+
+    // Inserts into memory, evicting the least recently used records to disk past the budget
+    fn insert_hot(&self, key: RecordKey, record: TransactionRecord) -> StorageResult<()> {
+        let mut hot = self.hot.borrow_mut();
+        hot.insert(key, record);
+        while hot.len() > self.hot_capacity {
+            match hot.remove_lru() {
+                Some((key, record)) => {
+                    self.cold
+                        .insert(encode_key(key), encode_record(&record).as_slice())?;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for SpillStorage {
+    fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+        Ok(self.accounts.get(client).cloned())
+    }
+
+    fn put_account(&mut self, client: ClientID, account: &Account) -> StorageResult<()> {
+        self.accounts.insert(client, account.clone());
+        Ok(())
+    }
+
+    fn record(&self, key: RecordKey) -> StorageResult<Option<TransactionRecord>> {
+        if let Some(record) = self.hot.borrow_mut().get(&key) {
+            return Ok(Some(*record));
+        }
+        match self.cold.remove(encode_key(key))? {
+            Some(bytes) => {
+                let record = decode_record(&bytes)?;
+                self.insert_hot(key, record)?;
+                Ok(Some(record))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put_record(&mut self, key: RecordKey, record: &TransactionRecord) -> StorageResult<()> {
+        // An update of a cold record must not leave the stale copy behind
+        self.cold.remove(encode_key(key))?;
+        self.insert_hot(key, *record)
+    }
+
+    fn accounts(&self) -> AccountEntries<'_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .map(|(client, account)| Ok((client, account.clone()))),
+        )
+    }
+
+    fn accounts_ordered(&self) -> bool {
+        true
+    }
+
+    fn records(&self) -> RecordEntries<'_> {
+        let hot = self
+            .hot
+            .borrow()
+            .iter()
+            .map(|(key, record)| Ok((*key, *record)))
+            .collect::<Vec<_>>();
+        let cold = self.cold.iter().map(|entry| {
+            let (key, value) = entry?;
+            Ok((decode_key(&key)?, decode_record(&value)?))
+        });
+        Box::new(hot.into_iter().chain(cold))
+    }
+
+    fn prune_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.hot.borrow_mut().remove(&key);
+        self.cold.remove(encode_key(key))?;
+        self.pruned.insert(key);
+        Ok(())
+    }
+
+    fn is_pruned(&self, key: RecordKey) -> StorageResult<bool> {
+        Ok(self.pruned.contains(key))
+    }
+
+    fn pruned(&self) -> PrunedEntries<'_> {
+        Box::new(self.pruned.iter().map(Ok))
+    }
+
+    fn remove_account(&mut self, client: ClientID) -> StorageResult<()> {
+        self.accounts.remove(client);
+        Ok(())
+    }
+
+    fn remove_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.hot.borrow_mut().remove(&key);
+        self.cold.remove(encode_key(key))?;
+        self.pruned.remove(key);
+        Ok(())
+    }
+
+    // Doesn't count as a reference, duplicate checks would otherwise keep every record hot
+    fn contains_record(&self, key: RecordKey) -> StorageResult<bool> {
+        Ok(self.hot.borrow().contains_key(&key) || self.cold.contains_key(encode_key(key))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType;
+    use rust_decimal::{Decimal, dec};
+
+    #[test]
+    fn test_cold_records_are_paged_back_in() {
+        let mut storage = SpillStorage::open(HOT_RECORD_BYTES * 2).unwrap();
+        for tx in 1..=5 {
+            storage
+                .put_record(
+                    RecordKey::from(tx),
+                    &TransactionRecord::new(&TransactionType::Deposit, 1, Decimal::from(tx)),
+                )
+                .unwrap();
+        }
+        assert_eq!(storage.hot.borrow().len(), 2);
+        assert_eq!(storage.cold.len(), 3);
+        assert!(storage.contains_record(RecordKey::from(1)).unwrap());
+        assert!(!storage.contains_record(RecordKey::from(6)).unwrap());
+
+        let mut record = storage.record(RecordKey::from(1)).unwrap().unwrap();
+        assert_eq!(record.amount(), dec!(1));
+        assert!(storage.hot.borrow().contains_key(&RecordKey::from(1)));
+        assert_eq!(storage.cold.len(), 3);
+
+        record.set_disputed(true);
+        storage.put_record(RecordKey::from(1), &record).unwrap();
+        assert!(
+            storage
+                .record(RecordKey::from(1))
+                .unwrap()
+                .unwrap()
+                .is_disputed()
+        );
+
+        let mut all = storage
+            .records()
+            .map(|entry| entry.unwrap().0.tx)
+            .collect::<Vec<_>>();
+        all.sort_unstable();
+        assert_eq!(all, vec![1, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,84 @@
+use rust_decimal::Decimal;
+
+use super::{StorageError, StorageResult};
+use crate::engine::{AccountStatus, ClientID, Currency, RecordKey, TransactionID, TransactionType};
+
+// How SqliteStorage and PostgresStorage store keys and values, laid out alike
+
+// Record keys of transaction ids unique across clients
+pub(super) const GLOBAL_SCOPE: i64 = -1;
+
+pub(super) fn encode_scope(key: RecordKey) -> i64 {
+    key.client.map_or(GLOBAL_SCOPE, i64::from)
+}
+
+pub(super) fn decode_key(scope: i64, tx: TransactionID) -> StorageResult<RecordKey> {
+    let client = match scope {
+        GLOBAL_SCOPE => None,
+        client => Some(
+            ClientID::try_from(client)
+                .map_err(|_| StorageError::Corrupt(format!("transaction scope {}", client)))?,
+        ),
+    };
+    Ok(RecordKey { client, tx })
+}
+
+pub(super) fn encode_status(status: AccountStatus) -> &'static str {
+    match status {
+        AccountStatus::Implicit => "implicit",
+        AccountStatus::Open => "open",
+        AccountStatus::Closed => "closed",
+    }
+}
+
+pub(super) fn decode_status(status: &str) -> StorageResult<AccountStatus> {
+    match status {
+        "implicit" => Ok(AccountStatus::Implicit),
+        "open" => Ok(AccountStatus::Open),
+        "closed" => Ok(AccountStatus::Closed),
+        other => Err(StorageError::Corrupt(format!(
+            "unknown account status {}",
+            other
+        ))),
+    }
+}
+
+// Only the types that leave a record
+pub(super) fn encode_tx_type(tx_type: &TransactionType) -> &'static str {
+    match tx_type {
+        TransactionType::Withdrawal => "withdrawal",
+        TransactionType::Transfer => "transfer",
+        TransactionType::Convert => "convert",
+        TransactionType::Fee => "fee",
+        _ => "deposit",
+    }
+}
+
+pub(super) fn decode_tx_type(tx_type: &str) -> StorageResult<TransactionType> {
+    match tx_type {
+        "deposit" => Ok(TransactionType::Deposit),
+        "withdrawal" => Ok(TransactionType::Withdrawal),
+        "transfer" => Ok(TransactionType::Transfer),
+        "convert" => Ok(TransactionType::Convert),
+        "fee" => Ok(TransactionType::Fee),
+        other => Err(StorageError::Corrupt(format!(
+            "unknown transaction type {}",
+            other
+        ))),
+    }
+}
+
+pub(super) fn decode_decimal(value: &str) -> StorageResult<Decimal> {
+    value
+        .parse()
+        .map_err(|_| StorageError::Corrupt(format!("invalid amount {}", value)))
+}
+
+pub(super) fn decode_currency(code: &str) -> StorageResult<Option<Currency>> {
+    match code {
+        "" => Ok(None),
+        code => Currency::new(code)
+            .map(Some)
+            .ok_or_else(|| StorageError::Corrupt(format!("invalid currency {}", code))),
+    }
+}
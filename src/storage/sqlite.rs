@@ -0,0 +1,496 @@
+use rusqlite::{Connection, Row, params};
+use std::{collections::BTreeMap, path::Path};
+
+use super::sql::{
+    decode_currency, decode_decimal, decode_key, decode_status, decode_tx_type, encode_scope,
+    encode_status, encode_tx_type,
+};
+use super::{
+    AccountEntries, PrunedEntries, RecordEntries, StorageBackend, StorageError, StorageResult,
+};
+use crate::engine::{
+    Account, Balance, ClientID, Currency, RecordKey, RiskCounters, TransactionRecord,
+};
+
+// Writes grouped into one SQLite transaction, committed sooner by flush()
+const BATCH: usize = 10_000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        locked INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        disputes INTEGER NOT NULL DEFAULT 0,
+        chargebacks INTEGER NOT NULL DEFAULT 0,
+        rejected_withdrawals INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS balances (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        PRIMARY KEY (client, currency)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS transactions (
+        scope INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        timestamp INTEGER,
+        currency TEXT,
+        disputed INTEGER NOT NULL,
+        resolved INTEGER NOT NULL,
+        charged_back INTEGER NOT NULL,
+        disputes INTEGER NOT NULL,
+        disputed_amount TEXT,
+        reversed INTEGER NOT NULL DEFAULT 0,
+        rejected INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (scope, tx)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS pruned (
+        scope INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        PRIMARY KEY (scope, tx)
+    ) WITHOUT ROWID;
+";
+
+// Files created before risk counters lack their columns
+const ADD_RISK_COUNTERS: &str = "
+    ALTER TABLE accounts ADD COLUMN disputes INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE accounts ADD COLUMN chargebacks INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE accounts ADD COLUMN rejected_withdrawals INTEGER NOT NULL DEFAULT 0;
+";
+
+// Nor do those created before partial disputes, reversals or rejected records
+const ADD_DISPUTED_AMOUNT: &str = "ALTER TABLE transactions ADD COLUMN disputed_amount TEXT";
+const ADD_REVERSED: &str =
+    "ALTER TABLE transactions ADD COLUMN reversed INTEGER NOT NULL DEFAULT 0";
+const ADD_REJECTED: &str =
+    "ALTER TABLE transactions ADD COLUMN rejected INTEGER NOT NULL DEFAULT 0";
+
+// Persists accounts and transaction records in a SQLite file, as plain tables that can be
+// queried with any SQLite client: accounts, their balances (one row per currency, '' standing
+// for none), the transactions with their dispute state and the keys of pruned ones. Amounts are decimal strings, so
+// nothing is lost to floating point. Writes are grouped into SQLite transactions of BATCH writes,
+// the last one committed by flush() or on drop.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Connection,
+    // Writes in the open transaction, none is open at 0
+    pending: usize,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> StorageResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        if conn
+            .prepare("SELECT chargebacks FROM accounts LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch(ADD_RISK_COUNTERS)?;
+        }
+        if conn
+            .prepare("SELECT disputed_amount FROM transactions LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch(ADD_DISPUTED_AMOUNT)?;
+        }
+        if conn
+            .prepare("SELECT reversed FROM transactions LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch(ADD_REVERSED)?;
+        }
+        if conn
+            .prepare("SELECT rejected FROM transactions LIMIT 0")
+            .is_err()
+        {
+            conn.execute_batch(ADD_REJECTED)?;
+        }
+        Ok(SqliteStorage { conn, pending: 0 })
+    }
+
+    // Called before every write, so it lands in the open transaction
+    fn write(&mut self) -> StorageResult<()> {
+        if self.pending >= BATCH {
+            self.commit()?;
+        }
+        if self.pending == 0 {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> StorageResult<()> {
+        if self.pending > 0 {
+            self.conn.execute_batch("COMMIT")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    fn load_accounts(&self) -> StorageResult<Vec<(ClientID, Account)>> {
+        let mut accounts = BTreeMap::new();
+        let mut statement = self.conn.prepare_cached(
+            "SELECT client, locked, status, disputes, chargebacks, rejected_withdrawals
+            FROM accounts",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            accounts.insert(row.get::<_, ClientID>(0)?, decode_account(row)?);
+        }
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT client, currency, available, held FROM balances")?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let (currency, balance) = decode_balance(row)?;
+            if let Some(account) = accounts.get_mut(&row.get::<_, ClientID>(0)?) {
+                account.balances.insert(currency, balance);
+            }
+        }
+        Ok(accounts.into_iter().collect())
+    }
+
+    fn load_records(&self) -> StorageResult<Vec<(RecordKey, TransactionRecord)>> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT scope, tx, type, client, amount, timestamp, currency, disputed, resolved,
+                charged_back, disputes, disputed_amount, reversed, rejected FROM transactions
+                ORDER BY scope, tx",
+        )?;
+        let mut rows = statement.query([])?;
+        let mut records = Vec::new();
+        while let Some(row) = rows.next()? {
+            let key = decode_key(row.get(0)?, row.get(1)?)?;
+            records.push((key, decode_record(row, 2)?));
+        }
+        Ok(records)
+    }
+
+    fn load_pruned(&self) -> StorageResult<Vec<RecordKey>> {
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT scope, tx FROM pruned ORDER BY scope, tx")?;
+        let mut rows = statement.query([])?;
+        let mut keys = Vec::new();
+        while let Some(row) = rows.next()? {
+            keys.push(decode_key(row.get(0)?, row.get(1)?)?);
+        }
+        Ok(keys)
+    }
+}
+
+impl Drop for SqliteStorage {
+    fn drop(&mut self) {
+        if let Err(err) = self.commit() {
+            tracing::error!(?err, "failed to commit the last SQLite batch");
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    fn account(&self, client: ClientID) -> StorageResult<Option<Account>> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT client, locked, status, disputes, chargebacks, rejected_withdrawals
+            FROM accounts WHERE client = ?1",
+        )?;
+        let mut rows = statement.query([client])?;
+        let Some(row) = rows.next()? else {
+            return Ok(None);
+        };
+        let mut account = decode_account(row)?;
+        let mut statement = self.conn.prepare_cached(
+            "SELECT client, currency, available, held FROM balances WHERE client = ?1",
+        )?;
+        let mut rows = statement.query([client])?;
+        while let Some(row) = rows.next()? {
+            let (currency, balance) = decode_balance(row)?;
+            account.balances.insert(currency, balance);
+        }
+        Ok(Some(account))
+    }
+
+    fn put_account(&mut self, client: ClientID, account: &Account) -> StorageResult<()> {
+        self.write()?;
+        self.conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO accounts (client, locked, status, disputes, chargebacks,
+                rejected_withdrawals) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                client,
+                account.locked,
+                encode_status(account.status),
+                account.risk.disputes,
+                account.risk.chargebacks,
+                account.risk.rejected_withdrawals
+            ])?;
+        self.conn
+            .prepare_cached("DELETE FROM balances WHERE client = ?1")?
+            .execute([client])?;
+        let mut statement = self.conn.prepare_cached(
+            "INSERT INTO balances (client, currency, available, held) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (currency, balance) in &account.balances {
+            statement.execute(params![
+                client,
+                currency.as_ref().map_or("", Currency::as_str),
+                balance.available.to_string(),
+                balance.held.to_string(),
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, key: RecordKey) -> StorageResult<Option<TransactionRecord>> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT type, client, amount, timestamp, currency, disputed, resolved, charged_back,
+                disputes, disputed_amount, reversed, rejected FROM transactions
+                WHERE scope = ?1 AND tx = ?2",
+        )?;
+        let mut rows = statement.query(params![encode_scope(key), key.tx])?;
+        rows.next()?.map(|row| decode_record(row, 0)).transpose()
+    }
+
+    fn put_record(&mut self, key: RecordKey, record: &TransactionRecord) -> StorageResult<()> {
+        self.write()?;
+        self.conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO transactions (scope, tx, type, client, amount, timestamp,
+                    currency, disputed, resolved, charged_back, disputes, disputed_amount,
+                    reversed, rejected)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            )?
+            .execute(params![
+                encode_scope(key),
+                key.tx,
+                encode_tx_type(&record.tx_type()),
+                record.client(),
+                record.amount().to_string(),
+                // SQLite integers are signed, timestamps past 2^63 don't exist in practice
+                record.timestamp().map(|t| t as i64),
+                record.currency().as_ref().map(Currency::as_str),
+                record.is_disputed(),
+                record.was_resolved(),
+                record.was_charged_back(),
+                record.dispute_count(),
+                // Only partial disputes, NULL holding the whole amount
+                (record.is_disputed() && record.disputed_amount() != record.amount())
+                    .then(|| record.disputed_amount().to_string()),
+                record.was_reversed(),
+                record.was_rejected(),
+            ])?;
+        Ok(())
+    }
+
+    fn accounts(&self) -> AccountEntries<'_> {
+        match self.load_accounts() {
+            Ok(accounts) => Box::new(accounts.into_iter().map(Ok)),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    // Loaded into a BTreeMap
+    fn accounts_ordered(&self) -> bool {
+        true
+    }
+
+    fn records(&self) -> RecordEntries<'_> {
+        match self.load_records() {
+            Ok(records) => Box::new(records.into_iter().map(Ok)),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    fn prune_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.write()?;
+        self.conn
+            .prepare_cached("DELETE FROM transactions WHERE scope = ?1 AND tx = ?2")?
+            .execute(params![encode_scope(key), key.tx])?;
+        self.conn
+            .prepare_cached("INSERT OR IGNORE INTO pruned (scope, tx) VALUES (?1, ?2)")?
+            .execute(params![encode_scope(key), key.tx])?;
+        Ok(())
+    }
+
+    fn is_pruned(&self, key: RecordKey) -> StorageResult<bool> {
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT 1 FROM pruned WHERE scope = ?1 AND tx = ?2")?;
+        Ok(statement.exists(params![encode_scope(key), key.tx])?)
+    }
+
+    fn pruned(&self) -> PrunedEntries<'_> {
+        match self.load_pruned() {
+            Ok(keys) => Box::new(keys.into_iter().map(Ok)),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    fn remove_account(&mut self, client: ClientID) -> StorageResult<()> {
+        self.write()?;
+        self.conn
+            .prepare_cached("DELETE FROM accounts WHERE client = ?1")?
+            .execute([client])?;
+        self.conn
+            .prepare_cached("DELETE FROM balances WHERE client = ?1")?
+            .execute([client])?;
+        Ok(())
+    }
+
+    fn remove_record(&mut self, key: RecordKey) -> StorageResult<()> {
+        self.write()?;
+        self.conn
+            .prepare_cached("DELETE FROM transactions WHERE scope = ?1 AND tx = ?2")?
+            .execute(params![encode_scope(key), key.tx])?;
+        self.conn
+            .prepare_cached("DELETE FROM pruned WHERE scope = ?1 AND tx = ?2")?
+            .execute(params![encode_scope(key), key.tx])?;
+        Ok(())
+    }
+
+    fn contains_record(&self, key: RecordKey) -> StorageResult<bool> {
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT 1 FROM transactions WHERE scope = ?1 AND tx = ?2")?;
+        Ok(statement.exists(params![encode_scope(key), key.tx])?)
+    }
+
+    fn flush(&mut self) -> StorageResult<()> {
+        self.commit()
+    }
+}
+
+// An accounts row, balances left to the caller
+fn decode_account(row: &Row) -> StorageResult<Account> {
+    let mut account = Account::new();
+    account.locked = row.get(1)?;
+    account.status = decode_status(&row.get::<_, String>(2)?)?;
+    account.risk = RiskCounters {
+        disputes: row.get(3)?,
+        chargebacks: row.get(4)?,
+        rejected_withdrawals: row.get(5)?,
+    };
+    Ok(account)
+}
+
+// A balances row, its client at 0
+fn decode_balance(row: &Row) -> StorageResult<(Option<Currency>, Balance)> {
+    let currency = decode_currency(&row.get::<_, String>(1)?)?;
+    let available = decode_decimal(&row.get::<_, String>(2)?)?;
+    let held = decode_decimal(&row.get::<_, String>(3)?)?;
+    Ok((currency, Balance { available, held }))
+}
+
+// The columns of a transactions row from type onwards, starting at index 'at'
+fn decode_record(row: &Row, at: usize) -> StorageResult<TransactionRecord> {
+    let currency: Option<String> = row.get(at + 4)?;
+    let mut record = TransactionRecord::new(
+        &decode_tx_type(&row.get::<_, String>(at)?)?,
+        row.get(at + 1)?,
+        decode_decimal(&row.get::<_, String>(at + 2)?)?,
+    )
+    .with_timestamp(row.get::<_, Option<i64>>(at + 3)?.map(|t| t as u64))
+    .with_currency(
+        currency
+            .as_deref()
+            .map(decode_currency)
+            .transpose()?
+            .flatten(),
+    );
+    record.set_disputed(row.get(at + 5)?);
+    if row.get(at + 6)? {
+        record.set_resolved();
+    }
+    if row.get(at + 7)? {
+        record.set_charged_back();
+    }
+    record.set_dispute_count(row.get(at + 8)?);
+    if let Some(partial) = row.get::<_, Option<String>>(at + 9)? {
+        record.set_disputed_amount(decode_decimal(&partial)?);
+    }
+    if row.get(at + 10)? {
+        record.set_reversed();
+    }
+    if row.get(at + 11)? {
+        record.set_rejected();
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{AccountStatus, TransactionType};
+    use rust_decimal::dec;
+
+    #[test]
+    fn test_state_survives_reopen_across_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("octopus.db");
+        let mut record = TransactionRecord::new(&TransactionType::Withdrawal, 3, dec!(1.5))
+            .with_timestamp(Some(42))
+            .with_currency(Currency::new("EUR"));
+        record.dispute(None);
+        record.set_charged_back();
+        record.set_reversed();
+        let mut account = Account::new();
+        account.deposit(None, dec!(1.25)).unwrap();
+        account.deposit(Currency::new("GBP"), dec!(2.5)).unwrap();
+        account.locked = true;
+        account.status = AccountStatus::Open;
+        account.risk.chargebacks = 2;
+        {
+            let mut storage = SqliteStorage::open(&path).unwrap();
+            storage.put_account(3, &account).unwrap();
+            storage.put_record(RecordKey::from(7), &record).unwrap();
+            let scoped = RecordKey {
+                client: Some(3),
+                tx: 7,
+            };
+            let mut partial = record;
+            partial.set_disputed(false);
+            partial.dispute(Some(dec!(0.5)));
+            storage.put_record(scoped, &partial).unwrap();
+            let mut rejected = TransactionRecord::new(&TransactionType::Deposit, 3, dec!(2));
+            rejected.set_rejected();
+            storage.put_record(RecordKey::from(9), &rejected).unwrap();
+            // Readable before the batch is committed
+            assert_eq!(storage.record(scoped).unwrap(), Some(partial));
+            storage.flush().unwrap();
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        let stored = storage.account(3).unwrap().unwrap();
+        assert_eq!(
+            stored.balances().collect::<Vec<_>>(),
+            account.balances().collect::<Vec<_>>()
+        );
+        assert!(stored.is_locked());
+        assert_eq!(stored.status(), AccountStatus::Open);
+        assert_eq!(stored.risk(), account.risk);
+        assert!(storage.account(4).unwrap().is_none());
+        assert_eq!(storage.record(RecordKey::from(7)).unwrap(), Some(record));
+        let scoped = storage.record(RecordKey {
+            client: Some(3),
+            tx: 7,
+        });
+        assert_eq!(scoped.unwrap().unwrap().disputed_amount(), dec!(0.5));
+        assert!(!storage.contains_record(RecordKey::from(8)).unwrap());
+        let rejected = storage.record(RecordKey::from(9)).unwrap().unwrap();
+        assert!(rejected.was_rejected() && !rejected.is_disputed());
+        assert_eq!(storage.records().count(), 3);
+        assert_eq!(storage.accounts().count(), 1);
+    }
+}
@@ -0,0 +1,130 @@
+use octopus::{
+    ClientID, PrecisionPolicy, RecordKey, Transaction, TransactionError, TransactionType, TxIdScope,
+};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+// The checks of --validate, which need no balances: amounts, duplicate tx ids and disputes
+// referring to a transaction that isn't in the input. Rows failing them would be rejected by a
+// real run too, with the same error code, though a real run may reject more rows for lack of
+// funds or locked accounts.
+pub struct Validator {
+    precision: PrecisionPolicy,
+    tx_id_scope: TxIdScope,
+    // Client of every transaction a dispute could refer to
+    clients: HashMap<RecordKey, ClientID>,
+}
+
+impl Validator {
+    pub fn new(precision: PrecisionPolicy, tx_id_scope: TxIdScope) -> Self {
+        Validator {
+            precision,
+            tx_id_scope,
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let key = self.tx_id_scope.key(transaction.client, transaction.tx);
+        match transaction.tx_type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Convert
+            | TransactionType::Fee => {
+                let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+                if self.precision.normalize(amount) <= Decimal::ZERO {
+                    return Err(TransactionError::NegativeAmount);
+                }
+                if transaction.tx_type == TransactionType::Transfer
+                    && transaction.to_client.is_none()
+                {
+                    return Err(TransactionError::MissingDestination);
+                }
+                if self.clients.contains_key(&key) {
+                    return Err(TransactionError::Duplicate);
+                }
+                self.clients.insert(key, transaction.client);
+                Ok(())
+            }
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                match self.clients.get(&key) {
+                    Some(&client) if client == transaction.client => Ok(()),
+                    Some(_) => Err(TransactionError::InvalidDispute),
+                    None => Err(TransactionError::ReferenceNotFound),
+                }
+            }
+            TransactionType::Reversal => match self.clients.get(&key) {
+                Some(&client) if client == transaction.client => Ok(()),
+                Some(_) => Err(TransactionError::InvalidReversal),
+                None => Err(TransactionError::ReferenceNotFound),
+            },
+            TransactionType::Unlock
+            | TransactionType::Open
+            | TransactionType::Close
+            | TransactionType::Settle => Ok(()),
+            TransactionType::Other(_) => Err(TransactionError::UnknownType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octopus::TransactionID;
+
+    fn transaction(
+        tx_type: TransactionType,
+        client: ClientID,
+        tx: TransactionID,
+        amount: Option<Decimal>,
+    ) -> Transaction {
+        Transaction::new(tx_type, client, tx, amount)
+    }
+
+    #[test]
+    fn test_validator_codes() {
+        let mut validator = Validator::new(PrecisionPolicy::default(), TxIdScope::Global);
+        let code = |validator: &mut Validator, transaction| {
+            validator
+                .check(&transaction)
+                .err()
+                .map(|err: TransactionError| err.code())
+        };
+        let deposit = |tx, amount| transaction(TransactionType::Deposit, 1, tx, amount);
+        assert_eq!(code(&mut validator, deposit(1, Some(Decimal::ONE))), None);
+        assert_eq!(
+            code(&mut validator, deposit(1, Some(Decimal::ONE))),
+            Some("duplicate")
+        );
+        assert_eq!(
+            code(&mut validator, deposit(2, Some(-Decimal::ONE))),
+            Some("negative_amount")
+        );
+        assert_eq!(
+            code(&mut validator, deposit(3, None)),
+            Some("missing_amount")
+        );
+        assert_eq!(
+            code(
+                &mut validator,
+                transaction(TransactionType::Dispute, 1, 1, None)
+            ),
+            None
+        );
+        assert_eq!(
+            code(
+                &mut validator,
+                transaction(TransactionType::Dispute, 2, 1, None)
+            ),
+            Some("invalid_dispute")
+        );
+        assert_eq!(
+            code(
+                &mut validator,
+                transaction(TransactionType::Chargeback, 1, 9, None)
+            ),
+            Some("reference_not_found")
+        );
+    }
+}